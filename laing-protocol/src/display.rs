@@ -0,0 +1,194 @@
+//! Decoding of the handset display, which the controller reports as raw seven-segment patterns.
+//!
+//! The display is reported in two registers. The low byte of the first register is the rightmost
+//! digit and the high byte is the middle digit, with the top bit of the high byte being the decimal
+//! point. The low byte of the second register is the leftmost digit. In each byte, bits 0 through 6
+//! are segments a through g.
+//!
+//! That is the layout of the LTC302. Other controller revisions are handled by the other
+//! `Decoder`s.
+
+use alloc::string::{String, ToString};
+
+/// What the handset display is showing.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Reading {
+    /// A height, in tenths of the display unit.
+    Height(u16),
+    /// Nothing is lit.
+    Blank,
+    /// Something other than a height, such as an error code.
+    Text(String),
+}
+
+/// Why the desk cannot move, as the controller signals with a code on the display.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Condition {
+    /// The desk is at the end of its range.
+    EndOfTravel,
+    /// The motors are drawing too much current, as when lifting too much or pushing on something.
+    Overload,
+    /// The motors have run too long and must cool down.
+    Overheat,
+}
+
+impl Condition {
+    pub const ALL: [Condition; 3] = [
+        Condition::EndOfTravel,
+        Condition::Overload,
+        Condition::Overheat,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Condition::EndOfTravel => "end_of_travel",
+            Condition::Overload => "overload",
+            Condition::Overheat => "overheat",
+        }
+    }
+}
+
+/// Decode a seven-segment pattern showing a digit.
+pub fn decode_digit(segments: u8) -> Option<u8> {
+    match segments & 0x7f {
+        0b0111111 => Some(0),
+        0b0000110 => Some(1),
+        0b1011011 => Some(2),
+        0b1001111 => Some(3),
+        0b1100110 => Some(4),
+        0b1101101 => Some(5),
+        0b1111101 => Some(6),
+        0b0000111 => Some(7),
+        0b1111111 => Some(8),
+        0b1101111 => Some(9),
+        _ => None,
+    }
+}
+
+/// Decode a seven-segment pattern showing any character, including the letters used for error
+/// codes.
+///
+/// Patterns that look the same as digits (such as `O` and `S`) are returned as digits.
+pub fn decode_char(segments: u8) -> Option<char> {
+    if let Some(digit) = decode_digit(segments) {
+        return char::from_digit(u32::from(digit), 10);
+    }
+    match segments & 0x7f {
+        0b0000000 => Some(' '),
+        0b1000000 => Some('-'),
+        0b1110111 => Some('A'),
+        0b1111100 => Some('b'),
+        0b0111001 => Some('C'),
+        0b1011110 => Some('d'),
+        0b1111001 => Some('E'),
+        0b1110001 => Some('F'),
+        0b1110110 => Some('H'),
+        0b0111000 => Some('L'),
+        0b1010100 => Some('n'),
+        0b1011100 => Some('o'),
+        0b1110011 => Some('P'),
+        0b1010000 => Some('r'),
+        0b1111000 => Some('t'),
+        0b0111110 => Some('U'),
+        _ => None,
+    }
+}
+
+/// Decode the display registers as a height, in tenths of the display unit.
+pub fn decode(values: &[u16; 2]) -> Option<u16> {
+    if values[0] & 0x8080 != 0x8000 || values[1] & 0xff80 != 0 {
+        None
+    } else {
+        Some(
+            decode_digit((values[0] & 0xff) as u8)? as u16
+                + 10 * decode_digit((values[0] >> 8 & 0x7f) as u8)? as u16
+                + 100 * decode_digit((values[1] & 0xff) as u8)? as u16,
+        )
+    }
+}
+
+/// Read the display registers.
+///
+/// Returns `None` if the registers do not look like a display at all.
+pub fn read(values: &[u16; 2]) -> Option<Reading> {
+    if values[1] & 0xff00 != 0 {
+        return None;
+    }
+    if let Some(height) = decode(values) {
+        return Some(Reading::Height(height));
+    }
+    let segments = [
+        (values[1] & 0x7f) as u8,
+        (values[0] >> 8 & 0x7f) as u8,
+        (values[0] & 0x7f) as u8,
+    ];
+    if segments == [0, 0, 0] {
+        return Some(Reading::Blank);
+    }
+    segments
+        .iter()
+        .map(|&segments| decode_char(segments))
+        .collect::<Option<String>>()
+        .map(|text| Reading::Text(text.trim().to_string()))
+}
+
+/// Find the display in the state registers, for firmware that reports it somewhere other than the
+/// first two, by looking for the first pair that reads as a height.
+///
+/// Zero is not a believable height, and is what empty registers read as in BCD, so it is skipped.
+pub fn find(decoder: &dyn Decoder, registers: &[u16]) -> Option<usize> {
+    registers.windows(2).position(|pair| {
+        matches!(
+            decoder.read(pair.try_into().unwrap()),
+            Some(Reading::Height(height)) if height > 0
+        )
+    })
+}
+
+/// A way of reading the display registers, which differs between controller revisions.
+pub trait Decoder: Send + Sync {
+    /// Read the display registers, returning `None` if they do not look like a display at all.
+    fn read(&self, values: &[u16; 2]) -> Option<Reading>;
+}
+
+/// Seven-segment patterns with the rightmost digit in the low byte of the first register, as
+/// described above.
+pub struct SevenSegmentLe;
+
+impl Decoder for SevenSegmentLe {
+    fn read(&self, values: &[u16; 2]) -> Option<Reading> {
+        read(values)
+    }
+}
+
+/// Seven-segment patterns with the digits in the opposite order: the leftmost digit in the low
+/// byte of the first register, the middle digit and decimal point in its high byte, and the
+/// rightmost digit in the low byte of the second register.
+pub struct SevenSegmentBe;
+
+impl Decoder for SevenSegmentBe {
+    fn read(&self, values: &[u16; 2]) -> Option<Reading> {
+        read(&[
+            (values[0] & 0xff00) | (values[1] & 0x00ff),
+            (values[1] & 0xff00) | (values[0] & 0x00ff),
+        ])
+    }
+}
+
+/// The height as three binary-coded decimal digits in the first register, with the rightmost digit
+/// in the lowest nibble. The second register must be zero. These controllers cannot show text.
+pub struct Bcd;
+
+impl Decoder for Bcd {
+    fn read(&self, values: &[u16; 2]) -> Option<Reading> {
+        if values[0] & 0xf000 != 0 || values[1] != 0 {
+            return None;
+        }
+        (0..3)
+            .try_fold(0, |height, digit| {
+                let nibble = values[0] >> (4 * (2 - digit)) & 0xf;
+                (nibble < 10).then(|| height * 10 + nibble)
+            })
+            .map(Reading::Height)
+    }
+}
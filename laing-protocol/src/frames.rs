@@ -0,0 +1,66 @@
+//! The command registers the handset writes, and where the controller keeps its registers.
+
+/// The first of the registers the handset writes its command to.
+pub const COMMAND_ADDRESS: u16 = 0xa8c;
+/// How many command registers there are.
+pub const COMMAND_LEN: usize = 14;
+/// The first of the registers the controller reports its state in.
+pub const STATE_ADDRESS: u16 = 0x9c4;
+/// How many state registers are read.
+pub const STATE_LEN: usize = 20;
+
+/// The key that wakes the controller, as the handset sends when it is first touched.
+pub const KEY_WAKE: u16 = 9;
+
+/// Build the command registers as the handset writes them.
+///
+/// - Register 2 is `key`, the key being pressed: 1 to 4 for the presets, [`KEY_WAKE`], or 0 for
+///   none.
+/// - Register 3 is `held`, which is 0 for the first message of a press and the same as `key` for
+///   the messages that follow while it is held. The desk only keeps moving while it is held.
+/// - Register 6 is 1 while the handset is `active`. Clearing it lets the controller's display time
+///   out, as it does when the handset has not been touched for a while.
+/// - Registers 4, 5, and 7 to 10 are the same in every message the handset sends, and the rest are
+///   zero. What they mean is not known.
+pub const fn request(key: u16, held: u16, active: bool) -> [u16; COMMAND_LEN] {
+    [
+        0x0000,
+        0x0000,
+        key,
+        held,
+        0x0008,
+        0x0005,
+        active as u16,
+        0x005A,
+        0x0011,
+        0x0008,
+        0x0017,
+        0x0000,
+        0x0000,
+        0x0000,
+    ]
+}
+
+/// The first message sent, to wake the controller.
+pub const WAKE: [u16; COMMAND_LEN] = request(KEY_WAKE, 0, true);
+/// The handset in use with no key pressed, which also stops the desk.
+pub const IDLE: [u16; COMMAND_LEN] = request(0, 0, true);
+/// The idle message with the handset's activity flag cleared, so the controller lets its display
+/// time out.
+///
+/// This is a guess from the activity flag's meaning in [`IDLE`]; no capture of a handset has shown
+/// it being sent.
+pub const STANDBY: [u16; COMMAND_LEN] = request(0, 0, false);
+
+/// The messages that move the desk to a preset: the first of a press, and the one repeated while
+/// it is held.
+pub const fn press(preset: u16) -> [[u16; COMMAND_LEN]; 2] {
+    [request(preset, 0, true), request(preset, preset, true)]
+}
+
+static PRESETS: [[[u16; COMMAND_LEN]; 2]; 4] = [press(1), press(2), press(3), press(4)];
+
+/// The messages that move the desk to a preset, for presets 1 to 4.
+pub fn preset(preset: u8) -> Option<&'static [[u16; COMMAND_LEN]; 2]> {
+    PRESETS.get(usize::from(preset).checked_sub(1)?)
+}
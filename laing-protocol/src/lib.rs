@@ -0,0 +1,26 @@
+//! The messages a Laing Innotech desk motor controller, such as the LTC302, exchanges with its
+//! handset over Modbus RTU, for controlling the desk in the handset's place.
+//!
+//! This only needs `core` and `alloc`, so the same logic can run on a microcontroller that is wired
+//! to the controller instead of a computer.
+//!
+//! # Registers
+//!
+//! The handset is the Modbus client and the controller is server 1, at 57600 baud. Every 500ms or
+//! so the handset writes the 14 command registers at [`frames::COMMAND_ADDRESS`] and reads back the
+//! 20 state registers at [`frames::STATE_ADDRESS`], in one Read/Write Multiple Registers request
+//! where the controller supports it.
+//!
+//! - The command registers say which key is pressed and whether the handset is in use. See
+//!   [`frames::request`] for the registers that are understood.
+//! - The state registers include what the handset display should show, which is how the height is
+//!   read. See [`display`] for how it is encoded.
+//!
+//! [`rtu`] encodes that request and decodes the answer for ports without a Modbus library.
+#![no_std]
+
+extern crate alloc;
+
+pub mod display;
+pub mod frames;
+pub mod rtu;
@@ -0,0 +1,129 @@
+//! Modbus RTU framing of the exchange with the controller, for ports without a Modbus library.
+//!
+//! Each exchange is one Read/Write Multiple Registers request (function 0x17) writing the command
+//! registers and reading the state registers. Frames end with a CRC-16 sent low byte first, and
+//! are separated by 3.5 characters of silence on the line, which is up to the caller.
+
+use crate::frames::{COMMAND_ADDRESS, COMMAND_LEN, STATE_ADDRESS, STATE_LEN};
+
+/// The controller's Modbus server address.
+pub const SERVER: u8 = 1;
+/// The length of an exchange request, in bytes.
+pub const REQUEST_LEN: usize = 11 + 2 * COMMAND_LEN + 2;
+/// The length of the controller's answer to an exchange, in bytes.
+pub const RESPONSE_LEN: usize = 3 + 2 * STATE_LEN + 2;
+/// The length of an exception answer, in bytes.
+pub const EXCEPTION_LEN: usize = 5;
+
+const READ_WRITE_MULTIPLE_REGISTERS: u8 = 0x17;
+/// Set in the function code of an answer that is an exception.
+const EXCEPTION_FLAG: u8 = 0x80;
+
+/// A Modbus exception code reported by the controller.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Exception {
+    IllegalFunction,
+    IllegalDataAddress,
+    IllegalDataValue,
+    ServerDeviceFailure,
+    Acknowledge,
+    ServerDeviceBusy,
+    Other,
+}
+
+impl Exception {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            1 => Exception::IllegalFunction,
+            2 => Exception::IllegalDataAddress,
+            3 => Exception::IllegalDataValue,
+            4 => Exception::ServerDeviceFailure,
+            5 => Exception::Acknowledge,
+            6 => Exception::ServerDeviceBusy,
+            _ => Exception::Other,
+        }
+    }
+}
+
+/// Why an answer could not be decoded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DecodeError {
+    /// Not all of the answer has arrived yet.
+    Incomplete,
+    /// The checksum did not match, as when the answer was damaged on the line.
+    Checksum,
+    /// The answer came from another server, or was to some other request.
+    Unexpected,
+    /// The controller answered with an exception instead of its state.
+    Exception(Exception),
+}
+
+/// The CRC-16 that ends each frame, as Modbus calculates it.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xffff;
+    for &byte in bytes {
+        crc ^= u16::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 0 {
+                crc >> 1
+            } else {
+                crc >> 1 ^ 0xa001
+            };
+        }
+    }
+    crc
+}
+
+/// Encode a request writing `command` to the command registers and reading the state registers.
+pub fn encode_exchange(server: u8, command: &[u16; COMMAND_LEN]) -> [u8; REQUEST_LEN] {
+    let mut frame = [0; REQUEST_LEN];
+    frame[0] = server;
+    frame[1] = READ_WRITE_MULTIPLE_REGISTERS;
+    frame[2..4].copy_from_slice(&STATE_ADDRESS.to_be_bytes());
+    frame[4..6].copy_from_slice(&(STATE_LEN as u16).to_be_bytes());
+    frame[6..8].copy_from_slice(&COMMAND_ADDRESS.to_be_bytes());
+    frame[8..10].copy_from_slice(&(COMMAND_LEN as u16).to_be_bytes());
+    frame[10] = (2 * COMMAND_LEN) as u8;
+    for (bytes, register) in frame[11..].chunks_exact_mut(2).zip(command) {
+        bytes.copy_from_slice(&register.to_be_bytes());
+    }
+    let crc = crc16(&frame[..REQUEST_LEN - 2]);
+    frame[REQUEST_LEN - 2..].copy_from_slice(&crc.to_le_bytes());
+    frame
+}
+
+/// Decode the controller's answer to an exchange with `server`, returning the state registers.
+///
+/// `bytes` is what has been received since the request was sent. Anything after the answer is
+/// ignored.
+pub fn decode_exchange(server: u8, bytes: &[u8]) -> Result<[u16; STATE_LEN], DecodeError> {
+    if bytes.len() < EXCEPTION_LEN {
+        return Err(DecodeError::Incomplete);
+    }
+    if bytes[0] != server {
+        return Err(DecodeError::Unexpected);
+    }
+    if bytes[1] == READ_WRITE_MULTIPLE_REGISTERS | EXCEPTION_FLAG {
+        check_crc(&bytes[..EXCEPTION_LEN])?;
+        return Err(DecodeError::Exception(Exception::from_code(bytes[2])));
+    }
+    if bytes[1] != READ_WRITE_MULTIPLE_REGISTERS || usize::from(bytes[2]) != 2 * STATE_LEN {
+        return Err(DecodeError::Unexpected);
+    }
+    let frame = bytes.get(..RESPONSE_LEN).ok_or(DecodeError::Incomplete)?;
+    check_crc(frame)?;
+    let mut registers = [0; STATE_LEN];
+    for (register, bytes) in registers.iter_mut().zip(frame[3..].chunks_exact(2)) {
+        *register = u16::from_be_bytes([bytes[0], bytes[1]]);
+    }
+    Ok(registers)
+}
+
+fn check_crc(frame: &[u8]) -> Result<(), DecodeError> {
+    let (body, crc) = frame.split_at(frame.len() - 2);
+    if crc16(body).to_le_bytes() == crc {
+        Ok(())
+    } else {
+        Err(DecodeError::Checksum)
+    }
+}
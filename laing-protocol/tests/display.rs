@@ -0,0 +1,95 @@
+use laing_protocol::display::{self, read, Bcd, Decoder, Reading, SevenSegmentBe, SevenSegmentLe};
+
+#[test]
+fn golden_vectors() {
+    let vectors: Vec<serde_json::Value> =
+        serde_json::from_str(include_str!("data/display.json")).unwrap();
+    for vector in vectors {
+        let description = vector["description"].as_str().unwrap();
+        let registers: [u16; 2] = serde_json::from_value(vector["registers"].clone()).unwrap();
+        let expected = &vector["expected"];
+        let expected = if expected.is_null() {
+            None
+        } else if expected == "blank" {
+            Some(Reading::Blank)
+        } else if let Some(height) = expected.get("height") {
+            Some(Reading::Height(height.as_u64().unwrap() as u16))
+        } else {
+            Some(Reading::Text(
+                expected["text"].as_str().unwrap().to_string(),
+            ))
+        };
+        assert_eq!(read(&registers), expected, "{}", description);
+    }
+}
+
+#[test]
+fn other_encodings() {
+    // 27.5 on each layout.
+    assert_eq!(
+        SevenSegmentLe.read(&[0x876d, 0x005b]),
+        Some(Reading::Height(275))
+    );
+    assert_eq!(
+        SevenSegmentBe.read(&[0x875b, 0x006d]),
+        Some(Reading::Height(275))
+    );
+    assert_eq!(Bcd.read(&[0x0275, 0x0000]), Some(Reading::Height(275)));
+    assert_eq!(Bcd.read(&[0x027a, 0x0000]), None);
+    assert_eq!(Bcd.read(&[0x0275, 0x0001]), None);
+}
+
+#[test]
+fn finds_shifted_display() {
+    // 27.5 on an LTC302, three registers in.
+    let mut registers = [0; 20];
+    registers[3] = 0x876d;
+    registers[4] = 0x005b;
+    assert_eq!(display::find(&SevenSegmentLe, &registers), Some(3));
+    assert_eq!(display::find(&SevenSegmentLe, &[0; 20]), None);
+    // Empty registers are a height of zero in BCD.
+    assert_eq!(display::find(&Bcd, &[0; 20]), None);
+}
+
+/// The seven-segment pattern of each digit.
+const DIGITS: [u16; 10] = [0x3f, 0x06, 0x5b, 0x4f, 0x66, 0x6d, 0x7d, 0x07, 0x7f, 0x6f];
+
+#[test]
+fn every_height_decodes() {
+    for height in 0..1000u16 {
+        let [left, middle, right] = [height / 100, height / 10 % 10, height % 10].map(usize::from);
+        let le = [
+            0x8000 | DIGITS[middle] << 8 | DIGITS[right],
+            DIGITS[left],
+        ];
+        assert_eq!(SevenSegmentLe.read(&le), Some(Reading::Height(height)));
+        let be = [
+            0x8000 | DIGITS[middle] << 8 | DIGITS[left],
+            DIGITS[right],
+        ];
+        assert_eq!(SevenSegmentBe.read(&be), Some(Reading::Height(height)));
+        let bcd = [(left << 8 | middle << 4 | right) as u16, 0];
+        assert_eq!(Bcd.read(&bcd), Some(Reading::Height(height)));
+    }
+}
+
+#[test]
+fn every_segment_pattern() {
+    let mut recognized = 0;
+    for segments in 0..=0x7fu8 {
+        let digit = display::decode_digit(segments);
+        if let Some(digit) = digit {
+            assert_eq!(u16::from(segments), DIGITS[usize::from(digit)]);
+        }
+        if let Some(c) = display::decode_char(segments) {
+            recognized += 1;
+            assert_eq!(c.to_digit(10), digit.map(u32::from), "{:#04x}", segments);
+        } else {
+            assert_eq!(digit, None);
+        }
+        // The top bit is the decimal point, not a segment.
+        assert_eq!(display::decode_char(segments | 0x80), display::decode_char(segments));
+    }
+    // Ten digits, a blank, a dash, and fourteen letters.
+    assert_eq!(recognized, 26);
+}
@@ -0,0 +1,47 @@
+use laing_protocol::frames::{self, request, IDLE, STANDBY, WAKE};
+
+/// The messages as captured from a handset.
+const CAPTURED_WAKE: [u16; 14] = [
+    0x0000, 0x0000, 0x0009, 0x0000, 0x0008, 0x0005, 0x0001, 0x005A, 0x0011, 0x0008, 0x0017, 0x0000,
+    0x0000, 0x0000,
+];
+const CAPTURED_IDLE: [u16; 14] = [
+    0x0000, 0x0000, 0x0000, 0x0000, 0x0008, 0x0005, 0x0001, 0x005A, 0x0011, 0x0008, 0x0017, 0x0000,
+    0x0000, 0x0000,
+];
+const CAPTURED_PRESET2: [[u16; 14]; 2] = [
+    [
+        0x0000, 0x0000, 0x0002, 0x0000, 0x0008, 0x0005, 0x0001, 0x005A, 0x0011, 0x0008, 0x0017,
+        0x0000, 0x0000, 0x0000,
+    ],
+    [
+        0x0000, 0x0000, 0x0002, 0x0002, 0x0008, 0x0005, 0x0001, 0x005A, 0x0011, 0x0008, 0x0017,
+        0x0000, 0x0000, 0x0000,
+    ],
+];
+
+#[test]
+fn matches_the_handset() {
+    assert_eq!(WAKE, CAPTURED_WAKE);
+    assert_eq!(IDLE, CAPTURED_IDLE);
+    assert_eq!(frames::preset(2), Some(&CAPTURED_PRESET2));
+}
+
+#[test]
+fn standby_only_clears_the_activity_flag() {
+    let differences: Vec<usize> = (0..14).filter(|&i| STANDBY[i] != IDLE[i]).collect();
+    assert_eq!(differences, [6]);
+    assert_eq!(STANDBY[6], 0);
+}
+
+#[test]
+fn every_preset() {
+    for preset in 1..=4 {
+        let frames = frames::preset(preset).unwrap();
+        let key = u16::from(preset);
+        assert_eq!(frames[0], request(key, 0, true));
+        assert_eq!(frames[1], request(key, key, true));
+    }
+    assert_eq!(frames::preset(0), None);
+    assert_eq!(frames::preset(5), None);
+}
@@ -0,0 +1,125 @@
+use laing_protocol::{
+    frames::{IDLE, STATE_LEN, WAKE},
+    rtu::{
+        crc16, decode_exchange, encode_exchange, DecodeError, Exception, REQUEST_LEN,
+        RESPONSE_LEN, SERVER,
+    },
+};
+
+#[test]
+fn crc_matches_modbus() {
+    // The standard check value for CRC-16/MODBUS.
+    assert_eq!(crc16(b"123456789"), 0x4b37);
+    // Read Holding Registers 0 to 9 from server 1, as commonly given in examples.
+    assert_eq!(
+        crc16(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0a]).to_le_bytes(),
+        [0xc5, 0xcd]
+    );
+    assert_eq!(crc16(&[]), 0xffff);
+}
+
+#[test]
+fn encodes_the_exchange() {
+    let frame = encode_exchange(SERVER, &WAKE);
+    assert_eq!(frame.len(), REQUEST_LEN);
+    assert_eq!(
+        frame[..11],
+        [0x01, 0x17, 0x09, 0xc4, 0x00, 0x14, 0x0a, 0x8c, 0x00, 0x0e, 0x1c]
+    );
+    // The wake key, in register 2.
+    assert_eq!(frame[15..17], [0x00, 0x09]);
+    // A frame followed by its own CRC has a CRC of zero.
+    assert_eq!(crc16(&frame), 0);
+    assert_ne!(encode_exchange(SERVER, &IDLE), frame);
+}
+
+fn response(server: u8, registers: &[u16; STATE_LEN]) -> Vec<u8> {
+    let mut frame = vec![server, 0x17, 40];
+    for register in registers {
+        frame.extend_from_slice(&register.to_be_bytes());
+    }
+    let crc = crc16(&frame);
+    frame.extend_from_slice(&crc.to_le_bytes());
+    frame
+}
+
+#[test]
+fn decodes_the_state() {
+    let mut registers = [0; STATE_LEN];
+    // 27.5 on an LTC302.
+    registers[0] = 0x876d;
+    registers[1] = 0x005b;
+    registers[19] = 0xffff;
+    let mut frame = response(SERVER, &registers);
+    assert_eq!(frame.len(), RESPONSE_LEN);
+    assert_eq!(decode_exchange(SERVER, &frame), Ok(registers));
+
+    // Anything after the answer is not part of it.
+    frame.push(0);
+    assert_eq!(decode_exchange(SERVER, &frame), Ok(registers));
+}
+
+#[test]
+fn waits_for_the_whole_answer() {
+    let frame = response(SERVER, &[0; STATE_LEN]);
+    for len in 0..RESPONSE_LEN {
+        assert_eq!(
+            decode_exchange(SERVER, &frame[..len]),
+            Err(DecodeError::Incomplete),
+            "{} bytes",
+            len
+        );
+    }
+}
+
+#[test]
+fn rejects_damaged_and_unexpected_answers() {
+    let frame = response(SERVER, &[0; STATE_LEN]);
+    // A flipped bit anywhere after the header fails the checksum.
+    for byte in 3..RESPONSE_LEN {
+        for bit in 0..8 {
+            let mut damaged = frame.clone();
+            damaged[byte] ^= 1 << bit;
+            assert_eq!(
+                decode_exchange(SERVER, &damaged),
+                Err(DecodeError::Checksum),
+                "byte {} bit {}",
+                byte,
+                bit
+            );
+        }
+    }
+    assert_eq!(
+        decode_exchange(2, &response(SERVER, &[0; STATE_LEN])),
+        Err(DecodeError::Unexpected)
+    );
+    let mut other_function = frame.clone();
+    other_function[1] = 0x03;
+    assert_eq!(
+        decode_exchange(SERVER, &other_function),
+        Err(DecodeError::Unexpected)
+    );
+}
+
+#[test]
+fn decodes_exceptions() {
+    for (code, exception) in [
+        (1, Exception::IllegalFunction),
+        (2, Exception::IllegalDataAddress),
+        (3, Exception::IllegalDataValue),
+        (4, Exception::ServerDeviceFailure),
+        (5, Exception::Acknowledge),
+        (6, Exception::ServerDeviceBusy),
+        (11, Exception::Other),
+    ] {
+        let mut frame = vec![SERVER, 0x97, code];
+        let crc = crc16(&frame);
+        frame.extend_from_slice(&crc.to_le_bytes());
+        assert_eq!(
+            decode_exchange(SERVER, &frame),
+            Err(DecodeError::Exception(exception))
+        );
+        frame[2] ^= 0x10;
+        assert_eq!(decode_exchange(SERVER, &frame), Err(DecodeError::Checksum));
+    }
+}
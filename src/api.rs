@@ -0,0 +1,571 @@
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use hyper::{
+    header::HeaderValue,
+    server::conn::{AddrStream, Http},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::{debug, info, warn};
+use rustls::{NoClientAuth, ServerConfig};
+use tokio::{net::TcpListener, sync::broadcast::error::RecvError};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    events::DeskEvent,
+    mqtt::{Command, RegisterRead, SceneCommand, Source, State},
+    settings::{ApiSettings, ApiTlsSettings},
+    tls,
+};
+
+/// A page for controlling the desk from a browser, served at `/`.
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// How many events to remember for `GET /events`.
+const EVENT_HISTORY: usize = 50;
+
+/// How long to wait for registers, which are only read once any movement has finished.
+const REGISTER_READ_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// A local HTTP API for administering the daemon, used by `laing-ctl`.
+///
+/// With `tls` set, the same API is also served over HTTPS on a second address, so dashboards
+/// served over HTTPS can call it.
+///
+/// - `GET /` is a page for controlling the desk from a browser, such as a phone's.
+/// - `GET /status` returns the current height, serial port statistics (including how often the port
+///   was revoked to recover), and diagnostics.
+/// - `POST /preset/<n>` moves to preset `n`.
+/// - `POST /refresh` asks the controller for its height.
+/// - `POST /sleep` puts the controller's display into standby.
+/// - `POST /calibrate` visits every preset to learn its height.
+/// - `POST /snapshot/<name>` saves the current height as the scene `name`.
+/// - `POST /restore/<name>` moves to the height saved as the scene `name`.
+/// - The commands above answer with an `id`, and `GET /commands/<id>/progress` streams that
+///   command's progress as server-sent events, with the height and speed while it runs, until it
+///   is done.
+/// - `POST /reload-config` re-reads the settings file.
+/// - `POST /mqtt-restart` reconnects to the broker with the MQTT settings from the settings file.
+/// - `POST /discovery-republish` publishes the Home Assistant discovery configuration again.
+/// - `POST /serial-reopen` closes the serial port and opens it again, such as after the adapter
+///   was unplugged.
+/// - `GET /events` returns recent events. With `?follow`, events are streamed as they happen, one
+///   JSON object per line, or as server-sent events if the client accepts `text/event-stream`.
+/// - `GET /history` returns recorded heights, and `GET /history/hourly` returns hourly summaries
+///   of older heights. Both accept `from` and `to` in seconds since 1970.
+/// - `GET /registers?addr=<address>&count=<count>` reads registers through the daemon's own
+///   connection to the controller, for `laing-controller snapshot` while the daemon has the port.
+/// - `GET /entities` returns the Home Assistant entities as published for discovery, with their
+///   names, topics, and units, for frontends that want to present the desk the same way.
+///
+/// The `POST` requests and `GET /registers` act on the desk, so with `token` set they need an
+/// `Authorization: Bearer <token>` header, and without it they are only accepted from this
+/// computer.
+pub async fn api_loop(settings: &ApiSettings, state: State) -> Result<()> {
+    let addr: SocketAddr = settings.bind.parse().context("Invalid API bind address")?;
+
+    let history = Arc::new(Mutex::new(VecDeque::with_capacity(EVENT_HISTORY)));
+    let mut events = state.events.subscribe();
+    let history_writer = history.clone();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                // Heights and speeds are reported several times a second and would push everything
+                // else out.
+                Ok(
+                    DeskEvent::Height(_) | DeskEvent::HeightChanged { .. } | DeskEvent::Speed(_),
+                ) => {}
+                Ok(event) => {
+                    let mut history = history_writer.lock().unwrap();
+                    if history.len() == EVENT_HISTORY {
+                        history.pop_front();
+                    }
+                    history.push_back(event.to_json());
+                }
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let token: Option<Arc<str>> = settings.token.as_deref().map(Arc::from);
+    let secure_state = state.clone();
+    let secure_history = history.clone();
+    let secure_token = token.clone();
+    let secure = async {
+        match &settings.tls {
+            Some(tls) => tls_loop(tls, secure_state, secure_history, secure_token).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let state = state.clone();
+        let history = history.clone();
+        let token = token.clone();
+        let peer = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle(state.clone(), history.clone(), token.clone(), peer, request)
+            }))
+        }
+    });
+
+    info!("API listening on {}", addr);
+    let server = Server::try_bind(&addr)
+        .context("Failed to bind API")?
+        .serve(make_service);
+    tokio::select! {
+        result = server => result.context("API failed"),
+        result = secure => result,
+    }
+}
+
+/// Serve the API over HTTPS too, making a self-signed certificate first if there is none.
+async fn tls_loop(
+    settings: &ApiTlsSettings,
+    state: State,
+    history: Arc<Mutex<VecDeque<serde_json::Value>>>,
+    token: Option<Arc<str>>,
+) -> Result<()> {
+    let addr: SocketAddr = settings
+        .bind
+        .parse()
+        .context("Invalid API TLS bind address")?;
+    tls::ensure_self_signed(
+        &settings.certificate_file,
+        &settings.key_file,
+        &settings.names,
+    )?;
+    let (chain, key) = tls::read_certificate(&settings.certificate_file, &settings.key_file)?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(chain, key)
+        .context("Invalid API certificate")?;
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind(addr)
+        .await
+        .context("Failed to bind API TLS")?;
+    info!("API listening on {} with TLS", addr);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            // Usually running out of file descriptors, which passes as connections close.
+            Err(err) => {
+                warn!("Failed to accept an API connection: {}", err);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let state = state.clone();
+        let history = history.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    debug!("API TLS handshake failed: {}", err);
+                    return;
+                }
+            };
+            let service = service_fn(move |request| {
+                handle(state.clone(), history.clone(), token.clone(), peer, request)
+            });
+            if let Err(err) = Http::new().serve_connection(stream, service).await {
+                debug!("API connection failed: {}", err);
+            }
+        });
+    }
+}
+
+async fn handle(
+    state: State,
+    history: Arc<Mutex<VecDeque<serde_json::Value>>>,
+    token: Option<Arc<str>>,
+    peer: SocketAddr,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let segments: Vec<&str> = request.uri().path().trim_matches('/').split('/').collect();
+    if request.method() == Method::POST || segments[..] == ["registers"] {
+        if let Some(refusal) = authorize(token.as_deref(), peer, &request) {
+            return Ok(refusal);
+        }
+    }
+    let response = match (request.method(), &segments[..]) {
+        (&Method::GET, ["status"]) => {
+            let height = state.events.height();
+            let available = *state.available.borrow();
+            json_response(
+                StatusCode::OK,
+                serde_json::json!({
+                    "height": height,
+                    "available": available,
+                    "port": state.port_metrics.to_json(),
+                    "transfer": state.transfer_metrics.to_json(),
+                    "diagnostics": state.diagnostics.to_json(),
+                }),
+            )
+        }
+        (&Method::POST, ["preset", preset]) => match *preset {
+            "1" => send_command(&state, Command::Preset1),
+            "2" => send_command(&state, Command::Preset2),
+            "3" => send_command(&state, Command::Preset3),
+            "4" => send_command(&state, Command::Preset4),
+            _ => error_response(StatusCode::NOT_FOUND, "no such preset"),
+        },
+        (&Method::POST, ["refresh"]) => send_command(&state, Command::Refresh),
+        (&Method::POST, ["sleep"]) => send_command(&state, Command::Sleep),
+        (&Method::POST, ["calibrate"]) => send_command(&state, Command::Calibrate),
+        (&Method::POST, [command @ ("snapshot" | "restore"), name]) => {
+            match SceneCommand::new(command, name.to_string()) {
+                Some(scene) => {
+                    let id = next_command_id(&state);
+                    match state.scenes.try_send((scene, Source::Api(id))) {
+                        Ok(_) => {
+                            json_response(StatusCode::ACCEPTED, serde_json::json!({ "id": id }))
+                        }
+                        Err(_) => {
+                            error_response(StatusCode::SERVICE_UNAVAILABLE, "scene queue is full")
+                        }
+                    }
+                }
+                None => error_response(StatusCode::BAD_REQUEST, "invalid scene name"),
+            }
+        }
+        (&Method::POST, ["reload-config"]) => {
+            state.reload.notify_one();
+            json_response(StatusCode::ACCEPTED, serde_json::json!({}))
+        }
+        (&Method::POST, ["mqtt-restart"]) => {
+            state.mqtt_restart.notify_one();
+            json_response(StatusCode::ACCEPTED, serde_json::json!({}))
+        }
+        (&Method::POST, ["discovery-republish"]) => {
+            state.republish.notify_one();
+            json_response(StatusCode::ACCEPTED, serde_json::json!({}))
+        }
+        (&Method::POST, ["serial-reopen"]) => match state.reopen_serial.try_send(None) {
+            Ok(()) => json_response(StatusCode::ACCEPTED, serde_json::json!({})),
+            Err(_) => error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "the serial port is already being reopened",
+            ),
+        },
+        (&Method::GET, [""]) => Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "text/html; charset=utf-8")
+            .body(Body::from(DASHBOARD))
+            .unwrap(),
+        (&Method::GET, ["events"]) => {
+            if request.uri().query() == Some("follow") {
+                // Browsers' EventSource asks for server-sent events.
+                let sse = request
+                    .headers()
+                    .get("accept")
+                    .and_then(|accept| accept.to_str().ok())
+                    .map_or(false, |accept| accept.contains("text/event-stream"));
+                follow_events(state, sse)
+            } else {
+                let history: Vec<_> = history.lock().unwrap().iter().cloned().collect();
+                json_response(StatusCode::OK, serde_json::Value::Array(history))
+            }
+        }
+        (&Method::GET, ["history"]) => history_response(&state, request.uri().query(), false),
+        (&Method::GET, ["history", "hourly"]) => {
+            history_response(&state, request.uri().query(), true)
+        }
+        (&Method::GET, ["registers"]) => registers_response(state, request.uri().query()).await,
+        (&Method::GET, ["entities"]) => {
+            let entities = state.entities.lock().unwrap().clone();
+            if entities.is_null() {
+                error_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "the MQTT connection has not started",
+                )
+            } else {
+                json_response(StatusCode::OK, entities)
+            }
+        }
+        (&Method::GET, ["commands", id, "progress"]) => match id.parse() {
+            Ok(id) => follow_progress(state, &history, id),
+            Err(_) => error_response(StatusCode::NOT_FOUND, "no such command"),
+        },
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    };
+    Ok(response)
+}
+
+/// Check that a request that acts on the desk may be made: with the token if one is set, or
+/// otherwise from this computer. Returns the response refusing it if not.
+fn authorize(
+    token: Option<&str>,
+    peer: SocketAddr,
+    request: &Request<Body>,
+) -> Option<Response<Body>> {
+    let token = match token {
+        Some(token) => token,
+        None if peer.ip().is_loopback() => return None,
+        None => {
+            return Some(error_response(
+                StatusCode::FORBIDDEN,
+                "set a token in the api settings to accept this from other computers",
+            ))
+        }
+    };
+    let given = request
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match given {
+        Some(given) if constant_time_eq(given.as_bytes(), token.as_bytes()) => None,
+        _ => {
+            let mut response =
+                error_response(StatusCode::UNAUTHORIZED, "a valid bearer token is required");
+            response
+                .headers_mut()
+                .insert("www-authenticate", HeaderValue::from_static("Bearer"));
+            Some(response)
+        }
+    }
+}
+
+/// Compare without stopping at the first difference, so the time taken does not give away how
+/// much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn history_response(state: &State, query: Option<&str>, hourly: bool) -> Response<Body> {
+    let history = match &state.history {
+        Some(history) => history,
+        None => return error_response(StatusCode::NOT_FOUND, "history is not enabled"),
+    };
+    let mut from = 0;
+    let mut to = i64::MAX;
+    for pair in query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+    {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let target = match name {
+            "from" => &mut from,
+            "to" => &mut to,
+            _ => return error_response(StatusCode::BAD_REQUEST, "unexpected parameter"),
+        };
+        match value.parse() {
+            Ok(value) => *target = value,
+            Err(_) => return error_response(StatusCode::BAD_REQUEST, "invalid time"),
+        }
+    }
+    let result = if hourly {
+        history.hourly(from, to)
+    } else {
+        history.raw(from, to)
+    };
+    match result {
+        Ok(rows) => json_response(StatusCode::OK, rows),
+        Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+async fn registers_response(state: State, query: Option<&str>) -> Response<Body> {
+    let mut address = None;
+    let mut count = None;
+    for pair in query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+    {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let target = match name {
+            "addr" => &mut address,
+            "count" => &mut count,
+            _ => return error_response(StatusCode::BAD_REQUEST, "unexpected parameter"),
+        };
+        match value.parse() {
+            Ok(value) => *target = Some(value),
+            Err(_) => return error_response(StatusCode::BAD_REQUEST, "invalid number"),
+        }
+    }
+    let read = match (address, count) {
+        (Some(address), Some(count)) if (1..=RegisterRead::MAX_COUNT).contains(&count) => {
+            RegisterRead { address, count }
+        }
+        _ => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "addr and a count from 1 to 125 are required",
+            )
+        }
+    };
+    // Subscribe before asking so the result cannot be missed.
+    let mut events = state.events.subscribe();
+    if state.register_reads.try_send(read).is_err() {
+        return error_response(StatusCode::SERVICE_UNAVAILABLE, "too many register reads");
+    }
+    let result = tokio::time::timeout(REGISTER_READ_TIMEOUT, async {
+        loop {
+            match events.recv().await {
+                Ok(DeskEvent::Registers {
+                    address,
+                    count,
+                    result,
+                }) if address == read.address && count == read.count => return Some(result),
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    .await;
+    match result {
+        Ok(Some(Ok(registers))) => json_response(
+            StatusCode::OK,
+            serde_json::json!({ "registers": registers }),
+        ),
+        Ok(Some(Err(err))) => error_response(StatusCode::BAD_GATEWAY, &err),
+        Ok(None) | Err(_) => error_response(
+            StatusCode::GATEWAY_TIMEOUT,
+            "the registers were not read in time",
+        ),
+    }
+}
+
+fn next_command_id(state: &State) -> u64 {
+    state.command_ids.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+fn send_command(state: &State, command: Command) -> Response<Body> {
+    let id = next_command_id(state);
+    match state.command.send((command, Source::Api(id))) {
+        Ok(_) => json_response(StatusCode::ACCEPTED, serde_json::json!({ "id": id })),
+        Err(_) => error_response(StatusCode::SERVICE_UNAVAILABLE, "not accepting commands"),
+    }
+}
+
+/// Stream the progress of the command the API gave `id` as server-sent events, along with the
+/// desk's height, speed, and movement while it runs, until it is done.
+fn follow_progress(
+    state: State,
+    history: &Mutex<VecDeque<serde_json::Value>>,
+    id: u64,
+) -> Response<Body> {
+    if id == 0 || id > state.command_ids.load(Ordering::Relaxed) {
+        return error_response(StatusCode::NOT_FOUND, "no such command");
+    }
+    // Subscribe before looking at what already happened so nothing is missed in between.
+    let mut events = state.events.subscribe();
+    let last = history
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .find(|event| event["type"] == "progress" && event["id"] == id)
+        .cloned();
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let send = |json: &serde_json::Value| format!("data: {}\n\n", json);
+        let mut running = false;
+        if let Some(last) = &last {
+            if sender.send_data(send(last).into()).await.is_err() || last["done"] == true {
+                return;
+            }
+            running = last["status"] == "running";
+        }
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            let json = event.to_json();
+            let done = match event {
+                DeskEvent::Progress {
+                    id: other,
+                    status,
+                    done,
+                } if other == id => {
+                    // The history and the subscription can both have the latest progress.
+                    if last.as_ref() == Some(&json) {
+                        continue;
+                    }
+                    running = status == "running";
+                    done
+                }
+                DeskEvent::Height(_) | DeskEvent::Speed(_) | DeskEvent::Moving { .. }
+                    if running =>
+                {
+                    false
+                }
+                _ => continue,
+            };
+            if sender.send_data(send(&json).into()).await.is_err() || done {
+                break;
+            }
+        }
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "text/event-stream")
+        .body(body)
+        .unwrap()
+}
+
+/// Stream events as they happen, one JSON object per line, or as server-sent events.
+fn follow_events(state: State, sse: bool) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    let mut events = state.events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let json = match events.recv().await {
+                Ok(event) => event.to_json(),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            let json = serde_json::to_string(&json).unwrap();
+            let line = if sse {
+                format!("data: {}\n\n", json)
+            } else {
+                format!("{}\n", json)
+            };
+            if sender.send_data(line.into()).await.is_err() {
+                // The client went away.
+                break;
+            }
+        }
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(
+            "content-type",
+            if sse {
+                "text/event-stream"
+            } else {
+                "application/x-ndjson"
+            },
+        )
+        .body(body)
+        .unwrap()
+}
+
+fn json_response(status: StatusCode, json: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string(&json).unwrap()))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, serde_json::json!({ "error": message }))
+}
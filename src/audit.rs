@@ -0,0 +1,252 @@
+//! An append-only record of movement commands, for finding out who moved the desk and when.
+//!
+//! Each line is a JSON entry holding the hash of the entry before it, so changing or removing an
+//! entry breaks the chain from there on. `laing-controller verify-audit <path>` checks the chain
+//! and prints the last hash; entries removed from the end can only be noticed by comparing that with
+//! a hash noted down earlier.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::error;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::mqtt::{Command, Source};
+
+/// The `previous` hash of the first entry.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Entry {
+    time: String,
+    source: String,
+    command: String,
+    /// The height a restore was heading for, in inches.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<f32>,
+    /// `completed`, `stopped`, `failed`, `queued`, or why the command was rejected.
+    result: String,
+    height_before: Option<f32>,
+    height_after: Option<f32>,
+    previous: String,
+    /// The hash of this entry as serialized without it.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    hash: String,
+}
+
+impl Entry {
+    fn digest(&self) -> Result<String> {
+        let unhashed = serde_json::to_string(&Entry {
+            hash: String::new(),
+            ..self.clone()
+        })?;
+        Ok(hex(&Sha256::digest(unhashed.as_bytes())))
+    }
+}
+
+pub struct AuditLog {
+    file: Option<File>,
+    /// The hash of the last entry written.
+    last_hash: String,
+}
+
+impl AuditLog {
+    /// Open the log at `path` to continue its chain, or a log that records nothing.
+    pub fn open(path: Option<&str>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path,
+            None => {
+                return Ok(Self {
+                    file: None,
+                    last_hash: String::new(),
+                })
+            }
+        };
+        let last_hash = match fs::read_to_string(path) {
+            Ok(contents) => match contents.lines().rev().find(|line| !line.trim().is_empty()) {
+                Some(line) => {
+                    serde_json::from_str::<Entry>(line)
+                        .context("Failed to read the last audit log entry")?
+                        .hash
+                }
+                None => GENESIS.to_string(),
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => GENESIS.to_string(),
+            Err(err) => return Err(err).context("Failed to read the audit log"),
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context("Failed to open the audit log")?;
+        Ok(Self {
+            file: Some(file),
+            last_hash,
+        })
+    }
+
+    /// Record what became of a movement command. Heights are in tenths of an inch.
+    pub fn record(
+        &mut self,
+        source: Source,
+        command: Command,
+        result: &str,
+        before: Option<u16>,
+        after: Option<u16>,
+    ) {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let inches = |height: u16| f32::from(height) / 10.0;
+        let mut entry = Entry {
+            time: chrono::Utc::now().to_rfc3339(),
+            source: source.name().to_string(),
+            command: command.payload().to_string(),
+            target: match command {
+                Command::Restore { target } => Some(inches(target)),
+                _ => None,
+            },
+            result: result.to_string(),
+            height_before: before.map(inches),
+            height_after: after.map(inches),
+            previous: self.last_hash.clone(),
+            hash: String::new(),
+        };
+        let written = entry.digest().and_then(|hash| {
+            entry.hash = hash;
+            writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+            Ok(())
+        });
+        match written {
+            Ok(()) => self.last_hash = entry.hash,
+            // The desk should still move if the disk is full.
+            Err(err) => error!("Failed to write to the audit log: {:?}", err),
+        }
+    }
+}
+
+/// Check that every entry in the log at `args[0]` is intact and follows the one before it.
+pub fn verify(args: &[String]) -> Result<()> {
+    let path = match args {
+        [path] => path,
+        _ => bail!("Usage: laing-controller verify-audit <path>"),
+    };
+    let contents = fs::read_to_string(path).context("Failed to read the audit log")?;
+    let mut previous = GENESIS.to_string();
+    let mut count = 0;
+    for (number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Entry = serde_json::from_str(line)
+            .with_context(|| format!("Line {} is not an audit log entry", number + 1))?;
+        if entry.previous != previous {
+            return Err(anyhow!(
+                "Line {} does not follow the entry before it; an entry was removed or reordered",
+                number + 1
+            ));
+        }
+        if entry.digest()? != entry.hash {
+            return Err(anyhow!("Line {} has been changed", number + 1));
+        }
+        previous = entry.hash;
+        count += 1;
+    }
+    println!("{} entries verified; the last hash is {}", count, previous);
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use super::{verify, AuditLog};
+    use crate::mqtt::{Command, Source};
+
+    fn temp_log(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("laing-audit-{}-{}.jsonl", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn write_entries(path: &Path) {
+        let mut log = AuditLog::open(path.to_str()).unwrap();
+        log.record(
+            Source::Mqtt,
+            Command::Preset2,
+            "completed",
+            Some(300),
+            Some(420),
+        );
+        log.record(
+            Source::Schedule,
+            Command::Restore { target: 300 },
+            "busy",
+            None,
+            None,
+        );
+        // Reopening continues the chain from the last entry.
+        let mut log = AuditLog::open(path.to_str()).unwrap();
+        log.record(
+            Source::Mqtt,
+            Command::Preset2,
+            "stopped",
+            Some(420),
+            Some(350),
+        );
+    }
+
+    fn verify_path(path: &Path) -> anyhow::Result<()> {
+        verify(&[path.to_str().unwrap().to_string()])
+    }
+
+    #[test]
+    fn intact_logs_verify() {
+        let path = temp_log("intact");
+        write_entries(&path);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+        verify_path(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn changed_entries_break_the_chain() {
+        let path = temp_log("changed");
+        write_entries(&path);
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::write(&path, contents.replacen("\"busy\"", "\"completed\"", 1)).unwrap();
+        let err = verify_path(&path).unwrap_err();
+        assert!(err.to_string().contains("Line 2"), "{}", err);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn removed_entries_break_the_chain() {
+        let path = temp_log("removed");
+        write_entries(&path);
+        let contents = fs::read_to_string(&path).unwrap();
+        let remaining: Vec<&str> = contents
+            .lines()
+            .enumerate()
+            .filter(|&(number, _)| number != 1)
+            .map(|(_, line)| line)
+            .collect();
+        fs::write(&path, remaining.join("\n")).unwrap();
+        let err = verify_path(&path).unwrap_err();
+        assert!(err.to_string().contains("Line 2"), "{}", err);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+//! Exporting the settings together with what has been learned, and importing them again, for
+//! backups and for setting up another desk the same way.
+//!
+//! The export is a single YAML file holding the settings file and the state file. It contains
+//! whatever credentials the settings file does, but not files the settings only name, such as
+//! certificates and password files.
+
+use std::fs;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hassio,
+    persist::{load_state, save_state, state_from_value, state_to_value},
+    settings::{load_settings_value, settings_path, Settings},
+};
+
+const USAGE: &str = "Usage:
+  laing-controller export-config <file>
+  laing-controller import-config <file> [--force]
+
+import-config will not replace an existing settings file without --force.";
+
+/// The layout of an export. Increase it when a change cannot be read by older versions.
+const EXPORT_VERSION: u64 = 1;
+
+#[derive(Deserialize, Serialize)]
+struct Export {
+    version: u64,
+    /// The settings file as written, so defaults left out stay defaults.
+    settings: serde_yaml::Value,
+    /// The learned travel model, preset heights, scenes, and total travel.
+    state: serde_json::Value,
+}
+
+pub fn export(args: &[String]) -> Result<()> {
+    let path = match args {
+        [path] => path,
+        _ => return Err(anyhow!(USAGE)),
+    };
+    let settings = load_settings_value()?;
+    // Exporting settings that cannot be loaded would only move the problem to the next machine.
+    serde_yaml::from_value::<Settings>(settings.clone()).context("Invalid settings")?;
+    let export = Export {
+        version: EXPORT_VERSION,
+        settings,
+        state: state_to_value(&load_state()?)?,
+    };
+    let yaml = serde_yaml::to_string(&export).context("Failed to export")?;
+    fs::write(path, yaml).with_context(|| format!("Failed to write {}", path))?;
+    println!("Exported the settings and learned state to {}", path);
+    Ok(())
+}
+
+pub fn import(args: &[String]) -> Result<()> {
+    let (path, force) = match args {
+        [path] => (path, false),
+        [path, flag] if flag == "--force" => (path, true),
+        _ => return Err(anyhow!(USAGE)),
+    };
+    if hassio::data_dir().is_some() {
+        bail!("The settings of a Home Assistant add-on are its options; set them there instead");
+    }
+    let file = fs::File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    let export: Export =
+        serde_yaml::from_reader(file).with_context(|| format!("Failed to load {}", path))?;
+    if export.version > EXPORT_VERSION {
+        bail!(
+            "{} was exported by a newer version of laing-controller (export version {})",
+            path,
+            export.version
+        );
+    }
+    // Check everything before writing anything.
+    serde_yaml::from_value::<Settings>(export.settings.clone()).context("Invalid settings")?;
+    let state = state_from_value(export.state)?;
+
+    let settings_path = settings_path()?;
+    if settings_path.exists() && !force {
+        bail!("{} already exists\n\n{}", settings_path.display(), USAGE);
+    }
+    let yaml = serde_yaml::to_string(&export.settings).context("Failed to import settings")?;
+    fs::write(&settings_path, yaml).context("Failed to write settings")?;
+    save_state(&state)?;
+    println!(
+        "Imported the settings and learned state into {}",
+        settings_path.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,87 @@
+//! Administers a running laing-controller through its local API.
+
+use std::io::Write;
+
+use anyhow::{anyhow, Context};
+use hyper::{body::HttpBody, Body, Client, Method, Request};
+
+const USAGE: &str = "Usage: laing-ctl [--api <address>] <command>
+
+Commands:
+  status                 show the current height
+  preset <1-4>           move to a preset
+  refresh                ask the controller for its height
+  sleep                  put the controller's display into standby
+  calibrate              visit every preset to learn its height
+  snapshot <name>        save the current height as a scene
+  restore <name>         move to the height saved as a scene
+  reload-config          re-read laing-controller.yaml
+  mqtt-restart           reconnect to the MQTT broker with the settings in laing-controller.yaml
+  discovery-republish    publish the Home Assistant discovery configuration again
+  serial-reopen          close the serial port and open it again
+  events [--follow]      show recent events, or keep showing events as they happen
+
+The API address defaults to the LC_API environment variable, or 127.0.0.1:7207. If the API has a
+token, set it in the LC_API_TOKEN environment variable.";
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1).peekable();
+    let mut address = std::env::var("LC_API").unwrap_or_else(|_| "127.0.0.1:7207".into());
+    if args.peek().map(String::as_str) == Some("--api") {
+        args.next();
+        address = args.next().ok_or_else(|| anyhow!(USAGE))?;
+    }
+
+    let (method, path) = match args.next().as_deref() {
+        Some("status") => (Method::GET, "/status".to_string()),
+        Some("preset") => {
+            let preset = args.next().ok_or_else(|| anyhow!(USAGE))?;
+            (Method::POST, format!("/preset/{}", preset))
+        }
+        Some("refresh") => (Method::POST, "/refresh".to_string()),
+        Some("sleep") => (Method::POST, "/sleep".to_string()),
+        Some("calibrate") => (Method::POST, "/calibrate".to_string()),
+        Some(command @ ("snapshot" | "restore")) => {
+            let name = args.next().ok_or_else(|| anyhow!(USAGE))?;
+            (Method::POST, format!("/{}/{}", command, name))
+        }
+        Some("reload-config") => (Method::POST, "/reload-config".to_string()),
+        Some("mqtt-restart") => (Method::POST, "/mqtt-restart".to_string()),
+        Some("discovery-republish") => (Method::POST, "/discovery-republish".to_string()),
+        Some("serial-reopen") => (Method::POST, "/serial-reopen".to_string()),
+        Some("events") => match args.next().as_deref() {
+            Some("--follow") => (Method::GET, "/events?follow".to_string()),
+            None => (Method::GET, "/events".to_string()),
+            Some(other) => return Err(anyhow!("Unexpected parameter: {}\n\n{}", other, USAGE)),
+        },
+        Some(other) => return Err(anyhow!("Unexpected command: {}\n\n{}", other, USAGE)),
+        None => return Err(anyhow!(USAGE)),
+    };
+
+    let mut request = Request::builder()
+        .method(method)
+        .uri(format!("http://{}{}", address, path));
+    if let Ok(token) = std::env::var("LC_API_TOKEN") {
+        request = request.header("authorization", format!("Bearer {}", token));
+    }
+    let request = request.body(Body::empty())?;
+    let mut response = Client::new()
+        .request(request)
+        .await
+        .with_context(|| format!("Failed to reach laing-controller at {}", address))?;
+
+    let stdout = std::io::stdout();
+    while let Some(chunk) = response.body_mut().data().await {
+        let mut stdout = stdout.lock();
+        stdout.write_all(&chunk?)?;
+        stdout.flush()?;
+    }
+    println!();
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(anyhow!("Request failed: {}", response.status()))
+    }
+}
@@ -0,0 +1,59 @@
+//! Correcting the heights the controller reports, for desks whose display is off by an amount that
+//! changes across their range.
+
+/// Maps reported heights to measured ones, interpolating linearly between measured points.
+///
+/// Beyond the first and last points the nearest pair's slope carries on, so a scale error keeps
+/// growing as it does between them. A single point is a plain offset, and with no points heights
+/// are left alone.
+pub struct HeightCorrection {
+    /// (reported, actual) in tenths of an inch, sorted by the reported height.
+    points: Vec<(f32, f32)>,
+}
+
+impl HeightCorrection {
+    /// Take (reported, actual) pairs in inches, in any order.
+    pub fn new(points: impl IntoIterator<Item = (f32, f32)>) -> Result<Self, String> {
+        let mut points: Vec<(f32, f32)> = points
+            .into_iter()
+            .map(|(reported, actual)| (reported * 10.0, actual * 10.0))
+            .collect();
+        if points
+            .iter()
+            .any(|(reported, actual)| !reported.is_finite() || !actual.is_finite())
+        {
+            return Err("height corrections must be numbers".into());
+        }
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        if let Some(pair) = points.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(format!(
+                "there is more than one height correction for {}",
+                pair[0].0 / 10.0
+            ));
+        }
+        Ok(Self { points })
+    }
+
+    /// Correct a height in tenths of an inch.
+    pub fn apply(&self, height: u16) -> u16 {
+        let height = f32::from(height);
+        let corrected = match self.points.as_slice() {
+            [] => height,
+            [(reported, actual)] => height + actual - reported,
+            points => {
+                // The pair of points around the height, or the nearest pair if it is outside them.
+                let upper = points
+                    .iter()
+                    .position(|&(reported, _)| reported > height)
+                    .unwrap_or(points.len())
+                    .clamp(1, points.len() - 1);
+                let (low_reported, low_actual) = points[upper - 1];
+                let (high_reported, high_actual) = points[upper];
+                low_actual
+                    + (height - low_reported) * (high_actual - low_actual)
+                        / (high_reported - low_reported)
+            }
+        };
+        corrected.round().clamp(0.0, f32::from(u16::MAX)) as u16
+    }
+}
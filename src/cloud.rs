@@ -0,0 +1,214 @@
+//! Signing in to cloud IoT platforms, which expect tokens rather than a fixed password.
+//!
+//! Signed tokens expire, so `renew_after` says how long the connection can be used before the MQTT
+//! connection has to be restarted with a new token.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use rumqttc::{MqttOptions, TlsConfiguration, Transport};
+use sha2::{Digest, Sha256};
+
+use crate::settings::{CloudAuthSettings, Settings};
+
+/// The API version Azure IoT Hub expects in the username.
+const AZURE_API_VERSION: &str = "2021-04-12";
+
+/// How long before a token expires to replace it.
+const RENEW_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// How long a connection made with these settings can be used before signing in again.
+pub fn renew_after(cloud: &CloudAuthSettings) -> Option<Duration> {
+    let lifetime = match cloud {
+        CloudAuthSettings::AzureIotHub {
+            token_lifetime_secs,
+            ..
+        }
+        | CloudAuthSettings::AwsSigV4 {
+            token_lifetime_secs,
+            ..
+        } => Duration::from_secs(*token_lifetime_secs),
+        CloudAuthSettings::AwsCustomAuthorizer { .. } => return None,
+    };
+    Some(lifetime.saturating_sub(RENEW_MARGIN).max(RENEW_MARGIN))
+}
+
+/// Set up `options` to sign in to a cloud platform, using `tls` for the connection.
+pub fn apply(
+    settings: &Settings,
+    cloud: &CloudAuthSettings,
+    options: &mut MqttOptions,
+    mut tls: rumqttc::ClientConfig,
+) -> Result<()> {
+    let host = &settings.mqtt.host;
+    match cloud {
+        CloudAuthSettings::AzureIotHub {
+            key,
+            token_lifetime_secs,
+        } => {
+            let key = base64::decode(key).context("Invalid Azure IoT Hub key")?;
+            let resource = format!("{}/devices/{}", host, settings.id);
+            options.set_credentials(
+                format!(
+                    "{}/{}/?api-version={}",
+                    host, settings.id, AZURE_API_VERSION
+                ),
+                azure_token(&resource, &key, unix_time() + token_lifetime_secs),
+            );
+            options.set_transport(Transport::Tls(TlsConfiguration::Rustls(tls.into())));
+        }
+        CloudAuthSettings::AwsCustomAuthorizer {
+            authorizer,
+            token_key_name,
+            token,
+            signature,
+            username,
+        } => {
+            let mut query = format!(
+                "x-amz-customauthorizer-name={}&{}={}",
+                encode(authorizer),
+                encode(token_key_name),
+                encode(token)
+            );
+            if let Some(signature) = signature {
+                query.push_str(&format!(
+                    "&x-amz-customauthorizer-signature={}",
+                    encode(signature)
+                ));
+            }
+            options.set_credentials(
+                format!("{}?{}", username.as_deref().unwrap_or_default(), query),
+                "",
+            );
+            // AWS IoT only accepts custom authentication over MQTT on port 443 when the TLS
+            // connection asks for the `mqtt` protocol.
+            tls.set_protocols(&[b"mqtt".to_vec()]);
+            options.set_transport(Transport::Tls(TlsConfiguration::Rustls(tls.into())));
+        }
+        CloudAuthSettings::AwsSigV4 {
+            region,
+            access_key_id,
+            secret_access_key,
+            session_token,
+            token_lifetime_secs,
+        } => {
+            let url = presign(
+                host,
+                region,
+                access_key_id,
+                secret_access_key,
+                session_token.as_deref(),
+                *token_lifetime_secs,
+                chrono::Utc::now(),
+            );
+            // The address is the whole URL when connecting over a WebSocket, so start over with it
+            // and carry over the options already set.
+            let mut ws = MqttOptions::new(&settings.id, url, 443);
+            let max_packet_size = options.max_packet_size();
+            ws.set_keep_alive(options.keep_alive())
+                .set_max_packet_size(max_packet_size, max_packet_size)
+                .set_clean_session(options.clean_session())
+                .set_transport(Transport::Wss(TlsConfiguration::Rustls(tls.into())));
+            *options = ws;
+        }
+    }
+    Ok(())
+}
+
+/// Build an Azure IoT Hub shared access signature for `resource`, valid until `expiry` in seconds
+/// since the Unix epoch.
+pub fn azure_token(resource: &str, key: &[u8], expiry: u64) -> String {
+    let signature = base64::encode(hmac(key, format!("{}\n{}", encode(resource), expiry)));
+    format!(
+        "SharedAccessSignature sr={}&sig={}&se={}",
+        encode(resource),
+        encode(&signature),
+        expiry
+    )
+}
+
+/// Build a SigV4 presigned URL for connecting to AWS IoT over a WebSocket, signed at `now`.
+pub fn presign(
+    host: &str,
+    region: &str,
+    access_key_id: &str,
+    secret_access_key: &str,
+    session_token: Option<&str>,
+    lifetime_secs: u64,
+    now: chrono::DateTime<chrono::Utc>,
+) -> String {
+    const SERVICE: &str = "iotdevicegateway";
+
+    let date = now.format("%Y%m%d").to_string();
+    let time = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let scope = format!("{}/{}/{}/aws4_request", date, region, SERVICE);
+    let query = format!(
+        "X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Credential={}&X-Amz-Date={}&X-Amz-Expires={}&X-Amz-SignedHeaders=host",
+        encode(&format!("{}/{}", access_key_id, scope)),
+        time,
+        lifetime_secs.min(7 * 24 * 60 * 60),
+    );
+    let canonical_request = format!(
+        "GET\n/mqtt\n{}\nhost:{}\n\nhost\n{}",
+        query,
+        host,
+        hex(&Sha256::digest(b""))
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        time,
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let key = signing_key(secret_access_key, &date, region, SERVICE);
+    let mut url = format!(
+        "wss://{}/mqtt?{}&X-Amz-Signature={}",
+        host,
+        query,
+        hex(&hmac(&key, string_to_sign))
+    );
+    // AWS IoT expects temporary credentials' session token to be left out of the signature.
+    if let Some(session_token) = session_token {
+        url.push_str(&format!("&X-Amz-Security-Token={}", encode(session_token)));
+    }
+    url
+}
+
+/// Derive the SigV4 key for signing requests to `service` on `date`, written as `YYYYMMDD`.
+pub fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    [date, region, service, "aws4_request"].iter().fold(
+        format!("AWS4{}", secret_access_key).into_bytes(),
+        |key, part| hmac(&key, part),
+    )
+}
+
+fn hmac(key: &[u8], message: impl AsRef<[u8]>) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key");
+    mac.update(message.as_ref());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Percent-encode everything except unreserved characters.
+fn encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
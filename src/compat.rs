@@ -0,0 +1,63 @@
+//! Topics for home automation platforms other than Home Assistant.
+
+use crate::settings::Settings;
+
+/// The base topic for the desk under the Homie convention, which openHAB discovers automatically.
+pub fn homie_base(settings: &Settings) -> String {
+    format!("homie/{}", settings.id)
+}
+
+pub fn homie_height_topic(settings: &Settings) -> String {
+    format!("{}/desk/height", homie_base(settings))
+}
+
+pub fn homie_command_topic(settings: &Settings) -> String {
+    format!("{}/desk/command/set", homie_base(settings))
+}
+
+pub fn homie_state_topic(settings: &Settings) -> String {
+    format!("{}/$state", homie_base(settings))
+}
+
+/// Build the retained Homie 4.0 device description as (topic, payload) pairs, offering `commands`
+/// as the values of the command property.
+pub fn homie_messages(settings: &Settings, commands: &[&str]) -> Vec<(String, String)> {
+    let base = homie_base(settings);
+    // The enum's values are separated by commas, so an alias containing one cannot be listed.
+    let commands: Vec<&str> = commands
+        .iter()
+        .copied()
+        .filter(|command| !command.contains(','))
+        .collect();
+    let format = commands.join(",");
+    [
+        ("$homie", "4.0"),
+        ("$name", settings.name.as_str()),
+        ("$nodes", "desk"),
+        ("$extensions", ""),
+        ("desk/$name", "Desk"),
+        ("desk/$type", "desk"),
+        ("desk/$properties", "height,command"),
+        ("desk/height/$name", "Height"),
+        ("desk/height/$datatype", "float"),
+        ("desk/height/$unit", "in"),
+        ("desk/command/$name", "Command"),
+        ("desk/command/$datatype", "enum"),
+        ("desk/command/$format", format.as_str()),
+        ("desk/command/$settable", "true"),
+        ("desk/command/$retained", "false"),
+    ]
+    .iter()
+    .map(|(topic, payload)| (format!("{}/{}", base, topic), payload.to_string()))
+    .collect()
+}
+
+/// Build a Domoticz `domoticz/in` message updating a sensor device with the height.
+pub fn domoticz_height_payload(idx: u32, height: f32) -> String {
+    serde_json::to_string(&serde_json::json!({
+        "idx": idx,
+        "nvalue": 0,
+        "svalue": format!("{}", height),
+    }))
+    .unwrap()
+}
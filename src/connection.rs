@@ -0,0 +1,83 @@
+//! A modbus client on a handle taken from the shared serial port, and how well it is doing.
+
+use std::io;
+use std::time::Instant;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_modbus::{client::Context, prelude::*};
+
+use crate::transport::transfer::TransferPort;
+
+/// How the exchanges on a connection have gone.
+#[derive(Debug, Default)]
+pub struct Health {
+    /// Exchanges that failed since the last one that succeeded.
+    pub consecutive_failures: u32,
+    /// How many times the handle was replaced to recover.
+    pub reconnects: u32,
+    /// When an exchange last succeeded.
+    pub last_success: Option<Instant>,
+}
+
+impl Health {
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.last_success = Some(Instant::now());
+    }
+
+    pub fn record_failure(&mut self) {
+        self.consecutive_failures += 1;
+    }
+}
+
+/// A modbus `Context` built once for each handle taken from the port.
+///
+/// tokio-modbus does not recover from a failed exchange, so `reconnect` is the way back: it drops
+/// the context and takes a new handle, which also abandons anything the old one left waiting on
+/// the port.
+pub struct Connection<'a, T> {
+    port: &'a TransferPort<T>,
+    server_addr: Slave,
+    client: Context,
+    health: Health,
+}
+
+impl<'a, T: AsyncRead + AsyncWrite + Send + 'static> Connection<'a, T> {
+    pub async fn open(port: &'a TransferPort<T>, server_addr: Slave) -> io::Result<Self> {
+        let client = rtu::connect_slave(port.take(), server_addr).await?;
+        Ok(Self {
+            port,
+            server_addr,
+            client,
+            health: Health::default(),
+        })
+    }
+
+    pub fn client(&mut self) -> &mut Context {
+        &mut self.client
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    /// Count an exchange towards the connection's health.
+    pub fn record<R, E>(&mut self, result: &Result<R, E>) {
+        match result {
+            Ok(_) => self.health.record_success(),
+            Err(_) => self.health.record_failure(),
+        }
+    }
+
+    /// Start again with a fresh handle after a failed exchange.
+    pub async fn reconnect(&mut self) -> io::Result<()> {
+        self.client.disconnect().await?;
+        self.client = rtu::connect_slave(self.port.take(), self.server_addr).await?;
+        self.health.reconnects += 1;
+        Ok(())
+    }
+
+    pub async fn close(mut self) -> io::Result<()> {
+        self.client.disconnect().await
+    }
+}
@@ -0,0 +1,74 @@
+//! Typing commands into a terminal, for trying things out at the bench without an MQTT client.
+
+use std::{io::IsTerminal, sync::OnceLock};
+
+use anyhow::{Context, Result};
+use log::info;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::mqtt::{Command, Source, State};
+
+const HELP: &str = "Commands: 1-4, refresh, sleep, calibrate, goto <inches>, +/-<inches>, quit";
+
+/// Lines typed into the terminal.
+///
+/// Reading standard input cannot be cancelled, so it is read on a thread of its own that outlives
+/// restarts, rather than on the runtime, whose shutdown would wait for the next line.
+static LINES: OnceLock<Mutex<mpsc::UnboundedReceiver<String>>> = OnceLock::new();
+
+fn read_lines() -> Mutex<mpsc::UnboundedReceiver<String>> {
+    let (send, receive) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let sent = line.map(|line| send.send(line).is_ok());
+            if !matches!(sent, Ok(true)) {
+                break;
+            }
+        }
+    });
+    Mutex::new(receive)
+}
+
+/// Read commands from standard input, one per line, until `quit` or the end of input.
+///
+/// Nothing is read unless standard input is a terminal, so running as a service is not affected.
+pub async fn console_loop(state: State) -> Result<()> {
+    if !std::io::stdin().is_terminal() {
+        return std::future::pending().await;
+    }
+    println!("{}", HELP);
+    let mut lines = LINES.get_or_init(read_lines).lock().await;
+    while let Some(line) = lines.recv().await {
+        let mut words = line.split_whitespace();
+        let command = match (words.next(), words.next()) {
+            (None, _) => continue,
+            (Some("quit"), None) => break,
+            (Some("goto"), Some(height)) => match height.parse::<f32>() {
+                Ok(height) if height > 0.0 && height < 6553.5 => Command::Restore {
+                    target: (height * 10.0).round() as u16,
+                },
+                _ => {
+                    println!("Not a height: {}", height);
+                    continue;
+                }
+            },
+            (Some(word), None) => match Command::parse(word.to_ascii_uppercase().as_bytes()) {
+                Some(command) => command,
+                None => {
+                    println!("{}", HELP);
+                    continue;
+                }
+            },
+            (Some(_), Some(_)) => {
+                println!("{}", HELP);
+                continue;
+            }
+        };
+        info!("Sending {:?} from the console", command);
+        state
+            .command
+            .send((command, Source::Console))
+            .context("failed to accept command")?;
+    }
+    Ok(())
+}
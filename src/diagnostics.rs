@@ -0,0 +1,246 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    mqtt::Command,
+    protocol::{Exception, ProtocolError},
+    settings::{MqttTransport, Settings},
+    watchdog::Heartbeat,
+};
+
+/// Where the time went while handling a command.
+///
+/// The time to the first write covers waiting for the power relay and waking the controller, and
+/// the rest of the time to completion is spent moving. Slowness before the command was received is
+/// on the MQTT side.
+#[derive(Clone, Copy, Debug)]
+pub struct CommandLatency {
+    pub command: Command,
+    /// From receiving the command to the first register write after waking the controller.
+    pub to_first_write: Option<Duration>,
+    /// From receiving the command to the controller being idle again.
+    pub to_completion: Duration,
+}
+
+impl CommandLatency {
+    pub fn to_json(self) -> serde_json::Value {
+        serde_json::json!({
+            "command": self.command.payload(),
+            "to_first_write_secs": self.to_first_write.map(|latency| latency.as_secs_f32()),
+            "to_completion_secs": self.to_completion.as_secs_f32(),
+        })
+    }
+}
+
+/// Conditions worth knowing about when troubleshooting, shared between the subsystems that detect
+/// them and the frontends that report them.
+#[derive(Default)]
+pub struct Diagnostics {
+    /// The wall clock jumped relative to the monotonic clock.
+    pub clock_skew_detected: AtomicBool,
+    /// Exchanges where the controller did not answer in time.
+    pub timeouts: AtomicU64,
+    /// Exchanges where the controller said it was busy.
+    pub busy_responses: AtomicU64,
+    /// Exchanges where the controller rejected the register addresses.
+    pub illegal_address_responses: AtomicU64,
+    /// Exchanges where the controller responded with any other exception.
+    pub other_exceptions: AtomicU64,
+    /// Operations abandoned by revoking the serial port and stopping the controller.
+    pub recoveries: AtomicU64,
+    /// REFRESH commands answered by a refresh that was already happening.
+    pub coalesced_refreshes: AtomicU64,
+    /// REFRESH commands answered with the height from a recent exchange.
+    pub cached_refreshes: AtomicU64,
+    /// Heights read from the display that were outside the possible range, and ignored.
+    pub implausible_heights: AtomicU64,
+    /// Heights read while nothing was listening to publish them.
+    pub undelivered_heights: AtomicU64,
+    /// Events the MQTT connection skipped because it fell behind.
+    pub skipped_events: AtomicU64,
+    /// Publishing the discovery configuration failed, or the broker seemed to refuse it.
+    pub discovery_failed: AtomicBool,
+    /// Why the last recovery happened, and when.
+    pub last_recovery: Mutex<Option<(&'static str, SystemTime)>>,
+    /// How long the last completed command and the last completed movement took.
+    pub last_latency: Mutex<(Option<CommandLatency>, Option<CommandLatency>)>,
+    /// Progress of the loop that talks to the controller.
+    pub control_heartbeat: Heartbeat,
+    /// Progress of the loop that talks to the broker.
+    pub mqtt_heartbeat: Heartbeat,
+}
+
+impl Diagnostics {
+    pub fn record_protocol_error(&self, err: &ProtocolError) {
+        let counter = match err {
+            ProtocolError::Timeout => &self.timeouts,
+            ProtocolError::Exception(Exception::ServerDeviceBusy, _) => &self.busy_responses,
+            ProtocolError::Exception(Exception::IllegalDataAddress, _) => {
+                &self.illegal_address_responses
+            }
+            ProtocolError::Exception(..) => &self.other_exceptions,
+            ProtocolError::Io(_) => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_recovery(&self, cause: &'static str) {
+        self.recoveries.fetch_add(1, Ordering::Relaxed);
+        *self.last_recovery.lock().unwrap() = Some((cause, SystemTime::now()));
+    }
+
+    pub fn record_latency(&self, latency: CommandLatency) {
+        let mut last_latency = self.last_latency.lock().unwrap();
+        last_latency.0 = Some(latency);
+        if latency.command.is_movement() {
+            last_latency.1 = Some(latency);
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let (last_command, last_movement) = *self.last_latency.lock().unwrap();
+        let last_recovery = self.last_recovery.lock().unwrap().map(|(cause, time)| {
+            serde_json::json!({
+                "cause": cause,
+                "time": time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            })
+        });
+        serde_json::json!({
+            "clock_skew_detected": self.clock_skew_detected.load(Ordering::Relaxed),
+            "timeouts": self.timeouts.load(Ordering::Relaxed),
+            "busy_responses": self.busy_responses.load(Ordering::Relaxed),
+            "illegal_address_responses": self.illegal_address_responses.load(Ordering::Relaxed),
+            "other_exceptions": self.other_exceptions.load(Ordering::Relaxed),
+            "recoveries": self.recoveries.load(Ordering::Relaxed),
+            "coalesced_refreshes": self.coalesced_refreshes.load(Ordering::Relaxed),
+            "cached_refreshes": self.cached_refreshes.load(Ordering::Relaxed),
+            "implausible_heights": self.implausible_heights.load(Ordering::Relaxed),
+            "undelivered_heights": self.undelivered_heights.load(Ordering::Relaxed),
+            "skipped_events": self.skipped_events.load(Ordering::Relaxed),
+            "discovery_failed": self.discovery_failed.load(Ordering::Relaxed),
+            "last_recovery": last_recovery,
+            "last_command_latency": last_command.map(|latency| latency.to_json()),
+            "last_movement_latency": last_movement.map(|latency| latency.to_json()),
+            "control_loop": self.control_heartbeat.to_json(),
+            "mqtt_loop": self.mqtt_heartbeat.to_json(),
+        })
+    }
+}
+
+/// Parts of setting names that hold credentials.
+const SECRET_NAMES: &[&str] = &["password", "secret", "token", "key", "signature"];
+
+/// Replace the values of settings that look like credentials, so the settings can be shared.
+pub fn redact(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (name, value) in map.iter_mut() {
+                let name = name.to_ascii_lowercase();
+                if !value.is_null() && SECRET_NAMES.iter().any(|secret| name.contains(secret)) {
+                    *value = "REDACTED".into();
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// The settings in effect, with defaults filled in and without credentials, for telling what an
+/// installation is doing without asking for its settings file.
+pub fn configuration_summary(settings: &Settings) -> serde_json::Value {
+    let mqtt = &settings.mqtt;
+    let port = mqtt.port.unwrap_or(match mqtt.transport {
+        MqttTransport::Tcp => 1883,
+        MqttTransport::Tls => 8883,
+    });
+    let mut features = Vec::new();
+    if cfg!(feature = "chaos") {
+        features.push("chaos");
+    }
+    if cfg!(feature = "sandbox") {
+        features.push("sandbox");
+    }
+    if cfg!(feature = "service") {
+        features.push("service");
+    }
+    let broker = serde_json::json!({
+        "host": mqtt.host,
+        "port": port,
+        "transport": mqtt.transport,
+        "cloud": mqtt.cloud.is_some(),
+        "username": mqtt.credentials.as_ref().map(|credentials| &credentials.username),
+        "client_certificate": mqtt.client_certificate.is_some(),
+        "command_topic_aliases": mqtt.command_topic_aliases,
+        "keep_alive_secs": mqtt.keep_alive_secs,
+        "max_packet_size": mqtt.max_packet_size,
+        "persistent_session": mqtt.persistent_session,
+        "encoding": mqtt.encoding,
+        "reconnect": {
+            "min_delay_ms": mqtt.reconnect.min_delay_ms,
+            "max_delay_secs": mqtt.reconnect.max_delay_secs,
+            "jitter_ms": mqtt.reconnect.jitter_ms,
+            "max_per_minute": mqtt.reconnect.max_per_minute,
+        },
+    });
+    let subsystems = serde_json::json!({
+        "api": settings.api.as_ref().map(|api| &api.bind),
+        "api_tls": settings
+            .api
+            .as_ref()
+            .and_then(|api| api.tls.as_ref())
+            .map(|tls| &tls.bind),
+        "api_token": settings.api.as_ref().and_then(|api| api.token.as_ref()).is_some(),
+        "schedule": settings.schedule.len(),
+        "history": settings.history.is_some(),
+        "hooks": !settings.on_event.is_empty(),
+        "power_relay": settings.power_relay.is_some(),
+        "interlock": settings.interlock.is_some(),
+        "command_auth": settings.command_auth.is_some(),
+        "command_aliases": settings.command_aliases.len(),
+        "availability_timeout": settings.availability_timeout_secs.is_some(),
+        "listen_while_idle": settings.listen_while_idle_secs,
+        "experimental_standby": settings.experimental_standby,
+        "gentle_movement": settings.gentle_movement.is_some(),
+        "refresh_cache": settings.refresh_cache_secs,
+        "jog_hold_ms": settings.jog_hold_ms,
+        "strict_publishing": settings.strict_publishing,
+        "height_sensors": settings.height_sensors.len(),
+        "openhab": settings.compatibility.openhab,
+        "domoticz": settings.compatibility.domoticz.is_some(),
+        "capture": settings.capture_file.is_some(),
+        "replay": settings.replay_file.is_some(),
+        "audit_log": settings.audit_log.is_some(),
+        "sandbox": settings.sandbox,
+        "watchdog": settings.watchdog.as_ref().map(|watchdog| watchdog.stall_secs),
+        "maintenance": settings.maintenance.is_some(),
+    });
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "features": features,
+        "id": settings.id,
+        "name": settings.name,
+        "prefix": settings.prefix,
+        "hass_prefix": settings.hass_prefixes,
+        "hass_discovery": settings.hass_discovery,
+        "hass_schema": settings.hass_schema,
+        "serial_port": settings.serial_port,
+        "log_level": settings.log_level,
+        "display_encoding": settings.display_encoding,
+        "display_offset": (!settings.scan_display_offset).then(|| settings.display_offset),
+        "preset_height_registers": settings.preset_height_registers,
+        "possible_heights": [settings.min_possible_height, settings.max_possible_height],
+        "height_correction_points": settings.height_correction.len(),
+        "register_access": settings.register_access,
+        "shared_bus": settings.shared_bus_quiet_ms.is_some(),
+        "broker": broker,
+        "subsystems": subsystems,
+    })
+}
@@ -0,0 +1,67 @@
+use std::io;
+
+use crate::protocol::ProtocolError;
+
+/// Why controlling the desk failed, by where the problem is.
+///
+/// The frontends turn these into messages with `anyhow`, but the category is kept until then so
+/// callers can decide what to do, such as retrying on a busy controller.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The serial port could not be opened, or reading or writing it failed.
+    #[error("serial port error: {0}")]
+    Serial(#[from] io::Error),
+    /// The controller refused or did not answer an exchange.
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    /// The controller answered with something that could not be understood.
+    #[error("could not decode response: {0}")]
+    Decode(String),
+    /// The settings could not be loaded.
+    #[error("invalid settings: {0}")]
+    Config(String),
+    /// Talking to the broker failed, or what came from it could not be handed on.
+    #[error("MQTT error: {0}")]
+    Mqtt(#[from] MqttError),
+}
+
+/// Why something could not be published to or received from the broker.
+#[derive(Debug, thiserror::Error)]
+pub enum MqttError {
+    /// The broker connection is gone, so a request could not be queued.
+    #[error(transparent)]
+    Client(#[from] rumqttc::ClientError),
+    /// The connection to the broker failed.
+    #[error(transparent)]
+    Connection(#[from] rumqttc::ConnectionError),
+    /// A height or command had nowhere to go, such as a height with `strict_publishing` set.
+    #[error("nothing is listening for {0}")]
+    Unheard(&'static str),
+}
+
+impl From<rumqttc::ClientError> for Error {
+    fn from(err: rumqttc::ClientError) -> Self {
+        Error::Mqtt(err.into())
+    }
+}
+
+impl From<rumqttc::ConnectionError> for Error {
+    fn from(err: rumqttc::ConnectionError) -> Self {
+        Error::Mqtt(err.into())
+    }
+}
+
+impl Error {
+    /// A short name for the category, for reporting to other programs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Serial(_) => "serial",
+            Error::Protocol(_) => "protocol",
+            Error::Decode(_) => "decode",
+            Error::Config(_) => "config",
+            Error::Mqtt(_) => "mqtt",
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,310 @@
+//! Fanning what the desk is doing out to every frontend.
+//!
+//! The serial side sends events without knowing who is listening, and the MQTT client, the API,
+//! history, and hooks each subscribe to the ones they care about.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use laing_protocol::display::Condition;
+use tokio::sync::broadcast;
+
+use crate::{
+    diagnostics::CommandLatency,
+    maintenance::MaintenanceStatus,
+    mqtt::{Command, Source},
+};
+
+/// Stands in for an unknown height. This is a NaN, which no height is.
+const NO_HEIGHT: u32 = u32::MAX;
+
+/// What went wrong, for automations that only care about some errors.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+    /// The controller answered with an error, or could not be put in standby.
+    Protocol,
+    /// An operation did not finish in time.
+    Timeout,
+    /// A movement ran too long or stopped short.
+    Movement,
+    /// A command could not be carried out as given, such as a scene that does not exist.
+    Command,
+    /// A command arrived while the controller was not responding.
+    Offline,
+    /// The interlock sensor stopped or prevented a movement.
+    Interlock,
+    /// The controller showed a code instead of a height.
+    Display,
+    /// The connection to the MQTT broker was lost.
+    Mqtt,
+}
+
+impl ErrorKind {
+    pub const ALL: [ErrorKind; 8] = [
+        ErrorKind::Protocol,
+        ErrorKind::Timeout,
+        ErrorKind::Movement,
+        ErrorKind::Command,
+        ErrorKind::Offline,
+        ErrorKind::Interlock,
+        ErrorKind::Display,
+        ErrorKind::Mqtt,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorKind::Protocol => "protocol",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Movement => "movement",
+            ErrorKind::Command => "command",
+            ErrorKind::Offline => "offline",
+            ErrorKind::Interlock => "interlock",
+            ErrorKind::Display => "display",
+            ErrorKind::Mqtt => "mqtt",
+        }
+    }
+}
+
+/// What the height was being read for when it changed, which says why it changed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum HeightSource {
+    /// Checking on the controller, such as for REFRESH or at startup.
+    #[default]
+    Poll,
+    /// Moving the desk for a command.
+    Command,
+    /// Listening while idle, which only notices moves made with the handset.
+    Handset,
+}
+
+impl HeightSource {
+    pub fn name(self) -> &'static str {
+        match self {
+            HeightSource::Poll => "poll",
+            HeightSource::Command => "command",
+            HeightSource::Handset => "handset",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum DeskEvent {
+    /// The desk's height, in display units.
+    Height(f32),
+    /// The height sent just before this differed from the one before it.
+    HeightChanged {
+        source: HeightSource,
+        time: SystemTime,
+    },
+    Error {
+        kind: ErrorKind,
+        message: String,
+    },
+    Moving {
+        preset: u8,
+        eta: Option<Duration>,
+        target: Option<f32>,
+    },
+    ClockSkew {
+        seconds: f64,
+    },
+    /// The known preset heights, in inches.
+    Presets(BTreeMap<u8, f32>),
+    /// The controller's power relay should be switched.
+    Power(bool),
+    /// How fast the desk is moving, in display units per second.
+    Speed(f32),
+    /// How far the desk has moved in all, in display units.
+    TotalTravel(f32),
+    /// How long it has been since the desk was looked after, and whether it is due again.
+    Maintenance(MaintenanceStatus),
+    /// The controller started or stopped showing why the desk cannot move.
+    Condition {
+        condition: Condition,
+        active: bool,
+    },
+    /// How long a command took to start and to finish.
+    Latency(CommandLatency),
+    /// A command was not run, or was interrupted.
+    ///
+    /// `reason` is `busy` if another command was running, `offline` if the controller was not
+    /// responding, `preempted` if a newer command interrupted it, `blocked` if the interlock
+    /// sensor prevented the desk from being lowered, `idle` if a scheduled movement was skipped
+    /// because nobody was using the computer, or `unreachable` if no preset is beyond a scene's
+    /// height.
+    Rejected {
+        command: Command,
+        reason: &'static str,
+        /// The command that was running, or that interrupted this one.
+        other: Option<Command>,
+    },
+    /// Registers read for the diagnostic API, or why they could not be.
+    Registers {
+        address: u16,
+        count: u16,
+        result: Result<Vec<u16>, String>,
+    },
+    /// How a command sent through the API is getting on.
+    ///
+    /// `status` is `running` when it starts moving the desk or reading the controller, `queued`
+    /// while it waits for the controller to respond, and otherwise what became of it: `completed`,
+    /// `failed`, one of the reasons it was rejected, `coalesced` if another REFRESH answered it,
+    /// `held` if it kept an UP or DOWN going, or `replaced` if a newer command took its place in
+    /// the queue. `done` is false while more is to come, as when calibrating visits each preset.
+    Progress {
+        id: u64,
+        status: &'static str,
+        done: bool,
+    },
+}
+
+impl DeskEvent {
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            DeskEvent::Height(height) => serde_json::json!({
+                "type": "height",
+                "height": height,
+            }),
+            DeskEvent::HeightChanged { source, time } => serde_json::json!({
+                "type": "height_changed",
+                "source": source.name(),
+                "time": time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            }),
+            DeskEvent::Error { kind, message } => serde_json::json!({
+                "type": "error",
+                "kind": kind.name(),
+                "message": message,
+            }),
+            DeskEvent::Moving {
+                preset,
+                eta,
+                target,
+            } => serde_json::json!({
+                "type": "moving",
+                "preset": preset,
+                "eta_secs": eta.map(|eta| eta.as_secs_f32()),
+                "target": target,
+            }),
+            DeskEvent::ClockSkew { seconds } => serde_json::json!({
+                "type": "clock_skew",
+                "seconds": seconds,
+            }),
+            DeskEvent::Presets(heights) => serde_json::json!({
+                "type": "presets",
+                "heights": heights,
+            }),
+            DeskEvent::Power(on) => serde_json::json!({
+                "type": "power",
+                "on": on,
+            }),
+            DeskEvent::Speed(speed) => serde_json::json!({
+                "type": "speed",
+                "speed": speed,
+            }),
+            DeskEvent::TotalTravel(distance) => serde_json::json!({
+                "type": "total_travel",
+                "distance": distance,
+            }),
+            DeskEvent::Maintenance(status) => {
+                let mut json = status.to_json();
+                json["type"] = "maintenance".into();
+                json
+            }
+            DeskEvent::Condition { condition, active } => serde_json::json!({
+                "type": "condition",
+                "condition": condition.name(),
+                "active": active,
+            }),
+            DeskEvent::Latency(latency) => {
+                let mut json = latency.to_json();
+                json["type"] = "latency".into();
+                json
+            }
+            DeskEvent::Rejected {
+                command,
+                reason,
+                other,
+            } => serde_json::json!({
+                "type": "rejected",
+                "command": command.payload(),
+                "reason": reason,
+                "other": other.map(|other| other.payload()),
+            }),
+            DeskEvent::Registers {
+                address,
+                count,
+                result,
+            } => match result {
+                Ok(registers) => serde_json::json!({
+                    "type": "registers",
+                    "addr": address,
+                    "count": count,
+                    "registers": registers,
+                }),
+                Err(message) => serde_json::json!({
+                    "type": "registers",
+                    "addr": address,
+                    "count": count,
+                    "error": message,
+                }),
+            },
+            DeskEvent::Progress { id, status, done } => serde_json::json!({
+                "type": "progress",
+                "id": id,
+                "status": status,
+                "done": done,
+            }),
+        }
+    }
+}
+
+/// Broadcasts desk events to every subscriber.
+///
+/// The latest height is also kept, so subscribers that start late or fall behind can catch up
+/// without waiting for the desk to move.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<DeskEvent>,
+    height: Arc<AtomicU32>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            sender: broadcast::channel(capacity).0,
+            height: Arc::new(AtomicU32::new(NO_HEIGHT)),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<DeskEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Send `event` to every current subscriber, returning whether there were any. Nobody
+    /// listening is not an error.
+    pub fn send(&self, event: DeskEvent) -> bool {
+        if let DeskEvent::Height(height) = event {
+            self.height.store(height.to_bits(), Ordering::Relaxed);
+        }
+        self.sender.send(event).is_ok()
+    }
+
+    /// Report the progress of a command, if it came from the API.
+    pub fn progress(&self, source: Source, status: &'static str, done: bool) {
+        if let Source::Api(id) = source {
+            self.send(DeskEvent::Progress { id, status, done });
+        }
+    }
+
+    /// The last height sent, if any.
+    pub fn height(&self) -> Option<f32> {
+        let bits = self.height.load(Ordering::Relaxed);
+        (bits != NO_HEIGHT).then(|| f32::from_bits(bits))
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::VecDeque;
+
+use crate::settings::HeightFilterSettings;
+
+/// Smooths the heights read while moving to hide single-reading flicker.
+///
+/// The filter only applies while readings are coming in; once the desk comes to rest the raw
+/// reading is published and the filter starts over.
+pub struct HeightFilter {
+    settings: HeightFilterSettings,
+    window: VecDeque<f32>,
+    average: Option<f32>,
+}
+
+impl HeightFilter {
+    pub fn new(settings: HeightFilterSettings) -> Self {
+        Self {
+            settings,
+            window: VecDeque::with_capacity(3),
+            average: None,
+        }
+    }
+
+    /// Add a reading and get the value to publish.
+    pub fn push(&mut self, height: f32) -> f32 {
+        match self.settings {
+            HeightFilterSettings::None => height,
+            HeightFilterSettings::Median3 => {
+                if self.window.len() == 3 {
+                    self.window.pop_front();
+                }
+                self.window.push_back(height);
+                let mut sorted: Vec<f32> = self.window.iter().copied().collect();
+                sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                sorted[(sorted.len() - 1) / 2]
+            }
+            HeightFilterSettings::Ema { alpha } => {
+                let average = match self.average {
+                    Some(average) => average + alpha * (height - average),
+                    None => height,
+                };
+                self.average = Some(average);
+                // Don't show more precision than the display has.
+                (average * 10.0).round() / 10.0
+            }
+        }
+    }
+
+    /// Forget previous readings.
+    pub fn reset(&mut self) {
+        self.window.clear();
+        self.average = None;
+    }
+}
@@ -0,0 +1,129 @@
+//! Running as a Home Assistant add-on.
+//!
+//! The Supervisor starts add-ons with `SUPERVISOR_TOKEN` set and the options chosen in the add-on
+//! configuration saved to `/data/options.json`. The options use the same structure as the settings
+//! file. If they leave out `mqtt`, the broker is looked up from the Supervisor's MQTT service, the
+//! same way `bashio::services mqtt` does.
+
+use std::{
+    fs::File,
+    io::{Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use serde_yaml::Value;
+
+/// Where the Supervisor keeps the add-on's persistent files.
+const DATA_DIR: &str = "/data";
+
+/// The Supervisor API, reachable from inside every add-on container.
+const SUPERVISOR_HOST: &str = "supervisor";
+
+/// The add-on's persistent directory, if running as an add-on.
+pub fn data_dir() -> Option<PathBuf> {
+    std::env::var_os("SUPERVISOR_TOKEN").map(|_| PathBuf::from(DATA_DIR))
+}
+
+/// Load the add-on options as if they were the settings file.
+pub fn load_options() -> Result<Value> {
+    let path = PathBuf::from(DATA_DIR).join("options.json");
+    let file = File::open(path).context("Failed to open add-on options")?;
+    let options: serde_json::Value =
+        serde_json::from_reader(file).context("Failed to load add-on options")?;
+    let mut value = serde_yaml::to_value(options).context("Failed to load add-on options")?;
+    if let Value::Mapping(mapping) = &mut value {
+        let key = Value::from("mqtt");
+        if matches!(mapping.get(&key), None | Some(Value::Null)) {
+            mapping.insert(key, mqtt_service()?);
+        }
+    }
+    Ok(value)
+}
+
+/// The broker details provided by the Supervisor's MQTT service.
+#[derive(Deserialize)]
+struct MqttService {
+    host: String,
+    port: u16,
+    #[serde(default)]
+    ssl: bool,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ServiceResponse {
+    result: String,
+    #[serde(default)]
+    data: Option<MqttService>,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+/// Ask the Supervisor which broker to use, as `mqtt` settings.
+fn mqtt_service() -> Result<Value> {
+    let token = std::env::var("SUPERVISOR_TOKEN").context("SUPERVISOR_TOKEN is not set")?;
+    let body = get(&token, "/services/mqtt").context("Failed to look up the MQTT service")?;
+    let response: ServiceResponse =
+        serde_json::from_slice(&body).context("Invalid MQTT service response")?;
+    let service = match response {
+        ServiceResponse {
+            result,
+            data: Some(service),
+            ..
+        } if result == "ok" => service,
+        ServiceResponse { message, .. } => bail!(
+            "The MQTT service is not available, add mqtt settings to the add-on options: {}",
+            message.unwrap_or_default()
+        ),
+    };
+
+    let mut mqtt = serde_json::json!({
+        "host": service.host,
+        "port": service.port,
+        "transport": if service.ssl { "Tls" } else { "Tcp" },
+    });
+    if let (Some(username), Some(password)) = (service.username, service.password) {
+        mqtt["credentials"] = serde_json::json!({
+            "username": username,
+            "password": password,
+        });
+    }
+    serde_yaml::to_value(mqtt).context("Invalid MQTT service response")
+}
+
+/// Make a GET request to the Supervisor API and return the body.
+///
+/// This runs before the async runtime starts and when settings are reloaded, so it is a plain
+/// blocking HTTP/1.0 request.
+fn get(token: &str, path: &str) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect((SUPERVISOR_HOST, 80))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAuthorization: Bearer {}\r\n\r\n",
+        path, SUPERVISOR_HOST, token
+    )?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+
+    let split = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| anyhow!("Incomplete response"))?;
+    let status = response[..split]
+        .split(|&b| b == b' ')
+        .nth(1)
+        .and_then(|status| std::str::from_utf8(status).ok())
+        .ok_or_else(|| anyhow!("Invalid response"))?;
+    if status != "200" {
+        bail!("Supervisor responded with status {}", status);
+    }
+    Ok(response.split_off(split + 4))
+}
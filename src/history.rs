@@ -0,0 +1,179 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::error;
+use rusqlite::{params, Connection};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{events::DeskEvent, mqtt::State, settings::HistorySettings};
+
+/// How often old readings are downsampled and expired.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A record of heights over time, kept in SQLite so it does not depend on an external recorder.
+///
+/// Readings are kept as they are for `raw_days`, then replaced by hourly minimum, maximum, and
+/// average heights, which are kept for `retention_days`.
+pub struct History {
+    connection: Mutex<Connection>,
+    raw_days: u64,
+    retention_days: u64,
+}
+
+impl History {
+    pub fn open(settings: &HistorySettings) -> Result<Self> {
+        let connection = Connection::open(&settings.path).context("Failed to open history")?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS heights (
+                    timestamp INTEGER NOT NULL,
+                    height REAL NOT NULL,
+                    source TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS heights_timestamp ON heights (timestamp);
+                CREATE TABLE IF NOT EXISTS hourly (
+                    hour INTEGER PRIMARY KEY,
+                    min REAL NOT NULL,
+                    max REAL NOT NULL,
+                    avg REAL NOT NULL,
+                    samples INTEGER NOT NULL
+                );",
+            )
+            .context("Failed to create history tables")?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            raw_days: settings.raw_days,
+            retention_days: settings.retention_days,
+        })
+    }
+
+    pub fn record(&self, height: f32, source: &str) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO heights (timestamp, height, source) VALUES (?, ?, ?)",
+            params![now(), height, source],
+        )?;
+        Ok(())
+    }
+
+    /// Replace old readings with hourly summaries and delete summaries that are too old.
+    pub fn maintain(&self) -> Result<()> {
+        let now = now();
+        let raw_cutoff = now - (self.raw_days * 24 * 60 * 60) as i64;
+        let retention_cutoff = now - (self.retention_days * 24 * 60 * 60) as i64;
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO hourly (hour, min, max, avg, samples)
+            SELECT timestamp / 3600 * 3600, MIN(height), MAX(height), AVG(height), COUNT(*)
+            FROM heights WHERE timestamp < ?1 GROUP BY timestamp / 3600
+            ON CONFLICT (hour) DO UPDATE SET
+                min = MIN(min, excluded.min),
+                max = MAX(max, excluded.max),
+                avg = (avg * samples + excluded.avg * excluded.samples)
+                    / (samples + excluded.samples),
+                samples = samples + excluded.samples",
+            params![raw_cutoff],
+        )?;
+        transaction.execute(
+            "DELETE FROM heights WHERE timestamp < ?1",
+            params![raw_cutoff],
+        )?;
+        transaction.execute(
+            "DELETE FROM hourly WHERE hour < ?1",
+            params![retention_cutoff],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Get the readings between two times, in seconds since the Unix epoch.
+    pub fn raw(&self, from: i64, to: i64) -> Result<serde_json::Value> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT timestamp, height, source FROM heights
+            WHERE timestamp >= ?1 AND timestamp < ?2 ORDER BY timestamp",
+        )?;
+        let rows = statement
+            .query_map(params![from, to], |row| {
+                Ok(serde_json::json!({
+                    "timestamp": row.get::<_, i64>(0)?,
+                    "height": row.get::<_, f64>(1)?,
+                    "source": row.get::<_, String>(2)?,
+                }))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(serde_json::Value::Array(rows))
+    }
+
+    /// Get the hourly summaries between two times, in seconds since the Unix epoch.
+    pub fn hourly(&self, from: i64, to: i64) -> Result<serde_json::Value> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT hour, min, max, avg, samples FROM hourly
+            WHERE hour >= ?1 AND hour < ?2 ORDER BY hour",
+        )?;
+        let rows = statement
+            .query_map(params![from, to], |row| {
+                Ok(serde_json::json!({
+                    "timestamp": row.get::<_, i64>(0)?,
+                    "min": row.get::<_, f64>(1)?,
+                    "max": row.get::<_, f64>(2)?,
+                    "avg": row.get::<_, f64>(3)?,
+                    "samples": row.get::<_, i64>(4)?,
+                }))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(serde_json::Value::Array(rows))
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Record every height change, labelled with the command that caused it.
+pub async fn history_loop(state: State) -> Result<()> {
+    let history = match &state.history {
+        Some(history) => history.clone(),
+        None => return std::future::pending().await,
+    };
+    let mut commands = state.command.subscribe();
+    let mut events = state.events.subscribe();
+    let mut source = "startup".to_string();
+    let mut maintenance = tokio::time::interval(MAINTENANCE_INTERVAL);
+    loop {
+        tokio::select! {
+            recv = commands.recv() => match recv {
+                Ok((command, _)) => source = format!("{:?}", command).to_lowercase(),
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            recv = events.recv() => {
+                let height = match recv {
+                    Ok(DeskEvent::Height(height)) => height,
+                    Ok(_) => continue,
+                    // Some heights were missed, but the latest is still worth recording.
+                    Err(RecvError::Lagged(_)) => match state.events.height() {
+                        Some(height) => height,
+                        None => continue,
+                    },
+                    Err(RecvError::Closed) => return Ok(()),
+                };
+                if let Err(err) = history.record(height, &source) {
+                    error!("Failed to record height: {:?}", err);
+                }
+            }
+            _ = maintenance.tick() => {
+                if let Err(err) = history.maintain() {
+                    error!("Failed to maintain history: {:?}", err);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Running external commands when things happen, for scripts that do not speak MQTT.
+
+use std::process::Command;
+
+use anyhow::Result;
+use log::{error, info};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{events::DeskEvent, mqtt::State, settings::HookSettings};
+
+/// Run the commands in `settings` as events happen.
+///
+/// Details are passed in environment variables: `LC_EVENT` is the name of the event, `LC_HEIGHT`
+/// is the current height if known, `LC_COMMAND` is the command that finished moving the desk, and
+/// `LC_MESSAGE` and `LC_ERROR_KIND` are the error message and what it was about. Commands are
+/// started without waiting for them to finish.
+pub async fn hooks_loop(settings: &HookSettings, state: State) -> Result<()> {
+    if settings.is_empty() {
+        return std::future::pending().await;
+    }
+    let mut events = state.events.subscribe();
+    let mut last_height = state.events.height();
+    loop {
+        let height = match events.recv().await {
+            Ok(DeskEvent::Height(height)) => height,
+            // Having fallen behind, compare against the latest height instead.
+            Err(RecvError::Lagged(_)) => match state.events.height() {
+                Some(height) => height,
+                None => continue,
+            },
+            Ok(DeskEvent::Latency(latency)) if latency.command.is_movement() => {
+                if let Some(command) = &settings.movement_finished {
+                    run(
+                        command,
+                        "movement_finished",
+                        state.events.height(),
+                        &[("LC_COMMAND", latency.command.payload())],
+                    );
+                }
+                continue;
+            }
+            Ok(DeskEvent::Error { kind, message }) => {
+                if let Some(command) = &settings.error {
+                    let height = state.events.height();
+                    run(
+                        command,
+                        "error",
+                        height,
+                        &[
+                            ("LC_MESSAGE", message.as_str()),
+                            ("LC_ERROR_KIND", kind.name()),
+                        ],
+                    );
+                }
+                continue;
+            }
+            Ok(_) => continue,
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        // Only crossings count, so a desk resting above a threshold does not keep triggering it.
+        if let Some(from) = last_height {
+            for hook in &settings.height_above {
+                if from <= hook.threshold && height > hook.threshold {
+                    run(&hook.command, "height_above", Some(height), &[]);
+                }
+            }
+            for hook in &settings.height_below {
+                if from >= hook.threshold && height < hook.threshold {
+                    run(&hook.command, "height_below", Some(height), &[]);
+                }
+            }
+        }
+        last_height = Some(height);
+    }
+}
+
+/// Start `command`, the program followed by its arguments.
+fn run(command: &[String], event: &str, height: Option<f32>, extra: &[(&str, &str)]) {
+    let (program, args) = match command.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    info!("Running {:?} for {}", program, event);
+    let mut process = Command::new(program);
+    process.args(args).env("LC_EVENT", event);
+    if let Some(height) = height {
+        process.env("LC_HEIGHT", height.to_string());
+    }
+    process.envs(extra.iter().copied());
+    match process.spawn() {
+        // Wait in the background so finished commands do not linger as zombies.
+        Ok(mut child) => {
+            tokio::task::spawn_blocking(move || child.wait());
+        }
+        Err(err) => error!("Failed to run {:?} for {}: {}", program, event, err),
+    }
+}
@@ -1,153 +1,374 @@
+mod api;
+mod audit;
+mod auth;
+mod backup;
+mod calibration;
+mod cloud;
+mod compat;
+mod connection;
+mod console;
+mod diagnostics;
+mod error;
+mod events;
+mod filter;
+mod hassio;
+mod history;
+mod hooks;
+mod maintenance;
 mod mqtt;
+mod names;
+mod persist;
+mod power;
+mod presence;
+mod protocol;
+mod reconnect;
+#[cfg(all(target_os = "linux", feature = "sandbox"))]
+mod sandbox;
+mod schedule;
+#[cfg(all(windows, feature = "service"))]
+mod service;
 mod settings;
-mod timeout;
-mod transfer;
-
-use anyhow::anyhow;
-use log::{debug, error, info};
-use mqtt::{MqttHandle, State};
-use settings::{load_settings, Settings};
-use std::time::Duration;
-use timeout::TimeoutPort;
+mod setup;
+mod snapshot;
+mod timetable;
+mod tls;
+mod transport;
+mod update;
+#[cfg(all(windows, feature = "service"))]
+mod user;
+mod watchdog;
+
+use anyhow::{anyhow, Context as _};
+use api::api_loop;
+use audit::AuditLog;
+use calibration::HeightCorrection;
+use connection::Connection;
+use console::console_loop;
+use diagnostics::{CommandLatency, Diagnostics};
+use error::Error;
+use events::{DeskEvent, ErrorKind, EventBus, HeightSource};
+use filter::HeightFilter;
+use history::{history_loop, History};
+use hooks::hooks_loop;
+use laing_protocol::{
+    display::{self, Reading},
+    frames::{self, COMMAND_ADDRESS, COMMAND_LEN, IDLE, STANDBY, STATE_ADDRESS, STATE_LEN, WAKE},
+};
+use log::{debug, error, info, warn};
+use maintenance::{parse_date, Reminder};
+use mqtt::{MqttHandle, RegisterRead, SceneCommand, Source, State};
+use persist::{load_state, save_state, PersistedState};
+use power::PowerRelay;
+use protocol::{Exception, ProtocolError};
+use reconnect::Backoff;
+use schedule::schedule_loop;
+use settings::{
+    load_settings, load_settings_value, needs_mqtt_restart, needs_restart, BusyCommands,
+    OfflineCommands, RegisterAccess, Settings, WakePulseSettings, WatchdogSettings,
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    sync::oneshot,
+    sync::{broadcast, mpsc, Notify},
 };
 use tokio_modbus::{client::Context, prelude::*};
 use tokio_serial::SerialStream;
-use transfer::TransferPort;
+use tokio_util::{either::Either, sync::CancellationToken};
+use transport::{
+    bus::SharedBusPort,
+    gap::{silent_interval, GapPort},
+    inspect::{trace_chunk, Capture, InspectPort, PortMetrics, ReplayPort},
+    timeout::TimeoutPort,
+    transfer::{TransferMetrics, TransferPort},
+    wake::WakePort,
+};
+use watchdog::{Verdict, Watch};
 
 use crate::mqtt::mqtt_loop;
 
-static WAKE: [u16; 14] = [
-    0x0000, 0x0000, 0x0009, 0x0000, 0x0008, 0x0005, 0x0001, 0x005A, 0x0011, 0x0008, 0x0017, 0x0000,
-    0x0000, 0x0000,
-];
-static IDLE: [u16; 14] = [
-    0x0000, 0x0000, 0x0000, 0x0000, 0x0008, 0x0005, 0x0001, 0x005A, 0x0011, 0x0008, 0x0017, 0x0000,
-    0x0000, 0x0000,
-];
-static PRESET1: [[u16; 14]; 2] = [
-    [
-        0x0000, 0x0000, 0x0001, 0x0000, 0x0008, 0x0005, 0x0001, 0x005A, 0x0011, 0x0008, 0x0017,
-        0x0000, 0x0000, 0x0000,
-    ],
-    [
-        0x0000, 0x0000, 0x0001, 0x0001, 0x0008, 0x0005, 0x0001, 0x005A, 0x0011, 0x0008, 0x0017,
-        0x0000, 0x0000, 0x0000,
-    ],
-];
-static PRESET2: [[u16; 14]; 2] = [
-    [
-        0x0000, 0x0000, 0x0002, 0x0000, 0x0008, 0x0005, 0x0001, 0x005A, 0x0011, 0x0008, 0x0017,
-        0x0000, 0x0000, 0x0000,
-    ],
-    [
-        0x0000, 0x0000, 0x0002, 0x0002, 0x0008, 0x0005, 0x0001, 0x005A, 0x0011, 0x0008, 0x0017,
-        0x0000, 0x0000, 0x0000,
-    ],
-];
-static PRESET3: [[u16; 14]; 2] = [
-    [
-        0x0000, 0x0000, 0x0003, 0x0000, 0x0008, 0x0005, 0x0001, 0x005A, 0x0011, 0x0008, 0x0017,
-        0x0000, 0x0000, 0x0000,
-    ],
-    [
-        0x0000, 0x0000, 0x0003, 0x0003, 0x0008, 0x0005, 0x0001, 0x005A, 0x0011, 0x0008, 0x0017,
-        0x0000, 0x0000, 0x0000,
-    ],
-];
-static PRESET4: [[u16; 14]; 2] = [
-    [
-        0x0000, 0x0000, 0x0004, 0x0000, 0x0008, 0x0005, 0x0001, 0x005A, 0x0011, 0x0008, 0x0017,
-        0x0000, 0x0000, 0x0000,
-    ],
-    [
-        0x0000, 0x0000, 0x0004, 0x0004, 0x0008, 0x0005, 0x0001, 0x005A, 0x0011, 0x0008, 0x0017,
-        0x0000, 0x0000, 0x0000,
-    ],
-];
-
-fn decode_digit(value: u8) -> Option<u8> {
-    match value & 0x7f {
-        0b0111111 => Some(0),
-        0b0000110 => Some(1),
-        0b1011011 => Some(2),
-        0b1001111 => Some(3),
-        0b1100110 => Some(4),
-        0b1101101 => Some(5),
-        0b1111101 => Some(6),
-        0b0000111 => Some(7),
-        0b1111111 => Some(8),
-        0b1101111 => Some(9),
-        _ => {
-            dbg!(value);
-            None
+/// Choose the preset to head for to pass through `target` when starting at `from`: the nearest
+/// one at or beyond it.
+fn restore_preset(heights: &BTreeMap<u8, u16>, from: u16, target: u16) -> Option<u8> {
+    let beyond = heights.iter().filter(|&(_, &height)| {
+        if target > from {
+            height >= target
+        } else {
+            height <= target
         }
-    }
+    });
+    beyond
+        .min_by_key(|&(_, &height)| height.abs_diff(target))
+        .map(|(&preset, _)| preset)
 }
 
-fn decode(values: &[u16; 2]) -> Option<u16> {
-    if values[0] & 0x8080 != 0x8000 || values[1] & 0xff80 != 0 {
-        None
+/// Choose the preset for an UP or DOWN command starting at `from`: the farthest known one in that
+/// direction, so the desk keeps going for as long as the command is held.
+fn jog_preset(heights: &BTreeMap<u8, u16>, from: u16, up: bool) -> Option<u8> {
+    let beyond = heights.iter().filter(|&(_, &height)| {
+        if up {
+            height > from.saturating_add(2)
+        } else {
+            height < from.saturating_sub(2)
+        }
+    });
+    if up {
+        beyond.max_by_key(|&(_, &height)| height)
     } else {
-        Some(
-            decode_digit((values[0] & 0xff) as u8)? as u16
-                + 10 * decode_digit((values[0] >> 8 & 0x7f) as u8)? as u16
-                + 100 * decode_digit((values[1] & 0xff) as u8)? as u16,
-        )
+        beyond.min_by_key(|&(_, &height)| height)
     }
+    .map(|(&preset, _)| preset)
 }
 
-async fn transmit(
+/// The presets visited to learn their heights.
+static CALIBRATION: [(u8, mqtt::Command); 4] = [
+    (1, mqtt::Command::Preset1),
+    (2, mqtt::Command::Preset2),
+    (3, mqtt::Command::Preset3),
+    (4, mqtt::Command::Preset4),
+];
+
+/// Write `send` to the command registers and read back the state registers.
+async fn exchange(
     client: &mut Context,
-    send: &[u16; 14],
+    send: &[u16; COMMAND_LEN],
     mqtt: &mut MqttHandle,
-) -> anyhow::Result<Option<u16>> {
-    let response = client
-        .read_write_multiple_registers(0x9c4, 20, 0xa8c, &send[..])
-        .await?;
-
-    let height = decode((&response[0..2]).try_into().unwrap());
-    if let Some(height) = height {
-        mqtt.set_height(f32::from(height) / 10.0f32)?;
+) -> Result<Vec<u16>, ProtocolError> {
+    if mqtt.register_access != RegisterAccess::Separate {
+        match client
+            .read_write_multiple_registers(
+                STATE_ADDRESS,
+                STATE_LEN as u16,
+                COMMAND_ADDRESS,
+                &send[..],
+            )
+            .await
+        {
+            Ok(response) => {
+                mqtt.register_access = RegisterAccess::Combined;
+                return Ok(response);
+            }
+            Err(err) => match ProtocolError::classify(err) {
+                ProtocolError::Exception(Exception::IllegalFunction, message)
+                    if mqtt.register_access == RegisterAccess::Auto =>
+                {
+                    warn!(
+                        "Read/Write Multiple Registers is not supported ({}); writing and reading separately",
+                        message
+                    );
+                    mqtt.register_access = RegisterAccess::Separate;
+                }
+                err => return Err(err),
+            },
+        }
     }
+    client
+        .write_multiple_registers(COMMAND_ADDRESS, &send[..])
+        .await
+        .map_err(ProtocolError::classify)?;
+    tokio::time::sleep(mqtt.register_gap).await;
+    client
+        .read_holding_registers(STATE_ADDRESS, STATE_LEN as u16)
+        .await
+        .map_err(ProtocolError::classify)
+}
+
+async fn transmit<T: AsyncRead + AsyncWrite + Send + 'static>(
+    connection: &mut Connection<'_, T>,
+    send: &[u16; COMMAND_LEN],
+    mqtt: &mut MqttHandle,
+) -> error::Result<Option<u16>> {
+    let response = exchange(connection.client(), send, mqtt).await;
+    connection.record(&response);
+    let response = match response {
+        Ok(response) => response,
+        Err(err) => {
+            mqtt.diagnostics.record_protocol_error(&err);
+            return Err(err.into());
+        }
+    };
+
+    let offset = match mqtt.display_offset {
+        Some(offset) => offset,
+        None => match display::find(mqtt.decoder.as_ref(), &response) {
+            Some(offset) => {
+                info!(
+                    "Found the display in state registers {} and {}",
+                    offset,
+                    offset + 1
+                );
+                mqtt.display_offset = Some(offset);
+                offset
+            }
+            // The display is blank while the controller sleeps, so look again next time.
+            None => {
+                debug!("The display is not in the state registers yet");
+                return Ok(None);
+            }
+        },
+    };
+    let registers = response.get(offset..offset + 2).ok_or_else(|| {
+        Error::Decode(format!(
+            "expected at least {} registers, got {}",
+            offset + 2,
+            response.len()
+        ))
+    })?;
+    let reading = match mqtt.decoder.read(registers.try_into().unwrap()) {
+        Some(Reading::Height(height)) => Some(Reading::Height(mqtt.correction.apply(height))),
+        reading => reading,
+    };
+    let height = match reading {
+        // A corrupted frame can still decode, as something no desk could be at.
+        Some(Reading::Height(height)) if !mqtt.possible_heights.contains(&height) => {
+            warn!(
+                "Ignoring an impossible height of {}",
+                f32::from(height) / 10.0
+            );
+            mqtt.diagnostics
+                .implausible_heights
+                .fetch_add(1, Ordering::Relaxed);
+            None
+        }
+        Some(Reading::Height(height)) => {
+            mqtt.set_height(f32::from(height) / 10.0f32)?;
+            mqtt.report_display(None);
+            Some(height)
+        }
+        Some(Reading::Text(text)) => {
+            mqtt.report_display(Some(text));
+            None
+        }
+        Some(Reading::Blank) | None => None,
+    };
 
     Ok(height)
 }
 
+/// What happened during a call to `operate`.
+#[derive(Default)]
+struct Outcome {
+    /// The height before any movement command was sent.
+    start_height: Option<u16>,
+    /// The height at the end of the operation.
+    end_height: Option<u16>,
+    /// How long the desk was seen to be moving for.
+    travel_time: Option<Duration>,
+    /// Whether the controller responded for the whole operation.
+    completed: bool,
+    /// When the first register write after waking the controller was sent.
+    first_write: Option<Instant>,
+}
+
 async fn operate<T: AsyncRead + AsyncWrite + Send + 'static>(
     port: &mut TransferPort<T>,
     server_addr: Slave,
-    command: Option<&[[u16; 14]; 2]>,
+    command: Option<&[[u16; COMMAND_LEN]; 2]>,
+    movement_limit: Option<Duration>,
+    stop_at: Option<u16>,
     mqtt: &mut MqttHandle,
-) -> anyhow::Result<Option<u16>> {
-    let mut client = rtu::connect_slave(port.take(), server_addr).await?;
+) -> error::Result<Outcome> {
+    let mut connection = Connection::open(port, server_addr).await?;
     debug!("sending wake message");
     loop {
         // The controller often reacts to but fails to respond to the first message.
         // Keep trying until we get a response.
-        match transmit(&mut client, &WAKE, mqtt).await {
+        match transmit(&mut connection, &WAKE, mqtt).await {
             Ok(_) => {
+                let reconnects = connection.health().reconnects;
+                if reconnects > 0 {
+                    debug!("controller woke after {} retries", reconnects);
+                }
                 break;
             }
             Err(err) => {
-                error!("Failed to wake controller (will retry): {:?}", err);
-                client.disconnect().await?;
-                client = rtu::connect_slave(port.take(), server_addr).await?;
+                match &err {
+                    Error::Protocol(ProtocolError::Exception(Exception::IllegalDataAddress, _)) => {
+                        // Retrying will not help if the registers are wrong.
+                        error!("Controller rejected the register addresses: {}", err);
+                        mqtt.report_error(
+                            ErrorKind::Protocol,
+                            format!(
+                                "controller rejected the register addresses, check that it is a supported model: {}",
+                                err
+                            ),
+                        );
+                        return Err(err);
+                    }
+                    Error::Protocol(ProtocolError::Exception(Exception::ServerDeviceBusy, _)) => {
+                        warn!("Controller is busy (will retry)");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                    _ => error!("Failed to wake controller (will retry): {:?}", err),
+                }
+                connection.reconnect().await?;
             }
         }
     }
     debug!("sending idle");
-    let mut last_height = transmit(&mut client, &IDLE, mqtt).await?;
+    let first_write = Instant::now();
+    let mut last_height = transmit(&mut connection, &IDLE, mqtt).await?;
+    let mut outcome = Outcome {
+        start_height: last_height,
+        completed: true,
+        first_write: Some(first_write),
+        ..Outcome::default()
+    };
     if let Some(command) = command {
         debug!("sending lead");
-        last_height = transmit(&mut client, &command[0], mqtt).await?;
+        let start = Instant::now();
+        let mut last_change = start;
+        last_height = transmit(&mut connection, &command[0], mqtt).await?;
+        let mut last_reading = (Instant::now(), last_height);
         let mut since_change = 0;
+        let mut stopped_early = false;
+        let mut moving_since = start;
         loop {
             tokio::time::sleep(Duration::from_millis(500)).await;
+            if let Some(gentle) = &mqtt.gentle_movement {
+                if moving_since.elapsed() >= Duration::from_millis(gentle.move_ms) {
+                    let pause = Duration::from_millis(gentle.pause_ms);
+                    debug!("pausing");
+                    transmit(&mut connection, &IDLE, mqtt).await?;
+                    mqtt.report_speed(0.0);
+                    tokio::time::sleep(pause).await;
+                    debug!("sending lead");
+                    last_height = transmit(&mut connection, &command[0], mqtt).await?;
+                    last_reading = (Instant::now(), last_height);
+                    moving_since = Instant::now();
+                    since_change = 0;
+                    continue;
+                }
+            }
+            if let Some(limit) = movement_limit {
+                if start.elapsed() > limit {
+                    stopped_early = true;
+                    warn!("Movement took longer than {:?}; stopping", limit);
+                    mqtt.report_error(
+                        ErrorKind::Movement,
+                        format!("movement took longer than {} seconds", limit.as_secs()),
+                    );
+                    break;
+                }
+            }
             debug!("sending command");
-            let res = transmit(&mut client, &command[1], mqtt).await?;
+            let res = transmit(&mut connection, &command[1], mqtt).await?;
+            if let (Some(from), Some(to)) = (last_reading.1, res) {
+                let elapsed = last_reading.0.elapsed().as_secs_f32();
+                let distance = (f32::from(to) - f32::from(from)).abs() / 10.0;
+                mqtt.report_speed(distance / elapsed);
+            }
+            last_reading = (Instant::now(), res);
+            if let (Some(target), Some(from), Some(height)) = (stop_at, outcome.start_height, res) {
+                if (from <= target && height >= target) || (from >= target && height <= target) {
+                    // Stopping short of the preset, so this is not a travel time to learn from.
+                    stopped_early = true;
+                    break;
+                }
+            }
             if res == last_height {
                 if since_change < 1 {
                     since_change += 1;
@@ -156,277 +377,1569 @@ async fn operate<T: AsyncRead + AsyncWrite + Send + 'static>(
                 }
             } else {
                 last_height = res;
+                last_change = Instant::now();
             }
         }
+        // Pauses would make the desk seem slower than it is.
+        if !stopped_early && mqtt.gentle_movement.is_none() {
+            outcome.travel_time = Some(last_change - start);
+        }
+        mqtt.report_speed(0.0);
         debug!("sending idle");
-        last_height = transmit(&mut client, &IDLE, mqtt).await?;
+        last_height = transmit(&mut connection, &IDLE, mqtt).await?;
     }
 
-    client.disconnect().await?;
+    connection.close().await?;
 
-    Ok(last_height)
+    if let Some(height) = last_height {
+        mqtt.set_resting_height(f32::from(height) / 10.0)?;
+    }
+    outcome.end_height = last_height;
+    Ok(outcome)
 }
 
-#[cfg(windows)]
-windows_service::define_windows_service!(ffi_service_main, service_main);
-
-#[cfg(windows)]
-fn service_main(_arguments: Vec<std::ffi::OsString>) {
-    if let Err(err) = real_service_main() {
-        error!("Service failed: {:?}", err);
-        std::process::exit(1);
+/// Put the controller's display into standby without waking it first.
+async fn standby<T: AsyncRead + AsyncWrite + Send + 'static>(
+    port: &mut TransferPort<T>,
+    server_addr: Slave,
+    mqtt: &mut MqttHandle,
+) -> error::Result<()> {
+    let mut connection = Connection::open(port, server_addr).await?;
+    debug!("sending standby");
+    let mut result = Ok(None);
+    // As with waking, the controller often fails to respond to the first message.
+    for _ in 0..3 {
+        result = transmit(&mut connection, &STANDBY, mqtt).await;
+        if result.is_ok() {
+            break;
+        }
+        connection.reconnect().await?;
     }
+    connection.close().await?;
+    result.map(|_| ())
 }
 
-#[cfg(windows)]
-fn real_service_main() -> anyhow::Result<()> {
-    use std::sync::{Arc, Mutex};
+/// Toggle the serial adapter's control lines, leaving them asserted as they are when the port opens.
+fn pulse_lines(serial: &mut SerialStream, pulse: &WakePulseSettings) -> std::io::Result<()> {
+    use tokio_serial::SerialPort;
 
-    use windows_service::{
-        service::{
-            ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceStatus, ServiceType,
-        },
-        service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
-    };
+    debug!("pulsing serial control lines");
+    for level in [false, true] {
+        if pulse.dtr {
+            serial.write_data_terminal_ready(level)?;
+        }
+        if pulse.rts {
+            serial.write_request_to_send(level)?;
+        }
+    }
+    Ok(())
+}
 
-    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel();
-    let status_handle = Arc::new(Mutex::new(Option::<ServiceStatusHandle>::None));
-
-    let main = {
-        let mut lock = status_handle.lock().unwrap();
-        let status_handle = status_handle.clone();
-        let mut stop_tx = Some(stop_tx);
-        *lock = Some(
-            service_control_handler::register("laing-controller", move |control_event| {
-                match control_event {
-                    ServiceControl::Shutdown | ServiceControl::Stop => {
-                        if let Some(stop_tx) = stop_tx.take() {
-                            match stop_tx.send(()) {
-                                Ok(()) => {
-                                    status_handle
-                                        .lock()
-                                        .unwrap()
-                                        .unwrap()
-                                        .set_service_status(ServiceStatus {
-                                            controls_accepted: ServiceControlAccept::empty(),
-                                            current_state:
-                                                windows_service::service::ServiceState::StopPending,
-                                            service_type: ServiceType::OWN_PROCESS,
-                                            exit_code: ServiceExitCode::NO_ERROR,
-                                            checkpoint: 0,
-                                            process_id: Some(std::process::id()),
-                                            // Give us some time to stop in case the desk is in motion.
-                                            wait_hint: Duration::from_secs(10),
-                                        })
-                                        .unwrap();
-                                    ServiceControlHandlerResult::NoError
-                                }
-                                Err(()) => {
-                                    error!("Clean service stop failed");
-                                    ServiceControlHandlerResult::NoError
-                                }
-                            }
-                        } else {
-                            ServiceControlHandlerResult::NoError
-                        }
-                    }
-                    ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
-                    _ => ServiceControlHandlerResult::NotImplemented,
-                }
-            })
-            .map_err(|e| anyhow!("Failed to register service: {:?}", e))?,
-        );
+/// Read the height without waking the controller's display.
+///
+/// This sends the message the handset sends when it has not been touched for a while, once, since
+/// a missed reading is simply taken again next time.
+async fn listen<T: AsyncRead + AsyncWrite + Send + 'static>(
+    port: &mut TransferPort<T>,
+    server_addr: Slave,
+    mqtt: &mut MqttHandle,
+) -> error::Result<Option<u16>> {
+    let mut connection = Connection::open(port, server_addr).await?;
+    debug!("sending standby to listen");
+    let result = transmit(&mut connection, &STANDBY, mqtt).await;
+    connection.close().await?;
+    result
+}
 
-        let main = Main::init()?;
-
-        lock.unwrap()
-            .set_service_status(ServiceStatus {
-                controls_accepted: ServiceControlAccept::SHUTDOWN | ServiceControlAccept::STOP,
-                current_state: windows_service::service::ServiceState::Running,
-                service_type: ServiceType::OWN_PROCESS,
-                exit_code: ServiceExitCode::NO_ERROR,
-                checkpoint: 0,
-                process_id: Some(std::process::id()),
-                wait_hint: Duration::ZERO,
-            })
-            .map_err(|e| anyhow!("Failed to set service to running: {:?}", e))?;
-        main
+/// Read registers for the diagnostic API, waking the controller first.
+async fn read_registers<T: AsyncRead + AsyncWrite + Send + 'static>(
+    port: &mut TransferPort<T>,
+    server_addr: Slave,
+    read: RegisterRead,
+    mqtt: &mut MqttHandle,
+) -> error::Result<Vec<u16>> {
+    let mut connection = Connection::open(port, server_addr).await?;
+    let mut result = Ok(None);
+    // As with waking, the controller often fails to respond to the first message.
+    for _ in 0..3 {
+        result = transmit(&mut connection, &WAKE, mqtt).await;
+        if result.is_ok() {
+            break;
+        }
+        connection.reconnect().await?;
+    }
+    let registers = match result {
+        Ok(_) => connection
+            .client()
+            .read_holding_registers(read.address, read.count)
+            .await
+            .map_err(|err| ProtocolError::classify(err).into()),
+        Err(err) => Err(err),
     };
+    connection.close().await?;
+    registers
+}
 
-    let result = main.run(stop_rx);
-    let lock = status_handle.lock().unwrap();
-    let code = if let Err(error) = result {
-        error!("Service died: {:?}", error);
-        ServiceExitCode::ServiceSpecific(1)
-    } else {
-        ServiceExitCode::NO_ERROR
-    };
-    lock.unwrap()
-        .set_service_status(ServiceStatus {
-            controls_accepted: ServiceControlAccept::empty(),
-            current_state: windows_service::service::ServiceState::Stopped,
-            service_type: ServiceType::OWN_PROCESS,
-            exit_code: code,
-            checkpoint: 0,
-            process_id: Some(std::process::id()),
-            wait_hint: Duration::ZERO,
-        })
-        .map_err(|e| anyhow!("Failed to set service to stopped: {:?}", e))?;
+/// Read the height the controller has stored for `preset` from the four registers at `address`.
+async fn read_preset_height<T: AsyncRead + AsyncWrite + Send + 'static>(
+    port: &mut TransferPort<T>,
+    server_addr: Slave,
+    address: u16,
+    preset: u8,
+    mqtt: &mut MqttHandle,
+) -> error::Result<Option<u16>> {
+    let registers =
+        read_registers(port, server_addr, RegisterRead { address, count: 4 }, mqtt).await?;
+    // Unset presets read as 0 on some controllers and 0xFFFF on others.
+    Ok(registers
+        .get(usize::from(preset) - 1)
+        .copied()
+        .filter(|&height| height != 0 && height != u16::MAX)
+        .map(|height| mqtt.correction.apply(height)))
+}
 
-    Ok(())
+/// Run `operate`, giving up if it does not finish within `deadline`.
+///
+/// A hung modbus future would otherwise block every future command. When the deadline passes, the
+/// port handle is revoked and the controller is sent an idle message from a fresh context so it
+/// stops moving.
+async fn operate_with_deadline<T: AsyncRead + AsyncWrite + Send + 'static>(
+    port: &mut TransferPort<T>,
+    server_addr: Slave,
+    command: Option<&[[u16; COMMAND_LEN]; 2]>,
+    movement_limit: Option<Duration>,
+    stop_at: Option<u16>,
+    mqtt: &mut MqttHandle,
+    deadline: Duration,
+) -> error::Result<Outcome> {
+    match tokio::time::timeout(
+        deadline,
+        operate(port, server_addr, command, movement_limit, stop_at, mqtt),
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(_) => {
+            error!("Operation did not finish within {:?}; resetting", deadline);
+            mqtt.report_error(
+                ErrorKind::Timeout,
+                format!(
+                    "operation did not finish within {} seconds",
+                    deadline.as_secs()
+                ),
+            );
+            // The outcome is not completed, so the controller is marked unavailable whether or not
+            // this works.
+            let _ = reset_with_retry(port, server_addr, mqtt, deadline, "timeout").await;
+            Ok(Outcome::default())
+        }
+    }
 }
 
-#[cfg(windows)]
-pub fn main() -> Result<(), Box<dyn std::error::Error>> {
-    use windows_service::{
-        service::{ServiceAccess, ServiceErrorControl, ServiceInfo, ServiceStartType, ServiceType},
-        service_dispatcher,
-        service_manager::{ServiceManager, ServiceManagerAccess},
-    };
+/// Count a REFRESH that is answered by one already being handled.
+fn coalesce_refresh(diagnostics: &Diagnostics, events: &EventBus, source: Source) {
+    debug!("Coalescing REFRESH");
+    diagnostics
+        .coalesced_refreshes
+        .fetch_add(1, Ordering::Relaxed);
+    events.progress(source, "coalesced", true);
+}
 
-    match std::env::args().nth(1).as_deref() {
-        Some("service-register") => {
-            let manager =
-                ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CREATE_SERVICE)?;
-            manager.create_service(
-                &ServiceInfo {
-                    name: "laing-controller".into(),
-                    display_name: "Laing Controller".into(),
-                    service_type: ServiceType::OWN_PROCESS,
-                    start_type: ServiceStartType::AutoStart,
-                    error_control: ServiceErrorControl::Normal,
-                    executable_path: std::env::current_exe()?,
-                    launch_arguments: vec!["service".into()],
-                    dependencies: vec![],
-                    account_name: None,
-                    account_password: None,
-                },
-                ServiceAccess::QUERY_STATUS,
-            )?;
-            Ok(())
-        }
-        Some("service-deregister") => {
-            let manager =
-                ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CREATE_SERVICE)?;
-            let service = manager.open_service("laing-controller", ServiceAccess::DELETE)?;
-            service.delete()?;
-            Ok(())
-        }
-        Some("log-register") => {
-            eventlog::register("laing-controller")?;
-            Ok(())
-        }
-        Some("log-deregister") => {
-            eventlog::deregister("laing-controller")?;
-            Ok(())
-        }
-        Some("service") => {
-            let level = match std::env::var("LC_LOG_LEVEL").ok().as_deref() {
-                Some("trace") => log::Level::Trace,
-                Some("debug") => log::Level::Debug,
-                Some("info") => log::Level::Info,
-                Some("warn") => log::Level::Warn,
-                Some("error") => log::Level::Error,
-                _ => log::Level::Info,
-            };
-            eventlog::init("laing-controller", level).unwrap();
+/// Report what became of a command from the API. It is done unless more of it is waiting to run,
+/// as when calibrating.
+fn finish(
+    events: &EventBus,
+    pending: &VecDeque<(mqtt::Command, Source)>,
+    source: Source,
+    status: &'static str,
+) {
+    let done = !pending.iter().any(|&(_, other)| other == source);
+    events.progress(source, status, done);
+}
+
+/// Why a command was stopped before it finished.
+enum Interruption {
+    /// A newer movement command replaced it.
+    Preempted(mqtt::Command, Source),
+    /// The interlock sensor reported blocked while lowering.
+    Blocked,
+    /// An UP or DOWN command was not sent again in time.
+    Released,
+    /// A STOP command was received.
+    Stopped(Source),
+    /// The program is stopping.
+    Shutdown,
+}
 
-            service_dispatcher::start("laing-controller", ffi_service_main)?;
-            Ok(())
+/// Revoke the port from an unfinished operation and tell the controller to stop, returning the
+/// height it stopped at if it answered.
+async fn reset<T: AsyncRead + AsyncWrite + Send + 'static>(
+    port: &mut TransferPort<T>,
+    server_addr: Slave,
+    mqtt: &mut MqttHandle,
+    deadline: Duration,
+    cause: &'static str,
+) -> error::Result<Option<u16>> {
+    mqtt.diagnostics.record_recovery(cause);
+    let mut connection = Connection::open(port, server_addr).await?;
+    let height = match tokio::time::timeout(deadline, transmit(&mut connection, &IDLE, mqtt)).await
+    {
+        Ok(Ok(height)) => height,
+        Ok(Err(err)) => {
+            error!("Failed to idle controller after reset: {:?}", err);
+            None
         }
-        Some(other) => return Err(anyhow!("Unexpected parameter: {}", other).into()),
-        _ => {
-            standard_main()?;
-            Ok(())
+        Err(_) => {
+            error!("Timed out idling controller after reset");
+            None
         }
+    };
+    connection.close().await?;
+    Ok(height)
+}
+
+/// Reset the connection, trying a few times, since failing to take the port back is not a reason to
+/// stop the control loop. Returns the last error if every attempt failed.
+async fn reset_with_retry<T: AsyncRead + AsyncWrite + Send + 'static>(
+    port: &mut TransferPort<T>,
+    server_addr: Slave,
+    mqtt: &mut MqttHandle,
+    deadline: Duration,
+    cause: &'static str,
+) -> error::Result<Option<u16>> {
+    const RESET_ATTEMPTS: u32 = 5;
+    let mut backoff = Backoff::new(
+        Duration::from_secs(1),
+        Duration::from_secs(10),
+        Duration::from_millis(250),
+    );
+    let mut attempt = 1;
+    loop {
+        match reset(port, server_addr, mqtt, deadline, cause).await {
+            Ok(height) => return Ok(height),
+            Err(err) => {
+                error!(
+                    "Failed to reset the controller ({}, attempt {} of {}): {:?}",
+                    cause, attempt, RESET_ATTEMPTS, err
+                );
+                if attempt == RESET_ATTEMPTS {
+                    return Err(err);
+                }
+            }
+        }
+        tokio::time::sleep(backoff.next_delay()).await;
+        attempt += 1;
     }
 }
 
-#[cfg(not(windows))]
 pub fn main() -> Result<(), Box<dyn std::error::Error>> {
-    standard_main()?;
+    let parameter = std::env::args().nth(1);
+    #[cfg(all(windows, feature = "service"))]
+    if let Some(result) = parameter.as_deref().and_then(service::command) {
+        return result;
+    }
+    match parameter.as_deref() {
+        Some("setup") => setup::setup()?,
+        Some("snapshot") => snapshot::snapshot(&std::env::args().skip(2).collect::<Vec<_>>())?,
+        Some("diff") => snapshot::diff(&std::env::args().skip(2).collect::<Vec<_>>())?,
+        Some("update") => update::update(&std::env::args().skip(2).collect::<Vec<_>>())?,
+        Some("verify-audit") => audit::verify(&std::env::args().skip(2).collect::<Vec<_>>())?,
+        Some("export-config") => backup::export(&std::env::args().skip(2).collect::<Vec<_>>())?,
+        Some("import-config") => backup::import(&std::env::args().skip(2).collect::<Vec<_>>())?,
+        Some(other) => return Err(anyhow!("Unexpected parameter: {}", other).into()),
+        None => standard_main()?,
+    }
     Ok(())
 }
 
 pub fn standard_main() -> anyhow::Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-    let (stop_tx, stop_rx) = oneshot::channel();
-    Main::init()?.run(stop_rx)?;
-    std::mem::drop(stop_tx);
-    Ok(())
+    // RUST_LOG takes priority over the settings file.
+    let level = settings::log_level().unwrap_or_else(|| "info".into());
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).init();
+    let main = Main::init()?;
+    if main.settings.sandbox {
+        #[cfg(all(target_os = "linux", feature = "sandbox"))]
+        sandbox::restrict(&main.settings)?;
+        #[cfg(not(all(target_os = "linux", feature = "sandbox")))]
+        warn!("The sandbox is only available on Linux builds with the sandbox feature");
+    }
+    // Nothing stops the program from the command line, so this is never cancelled.
+    run_until_stopped(main, CancellationToken::new())
+}
+
+/// Run until `stop` is cancelled, starting over whenever the settings are reloaded.
+fn run_until_stopped(mut main: Main, stop: CancellationToken) -> anyhow::Result<()> {
+    loop {
+        match main.run(&stop)? {
+            Exit::Stop => return Ok(()),
+            Exit::Reload => {
+                info!("Reloading settings");
+                main = Main::init()?;
+            }
+        }
+    }
+}
+
+/// How long to wait for the MQTT connection to close when stopping.
+const MQTT_SHUTDOWN: Duration = Duration::from_secs(5);
+
+/// Why `Main::run` returned.
+enum Exit {
+    Stop,
+    Reload,
 }
 
 struct Main {
     settings: Settings,
+    /// The settings file as it was loaded, to tell what has changed when it is reloaded.
+    settings_value: serde_yaml::Value,
+    persisted: PersistedState,
     mqtt: MqttHandle,
+    commands: broadcast::Receiver<(mqtt::Command, Source)>,
+    scenes: mpsc::Receiver<(SceneCommand, Source)>,
+    register_reads: mpsc::Receiver<RegisterRead>,
+    reopen_serial: mpsc::Receiver<Option<String>>,
+    audit: AuditLog,
     state: State,
 }
 
 impl Main {
     pub fn init() -> anyhow::Result<Main> {
-        let settings = load_settings()?;
+        let settings_value = load_settings_value()?;
+        let settings: Settings =
+            serde_yaml::from_value(settings_value.clone()).context("Failed to load settings")?;
+        info!(
+            "Effective configuration: {}",
+            diagnostics::configuration_summary(&settings)
+        );
+        let persisted = load_state()?;
 
-        let (height_send, height_receive) = tokio::sync::watch::channel(None);
+        let (available_send, available_receive) = tokio::sync::watch::channel(true);
         let (command_send, command_receive) = tokio::sync::broadcast::channel(2);
+        // Heights and speeds share the bus, so leave room for a few seconds of movement.
+        let events = EventBus::new(64);
+        let (scenes_send, scenes_receive) = mpsc::channel(4);
+        let (register_reads_send, register_reads_receive) = mpsc::channel(4);
+        let (reopen_serial_send, reopen_serial_receive) = mpsc::channel(1);
+        let diagnostics = Arc::new(Diagnostics::default());
+        let (blocked_send, blocked_receive) = tokio::sync::watch::channel(
+            settings
+                .interlock
+                .as_ref()
+                .map_or(false, |interlock| interlock.blocked_until_known),
+        );
 
         let mqtt = MqttHandle {
-            height: height_send,
-            command: command_receive,
+            available: available_send,
+            events: events.clone(),
+            display: None,
+            display_codes: settings.display_codes.clone(),
+            condition: None,
+            decoder: settings.display_encoding.decoder(),
+            display_offset: (!settings.scan_display_offset).then(|| settings.display_offset),
+            register_access: settings.register_access,
+            register_gap: Duration::from_millis(settings.separate_access_gap_ms),
+            gentle_movement: settings.gentle_movement.clone(),
+            strict_publishing: settings.strict_publishing,
+            diagnostics: diagnostics.clone(),
+            possible_heights: (settings.min_possible_height * 10.0).round() as u16
+                ..=(settings.max_possible_height * 10.0).round() as u16,
+            correction: HeightCorrection::new(
+                settings
+                    .height_correction
+                    .iter()
+                    .map(|point| (point.reported, point.actual)),
+            )
+            .map_err(|err| anyhow!("Invalid height_correction: {}", err))?,
+            filter: HeightFilter::new(settings.height_filter),
+            blocked: blocked_receive,
+            height_source: HeightSource::default(),
+            maintenance: None,
         };
 
         let state = State {
-            height: height_receive,
+            available: available_receive,
             command: command_send,
+            scenes: scenes_send,
+            register_reads: register_reads_send,
+            reopen_serial: reopen_serial_send,
+            events,
+            reload: Arc::new(Notify::new()),
+            mqtt_restart: Arc::new(Notify::new()),
+            blocked: Arc::new(blocked_send),
+            republish: Arc::new(Notify::new()),
+            port_metrics: Arc::new(PortMetrics::default()),
+            transfer_metrics: Arc::new(TransferMetrics::default()),
+            diagnostics,
+            history: settings
+                .history
+                .as_ref()
+                .map(History::open)
+                .transpose()?
+                .map(Arc::new),
+            command_ids: Arc::default(),
+            reconnects: Arc::default(),
+            // Replaced with one tied to the token `run` is given.
+            shutdown: CancellationToken::new(),
+            entities: Arc::default(),
         };
 
+        let audit = AuditLog::open(settings.audit_log.as_deref())?;
         Ok(Main {
             settings,
+            settings_value,
+            persisted,
             mqtt,
+            commands: command_receive,
+            scenes: scenes_receive,
+            register_reads: register_reads_receive,
+            reopen_serial: reopen_serial_receive,
+            audit,
             state,
         })
     }
 
     #[tokio::main(flavor = "current_thread")]
-    pub async fn run(self, stop: oneshot::Receiver<()>) -> anyhow::Result<()> {
-        let port = TransferPort::new(TimeoutPort::new(
-            SerialStream::open(
-                &tokio_serial::new(&self.settings.serial_port, 57600)
-                    .timeout(Duration::from_millis(250)),
-            )?,
-            Duration::from_millis(500),
+    pub async fn run(self, stop: &CancellationToken) -> anyhow::Result<Exit> {
+        let Main {
+            settings,
+            settings_value,
+            persisted,
+            mqtt,
+            commands,
+            scenes,
+            register_reads,
+            reopen_serial,
+            audit,
+            state,
+        } = self;
+        // Cancelled when stopping, and also when this run ends for any other reason.
+        let state = State {
+            shutdown: stop.child_token(),
+            ..state
+        };
+        let shutdown = state.shutdown.clone();
+
+        // Shared by each port opened, so reopening the serial port keeps the same capture.
+        let capture = Arc::new(std::sync::Mutex::new(
+            settings
+                .capture_file
+                .as_deref()
+                .map(Capture::create)
+                .transpose()?,
         ));
+        const BAUD: u32 = 57600;
+        let (wake_idle, wake_settle) = match &settings.wake_pulse {
+            Some(pulse) => (
+                Some(Duration::from_secs(pulse.idle_secs)),
+                Duration::from_millis(pulse.settle_ms),
+            ),
+            None => (None, Duration::ZERO),
+        };
+        let open = |path: &str| -> anyhow::Result<_> {
+            let wake_pulse = settings.wake_pulse.clone();
+            let serial = match &settings.replay_file {
+                Some(replay) => Either::Right(ReplayPort::open(replay)?),
+                None => Either::Left(SerialStream::open(
+                    &tokio_serial::new(path, BAUD).timeout(Duration::from_millis(250)),
+                )?),
+            };
+            let serial = WakePort::new(
+                serial,
+                move |serial: &mut Either<SerialStream, ReplayPort>| match (serial, &wake_pulse) {
+                    (Either::Left(serial), Some(pulse)) => pulse_lines(serial, pulse),
+                    _ => Ok(()),
+                },
+                wake_idle,
+                wake_settle,
+            );
+            let serial = GapPort::new(
+                SharedBusPort::new(
+                    serial,
+                    settings.shared_bus_quiet_ms.map(Duration::from_millis),
+                ),
+                settings
+                    .inter_frame_gap_us
+                    .map_or_else(|| silent_interval(BAUD), Duration::from_micros),
+            );
+            #[cfg(feature = "chaos")]
+            let serial = transport::chaos::ChaosPort::new(serial, settings.chaos.clone());
+            let port_metrics = state.port_metrics.clone();
+            let capture = capture.clone();
+            Ok(TimeoutPort::new(
+                InspectPort::new(serial, move |direction, bytes: &[u8], time| {
+                    trace_chunk(direction, bytes);
+                    port_metrics.record(direction, bytes);
+                    if let Some(capture) = &mut *capture.lock().unwrap() {
+                        capture.record(direction, bytes, time);
+                    }
+                }),
+                Duration::from_millis(500),
+            ))
+        };
+        let port = TransferPort::new(open(&settings.serial_port)?, state.transfer_metrics.clone());
+
+        let api_state = state.clone();
+        let api = async {
+            match &settings.api {
+                Some(api) => api_loop(api, api_state).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let schedule = schedule_loop(
+            &settings.schedule,
+            settings
+                .schedule_idle_limit_mins
+                .map(|mins| Duration::from_secs(mins * 60)),
+            state.clone(),
+        );
+        let history = history_loop(state.clone());
+        let hooks = hooks_loop(&settings.on_event, state.clone());
+        let console = console_loop(state.clone());
+
+        let mqtt_state = state.clone();
+        let mut mqtt_running = true;
+        let mqtt_task = async {
+            let mut reloaded = None;
+            loop {
+                let current = reloaded.as_ref().unwrap_or(&settings);
+                if !mqtt_loop(current, mqtt_state.clone()).await? {
+                    return Ok::<(), anyhow::Error>(());
+                }
+                match load_settings() {
+                    Ok(settings) => reloaded = Some(settings),
+                    Err(err) => error!("Keeping the previous MQTT settings: {:?}", err),
+                }
+            }
+        };
 
-        tokio::select! {
-            result = main_loop(port, self.mqtt, stop) => result?,
-            result = mqtt_loop(&self.settings, self.state) => result?,
+        tokio::pin!(mqtt_task);
+
+        let restart = Arc::new(Notify::new());
+        let reload = reload_loop(settings_value, state.clone(), restart.clone());
+        let context = LoopContext {
+            mqtt,
+            commands,
+            scenes,
+            register_reads,
+            reopen_serial,
+            audit,
+            persisted,
+            restart,
+        };
+
+        let stalled = Arc::new(Notify::new());
+        if let Some(watchdog) = &settings.watchdog {
+            spawn_watchdog(
+                &settings,
+                watchdog,
+                &state.diagnostics,
+                state.mqtt_restart.clone(),
+                stalled.clone(),
+            );
         }
 
-        Ok(())
+        let exit = tokio::select! {
+            result = main_loop(port, open, context, &settings, &shutdown) => result?,
+            result = &mut mqtt_task => {
+                mqtt_running = false;
+                result?;
+                Exit::Stop
+            }
+            result = reload => {
+                result?;
+                Exit::Stop
+            }
+            result = api => {
+                result?;
+                Exit::Stop
+            }
+            result = schedule => {
+                result?;
+                Exit::Stop
+            }
+            result = history => {
+                result?;
+                Exit::Stop
+            }
+            result = hooks => {
+                result?;
+                Exit::Stop
+            }
+            result = console => {
+                result?;
+                Exit::Stop
+            }
+            // Dropping the stuck loop is the only way to stop it, so everything starts over.
+            _ = stalled.notified() => Exit::Reload,
+        };
+
+        shutdown.cancel();
+        if matches!(exit, Exit::Stop) && mqtt_running {
+            // Give the broker a clean disconnect, so the desk shows as offline right away.
+            match tokio::time::timeout(MQTT_SHUTDOWN, &mut mqtt_task).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => warn!("Failed to disconnect from the broker: {:?}", err),
+                Err(_) => warn!("Timed out disconnecting from the broker"),
+            }
+        }
+
+        Ok(exit)
+    }
+}
+
+/// Check on the loops that talk to the controller and the broker, asking the one that has stalled
+/// to restart.
+///
+/// This runs on a thread of its own so it keeps going even if something blocks the runtime, and
+/// stops once `diagnostics` is dropped by the next restart. A loop still stuck after it was asked
+/// to restart ends the process, for the service manager to start again.
+fn spawn_watchdog(
+    settings: &Settings,
+    watchdog: &WatchdogSettings,
+    diagnostics: &Arc<Diagnostics>,
+    mqtt_restart: Arc<Notify>,
+    control_restart: Arc<Notify>,
+) {
+    if watchdog.stall_secs <= settings.operation_timeout_secs
+        || watchdog.stall_secs <= settings.mqtt.keep_alive_secs
+    {
+        warn!("watchdog.stall_secs is not longer than operation_timeout_secs and keep_alive_secs, so loops may be restarted while they are only waiting");
+    }
+    let stall = Duration::from_secs(watchdog.stall_secs);
+    let diagnostics = Arc::downgrade(diagnostics);
+    std::thread::spawn(move || {
+        let mut control = Watch::new(stall);
+        let mut mqtt = Watch::new(stall);
+        loop {
+            std::thread::sleep((stall / 10).max(Duration::from_secs(1)));
+            let diagnostics = match diagnostics.upgrade() {
+                Some(diagnostics) => diagnostics,
+                None => break,
+            };
+            let now = Instant::now();
+            for (name, watch, heartbeat, restart) in [
+                (
+                    "controller",
+                    &mut control,
+                    &diagnostics.control_heartbeat,
+                    &control_restart,
+                ),
+                (
+                    "MQTT",
+                    &mut mqtt,
+                    &diagnostics.mqtt_heartbeat,
+                    &mqtt_restart,
+                ),
+            ] {
+                match watch.check(heartbeat, now) {
+                    Verdict::Healthy | Verdict::Waiting => {}
+                    Verdict::Restart => {
+                        error!(
+                            "The {} loop has made no progress for {:?} while {}; restarting it. Diagnostics: {}",
+                            name,
+                            heartbeat.age(now),
+                            heartbeat.activity(),
+                            diagnostics.to_json()
+                        );
+                        restart.notify_one();
+                    }
+                    Verdict::Exit => {
+                        error!(
+                            "The {} loop is still stuck after restarting it; exiting",
+                            name
+                        );
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Decide what to restart when the settings file is reloaded.
+///
+/// Changes that only affect MQTT reconnect to the broker without disturbing the controller, and a
+/// new serial port is opened in place of the old one. Anything else restarts everything once the
+/// current command has finished.
+async fn reload_loop(
+    mut current: serde_yaml::Value,
+    state: State,
+    restart: Arc<Notify>,
+) -> anyhow::Result<()> {
+    loop {
+        state.reload.notified().await;
+        let (value, settings) = match load_settings_value().and_then(|value| {
+            let settings = serde_yaml::from_value::<Settings>(value.clone())
+                .map_err(|err| Error::Config(err.to_string()))?;
+            Ok((value, settings))
+        }) {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                error!("Not reloading settings: {:?}", err);
+                continue;
+            }
+        };
+        if needs_restart(&current, &value) {
+            info!("Settings changed, restarting");
+            restart.notify_one();
+        } else {
+            if current.get("serial_port") != value.get("serial_port") {
+                info!("Serial port changed, reopening it");
+                let _ = state.reopen_serial.send(Some(settings.serial_port)).await;
+            }
+            if needs_mqtt_restart(&current, &value) {
+                info!("Reloading MQTT settings");
+                state.mqtt_restart.notify_one();
+            }
+        }
+        current = value;
+    }
+}
+
+/// The channels and state `main_loop` takes over from `Main`.
+struct LoopContext {
+    mqtt: MqttHandle,
+    commands: broadcast::Receiver<(mqtt::Command, Source)>,
+    scenes: mpsc::Receiver<(SceneCommand, Source)>,
+    register_reads: mpsc::Receiver<RegisterRead>,
+    reopen_serial: mpsc::Receiver<Option<String>>,
+    audit: AuditLog,
+    persisted: PersistedState,
+    /// Notified when the settings changed in a way that needs the loop to start over.
+    restart: Arc<Notify>,
+}
+
+/// Report whether maintenance is due, if reminders are configured.
+fn report_maintenance(
+    mqtt: &mut MqttHandle,
+    reminder: Option<&Reminder>,
+    persisted: &PersistedState,
+) {
+    if let Some(reminder) = reminder {
+        let today = chrono::Local::now().date_naive();
+        mqtt.report_maintenance(reminder.status(
+            &persisted.maintenance,
+            persisted.total_travel,
+            today,
+        ));
     }
 }
 
 async fn main_loop<T: AsyncRead + AsyncWrite + Send + 'static>(
     mut port: TransferPort<T>,
-    mut mqtt: MqttHandle,
-    mut stop: oneshot::Receiver<()>,
-) -> anyhow::Result<()> {
+    open: impl Fn(&str) -> anyhow::Result<T>,
+    context: LoopContext,
+    settings: &Settings,
+    stop: &CancellationToken,
+) -> anyhow::Result<Exit> {
+    // How often to check whether the controller has come back after it stops responding.
+    const RECOVERY_POLL: Duration = Duration::from_secs(30);
+    // How far from a preset's stored height, in tenths of an inch, the desk can stop.
+    const STALL_TOLERANCE: u16 = 5;
+    // How often to show the watchdog that the loop is still waiting, rather than stuck.
+    const HEARTBEAT: Duration = Duration::from_secs(10);
+    // How often to check whether maintenance has become due with the passing days.
+    const MAINTENANCE_CHECK: Duration = Duration::from_secs(60 * 60);
+
+    let LoopContext {
+        mut mqtt,
+        mut commands,
+        mut scenes,
+        mut register_reads,
+        mut reopen_serial,
+        mut audit,
+        mut persisted,
+        restart,
+    } = context;
     let server_addr = Slave(0x01);
-    operate(&mut port, server_addr, None, &mut mqtt).await?;
-    info!("Controller initialized");
+    let deadline = Duration::from_secs(settings.operation_timeout_secs);
+    let max_offline_age = Duration::from_secs(settings.offline_command_max_age_secs);
+    let refresh_window = Duration::from_millis(settings.refresh_coalesce_ms);
+    let refresh_cache = settings.refresh_cache_secs.map(Duration::from_secs);
+    let availability_timeout = settings.availability_timeout_secs.map(Duration::from_secs);
+    let listen_interval = settings
+        .listen_while_idle_secs
+        .filter(|_| settings.experimental_standby)
+        .map(Duration::from_secs);
+    if settings.listen_while_idle_secs.is_some() && listen_interval.is_none() {
+        warn!("Not listening while idle because it needs experimental_standby");
+    }
+    let mut serial_port = settings.serial_port.clone();
+
+    // The power state is unknown at startup, so make sure it is on.
+    let mut relay = PowerRelay::new(settings.power_relay.as_ref());
+    relay.force_on(&mut mqtt).await?;
+    let mut last_activity = Instant::now();
+    // When the height was last read while idle.
+    let mut last_listen = Instant::now();
 
+    let outcome = tokio::select! {
+        outcome = operate_with_deadline(&mut port, server_addr, None, None, None, &mut mqtt, deadline) => outcome?,
+        // Only waking the controller, so there is no movement to stop.
+        _ = stop.cancelled() => return Ok(Exit::Stop),
+    };
+    let mut known_height = outcome.end_height;
+    let mut available = outcome.completed;
+    if available {
+        info!("Controller initialized");
+    } else {
+        warn!("Controller is not responding");
+    }
+    mqtt.set_available(available);
+    // When the controller last answered.
+    let mut last_exchange = Instant::now();
+
+    // A movement command received while the controller was not responding.
+    let mut queued: Option<(Instant, mqtt::Command, Source)> = None;
+    // Commands to run before accepting new ones.
+    let mut pending = VecDeque::new();
+
+    mqtt.report_presets(&persisted.travel.preset_heights);
+    mqtt.report_total_travel(persisted.total_travel);
+    mqtt.clear_conditions();
+    let reminder = settings.maintenance.as_ref().map(|maintenance| {
+        let last_done = maintenance.last_done.as_deref().and_then(|date| {
+            let parsed = parse_date(date);
+            if parsed.is_none() {
+                warn!(
+                    "Ignoring maintenance.last_done {:?}, which is not YYYY-MM-DD",
+                    date
+                );
+            }
+            parsed
+        });
+        let today = chrono::Local::now().date_naive();
+        if persisted
+            .maintenance
+            .start(last_done, today, persisted.total_travel)
+        {
+            if let Err(err) = save_state(&persisted) {
+                error!("Failed to save maintenance date: {:?}", err);
+            }
+        }
+        Reminder {
+            every_days: maintenance.every_days,
+            every_travel: maintenance
+                .every_inches
+                .map(|inches| (inches * 10.0).round() as u64),
+        }
+    });
+    report_maintenance(&mut mqtt, reminder.as_ref(), &persisted);
+    let mut maintenance_timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + MAINTENANCE_CHECK,
+        MAINTENANCE_CHECK,
+    );
+    if settings.calibrate_missing_presets {
+        pending.extend(
+            CALIBRATION
+                .iter()
+                .filter(|&&(preset, _)| !persisted.travel.preset_heights.contains_key(&preset))
+                .map(|&(_, command)| (command, Source::Internal)),
+        );
+        if !pending.is_empty() {
+            info!("Calibrating presets with unknown heights");
+        }
+    }
+
+    // Kept apart from `mqtt`, which operations borrow mutably.
+    let diagnostics = mqtt.diagnostics.clone();
+    let heartbeat = &diagnostics.control_heartbeat;
+    let mut heartbeat_timer =
+        tokio::time::interval_at(tokio::time::Instant::now() + HEARTBEAT, HEARTBEAT);
     loop {
-        let command = tokio::select! {
-            command = mqtt.command.recv() => command?,
-            _ = &mut stop => return Ok(()),
+        heartbeat.beat(if pending.is_empty() {
+            "waiting for a command"
+        } else {
+            "running queued commands"
+        });
+        // Whether this is only checking that the controller still answers.
+        let mut poll = false;
+        let (command, source) = match pending.pop_front() {
+            Some(command) => command,
+            None => tokio::select! {
+                command = commands.recv() => command?,
+                Some((scene, source)) = scenes.recv() => match scene {
+                    SceneCommand::Snapshot(name) => {
+                        match known_height {
+                            Some(height) => {
+                                info!("Saving scene {:?} at {}", name, f32::from(height) / 10.0);
+                                persisted.scenes.insert(name, height);
+                                match save_state(&persisted) {
+                                    Ok(()) => mqtt.events.progress(source, "completed", true),
+                                    Err(err) => {
+                                        error!("Failed to save scene: {:?}", err);
+                                        mqtt.events.progress(source, "failed", true);
+                                    }
+                                }
+                            }
+                            None => {
+                                warn!("Not saving scene {:?} because the height is unknown", name);
+                                mqtt.report_error(
+                                    ErrorKind::Command,
+                                    format!(
+                                        "cannot save scene {} because the height is unknown",
+                                        name
+                                    ),
+                                );
+                                mqtt.events.progress(source, "failed", true);
+                            }
+                        }
+                        continue;
+                    }
+                    SceneCommand::SetPreset { preset, height } => {
+                        if mqtt.possible_heights.contains(&height) {
+                            info!("Setting preset {} to {}", preset, f32::from(height) / 10.0);
+                            persisted.travel.preset_heights.insert(preset, height);
+                            if let Err(err) = save_state(&persisted) {
+                                error!("Failed to save preset height: {:?}", err);
+                            }
+                            mqtt.report_presets(&persisted.travel.preset_heights);
+                        } else {
+                            warn!("Not setting preset {} to an impossible height", preset);
+                            mqtt.report_error(
+                                ErrorKind::Command,
+                                format!(
+                                    "cannot set preset {} to {}, which the desk cannot reach",
+                                    preset,
+                                    f32::from(height) / 10.0
+                                ),
+                            );
+                        }
+                        continue;
+                    }
+                    SceneCommand::MaintenanceDone => {
+                        info!("Recording maintenance as done");
+                        let today = chrono::Local::now().date_naive();
+                        persisted.maintenance.reset(today, persisted.total_travel);
+                        match save_state(&persisted) {
+                            Ok(()) => mqtt.events.progress(source, "completed", true),
+                            Err(err) => {
+                                error!("Failed to save maintenance date: {:?}", err);
+                                mqtt.events.progress(source, "failed", true);
+                            }
+                        }
+                        report_maintenance(&mut mqtt, reminder.as_ref(), &persisted);
+                        continue;
+                    }
+                    SceneCommand::Restore(name) => match persisted.scenes.get(&name) {
+                        Some(&target) => (mqtt::Command::Restore { target }, source),
+                        None => {
+                            warn!("No scene named {:?}", name);
+                            mqtt.report_error(ErrorKind::Command, format!("no scene named {}", name));
+                            mqtt.events.progress(source, "failed", true);
+                            continue;
+                        }
+                    },
+                },
+                Some(read) = register_reads.recv() => {
+                    relay.ensure_on(&mut mqtt).await?;
+                    let result = match tokio::time::timeout(
+                        deadline,
+                        read_registers(&mut port, server_addr, read, &mut mqtt),
+                    )
+                    .await
+                    {
+                        Ok(result) => result.map_err(|err| err.to_string()),
+                        Err(_) => Err("timed out".into()),
+                    };
+                    if let Err(err) = &result {
+                        warn!("Failed to read registers for {:?}: {}", read, err);
+                    }
+                    mqtt.events.send(DeskEvent::Registers {
+                        address: read.address,
+                        count: read.count,
+                        result,
+                    });
+                    last_activity = Instant::now();
+                    continue;
+                }
+                Some(path) = reopen_serial.recv() => {
+                    let path = path.unwrap_or_else(|| serial_port.clone());
+                    match open(&path) {
+                        Ok(inner) => {
+                            info!("Reopened the serial port {}", path);
+                            // Revokes the old port's handles, which closes it once they are gone.
+                            port.replace(inner);
+                            serial_port = path;
+                            // Wake the controller on the new port as at startup.
+                            pending.push_back((mqtt::Command::Refresh, Source::Internal));
+                        }
+                        Err(err) => {
+                            error!("Failed to open the serial port {}: {:?}", path, err);
+                            mqtt.report_error(
+                                ErrorKind::Offline,
+                                format!("failed to open the serial port {}: {}", path, err),
+                            );
+                        }
+                    }
+                    continue;
+                }
+                _ = tokio::time::sleep(RECOVERY_POLL), if !available => (mqtt::Command::Refresh, Source::Internal),
+                _ = tokio::time::sleep_until((last_exchange + availability_timeout.unwrap_or_default()).into()),
+                    if available && relay.is_on() && availability_timeout.is_some() =>
+                {
+                    debug!("Checking that the controller is still responding");
+                    poll = true;
+                    (mqtt::Command::Refresh, Source::Internal)
+                }
+                _ = tokio::time::sleep_until((last_listen + listen_interval.unwrap_or_default()).into()),
+                    if available && relay.is_on() && listen_interval.is_some() =>
+                {
+                    mqtt.height_source = HeightSource::Handset;
+                    match tokio::time::timeout(deadline, listen(&mut port, server_addr, &mut mqtt)).await {
+                        Ok(Ok(height)) => {
+                            last_exchange = Instant::now();
+                            if height.is_some() && height != known_height {
+                                debug!("The desk was moved with the handset");
+                                known_height = height;
+                            }
+                        }
+                        // Left to the availability check, since one missed reading is not a failure.
+                        Ok(Err(err)) => debug!("Failed to listen for the height: {:?}", err),
+                        Err(_) => debug!("Timed out listening for the height"),
+                    }
+                    last_listen = Instant::now();
+                    continue;
+                }
+                _ = tokio::time::sleep_until((last_activity + relay.idle_timeout().unwrap_or_default()).into()),
+                    if available && relay.idle_timeout().is_some() =>
+                {
+                    relay.turn_off(&mut mqtt)?;
+                    continue;
+                }
+                _ = heartbeat_timer.tick() => continue,
+                _ = maintenance_timer.tick(), if reminder.is_some() => {
+                    report_maintenance(&mut mqtt, reminder.as_ref(), &persisted);
+                    continue;
+                }
+                _ = restart.notified() => return Ok(Exit::Reload),
+                _ = stop.cancelled() => return Ok(Exit::Stop),
+            },
         };
+        heartbeat.beat("running a command");
         info!("Got command {:?}", command);
+        let received = Instant::now();
+
+        // A relative movement starts from wherever the desk is by the time it runs.
         let command = match command {
-            mqtt::Command::Preset1 => Some(&PRESET1),
-            mqtt::Command::Preset2 => Some(&PRESET2),
-            mqtt::Command::Preset3 => Some(&PRESET3),
-            mqtt::Command::Preset4 => Some(&PRESET4),
-            mqtt::Command::Refresh => None,
+            mqtt::Command::MoveBy { delta } => {
+                match known_height.map(|from| from.saturating_add_signed(delta)) {
+                    Some(target) => mqtt::Command::Restore { target },
+                    None => {
+                        warn!(
+                            "Not moving by {} from an unknown height",
+                            f32::from(delta) / 10.0
+                        );
+                        mqtt.report_error(
+                            ErrorKind::Command,
+                            format!(
+                                "cannot move by {} because the height is unknown",
+                                f32::from(delta) / 10.0
+                            ),
+                        );
+                        mqtt.report_rejected(command, "unreachable", None);
+                        audit.record(source, command, "unreachable", known_height, known_height);
+                        finish(&mqtt.events, &pending, source, "unreachable");
+                        continue;
+                    }
+                }
+            }
+            command => command,
+        };
+
+        // Checks on the controller itself still need to reach it.
+        if command == mqtt::Command::Refresh && source != Source::Internal && available {
+            if let (Some(cache), Some(height)) = (refresh_cache, known_height) {
+                if last_exchange.elapsed() < cache {
+                    debug!("Answering REFRESH with the last height");
+                    mqtt.diagnostics
+                        .cached_refreshes
+                        .fetch_add(1, Ordering::Relaxed);
+                    if let Err(err) = mqtt.set_resting_height(f32::from(height) / 10.0) {
+                        error!("Failed to answer REFRESH: {}", err);
+                    }
+                    mqtt.events.progress(source, "completed", true);
+                    continue;
+                }
+            }
+        }
+        if command == mqtt::Command::Refresh && !refresh_window.is_zero() {
+            // Several dashboards refreshing at once should only wake the controller once.
+            let window = tokio::time::sleep(refresh_window);
+            tokio::pin!(window);
+            loop {
+                tokio::select! {
+                    _ = &mut window => break,
+                    other = commands.recv() => match other? {
+                        (mqtt::Command::Refresh, other) => {
+                            coalesce_refresh(&mqtt.diagnostics, &mqtt.events, other)
+                        }
+                        other => {
+                            pending.push_front(other);
+                            break;
+                        }
+                    },
+                }
+            }
+        }
+        let (preset, frames) = match command {
+            mqtt::Command::Preset1 => (1, frames::preset(1)),
+            mqtt::Command::Preset2 => (2, frames::preset(2)),
+            mqtt::Command::Preset3 => (3, frames::preset(3)),
+            mqtt::Command::Preset4 => (4, frames::preset(4)),
+            // With nothing moving, STOP only reads the height.
+            mqtt::Command::Refresh
+            | mqtt::Command::Sleep
+            | mqtt::Command::Calibrate
+            | mqtt::Command::Stop => (0, None),
+            // Already there, so only read the height.
+            mqtt::Command::Restore { target }
+                if known_height.map_or(false, |from| from.abs_diff(target) <= 2) =>
+            {
+                (0, None)
+            }
+            mqtt::Command::Restore { target } => match known_height
+                .and_then(|from| restore_preset(&persisted.travel.preset_heights, from, target))
+            {
+                Some(preset) => (preset, frames::preset(preset)),
+                None => {
+                    warn!("No preset is beyond {}", f32::from(target) / 10.0);
+                    mqtt.report_error(
+                        ErrorKind::Command,
+                        format!(
+                            "cannot reach {} because no known preset is beyond it",
+                            f32::from(target) / 10.0
+                        ),
+                    );
+                    mqtt.report_rejected(command, "unreachable", None);
+                    audit.record(source, command, "unreachable", known_height, known_height);
+                    finish(&mqtt.events, &pending, source, "unreachable");
+                    continue;
+                }
+            },
+            mqtt::Command::MoveBy { .. } => unreachable!("relative movements are resolved first"),
+            mqtt::Command::Up | mqtt::Command::Down => match known_height.and_then(|from| {
+                jog_preset(
+                    &persisted.travel.preset_heights,
+                    from,
+                    command == mqtt::Command::Up,
+                )
+            }) {
+                Some(preset) => (preset, frames::preset(preset)),
+                None => {
+                    // Holding the command at the end of travel is expected, so this is not an
+                    // error, and is not audited since it repeats for as long as it is held.
+                    debug!("No known preset beyond the desk for {:?}", command);
+                    mqtt.report_rejected(command, "unreachable", None);
+                    finish(&mqtt.events, &pending, source, "unreachable");
+                    continue;
+                }
+            },
+        };
+        let stop_at = match command {
+            mqtt::Command::Restore { target } => Some(target),
+            _ => None,
         };
-        operate(&mut port, server_addr, command, &mut mqtt).await?;
+
+        if command == mqtt::Command::Sleep && !settings.experimental_standby {
+            warn!("Rejecting SLEEP because experimental_standby is not set");
+            mqtt.report_rejected(command, "unsupported", None);
+            audit.record(source, command, "unsupported", known_height, known_height);
+            finish(&mqtt.events, &pending, source, "unsupported");
+            continue;
+        }
+
+        if command == mqtt::Command::Calibrate {
+            info!("Calibrating all presets");
+            pending.extend(CALIBRATION.iter().map(|&(_, command)| (command, source)));
+            continue;
+        }
+
+        relay.ensure_on(&mut mqtt).await?;
+        // Polling should not keep the power on.
+        if !poll {
+            last_activity = Instant::now();
+        }
+
+        if command == mqtt::Command::Sleep {
+            let status =
+                match tokio::time::timeout(deadline, standby(&mut port, server_addr, &mut mqtt))
+                    .await
+                {
+                    Ok(Ok(())) => "completed",
+                    Ok(Err(err)) => {
+                        error!(
+                            "Failed to put controller in standby ({} error): {:?}",
+                            err.kind(),
+                            err
+                        );
+                        mqtt.report_error(
+                            ErrorKind::Protocol,
+                            format!("failed to put controller in standby: {}", err),
+                        );
+                        "failed"
+                    }
+                    Err(_) => {
+                        error!("Timed out putting controller in standby");
+                        mqtt.report_error(
+                            ErrorKind::Timeout,
+                            "timed out putting controller in standby".into(),
+                        );
+                        "failed"
+                    }
+                };
+            finish(&mqtt.events, &pending, source, status);
+            continue;
+        }
+
+        if !available && frames.is_some() {
+            match settings.offline_commands {
+                OfflineCommands::QueueLatest if !command.is_jog() => {
+                    info!("Queueing {:?} until the controller responds", command);
+                    if let Some((_, _, replaced)) =
+                        queued.replace((Instant::now(), command, source))
+                    {
+                        finish(&mqtt.events, &pending, replaced, "replaced");
+                    }
+                    audit.record(source, command, "queued", known_height, None);
+                    mqtt.events.progress(source, "queued", false);
+                }
+                // Nobody would still be holding UP or DOWN by the time it ran.
+                OfflineCommands::Reject | OfflineCommands::QueueLatest => {
+                    warn!(
+                        "Rejecting {:?} because the controller is not responding",
+                        command
+                    );
+                    mqtt.report_error(
+                        ErrorKind::Offline,
+                        format!(
+                            "rejected {:?} because the controller is not responding",
+                            command
+                        ),
+                    );
+                    mqtt.report_rejected(command, "offline", None);
+                    audit.record(source, command, "offline", known_height, None);
+                    finish(&mqtt.events, &pending, source, "offline");
+                }
+            }
+            continue;
+        }
+
+        // The height the controller itself will head for, rather than where the preset last ended.
+        let mut stored_target = None;
+        if let (Some(address), Some(_), None) = (settings.preset_height_registers, frames, stop_at)
+        {
+            match tokio::time::timeout(
+                deadline,
+                read_preset_height(&mut port, server_addr, address, preset, &mut mqtt),
+            )
+            .await
+            {
+                Ok(Ok(height)) => stored_target = height,
+                Ok(Err(err)) => warn!("Failed to read the height of preset {}: {}", preset, err),
+                Err(_) => warn!("Timed out reading the height of preset {}", preset),
+            }
+        }
+        // Without both heights, assume the desk could be going down.
+        let target_height = stop_at
+            .or(stored_target)
+            .or_else(|| persisted.travel.preset_heights.get(&preset).copied());
+        let lowering = frames.is_some()
+            && match (known_height, target_height) {
+                (Some(from), Some(to)) => to < from,
+                _ => true,
+            };
+        if lowering && *mqtt.blocked.borrow() {
+            warn!("Rejecting {:?} because the interlock is blocked", command);
+            mqtt.report_error(
+                ErrorKind::Interlock,
+                format!("rejected {:?} because the interlock is blocked", command),
+            );
+            mqtt.report_rejected(command, "blocked", None);
+            audit.record(source, command, "blocked", known_height, known_height);
+            finish(&mqtt.events, &pending, source, "blocked");
+            continue;
+        }
+
+        let mut movement_limit = None;
+        if frames.is_some() {
+            let eta = known_height
+                .zip(target_height)
+                .and_then(|(from, to)| persisted.travel.estimate(from, to))
+                .map(|eta| {
+                    settings
+                        .gentle_movement
+                        .as_ref()
+                        .map_or(eta, |gentle| gentle.stretch(eta))
+                });
+            // Allow for slow starts and stops on top of the learned travel time.
+            movement_limit = eta.map(|eta| eta * 2 + Duration::from_secs(5));
+            mqtt.report_moving(
+                preset,
+                eta,
+                target_height.map(|height| f32::from(height) / 10.0),
+            );
+        }
+
+        mqtt.events.progress(source, "running", false);
+        mqtt.height_source = if frames.is_some() {
+            HeightSource::Command
+        } else {
+            HeightSource::Poll
+        };
+
+        // Handle commands that arrive while this one runs, instead of leaving them queued.
+        let events = mqtt.events.clone();
+        let diagnostics = mqtt.diagnostics.clone();
+        let mut blocked = mqtt.blocked.clone();
+        let mut watch_blocked = lowering;
+        let start = Instant::now();
+        // Stops UP and DOWN unless they are sent again in time.
+        let hold = Duration::from_millis(settings.jog_hold_ms);
+        let released = tokio::time::sleep(hold);
+        tokio::pin!(released);
+        let (outcome, interruption) = {
+            let operation = operate_with_deadline(
+                &mut port,
+                server_addr,
+                frames,
+                movement_limit,
+                stop_at,
+                &mut mqtt,
+                deadline,
+            );
+            tokio::pin!(operation);
+            loop {
+                tokio::select! {
+                    outcome = &mut operation => break (outcome?, None),
+                    changed = blocked.changed(), if watch_blocked => {
+                        if changed.is_err() {
+                            watch_blocked = false;
+                        } else if *blocked.borrow() {
+                            break (Outcome::default(), Some(Interruption::Blocked));
+                        }
+                    }
+                    _ = &mut released, if command.is_jog() => {
+                        break (Outcome::default(), Some(Interruption::Released));
+                    }
+                    _ = stop.cancelled() => {
+                        break (Outcome::default(), Some(Interruption::Shutdown));
+                    }
+                    received = commands.recv() => {
+                        let (received, received_source) = received?;
+                        if command.is_jog() && received == command {
+                            released.as_mut().reset(tokio::time::Instant::now() + hold);
+                            events.progress(received_source, "held", true);
+                            continue;
+                        }
+                        if command == mqtt::Command::Refresh
+                            && received == mqtt::Command::Refresh
+                            && start.elapsed() <= refresh_window
+                        {
+                            coalesce_refresh(&diagnostics, &events, received_source);
+                            continue;
+                        }
+                        if received == mqtt::Command::Stop && command.is_movement() {
+                            break (Outcome::default(), Some(Interruption::Stopped(received_source)));
+                        }
+                        if settings.busy_commands == BusyCommands::Preempt
+                            && command.is_movement()
+                            && received.is_movement()
+                        {
+                            break (Outcome::default(), Some(Interruption::Preempted(received, received_source)));
+                        }
+                        info!("Rejecting {:?} because {:?} is running", received, command);
+                        events.send(DeskEvent::Rejected {
+                            command: received,
+                            reason: "busy",
+                            other: Some(command),
+                        });
+                        if received.is_movement() {
+                            audit.record(received_source, received, "busy", None, None);
+                        }
+                        finish(&events, &pending, received_source, "busy");
+                    }
+                }
+            }
+        };
+        match interruption {
+            Some(Interruption::Preempted(received, received_source)) => {
+                info!("{:?} interrupted {:?}", received, command);
+                if reset_with_retry(&mut port, server_addr, &mut mqtt, deadline, "preempted")
+                    .await
+                    .is_err()
+                {
+                    available = false;
+                    mqtt.set_available(false);
+                }
+                mqtt.report_rejected(command, "preempted", Some(received));
+                audit.record(source, command, "preempted", known_height, None);
+                finish(&mqtt.events, &pending, source, "preempted");
+                pending.push_front((received, received_source));
+                last_activity = Instant::now();
+                continue;
+            }
+            Some(Interruption::Blocked) => {
+                warn!("Stopping {:?} because the interlock is blocked", command);
+                if reset_with_retry(&mut port, server_addr, &mut mqtt, deadline, "interlock")
+                    .await
+                    .is_err()
+                {
+                    available = false;
+                    mqtt.set_available(false);
+                }
+                mqtt.report_error(
+                    ErrorKind::Interlock,
+                    format!("stopped {:?} because the interlock is blocked", command),
+                );
+                mqtt.report_rejected(command, "blocked", None);
+                audit.record(source, command, "blocked", known_height, None);
+                finish(&mqtt.events, &pending, source, "blocked");
+                last_activity = Instant::now();
+                continue;
+            }
+            Some(Interruption::Shutdown) => {
+                info!("Stopping {:?} to shut down", command);
+                // Shutting down anyway, so there is nothing more to do if this fails.
+                let height =
+                    reset_with_retry(&mut port, server_addr, &mut mqtt, deadline, "shutdown")
+                        .await
+                        .unwrap_or_default();
+                audit.record(source, command, "shutdown", known_height, height);
+                finish(&mqtt.events, &pending, source, "shutdown");
+                return Ok(Exit::Stop);
+            }
+            Some(interruption @ (Interruption::Released | Interruption::Stopped(_))) => {
+                let reason = match interruption {
+                    Interruption::Stopped(_) => "stopped",
+                    _ => "released",
+                };
+                info!("Stopping {:?} because it was {}", command, reason);
+                let height =
+                    match reset_with_retry(&mut port, server_addr, &mut mqtt, deadline, reason)
+                        .await
+                    {
+                        Ok(height) => height,
+                        Err(_) => {
+                            available = false;
+                            mqtt.set_available(false);
+                            None
+                        }
+                    };
+                if let Some(height) = height {
+                    if let Err(err) = mqtt.set_resting_height(f32::from(height) / 10.0) {
+                        error!("Failed to publish the height after stopping: {}", err);
+                    }
+                }
+                audit.record(source, command, reason, known_height, height);
+                finish(&mqtt.events, &pending, source, reason);
+                if let Interruption::Stopped(stop_source) = interruption {
+                    finish(&mqtt.events, &pending, stop_source, "completed");
+                }
+                // The next UP or DOWN chooses its preset from here.
+                known_height = height.or(known_height);
+                last_activity = Instant::now();
+                continue;
+            }
+            None => {}
+        }
+        let result = if outcome.completed {
+            "completed"
+        } else {
+            "failed"
+        };
+        if command.is_movement() {
+            audit.record(source, command, result, known_height, outcome.end_height);
+        }
+        finish(&mqtt.events, &pending, source, result);
+        known_height = outcome.end_height.or(known_height);
+        if !poll {
+            last_activity = Instant::now();
+        }
+        last_listen = Instant::now();
+        if outcome.completed {
+            last_exchange = Instant::now();
+        }
+
+        if outcome.completed {
+            let latency = CommandLatency {
+                command,
+                to_first_write: outcome
+                    .first_write
+                    .map(|first_write| first_write - received),
+                to_completion: received.elapsed(),
+            };
+            debug!("{:?}", latency);
+            mqtt.report_latency(latency);
+        }
+
+        if outcome.completed && !available {
+            info!("Controller is responding again");
+            if let Some((received, command, source)) = queued.take() {
+                if received.elapsed() <= max_offline_age {
+                    pending.push_back((command, source));
+                } else {
+                    warn!("Dropping {:?} because it is too old", command);
+                    audit.record(source, command, "expired", known_height, None);
+                    finish(&mqtt.events, &pending, source, "expired");
+                }
+            }
+        } else if !outcome.completed && available {
+            warn!("Controller is not responding");
+        }
+        available = outcome.completed;
+        mqtt.set_available(available);
+
+        // The desk stopping on its own well short of where the controller was heading means it
+        // stalled, such as on an obstruction.
+        if let (Some(target), Some(to), Some(_)) =
+            (stored_target, outcome.end_height, outcome.travel_time)
+        {
+            if target.abs_diff(to) > STALL_TOLERANCE {
+                warn!(
+                    "Stopped at {} short of preset {} at {}",
+                    f32::from(to) / 10.0,
+                    preset,
+                    f32::from(target) / 10.0
+                );
+                mqtt.report_error(
+                    ErrorKind::Movement,
+                    format!(
+                        "stopped at {} short of preset {} at {}",
+                        f32::from(to) / 10.0,
+                        preset,
+                        f32::from(target) / 10.0
+                    ),
+                );
+            }
+        }
+
+        if let Outcome {
+            start_height: Some(from),
+            end_height: Some(to),
+            travel_time,
+            ..
+        } = outcome
+        {
+            let mut learned = false;
+            if command.is_movement() && from != to {
+                persisted.total_travel += u64::from(from.abs_diff(to));
+                mqtt.report_total_travel(persisted.total_travel);
+                report_maintenance(&mut mqtt, reminder.as_ref(), &persisted);
+                learned = true;
+            }
+            if let Some(travel_time) = travel_time {
+                persisted.travel.learn(preset, from, to, travel_time);
+                mqtt.report_presets(&persisted.travel.preset_heights);
+                learned = true;
+            }
+            if learned {
+                if let Err(err) = save_state(&persisted) {
+                    error!("Failed to save learned travel: {:?}", err);
+                }
+            }
+        }
     }
 }
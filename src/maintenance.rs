@@ -0,0 +1,102 @@
+//! Reminding to look after the desk, such as re-tightening bolts and checking cables, once it has
+//! been long enough or moved far enough since the last time.
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+/// Parse a date written as `YYYY-MM-DD`.
+pub fn parse_date(text: &str) -> Option<NaiveDate> {
+    let mut parts = text.trim().splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok()?;
+    let day = parts.next()?.parse().ok()?;
+    NaiveDate::from_ymd_opt(year, month, day)
+}
+
+/// When maintenance was last done, kept in the state file.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MaintenanceRecord {
+    /// As `YYYY-MM-DD`, or `None` before anything has been counted.
+    #[serde(default)]
+    pub done_on: Option<String>,
+    /// The total travel then, in tenths of an inch.
+    #[serde(default)]
+    pub travel_at: u64,
+}
+
+impl MaintenanceRecord {
+    /// Record maintenance as done today.
+    pub fn reset(&mut self, today: NaiveDate, total_travel: u64) {
+        self.done_on = Some(today.format("%Y-%m-%d").to_string());
+        self.travel_at = total_travel;
+    }
+
+    /// Start counting from `last_done` in the settings, or from today if nothing has been counted
+    /// yet, unless a later reset was recorded. Returns whether the record changed.
+    pub fn start(
+        &mut self,
+        last_done: Option<NaiveDate>,
+        today: NaiveDate,
+        total_travel: u64,
+    ) -> bool {
+        let recorded = self.done_on.as_deref().and_then(parse_date);
+        let since = match (recorded, last_done) {
+            (Some(recorded), Some(last_done)) if last_done > recorded => last_done,
+            (Some(_), _) => return false,
+            (None, last_done) => last_done.unwrap_or(today),
+        };
+        // The travel before then is not known, so it is counted from now.
+        self.reset(since, total_travel);
+        true
+    }
+}
+
+/// How often maintenance is needed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Reminder {
+    pub every_days: Option<u64>,
+    /// In tenths of an inch.
+    pub every_travel: Option<u64>,
+}
+
+/// How long it has been since maintenance was done, and whether it is due again.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaintenanceStatus {
+    pub due: bool,
+    pub days_since: Option<u64>,
+    /// In tenths of an inch.
+    pub travel_since: u64,
+}
+
+impl MaintenanceStatus {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "due": self.due,
+            "days_since": self.days_since,
+            "travel_since": self.travel_since as f32 / 10.0,
+        })
+    }
+}
+
+impl Reminder {
+    pub fn status(
+        &self,
+        record: &MaintenanceRecord,
+        total_travel: u64,
+        today: NaiveDate,
+    ) -> MaintenanceStatus {
+        let days_since = record
+            .done_on
+            .as_deref()
+            .and_then(parse_date)
+            .map(|done_on| (today - done_on).num_days().max(0) as u64);
+        let travel_since = total_travel.saturating_sub(record.travel_at);
+        let due = matches!((self.every_days, days_since), (Some(every), Some(since)) if since >= every)
+            || matches!(self.every_travel, Some(every) if travel_since >= every);
+        MaintenanceStatus {
+            due,
+            days_since,
+            travel_since,
+        }
+    }
+}
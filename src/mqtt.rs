@@ -1,16 +1,52 @@
 use std::{
-    sync::Arc,
-    time::{Duration, Instant},
+    collections::{BTreeMap, HashSet, VecDeque},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{anyhow, Context, Result};
-use log::{error, info};
+use anyhow::{Context, Result};
+use laing_protocol::display::{Condition, Decoder};
+use log::{debug, error, info, warn};
 use rumqttc::{
-    AsyncClient, ConnAck, Event, LastWill, MqttOptions, Outgoing, Packet, Publish, QoS,
+    AsyncClient, ConnAck, Event, LastWill, MqttOptions, Outgoing, Packet, PubAck, Publish, QoS,
     TlsConfiguration, Transport,
 };
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
 
-use crate::settings::{MqttTransport, Settings};
+use crate::{
+    auth::{self, CommandAuth},
+    calibration::HeightCorrection,
+    cloud,
+    compat::{
+        domoticz_height_payload, homie_command_topic, homie_height_topic, homie_messages,
+        homie_state_topic,
+    },
+    diagnostics::{configuration_summary, redact, CommandLatency, Diagnostics},
+    error::{self, Error, MqttError},
+    events::{DeskEvent, ErrorKind, EventBus, HeightSource},
+    filter::HeightFilter,
+    history::History,
+    maintenance::MaintenanceStatus,
+    names::{entity_name, Entity},
+    reconnect::{Backoff, RateLimiter},
+    settings::{
+        load_settings_value, ClientCertificate, DisplayCodes, GentleMovementSettings,
+        HassDiscovery, HassSchema, HeightSensor, MqttTransport, PayloadEncoding, RegisterAccess,
+        Settings,
+    },
+    tls,
+    transport::{inspect::PortMetrics, transfer::TransferMetrics},
+};
+
+/// How many events to include in the diagnostics report.
+const RECENT_EVENTS: usize = 20;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Command {
@@ -19,59 +55,790 @@ pub enum Command {
     Preset3,
     Preset4,
     Refresh,
+    Sleep,
+    Calibrate,
+    /// Move to a height, in tenths of an inch, such as one saved as a scene.
+    ///
+    /// The controller can only move to presets, so this heads for a preset beyond the height and
+    /// stops on the way.
+    Restore {
+        target: u16,
+    },
+    /// Move up or down by a distance, in tenths of an inch, from wherever the desk is when the
+    /// command runs. It then moves like `Restore`.
+    MoveBy {
+        delta: i16,
+    },
+    /// Move up for as long as the command keeps being sent, like holding a button.
+    ///
+    /// The controller can only move to presets, so this heads for the highest known preset and
+    /// stops once the command has not been sent again within `jog_hold_ms`.
+    Up,
+    /// Move down for as long as the command keeps being sent, towards the lowest known preset.
+    Down,
+    /// Stop the movement that is running, if any.
+    Stop,
+}
+
+impl Command {
+    /// The commands with a payload of their own, rather than one carrying a height or distance.
+    pub const FIXED: [Command; 10] = [
+        Command::Preset1,
+        Command::Preset2,
+        Command::Preset3,
+        Command::Preset4,
+        Command::Refresh,
+        Command::Sleep,
+        Command::Calibrate,
+        Command::Up,
+        Command::Down,
+        Command::Stop,
+    ];
+
+    /// Parse a command as published to the command topic.
+    pub fn parse(payload: &[u8]) -> Option<Command> {
+        match payload {
+            b"1" => Some(Command::Preset1),
+            b"2" => Some(Command::Preset2),
+            b"3" => Some(Command::Preset3),
+            b"4" => Some(Command::Preset4),
+            b"REFRESH" => Some(Command::Refresh),
+            b"SLEEP" => Some(Command::Sleep),
+            b"CALIBRATE" => Some(Command::Calibrate),
+            b"UP" => Some(Command::Up),
+            b"DOWN" => Some(Command::Down),
+            b"STOP" => Some(Command::Stop),
+            _ => Command::parse_move_by(payload),
+        }
+    }
+
+    /// Parse a relative movement in inches, either signed like `+1.0` and `-2.5`, or as
+    /// `{"action": "move_by", "delta": -1.0}`.
+    fn parse_move_by(payload: &[u8]) -> Option<Command> {
+        #[derive(Deserialize)]
+        struct Payload {
+            action: String,
+            delta: f32,
+        }
+
+        let delta = match payload.first() {
+            // A sign is required so the numbers of presets are not taken for distances.
+            Some(b'+' | b'-') => std::str::from_utf8(payload).ok()?.parse::<f32>().ok()?,
+            _ => {
+                let payload: Payload = serde_json::from_slice(payload).ok()?;
+                if payload.action != "move_by" {
+                    return None;
+                }
+                payload.delta
+            }
+        };
+        let delta = (delta * 10.0).round();
+        if !(f32::from(i16::MIN)..=f32::from(i16::MAX)).contains(&delta) {
+            return None;
+        }
+        Some(Command::MoveBy {
+            delta: delta as i16,
+        })
+    }
+
+    /// The payload that would be published to the command topic to send this command.
+    pub fn payload(&self) -> &'static str {
+        match self {
+            Command::Preset1 => "1",
+            Command::Preset2 => "2",
+            Command::Preset3 => "3",
+            Command::Preset4 => "4",
+            Command::Refresh => "REFRESH",
+            Command::Sleep => "SLEEP",
+            Command::Calibrate => "CALIBRATE",
+            Command::Restore { .. } => "RESTORE",
+            Command::MoveBy { .. } => "MOVE_BY",
+            Command::Up => "UP",
+            Command::Down => "DOWN",
+            Command::Stop => "STOP",
+        }
+    }
+
+    /// Whether this command moves the desk.
+    pub fn is_movement(&self) -> bool {
+        matches!(
+            self,
+            Command::Preset1
+                | Command::Preset2
+                | Command::Preset3
+                | Command::Preset4
+                | Command::Restore { .. }
+                | Command::MoveBy { .. }
+                | Command::Up
+                | Command::Down
+        )
+    }
+
+    /// Whether this command only keeps moving while it is sent again and again.
+    pub fn is_jog(&self) -> bool {
+        matches!(self, Command::Up | Command::Down)
+    }
+}
+
+/// Friendlier payloads for the command topic, such as the intents of a voice assistant, matched
+/// ignoring case.
+///
+/// They come from `command_aliases` and the names given to presets in `entity_names`, and the
+/// commands themselves are also accepted in any case.
+pub struct CommandAliases(BTreeMap<String, Command>);
+
+impl CommandAliases {
+    pub fn new(settings: &Settings) -> Self {
+        let mut aliases = BTreeMap::new();
+        for (&preset, template) in &settings.entity_names.presets {
+            let command = match Command::parse(preset.to_string().as_bytes()) {
+                Some(command) => command,
+                None => continue,
+            };
+            // Also without the desk name, as someone would say it.
+            let short = template.replace("{name}", "");
+            aliases.insert(short.trim().to_lowercase(), command);
+            aliases.insert(
+                entity_name(settings, Entity::Preset(preset)).to_lowercase(),
+                command,
+            );
+        }
+        for (alias, payload) in &settings.command_aliases {
+            match Command::parse(payload.as_bytes()) {
+                Some(command) => {
+                    aliases.insert(alias.trim().to_lowercase(), command);
+                }
+                None => warn!(
+                    "Ignoring the alias {:?} for {:?}, which is not a command",
+                    alias, payload
+                ),
+            }
+        }
+        aliases.remove("");
+        CommandAliases(aliases)
+    }
+
+    /// The aliases, in lower case.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+
+    /// Parse a command payload, falling back to the aliases and to commands in other cases.
+    pub fn parse(&self, payload: &[u8]) -> Option<Command> {
+        Command::parse(payload).or_else(|| {
+            let payload = std::str::from_utf8(payload).ok()?.trim().to_lowercase();
+            self.0
+                .get(&payload)
+                .copied()
+                .or_else(|| Command::parse(payload.to_uppercase().as_bytes()))
+        })
+    }
+}
+
+/// Where a command came from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Source {
+    Mqtt,
+    /// The API, with the id it answered with so the command's progress can be followed.
+    Api(u64),
+    Schedule,
+    Console,
+    /// The program itself, such as when checking on the controller or calibrating presets with
+    /// unknown heights at startup.
+    Internal,
+}
+
+impl Source {
+    pub fn name(self) -> &'static str {
+        match self {
+            Source::Mqtt => "mqtt",
+            Source::Api(_) => "api",
+            Source::Schedule => "schedule",
+            Source::Console => "console",
+            Source::Internal => "internal",
+        }
+    }
+}
+
+/// A request to read registers, published to `<prefix>/<id>/modbus/read` as `{"addr", "count"}`.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct RegisterRead {
+    #[serde(rename = "addr")]
+    pub address: u16,
+    pub count: u16,
+}
+
+impl RegisterRead {
+    /// Modbus cannot read more than this many registers at once.
+    pub const MAX_COUNT: u16 = 125;
+}
+
+/// Saving and returning to named heights, and setting the heights presets are taken to be at.
+#[derive(Clone, Debug)]
+pub enum SceneCommand {
+    /// Remember the current height under a name.
+    Snapshot(String),
+    /// Move back to a remembered height.
+    Restore(String),
+    /// Take a preset to be at a height, in tenths of an inch, such as after it was changed on the
+    /// handset.
+    SetPreset { preset: u8, height: u16 },
+    /// Record that the desk was looked after today, published to the command topic as
+    /// `MAINTENANCE_DONE`.
+    MaintenanceDone,
+}
+
+impl SceneCommand {
+    /// Parse a command published to the command topic as
+    /// `{"command": "snapshot" or "restore", "name": "..."}`.
+    pub fn parse(payload: &[u8]) -> Option<SceneCommand> {
+        if payload == b"MAINTENANCE_DONE" {
+            return Some(SceneCommand::MaintenanceDone);
+        }
+
+        #[derive(Deserialize)]
+        struct Payload {
+            command: String,
+            name: String,
+        }
+
+        let payload: Payload = serde_json::from_slice(payload).ok()?;
+        SceneCommand::new(&payload.command, payload.name)
+    }
+
+    /// Parse a preset height published to `<prefix>/<id>/set_preset` as
+    /// `{"slot": 2, "height": 40.5}`, with the height in inches.
+    pub fn parse_set_preset(payload: &[u8]) -> Option<SceneCommand> {
+        #[derive(Deserialize)]
+        struct Payload {
+            slot: u8,
+            height: f32,
+        }
+
+        let payload: Payload = serde_json::from_slice(payload).ok()?;
+        let height = (payload.height * 10.0).round();
+        if !(1..=4).contains(&payload.slot) || !(0.0..=f32::from(u16::MAX)).contains(&height) {
+            return None;
+        }
+        Some(SceneCommand::SetPreset {
+            preset: payload.slot,
+            height: height as u16,
+        })
+    }
+
+    /// A command from its name, if the scene name is valid.
+    ///
+    /// Scene names are limited to letters, digits, `-`, and `_` so they can be used in API paths.
+    pub fn new(command: &str, name: String) -> Option<SceneCommand> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return None;
+        }
+        match command {
+            "snapshot" => Some(SceneCommand::Snapshot(name)),
+            "restore" => Some(SceneCommand::Restore(name)),
+            _ => None,
+        }
+    }
 }
 
 pub struct MqttHandle {
-    pub height: tokio::sync::watch::Sender<Option<f32>>,
-    pub command: tokio::sync::broadcast::Receiver<Command>,
+    /// Whether the controller has answered recently.
+    pub available: tokio::sync::watch::Sender<bool>,
+    pub events: EventBus,
+    /// Text other than a height last seen on the display.
+    pub display: Option<String>,
+    /// Which display codes mean the desk cannot move.
+    pub display_codes: DisplayCodes,
+    /// The condition the display last showed.
+    pub condition: Option<Condition>,
+    pub decoder: Box<dyn Decoder>,
+    /// Which state registers hold the display, or `None` until it has been found by scanning.
+    pub display_offset: Option<usize>,
+    /// How to exchange registers with the controller. `Auto` is replaced once it is known whether
+    /// Read/Write Multiple Registers works.
+    pub register_access: RegisterAccess,
+    /// The time between writing and reading with `RegisterAccess::Separate`.
+    pub register_gap: Duration,
+    /// Pause movements regularly to move more slowly.
+    pub gentle_movement: Option<GentleMovementSettings>,
+    /// Fail instead of counting heights nothing was listening for.
+    pub strict_publishing: bool,
+    pub diagnostics: Arc<Diagnostics>,
+    /// The heights the desk can be at, in tenths of an inch.
+    pub possible_heights: RangeInclusive<u16>,
+    /// Applied to every height read from the controller.
+    pub correction: HeightCorrection,
+    pub filter: HeightFilter,
+    /// Whether the interlock sensor is preventing the desk from being lowered.
+    pub blocked: tokio::sync::watch::Receiver<bool>,
+    /// Why heights are being read, to report with those that changed.
+    pub height_source: HeightSource,
+    /// The maintenance status last reported.
+    pub maintenance: Option<MaintenanceStatus>,
 }
 
 impl MqttHandle {
-    pub fn set_height(&mut self, height: f32) -> Result<()> {
-        self.height
-            .send(Some(height))
-            .map_err(|_| anyhow!("Failed to send message"))
+    pub fn set_height(&mut self, height: f32) -> error::Result<()> {
+        let height = self.filter.push(height);
+        self.send_height(height)
+    }
+
+    pub fn set_available(&mut self, available: bool) {
+        if *self.available.borrow() != available {
+            let _ = self.available.send(available);
+        }
+    }
+
+    /// Set the height once the desk has stopped, bypassing the filter.
+    pub fn set_resting_height(&mut self, height: f32) -> error::Result<()> {
+        self.filter.reset();
+        self.send_height(height)
+    }
+
+    fn send_height(&mut self, height: f32) -> error::Result<()> {
+        let changed = self.events.height() != Some(height);
+        if self.events.send(DeskEvent::Height(height)) {
+            if changed {
+                self.events.send(DeskEvent::HeightChanged {
+                    source: self.height_source,
+                    time: SystemTime::now(),
+                });
+            }
+            return Ok(());
+        }
+        self.diagnostics
+            .undelivered_heights
+            .fetch_add(1, Ordering::Relaxed);
+        if self.strict_publishing {
+            return Err(MqttError::Unheard("heights").into());
+        }
+        Ok(())
+    }
+
+    pub fn report_error(&mut self, kind: ErrorKind, message: String) {
+        self.events.send(DeskEvent::Error { kind, message });
+    }
+
+    /// Report text, such as an error code, shown on the display instead of a height.
+    ///
+    /// Only changes are reported, since the display is read several times a second while moving.
+    pub fn report_display(&mut self, text: Option<String>) {
+        if text == self.display {
+            return;
+        }
+        let condition = text
+            .as_deref()
+            .and_then(|text| self.display_codes.condition(text));
+        if let Some(text) = &text {
+            match condition {
+                Some(condition) => {
+                    warn!("Controller displays {:?} ({})", text, condition.name());
+                    self.report_error(
+                        ErrorKind::Display,
+                        format!("controller displays {} ({})", text, condition.name()),
+                    );
+                }
+                None => {
+                    warn!("Controller displays {:?}", text);
+                    self.report_error(ErrorKind::Display, format!("controller displays {}", text));
+                }
+            }
+        }
+        if condition != self.condition {
+            if let Some(previous) = self.condition {
+                self.events.send(DeskEvent::Condition {
+                    condition: previous,
+                    active: false,
+                });
+            }
+            if let Some(condition) = condition {
+                self.events.send(DeskEvent::Condition {
+                    condition,
+                    active: true,
+                });
+            }
+            self.condition = condition;
+        }
+        self.display = text;
+    }
+
+    /// Report every condition as clear, so their sensors have a state before any is shown.
+    pub fn clear_conditions(&mut self) {
+        for condition in Condition::ALL {
+            self.events.send(DeskEvent::Condition {
+                condition,
+                active: false,
+            });
+        }
+    }
+
+    pub fn request_power(&mut self, on: bool) {
+        self.events.send(DeskEvent::Power(on));
+    }
+
+    pub fn report_presets(&mut self, heights: &BTreeMap<u8, u16>) {
+        self.events.send(DeskEvent::Presets(
+            heights
+                .iter()
+                .map(|(&preset, &height)| (preset, f32::from(height) / 10.0))
+                .collect(),
+        ));
+    }
+
+    /// Report the total distance moved, in tenths of an inch.
+    pub fn report_total_travel(&mut self, total: u64) {
+        self.events
+            .send(DeskEvent::TotalTravel(total as f32 / 10.0));
+    }
+
+    /// Report whether maintenance is due. Only changes are reported, since this is checked
+    /// regularly.
+    pub fn report_maintenance(&mut self, status: MaintenanceStatus) {
+        if self.maintenance.as_ref() == Some(&status) {
+            return;
+        }
+        self.maintenance = Some(status.clone());
+        self.events.send(DeskEvent::Maintenance(status));
+    }
+
+    pub fn report_rejected(
+        &mut self,
+        command: Command,
+        reason: &'static str,
+        other: Option<Command>,
+    ) {
+        self.events.send(DeskEvent::Rejected {
+            command,
+            reason,
+            other,
+        });
+    }
+
+    pub fn report_speed(&mut self, speed: f32) {
+        self.events.send(DeskEvent::Speed(speed));
+    }
+
+    pub fn report_latency(&mut self, latency: CommandLatency) {
+        self.diagnostics.record_latency(latency);
+        self.events.send(DeskEvent::Latency(latency));
+    }
+
+    pub fn report_moving(&mut self, preset: u8, eta: Option<Duration>, target: Option<f32>) {
+        self.events.send(DeskEvent::Moving {
+            preset,
+            eta,
+            target,
+        });
     }
 }
 
 #[derive(Clone)]
 pub struct State {
-    pub height: tokio::sync::watch::Receiver<Option<f32>>,
-    pub command: tokio::sync::broadcast::Sender<Command>,
+    pub available: tokio::sync::watch::Receiver<bool>,
+    pub command: tokio::sync::broadcast::Sender<(Command, Source)>,
+    pub scenes: tokio::sync::mpsc::Sender<(SceneCommand, Source)>,
+    pub register_reads: tokio::sync::mpsc::Sender<RegisterRead>,
+    /// Open the serial port again, at a new path if one is given.
+    pub reopen_serial: tokio::sync::mpsc::Sender<Option<String>>,
+    pub events: EventBus,
+    /// Notified when the settings file should be read again.
+    pub reload: Arc<tokio::sync::Notify>,
+    /// Notified when the MQTT connection should be restarted with the current settings file.
+    pub mqtt_restart: Arc<tokio::sync::Notify>,
+    pub blocked: Arc<tokio::sync::watch::Sender<bool>>,
+    /// Notified when the discovery configuration should be published again.
+    pub republish: Arc<tokio::sync::Notify>,
+    pub port_metrics: Arc<PortMetrics>,
+    pub transfer_metrics: Arc<TransferMetrics>,
+    pub diagnostics: Arc<Diagnostics>,
+    pub history: Option<Arc<History>>,
+    /// The id of the last command sent through the API.
+    pub command_ids: Arc<AtomicU64>,
+    /// Attempts to connect to the broker, shared by each MQTT connection in turn.
+    pub reconnects: Arc<Mutex<RateLimiter>>,
+    /// Cancelled when the program is stopping, for subsystems to finish up.
+    pub shutdown: CancellationToken,
+    /// The Home Assistant entities last published, for the API.
+    pub entities: Arc<Mutex<serde_json::Value>>,
 }
 
-pub async fn mqtt_loop(settings: &Settings, mut state: State) -> Result<()> {
-    let connected_topic = format!("{}/{}/connected", settings.prefix, settings.id);
-    let height_topic = format!("{}/{}/height", settings.prefix, settings.id);
-    let command_topic = format!("{}/{}/command", settings.prefix, settings.id);
-
-    let port = settings
-        .mqtt
-        .port
-        .unwrap_or_else(|| match settings.mqtt.transport {
-            MqttTransport::Tcp => 1883,
-            MqttTransport::Tls => 8883,
-        });
+/// The options for connecting to the broker, without a last will.
+pub fn mqtt_options(settings: &Settings) -> Result<MqttOptions> {
+    let port = settings.mqtt.port.unwrap_or(match settings.mqtt.transport {
+        MqttTransport::Tcp => 1883,
+        MqttTransport::Tls => 8883,
+    });
     let mut mqtt_options = MqttOptions::new(&settings.id, &settings.mqtt.host, port);
+    // rumqttc panics below 5 seconds.
+    mqtt_options.set_keep_alive(Duration::from_secs(settings.mqtt.keep_alive_secs.max(5)));
+    mqtt_options.set_max_packet_size(settings.mqtt.max_packet_size, settings.mqtt.max_packet_size);
+    mqtt_options.set_clean_session(!settings.mqtt.persistent_session);
+    if let Some(cloud) = &settings.mqtt.cloud {
+        // Cloud platforms always require TLS.
+        cloud::apply(settings, cloud, &mut mqtt_options, tls_config()?)?;
+        return Ok(mqtt_options);
+    }
     match settings.mqtt.transport {
         MqttTransport::Tcp => mqtt_options.set_transport(Transport::Tcp),
         MqttTransport::Tls => {
-            let mut config = rumqttc::ClientConfig::new();
-            for cert in rustls_native_certs::load_native_certs()? {
-                config.root_store.add(&rustls::Certificate(cert.0))?;
+            let mut config = tls_config()?;
+            if let Some(certificate) = &settings.mqtt.client_certificate {
+                let (chain, key) = read_client_certificate(certificate)?;
+                config.set_single_client_cert(chain, key)?;
             }
             mqtt_options.set_transport(Transport::Tls(TlsConfiguration::Rustls(Arc::new(config))))
         }
     };
     if let Some(credentials) = &settings.mqtt.credentials {
-        mqtt_options.set_credentials(&credentials.username, &credentials.password);
+        match &credentials.password_file {
+            Some(path) => {
+                let password = read_password(path)?;
+                mqtt_options.set_credentials(&credentials.username, password)
+            }
+            None => mqtt_options.set_credentials(&credentials.username, &credentials.password),
+        };
+    }
+    Ok(mqtt_options)
+}
+
+fn read_password(path: &Path) -> Result<String> {
+    let password = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read MQTT password from {}", path.display()))?;
+    // Editors and `echo` leave a newline at the end.
+    Ok(password.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+fn read_client_certificate(
+    files: &ClientCertificate,
+) -> Result<(Vec<rustls::Certificate>, rustls::PrivateKey)> {
+    tls::read_certificate(&files.certificate_file, &files.key_file)
+}
+
+/// Notices when credential files are replaced.
+struct CredentialFiles {
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+    /// Whether a change was seen that has not been acted on yet.
+    changed: bool,
+}
+
+impl CredentialFiles {
+    fn new(paths: Vec<PathBuf>) -> Self {
+        let files = paths
+            .into_iter()
+            .map(|path| {
+                let modified = modified(&path);
+                (path, modified)
+            })
+            .collect();
+        Self {
+            files,
+            changed: false,
+        }
     }
+
+    fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Whether the files changed, and have stayed the same since the previous check.
+    ///
+    /// Waiting a check avoids reconnecting between a certificate and its key being replaced.
+    fn settled(&mut self) -> bool {
+        let mut changing = false;
+        for (path, last_modified) in &mut self.files {
+            let modified = modified(path);
+            if modified != *last_modified {
+                *last_modified = modified;
+                changing = true;
+            }
+        }
+        let settled = self.changed && !changing;
+        self.changed = changing;
+        settled
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// A TLS configuration trusting the system's certificate authorities.
+fn tls_config() -> Result<rumqttc::ClientConfig> {
+    let mut config = rumqttc::ClientConfig::new();
+    for cert in rustls_native_certs::load_native_certs()? {
+        config.root_store.add(&rustls::Certificate(cert.0))?;
+    }
+    Ok(config)
+}
+
+/// The topics the desk is published and controlled under, below `<prefix>/<id>/`.
+pub struct Topics {
+    pub connected: String,
+    pub height: String,
+    pub height_attributes: String,
+    pub command: String,
+    pub error: String,
+    pub error_event: String,
+    pub movement: String,
+    pub presets: String,
+    pub rejected: String,
+    pub speed: String,
+    pub total_travel: String,
+    pub condition: String,
+    pub maintenance: String,
+    pub height_log: String,
+    pub latency: String,
+    pub diagnostics: String,
+    pub info: String,
+    pub modbus_response: String,
+    pub set_preset: String,
+    /// Only with `enable_diagnostic_api`.
+    pub modbus_read: Option<String>,
+    /// Only with `availability_timeout_secs`.
+    pub available: Option<String>,
+}
+
+impl Topics {
+    pub fn new(settings: &Settings) -> Self {
+        let topic = |name: &str| format!("{}/{}/{}", settings.prefix, settings.id, name);
+        Topics {
+            connected: topic("connected"),
+            height: topic("height"),
+            height_attributes: topic("height/attributes"),
+            command: topic("command"),
+            error: topic("error"),
+            error_event: topic("error/event"),
+            movement: topic("movement"),
+            presets: topic("presets"),
+            rejected: topic("rejected"),
+            speed: topic("speed"),
+            total_travel: topic("total_travel"),
+            condition: topic("condition"),
+            maintenance: topic("maintenance_due"),
+            height_log: topic("height_log"),
+            latency: topic("latency"),
+            diagnostics: topic("diagnostics"),
+            info: topic("info"),
+            modbus_response: topic("modbus/response"),
+            set_preset: topic("set_preset"),
+            modbus_read: settings.enable_diagnostic_api.then(|| topic("modbus/read")),
+            available: settings
+                .availability_timeout_secs
+                .map(|_| topic("available")),
+        }
+    }
+}
+
+/// Connect to the broker and relay state and commands.
+///
+/// Returns `true` if the connection was closed so it can be restarted with new settings.
+pub async fn mqtt_loop(settings: &Settings, mut state: State) -> error::Result<bool> {
+    let topics = Topics::new(settings);
+    let entities = hass_entities(settings, &topics);
+    let Topics {
+        connected: connected_topic,
+        height: height_topic,
+        height_attributes: height_attributes_topic,
+        command: command_topic,
+        error: error_topic,
+        error_event: error_event_topic,
+        movement: movement_topic,
+        presets: presets_topic,
+        rejected: rejected_topic,
+        speed: speed_topic,
+        total_travel: total_travel_topic,
+        condition: condition_topic,
+        maintenance: maintenance_topic,
+        height_log: height_log_topic,
+        latency: latency_topic,
+        diagnostics: diagnostics_topic,
+        info: info_topic,
+        modbus_response: modbus_response_topic,
+        set_preset: set_preset_topic,
+        modbus_read: modbus_read_topic,
+        available: available_topic,
+    } = topics;
+    let info = configuration_summary(settings).to_string();
+    // Each sensor with its topic and the state last published, if any.
+    let mut height_sensors: Vec<_> = settings
+        .height_sensors
+        .iter()
+        .map(|sensor| (height_sensor_topic(settings, sensor), sensor.clone(), None))
+        .collect();
+
+    let mut command_topics = vec![command_topic.clone()];
+    command_topics.extend(settings.mqtt.command_topic_aliases.iter().cloned());
+    let homie = settings.compatibility.openhab.then(|| {
+        command_topics.push(homie_command_topic(settings));
+        (homie_height_topic(settings), homie_state_topic(settings))
+    });
+    let domoticz = settings
+        .compatibility
+        .domoticz
+        .as_ref()
+        .map(|domoticz| (domoticz.topic.clone(), domoticz.idx));
+
+    let power_relay = settings
+        .power_relay
+        .as_ref()
+        .and_then(|relay| relay.mqtt.as_ref())
+        .map(|relay| {
+            (
+                relay.command_topic.clone(),
+                relay.payload_on.clone(),
+                relay.payload_off.clone(),
+            )
+        });
+
+    let mut mqtt_options =
+        mqtt_options(settings).map_err(|err| Error::Config(format!("{:#}", err)))?;
     mqtt_options.set_last_will(LastWill::new(
         &connected_topic,
         "OFF",
         QoS::AtLeastOnce,
-        true,
+        settings.mqtt.retain.connected,
     ));
+    let retain_connected = settings.mqtt.retain.connected;
+    let retain_height = settings.mqtt.retain.height;
+    let retain_movement = settings.mqtt.retain.movement;
+    let retain_error = settings.mqtt.retain.error;
+    let retain_info = settings.mqtt.retain.info;
+    let retain_available = settings.mqtt.retain.available;
+    let retain_total_travel = settings.mqtt.retain.total_travel;
+    let retain_maintenance = settings.mqtt.retain.maintenance;
+    let retain_condition = settings.mqtt.retain.condition;
+    let retain_presets = settings.mqtt.retain.presets;
+    let retain_height_sensors = settings.mqtt.retain.height_sensors;
+    let offline_buffer = settings.mqtt.offline_buffer;
+    let encoding = settings.mqtt.encoding;
+    let renew_after = settings.mqtt.cloud.as_ref().and_then(cloud::renew_after);
+    let refresh = settings.mqtt.refresh.as_ref().map(|refresh| {
+        (
+            Duration::from_secs(refresh.interval_hours.max(1) * 60 * 60),
+            refresh.discovery,
+        )
+    });
+    let mut credential_files = CredentialFiles::new(
+        settings
+            .mqtt
+            .credential_files()
+            .into_iter()
+            .map(Path::to_path_buf)
+            .collect(),
+    );
+    let credential_check = Duration::from_secs(settings.mqtt.credential_check_secs.max(1));
+    let password_file = settings
+        .mqtt
+        .credentials
+        .as_ref()
+        .and_then(|credentials| credentials.password_file.clone());
+    let client_certificate = settings.mqtt.client_certificate.clone();
 
     // Set capacity to 1.
     // Backpressure is handled more intelligently and for this application it just
@@ -79,23 +846,86 @@ pub async fn mqtt_loop(settings: &Settings, mut state: State) -> Result<()> {
     let (client, mut event_loop) = AsyncClient::new(mqtt_options, 1);
 
     let (connect_send, mut connect_receive) = tokio::sync::mpsc::channel(1);
-    let command_topic_listen = command_topic.clone();
-    let event_loop = tokio::spawn(async move {
+    let (diagnostics_send, mut diagnostics_receive) = tokio::sync::mpsc::channel(1);
+    // Home Assistant announces on its status topic when it starts, and then needs the discovery
+    // configuration again. Each instance is told apart by the index of its prefix.
+    let (birth_send, mut birth_receive) = tokio::sync::mpsc::channel(4);
+    let birth_topics: Vec<String> = settings
+        .hass_prefixes
+        .iter()
+        .map(|prefix| format!("{}/status", prefix))
+        .collect();
+    let birth_topics_listen = birth_topics.clone();
+    // Whether the broker is reachable, so heights can be kept instead of waiting to be sent.
+    let online = Arc::new(AtomicBool::new(false));
+    let online_listen = online.clone();
+    let mut events = state.events.subscribe();
+    let command_topics_listen = command_topics.clone();
+    let modbus_read_listen = modbus_read_topic.clone();
+    let set_preset_listen = set_preset_topic.clone();
+    let mut auth = settings
+        .command_auth
+        .as_ref()
+        .map(|auth| CommandAuth::new(&auth.key, auth.max_age_secs));
+    let aliases = CommandAliases::new(settings);
+    let interlock = settings.interlock.as_ref().map(|interlock| {
+        (
+            interlock.topic.clone(),
+            interlock.payload_blocked.clone(),
+            interlock.payload_clear.clone(),
+        )
+    });
+    let interlock_topic = interlock.as_ref().map(|(topic, _, _)| topic.clone());
+    let blocked = state.blocked.clone();
+    let discovery_acks = Arc::new(Mutex::new(DiscoveryAcks::default()));
+    let discovery_acks_listen = discovery_acks.clone();
+    let diagnostics_listen = state.diagnostics.clone();
+    let hass_prefixes = settings.hass_prefixes.join("/, ");
+    let state_listen = state.clone();
+    let persistent_session = settings.mqtt.persistent_session;
+    // The broker only keeps messages for a persistent session up to the QoS subscribed with.
+    let command_qos = if persistent_session {
+        QoS::AtLeastOnce
+    } else {
+        QoS::AtMostOnce
+    };
+    let queued_command_max_age_secs = settings.mqtt.queued_command_max_age_secs;
+    let reconnect = &settings.mqtt.reconnect;
+    let mut backoff = Backoff::new(
+        Duration::from_millis(reconnect.min_delay_ms),
+        Duration::from_secs(reconnect.max_delay_secs),
+        Duration::from_millis(reconnect.jitter_ms),
+    );
+    let max_reconnects = reconnect.max_per_minute;
+    let reconnects = state.reconnects.clone();
+    let mut event_loop = tokio::spawn(async move {
         // Keep this separate from the `publish(..).await`s.
         // There's an in-memory queue that holds messages until they are dispatched from
         // this coroutine. If that queue fills up, `publish(..).await` will pause the
         // coroutine until this coroutine makes progress emptying the queue. If they're
         // the same coroutine the code will deadlock as soon as the queue overflows.
-        const MIN_DELAY: Duration = Duration::from_secs(1);
         let mut start = Instant::now();
         let mut stop = false;
+        let mut connected_at = None;
+        // Why the connection was lost, to report once it is back.
+        let mut lost = None;
         loop {
+            diagnostics_listen.mqtt_heartbeat.beat("polling the broker");
             match event_loop.poll().await {
                 Ok(Event::Incoming(Packet::ConnAck(ConnAck {
                     code: rumqttc::ConnectReturnCode::Success,
                     ..
                 }))) => {
                     info!("MQTT connected");
+                    online_listen.store(true, Ordering::Relaxed);
+                    backoff.reset();
+                    connected_at = Some(Instant::now());
+                    if let Some(error) = lost.take() {
+                        state_listen.events.send(DeskEvent::Error {
+                            kind: ErrorKind::Mqtt,
+                            message: format!("lost the connection to the broker: {}", error),
+                        });
+                    }
                     // LWT sets power to off on disconnect so we need to set power to on
                     // after every connect.
                     // Don't do it from this coroutine or the code can deadlock.
@@ -104,20 +934,115 @@ pub async fn mqtt_loop(settings: &Settings, mut state: State) -> Result<()> {
                 Ok(Event::Outgoing(Outgoing::Disconnect)) => {
                     stop = true;
                 }
+                Ok(Event::Outgoing(Outgoing::Publish(pkid))) => {
+                    // The client keeps each publish by its packet id until it is acknowledged.
+                    let sent = event_loop
+                        .state
+                        .outgoing_pub
+                        .get(usize::from(pkid))
+                        .and_then(Option::as_ref);
+                    if let Some(publish) = sent {
+                        discovery_acks_listen
+                            .lock()
+                            .unwrap()
+                            .sent(pkid, &publish.topic);
+                    }
+                }
+                Ok(Event::Incoming(Packet::PubAck(PubAck { pkid }))) => {
+                    discovery_acks_listen.lock().unwrap().acknowledged(pkid);
+                }
                 Ok(Event::Incoming(Packet::Publish(Publish { topic, payload, .. }))) => {
-                    if topic == command_topic_listen {
-                        if let Some(preset) = match &payload[..] {
-                            b"1" => Some(Command::Preset1),
-                            b"2" => Some(Command::Preset2),
-                            b"3" => Some(Command::Preset3),
-                            b"4" => Some(Command::Preset4),
-                            b"REFRESH" => Some(Command::Refresh),
-                            _ => None,
-                        } {
-                            state
+                    if command_topics_listen.contains(&topic) {
+                        // The broker may have kept a command for a persistent session for any
+                        // length of time, so unless it is signed, it has to say when it was sent.
+                        let payload = if persistent_session && auth.is_none() {
+                            auth::check_age(&payload, queued_command_max_age_secs)
+                                .map(String::into_bytes)
+                        } else {
+                            authenticate(&mut auth, &payload)
+                        };
+                        let payload = match payload {
+                            Some(payload) => payload,
+                            None => continue,
+                        };
+                        if payload == b"DIAG" {
+                            // Publishing from this coroutine could deadlock, as with connecting.
+                            let _ = diagnostics_send.try_send(());
+                        } else if let Some(command) = aliases.parse(&payload) {
+                            state_listen
                                 .command
-                                .send(preset)
-                                .context("failed to accept command")?;
+                                .send((command, Source::Mqtt))
+                                .map_err(|_| MqttError::Unheard("commands"))?;
+                        } else if let Some(scene) = SceneCommand::parse(&payload) {
+                            if state_listen.scenes.try_send((scene, Source::Mqtt)).is_err() {
+                                warn!("Too many scene commands; ignoring");
+                            }
+                        }
+                    } else if let Some(index) =
+                        birth_topics_listen.iter().position(|birth| *birth == topic)
+                    {
+                        if payload.as_ref() == b"online" {
+                            // Publishing from this coroutine could deadlock, as with connecting.
+                            let _ = birth_send.try_send(index);
+                        }
+                    } else if topic == set_preset_listen {
+                        let payload = match authenticate(&mut auth, &payload) {
+                            Some(payload) => payload,
+                            None => continue,
+                        };
+                        match SceneCommand::parse_set_preset(&payload) {
+                            Some(preset) => {
+                                if state_listen
+                                    .scenes
+                                    .try_send((preset, Source::Mqtt))
+                                    .is_err()
+                                {
+                                    warn!("Too many scene commands; ignoring");
+                                }
+                            }
+                            None => warn!("Invalid preset height {:?}", payload),
+                        }
+                    } else if modbus_read_listen.as_ref() == Some(&topic) {
+                        let payload = match authenticate(&mut auth, &payload) {
+                            Some(payload) => payload,
+                            None => continue,
+                        };
+                        match serde_json::from_slice::<RegisterRead>(&payload) {
+                            Ok(read) if (1..=RegisterRead::MAX_COUNT).contains(&read.count) => {
+                                if state_listen.register_reads.try_send(read).is_err() {
+                                    warn!("Too many register reads; ignoring");
+                                }
+                            }
+                            Ok(read) => {
+                                state_listen.events.send(DeskEvent::Registers {
+                                    address: read.address,
+                                    count: read.count,
+                                    result: Err(format!(
+                                        "count must be between 1 and {}",
+                                        RegisterRead::MAX_COUNT
+                                    )),
+                                });
+                            }
+                            Err(err) => warn!("Invalid register read {:?}: {}", payload, err),
+                        }
+                    } else if let Some((interlock_topic, payload_blocked, payload_clear)) =
+                        &interlock
+                    {
+                        if &topic == interlock_topic {
+                            // Blocking only stops movement, so it is accepted unsigned.
+                            if &payload[..] == payload_blocked.as_bytes() {
+                                let _ = blocked.send(true);
+                                continue;
+                            }
+                            let payload = match authenticate(&mut auth, &payload) {
+                                Some(payload) => payload,
+                                None => continue,
+                            };
+                            if payload == payload_blocked.as_bytes() {
+                                let _ = blocked.send(true);
+                            } else if payload == payload_clear.as_bytes() {
+                                let _ = blocked.send(false);
+                            }
                         }
                     }
                 }
@@ -127,138 +1052,846 @@ pub async fn mqtt_loop(settings: &Settings, mut state: State) -> Result<()> {
                         break;
                     }
                     error!("MQTT error: {:?}", error);
+                    lost.get_or_insert_with(|| error.to_string());
+                    online_listen.store(false, Ordering::Relaxed);
+                    let was_connected = connected_at.take().is_some();
+                    // Some brokers close the connection over a publish they do not allow.
+                    if discovery_acks_listen.lock().unwrap().disconnected() && was_connected {
+                        warn!(
+                            "The broker disconnected before acknowledging the discovery configuration; check that it allows publishing under {}/",
+                            hass_prefixes
+                        );
+                        diagnostics_listen
+                            .discovery_failed
+                            .store(true, Ordering::Relaxed);
+                    }
 
                     // Wait so we don't flood the network with requests and then try again.
+                    diagnostics_listen
+                        .mqtt_heartbeat
+                        .beat("waiting to reconnect");
                     let elapsed = start.elapsed();
-                    if elapsed < MIN_DELAY {
-                        tokio::time::sleep(MIN_DELAY - elapsed).await;
+                    let delay = backoff.next_delay();
+                    if elapsed < delay {
+                        tokio::time::sleep(delay - elapsed).await;
+                    }
+                    if let Some(limit) = max_reconnects {
+                        let wait = reconnects.lock().unwrap().reserve(
+                            limit,
+                            Duration::from_secs(60),
+                            Instant::now(),
+                        );
+                        if !wait.is_zero() {
+                            debug!("Waiting {:?} to reconnect to stay within the limit", wait);
+                            tokio::time::sleep(wait).await;
+                        }
                     }
                     start = Instant::now();
                 }
             }
         }
 
-        Result::<(), anyhow::Error>::Ok(())
+        error::Result::Ok(())
     });
 
-    if !settings.hass_prefix.is_empty() {
-        client
-            .publish(
-                format!(
-                    "{}/binary_sensor/{}_connected/config",
-                    settings.hass_prefix, settings.id
-                ),
-                QoS::AtLeastOnce,
-                true,
-                serde_json::to_string(&serde_json::json!({
-                    "name": format!("{} Connected", settings.name),
-                    "device_class": "connectivity",
-                    "state_topic": &connected_topic,
-                }))
-                .unwrap(),
-            )
-            .await?;
-        client
-            .publish(
-                format!(
-                    "{}/sensor/{}_height/config",
-                    settings.hass_prefix, settings.id
-                ),
-                QoS::AtLeastOnce,
-                true,
-                serde_json::to_string(&serde_json::json!({
-                    "name": format!("{} Height", settings.name),
-                    "unit_of_measurement": "in",
-                    "state_topic": &height_topic,
-                    "availability": [{
-                        "topic": &connected_topic,
-                        "payload_available": "ON",
-                        "payload_not_available": "OFF",
-                    }],
-                    "icon": "mdi:human-male-height",
-                }))
-                .unwrap(),
-            )
-            .await?;
-
-        for i in 1..=4 {
-            client
-                .publish(
-                    format!(
-                        "{}/button/{}_preset_{}/config",
-                        settings.hass_prefix, settings.id, i
-                    ),
-                    QoS::AtLeastOnce,
-                    true,
-                    serde_json::to_string(&serde_json::json!({
-                        "name": format!("{} {}", settings.name, i),
-                        "command_topic": &command_topic,
-                        "payload_press": format!("{}", i),
-                        "availability": [{
-                            "topic": &connected_topic,
-                            "payload_available": "ON",
-                            "payload_not_available": "OFF",
-                        }],
-                        "icon": format!("mdi:numeric-{}-circle", i),
-                    }))
-                    .unwrap(),
-                )
-                .await?;
-        }
-        client
-            .publish(
-                format!(
-                    "{}/button/{}_refresh/config",
-                    settings.hass_prefix, settings.id
-                ),
-                QoS::AtLeastOnce,
-                true,
-                serde_json::to_string(&serde_json::json!({
-                    "name": format!("{} refresh", settings.name),
-                    "command_topic": &command_topic,
-                    "payload_press": "REFRESH",
-                    "availability": [{
-                        "topic": &connected_topic,
-                        "payload_available": "ON",
-                        "payload_not_available": "OFF",
-                    }],
-                    "icon": "mdi:refresh",
-                }))
-                .unwrap(),
-            )
-            .await?;
+    let discovery = |messages| Discovery {
+        messages: Arc::new(messages),
+        acks: discovery_acks.clone(),
+        diagnostics: state.diagnostics.clone(),
+    };
+    *state.entities.lock().unwrap() = entities.to_json(settings);
+    // One for each Home Assistant instance, in the order of their birth topics, then openHAB's.
+    let mut discoveries: Vec<Discovery> = settings
+        .hass_prefixes
+        .iter()
+        .map(|prefix| discovery(discovery_messages(settings, prefix, &entities)))
+        .collect();
+    if homie.is_some() {
+        // The listener has its own copy of the aliases.
+        let aliases = CommandAliases::new(settings);
+        let commands: Vec<&str> = Command::FIXED
+            .iter()
+            .map(|command| command.payload())
+            .chain(aliases.names())
+            .collect();
+        discoveries.push(discovery(homie_messages(settings, &commands)));
+    }
+    for discovery in &discoveries {
+        discovery.publish(&client);
     }
 
-    let worker = tokio::spawn(async move {
+    let mut worker = tokio::spawn(async move {
+        let mut restart = false;
+        // The timer is not polled without refresh settings, so the period does not matter then.
+        let refresh_period = refresh.map_or(Duration::from_secs(60 * 60), |(period, _)| period);
+        let mut refresh_timer =
+            tokio::time::interval_at(tokio::time::Instant::now() + refresh_period, refresh_period);
+        let renew = tokio::time::sleep(renew_after.unwrap_or_default());
+        tokio::pin!(renew);
+        let mut credential_timer = tokio::time::interval_at(
+            tokio::time::Instant::now() + credential_check,
+            credential_check,
+        );
+        let mut offline_heights = OfflineHeights::new(offline_buffer);
+        let mut recent_events = VecDeque::with_capacity(RECENT_EVENTS);
+        // The last movement, and when and why the height last changed, for the height sensor.
+        let mut height_attributes = serde_json::Map::new();
         loop {
             tokio::select! {
                 recv = connect_receive.recv() => {
                     if recv.is_some() {
-                        client.subscribe(&command_topic, QoS::AtMostOnce).await?;
-                        client.publish(&connected_topic, QoS::AtLeastOnce, true, "ON").await?;
+                        for topic in &command_topics {
+                            client.subscribe(topic, command_qos).await?;
+                        }
+                        if let Some(topic) = &interlock_topic {
+                            client.subscribe(topic, QoS::AtLeastOnce).await?;
+                        }
+                        client.subscribe(&set_preset_topic, QoS::AtLeastOnce).await?;
+                        for topic in &birth_topics {
+                            client.subscribe(topic, QoS::AtLeastOnce).await?;
+                        }
+                        if let Some(topic) = &modbus_read_topic {
+                            client.subscribe(topic, QoS::AtMostOnce).await?;
+                        }
+                        client.publish(&connected_topic, QoS::AtLeastOnce, retain_connected, "ON").await?;
+                        client.publish(&info_topic, QoS::AtLeastOnce, retain_info, info.clone()).await?;
+                        if let Some(topic) = &available_topic {
+                            let available = *state.available.borrow();
+                            client.publish(topic, QoS::AtLeastOnce, retain_available, on_off(available)).await?;
+                        }
+                        if let Some((_, state_topic)) = &homie {
+                            client.publish(state_topic, QoS::AtLeastOnce, true, "ready").await?;
+                        }
+                        if let Some(height) = state.events.height() {
+                            // The broker may have restarted without keeping them.
+                            for (_, _, published) in &mut height_sensors {
+                                *published = None;
+                            }
+                            publish_height_sensors(&client, &mut height_sensors, height, retain_height_sensors).await?;
+                        }
+                        if !offline_heights.is_empty() {
+                            info!("Publishing {} heights recorded while disconnected", offline_heights.len());
+                            for payload in offline_heights.drain() {
+                                publish_value(&client, encoding, &height_log_topic, false, &payload).await?;
+                            }
+                            if let Some(height) = state.events.height() {
+                                client.publish(&height_topic, QoS::AtLeastOnce, retain_height, format!("{}", height)).await?;
+                            }
+                        }
                     } else {
                         break;
                     }
                 }
-                recv = state.height.changed() => {
+                recv = state.available.changed(), if available_topic.is_some() => {
                     if recv.is_err() {
                         break;
                     }
-                    let height = *state.height.borrow_and_update();
-                    if let Some(height) = height {
-                        client.publish(&height_topic, QoS::AtLeastOnce, true, format!("{}", height)).await?;
+                    let available = *state.available.borrow_and_update();
+                    if let Some(topic) = &available_topic {
+                        client.publish(topic, QoS::AtLeastOnce, retain_available, on_off(available)).await?;
+                    }
+                }
+                _ = state.shutdown.cancelled() => {
+                    info!("Disconnecting from MQTT");
+                    // A clean disconnect does not send the last will.
+                    client.publish(&connected_topic, QoS::AtLeastOnce, retain_connected, "OFF").await?;
+                    break;
+                }
+                _ = state.mqtt_restart.notified() => {
+                    info!("Restarting MQTT connection");
+                    // A clean disconnect does not send the last will.
+                    client.publish(&connected_topic, QoS::AtLeastOnce, retain_connected, "OFF").await?;
+                    restart = true;
+                    break;
+                }
+                _ = refresh_timer.tick(), if refresh.is_some() => {
+                    info!("Refreshing retained state");
+                    client.publish(&connected_topic, QoS::AtLeastOnce, retain_connected, "ON").await?;
+                    if let Some(height) = state.events.height() {
+                        client.publish(&height_topic, QoS::AtLeastOnce, retain_height, format!("{}", height)).await?;
+                    }
+                    if matches!(refresh, Some((_, true))) {
+                        for discovery in &discoveries {
+                            discovery.publish(&client);
+                        }
+                    }
+                }
+                _ = credential_timer.tick(), if !credential_files.is_empty() => {
+                    if credential_files.settled() {
+                        // Reconnecting with credentials that cannot be read would stop the program.
+                        let check = password_file
+                            .as_deref()
+                            .map_or(Ok(()), |path| read_password(path).map(drop))
+                            .and_then(|()| {
+                                client_certificate
+                                    .as_ref()
+                                    .map_or(Ok(()), |files| read_client_certificate(files).map(drop))
+                            });
+                        match check {
+                            Ok(()) => {
+                                info!("MQTT credentials changed; reconnecting");
+                                restart = true;
+                                break;
+                            }
+                            Err(err) => error!("Keeping the current MQTT connection: {:?}", err),
+                        }
+                    }
+                }
+                _ = &mut renew, if renew_after.is_some() => {
+                    info!("Reconnecting to MQTT before the token expires");
+                    restart = true;
+                    break;
+                }
+                Some(()) = diagnostics_receive.recv() => {
+                    info!("Publishing diagnostics");
+                    let payload = diagnostics_report(&state, &recent_events);
+                    client.publish(&diagnostics_topic, QoS::AtLeastOnce, false, payload).await?;
+                }
+                _ = state.republish.notified() => {
+                    info!("Republishing discovery configuration");
+                    for discovery in &discoveries {
+                        discovery.publish(&client);
+                    }
+                }
+                Some(index) = birth_receive.recv() => {
+                    info!("Home Assistant came online at {}; publishing discovery configuration", birth_topics[index]);
+                    discoveries[index].publish(&client);
+                }
+                recv = events.recv() => {
+                    // Keep heights and speeds out of the report, or a single movement would fill it.
+                    match &recv {
+                        Ok(DeskEvent::Height(_) | DeskEvent::HeightChanged { .. } | DeskEvent::Speed(_)) | Err(_) => {}
+                        Ok(event) => {
+                            if recent_events.len() == RECENT_EVENTS {
+                                recent_events.pop_front();
+                            }
+                            recent_events.push_back(event.to_json());
+                        }
+                    }
+                    if let Err(RecvError::Lagged(skipped)) = &recv {
+                        state.diagnostics.skipped_events.fetch_add(*skipped, Ordering::Relaxed);
+                    }
+                    // Having fallen behind, only the latest height matters.
+                    let height = match &recv {
+                        Ok(DeskEvent::Height(height)) => Some(*height),
+                        Err(RecvError::Lagged(_)) => state.events.height(),
+                        _ => None,
+                    };
+                    if let Some(height) = height.filter(|_| !online.load(Ordering::Relaxed) && offline_buffer > 0) {
+                        offline_heights.push(height);
+                    } else if let Some(height) = height {
+                        client.publish(&height_topic, QoS::AtLeastOnce, retain_height, format!("{}", height)).await?;
+                        if let Some((height_topic, _)) = &homie {
+                            client.publish(height_topic, QoS::AtLeastOnce, true, format!("{}", height)).await?;
+                        }
+                        if let Some((topic, idx)) = &domoticz {
+                            client.publish(topic, QoS::AtLeastOnce, false, domoticz_height_payload(*idx, height)).await?;
+                        }
+                        publish_height_sensors(&client, &mut height_sensors, height, retain_height_sensors).await?;
+                    }
+                    match recv {
+                        Ok(DeskEvent::Error { kind, message }) => {
+                            let event = serde_json::json!({ "event_type": kind.name(), "message": message });
+                            client.publish(&error_event_topic, QoS::AtLeastOnce, false, event.to_string()).await?;
+                            client.publish(&error_topic, QoS::AtLeastOnce, retain_error, message).await?;
+                        }
+                        Ok(event @ DeskEvent::Moving { .. }) => {
+                            publish_value(&client, encoding, &movement_topic, retain_movement, &event.to_json()).await?;
+                            if let serde_json::Value::Object(movement) = event.to_json() {
+                                height_attributes.extend(movement.into_iter().filter(|(key, _)| key != "type"));
+                            }
+                            client.publish(&height_attributes_topic, QoS::AtLeastOnce, retain_height, serde_json::Value::from(height_attributes.clone()).to_string()).await?;
+                        }
+                        Ok(DeskEvent::HeightChanged { source, time }) => {
+                            let time = chrono::DateTime::<chrono::Utc>::from(time);
+                            height_attributes.insert("last_updated".into(), time.to_rfc3339().into());
+                            height_attributes.insert("source".into(), source.name().into());
+                            client.publish(&height_attributes_topic, QoS::AtLeastOnce, retain_height, serde_json::Value::from(height_attributes.clone()).to_string()).await?;
+                        }
+                        Ok(event @ DeskEvent::Rejected { .. }) => {
+                            publish_value(&client, encoding, &rejected_topic, false, &event.to_json()).await?;
+                        }
+                        Ok(DeskEvent::Speed(speed)) => {
+                            client.publish(&speed_topic, QoS::AtLeastOnce, false, format!("{:.2}", speed)).await?;
+                        }
+                        Ok(event @ DeskEvent::Latency(_)) => {
+                            publish_value(&client, encoding, &latency_topic, false, &event.to_json()).await?;
+                        }
+                        Ok(event @ DeskEvent::Registers { .. }) => {
+                            publish_value(&client, encoding, &modbus_response_topic, false, &event.to_json()).await?;
+                        }
+                        Ok(DeskEvent::TotalTravel(distance)) => {
+                            client.publish(&total_travel_topic, QoS::AtLeastOnce, retain_total_travel, format!("{:.1}", distance)).await?;
+                        }
+                        Ok(DeskEvent::Maintenance(status)) => {
+                            client.publish(format!("{}/attributes", maintenance_topic), QoS::AtLeastOnce, retain_maintenance, status.to_json().to_string()).await?;
+                            client.publish(&maintenance_topic, QoS::AtLeastOnce, retain_maintenance, on_off(status.due)).await?;
+                        }
+                        Ok(DeskEvent::Condition { condition, active }) => {
+                            let topic = format!("{}/{}", condition_topic, condition.name());
+                            client.publish(topic, QoS::AtLeastOnce, retain_condition, on_off(active)).await?;
+                        }
+                        Ok(DeskEvent::Presets(heights)) => {
+                            let payload = serde_json::to_value(&heights).unwrap();
+                            publish_value(&client, encoding, &presets_topic, retain_presets, &payload).await?;
+                        }
+                        Ok(DeskEvent::Power(on)) => {
+                            if let Some((topic, payload_on, payload_off)) = &power_relay {
+                                let payload = if on { payload_on } else { payload_off };
+                                client.publish(topic, QoS::AtLeastOnce, false, payload.clone()).await?;
+                            }
+                        }
+                        Ok(_) | Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => break,
                     }
                 }
             }
         }
         client.disconnect().await?;
-        Result::<(), anyhow::Error>::Ok(())
+        error::Result::Ok(restart)
     });
 
-    tokio::select! {
-        res = worker => res??,
-        res = event_loop => res??,
+    let restart = tokio::select! {
+        res = &mut worker => joined(res)?,
+        res = &mut event_loop => {
+            joined(res)?;
+            // The event loop only stops once the worker has disconnected.
+            joined(worker.await)?
+        }
     };
 
+    Ok(restart)
+}
+
+/// The result of a task, passing on its panic. Nothing here cancels the tasks.
+fn joined<T>(result: Result<T, tokio::task::JoinError>) -> T {
+    result.unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+}
+
+/// The payload to act on: the command inside it if `command_auth` is set and the signature checks
+/// out, the payload itself if `command_auth` is not set, or `None` to ignore it.
+fn authenticate(auth: &mut Option<CommandAuth>, payload: &[u8]) -> Option<Vec<u8>> {
+    match auth {
+        Some(auth) => auth.verify(payload).map(String::into_bytes),
+        None => Some(payload.to_vec()),
+    }
+}
+
+/// Heights seen while the broker was unreachable, with when they were seen.
+struct OfflineHeights {
+    capacity: usize,
+    heights: VecDeque<(SystemTime, f32)>,
+}
+
+impl OfflineHeights {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            heights: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Remember a height, dropping the oldest if full. Repeats of the last height are ignored.
+    fn push(&mut self, height: f32) {
+        if self.heights.back().map(|&(_, last)| last) == Some(height) {
+            return;
+        }
+        if self.heights.len() == self.capacity {
+            self.heights.pop_front();
+        }
+        self.heights.push_back((SystemTime::now(), height));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.heights.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.heights.len()
+    }
+
+    /// Take the remembered heights as `{"height", "time"}` payloads, oldest first.
+    fn drain(&mut self) -> Vec<serde_json::Value> {
+        self.heights
+            .drain(..)
+            .map(|(time, height)| {
+                serde_json::json!({
+                    "height": height,
+                    "time": time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// The retained (topic, payload) pairs that configure Home Assistant and other platforms.
+///
+/// Failing to publish these is not worth stopping for, since commands and state still work without
+/// them.
+struct Discovery {
+    messages: Arc<Vec<(String, String)>>,
+    acks: Arc<Mutex<DiscoveryAcks>>,
+    diagnostics: Arc<Diagnostics>,
+}
+
+/// The discovery configuration sent but not yet acknowledged by the broker, so that a broker
+/// closing the connection before then can be taken to have refused the configuration.
+#[derive(Default)]
+struct DiscoveryAcks {
+    /// The configuration topics, to tell its publishes apart from state published alongside it.
+    topics: HashSet<String>,
+    unacknowledged: HashSet<u16>,
+}
+
+impl DiscoveryAcks {
+    fn sent(&mut self, pkid: u16, topic: &str) {
+        if self.topics.contains(topic) {
+            self.unacknowledged.insert(pkid);
+        }
+    }
+
+    fn acknowledged(&mut self, pkid: u16) {
+        self.unacknowledged.remove(&pkid);
+    }
+
+    /// Forget what was pending when the connection was lost, returning whether anything was.
+    fn disconnected(&mut self) -> bool {
+        let pending = !self.unacknowledged.is_empty();
+        self.unacknowledged.clear();
+        pending
+    }
+}
+
+impl Discovery {
+    /// Publish the messages from a task of their own, without holding up commands and state.
+    fn publish(&self, client: &AsyncClient) {
+        if self.messages.is_empty() {
+            return;
+        }
+        self.acks
+            .lock()
+            .unwrap()
+            .topics
+            .extend(self.messages.iter().map(|(topic, _)| topic.clone()));
+        let client = client.clone();
+        let messages = self.messages.clone();
+        let diagnostics = self.diagnostics.clone();
+        tokio::spawn(async move {
+            // The client hands publishes to its event loop one at a time, so there is nothing to
+            // gain from starting several at once.
+            for (topic, payload) in messages.iter() {
+                if let Err(err) = client
+                    .publish(topic, QoS::AtLeastOnce, true, payload.clone())
+                    .await
+                {
+                    error!("Failed to publish configuration: {:?}", err);
+                    diagnostics.discovery_failed.store(true, Ordering::Relaxed);
+                }
+            }
+        });
+    }
+}
+
+fn height_sensor_topic(settings: &Settings, sensor: &HeightSensor) -> String {
+    format!(
+        "{}/{}/height_sensor/{}",
+        settings.prefix,
+        settings.id,
+        sensor.slug()
+    )
+}
+
+/// Publish the states of the height sensors that have changed.
+async fn publish_height_sensors(
+    client: &AsyncClient,
+    sensors: &mut [(String, HeightSensor, Option<bool>)],
+    height: f32,
+    retain: bool,
+) -> error::Result<()> {
+    for (topic, sensor, published) in sensors {
+        let on = sensor.is_on(height);
+        if *published != Some(on) {
+            client
+                .publish(topic.as_str(), QoS::AtLeastOnce, retain, on_off(on))
+                .await?;
+            *published = Some(on);
+        }
+    }
+    Ok(())
+}
+
+/// Publish `value` as JSON, CBOR, or both, as `encoding` says.
+async fn publish_value(
+    client: &AsyncClient,
+    encoding: PayloadEncoding,
+    topic: &str,
+    retain: bool,
+    value: &serde_json::Value,
+) -> error::Result<()> {
+    if encoding != PayloadEncoding::Cbor {
+        let payload = serde_json::to_string(value).unwrap();
+        client
+            .publish(topic, QoS::AtLeastOnce, retain, payload)
+            .await?;
+    }
+    if encoding != PayloadEncoding::Json {
+        let mut payload = Vec::new();
+        // Writing to a Vec cannot fail.
+        ciborium::ser::into_writer(value, &mut payload).unwrap();
+        let topic = match encoding {
+            PayloadEncoding::Both => format!("{}/cbor", topic),
+            _ => topic.to_string(),
+        };
+        client
+            .publish(topic, QoS::AtLeastOnce, retain, payload)
+            .await?;
+    }
     Ok(())
 }
+
+/// Everything useful for a bug report, published in answer to `DIAG`.
+fn diagnostics_report(state: &State, recent_events: &VecDeque<serde_json::Value>) -> String {
+    let mut settings = match load_settings_value() {
+        Ok(value) => serde_json::to_value(value).unwrap_or_default(),
+        Err(err) => serde_json::json!({ "error": err.to_string() }),
+    };
+    redact(&mut settings);
+    serde_json::to_string(&serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "settings": settings,
+        "height": state.events.height(),
+        "available": *state.available.borrow(),
+        "port": state.port_metrics.to_json(),
+        "transfer": state.transfer_metrics.to_json(),
+        "diagnostics": state.diagnostics.to_json(),
+        "recent_events": recent_events,
+    }))
+    .unwrap()
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "ON"
+    } else {
+        "OFF"
+    }
+}
+
+/// The Home Assistant entities describing the desk, the same under every discovery prefix.
+struct HassEntities {
+    /// Each entity's platform, object ID, and config.
+    entities: Vec<(&'static str, String, serde_json::Value)>,
+    /// The platform and object ID of entities that another schema or other settings may have
+    /// published before, so nothing lingers.
+    stale: Vec<(&'static str, String)>,
+}
+
+/// Describe the desk's entities for Home Assistant. `hass_schema: Legacy` leaves out what older
+/// releases of Home Assistant reject.
+fn hass_entities(settings: &Settings, topics: &Topics) -> HassEntities {
+    let legacy = settings.hass_schema == HassSchema::Legacy;
+    let mut messages = Vec::new();
+    let mut stale = Vec::new();
+    // Everything but the connection sensor is unavailable if either the broker connection or the
+    // controller is down.
+    let mut availability = vec![serde_json::json!({
+        "topic": topics.connected,
+        "payload_available": "ON",
+        "payload_not_available": "OFF",
+    })];
+    if let Some(available_topic) = &topics.available {
+        availability.push(serde_json::json!({
+            "topic": available_topic,
+            "payload_available": "ON",
+            "payload_not_available": "OFF",
+        }));
+    }
+    messages.push((
+        "binary_sensor",
+        format!("{}_connected", settings.id),
+        serde_json::json!({
+            "name": entity_name(settings, Entity::Connected),
+            "device_class": "connectivity",
+            "state_topic": topics.connected,
+        }),
+    ));
+    let mut height_config = serde_json::json!({
+        "name": entity_name(settings, Entity::Height),
+        "unit_of_measurement": "in",
+        "state_topic": topics.height,
+        "json_attributes_topic": topics.height_attributes,
+        "availability": availability.clone(),
+        "availability_mode": "all",
+        "icon": "mdi:human-male-height",
+    });
+    if let Some(expire_after) = settings.height_expire_after_secs {
+        height_config["expire_after"] = expire_after.into();
+    }
+    messages.push(("sensor", format!("{}_height", settings.id), height_config));
+    messages.push((
+        "sensor",
+        format!("{}_speed", settings.id),
+        serde_json::json!({
+            "name": entity_name(settings, Entity::Speed),
+            "unit_of_measurement": "in/s",
+            "state_class": "measurement",
+            "state_topic": topics.speed,
+            "availability": availability.clone(),
+            "availability_mode": "all",
+            "icon": "mdi:speedometer",
+        }),
+    ));
+    messages.push((
+        "sensor",
+        format!("{}_total_travel", settings.id),
+        serde_json::json!({
+            "name": entity_name(settings, Entity::TotalTravel),
+            "unit_of_measurement": "in",
+            "state_class": "total_increasing",
+            "state_topic": topics.total_travel,
+            "availability": availability.clone(),
+            "availability_mode": "all",
+            "icon": "mdi:counter",
+        }),
+    ));
+    for condition in Condition::ALL {
+        let mut config = serde_json::json!({
+            "name": entity_name(settings, Entity::Condition(condition)),
+            "state_topic": format!("{}/{}", topics.condition, condition.name()),
+            "availability": availability.clone(),
+            "availability_mode": "all",
+        });
+        match condition {
+            Condition::EndOfTravel => config["icon"] = "mdi:arrow-collapse-vertical".into(),
+            Condition::Overload => config["device_class"] = "problem".into(),
+            Condition::Overheat => config["device_class"] = "heat".into(),
+        }
+        messages.push((
+            "binary_sensor",
+            format!("{}_{}", settings.id, condition.name()),
+            config,
+        ));
+    }
+    // Not tied to the controller being available, since losing it is one of the errors.
+    let error_id = format!("{}_error", settings.id);
+    if legacy {
+        // Without the event platform, the last error message is shown as a sensor.
+        stale.push(("event", error_id.clone()));
+        messages.push((
+            "sensor",
+            error_id,
+            serde_json::json!({
+                "name": entity_name(settings, Entity::Error),
+                "state_topic": topics.error,
+                "icon": "mdi:alert-circle-outline",
+            }),
+        ));
+    } else {
+        stale.push(("sensor", error_id.clone()));
+        messages.push((
+            "event",
+            error_id,
+            serde_json::json!({
+                "name": entity_name(settings, Entity::Error),
+                "state_topic": topics.error_event,
+                "event_types": ErrorKind::ALL.iter().map(|kind| kind.name()).collect::<Vec<_>>(),
+                "icon": "mdi:alert-circle-outline",
+            }),
+        ));
+    }
+
+    for i in 1..=4 {
+        messages.push((
+            "button",
+            format!("{}_preset_{}", settings.id, i),
+            serde_json::json!({
+                "name": entity_name(settings, Entity::Preset(i)),
+                "command_topic": topics.command,
+                "payload_press": format!("{}", i),
+                "availability": availability.clone(),
+                "availability_mode": "all",
+                "icon": format!("mdi:numeric-{}-circle", i),
+            }),
+        ));
+    }
+    for sensor in &settings.height_sensors {
+        messages.push((
+            "binary_sensor",
+            format!("{}_{}", settings.id, sensor.slug()),
+            serde_json::json!({
+                "name": sensor.name,
+                "state_topic": height_sensor_topic(settings, sensor),
+                "availability": availability.clone(),
+                "availability_mode": "all",
+                "icon": "mdi:human-male-height-variant",
+            }),
+        ));
+    }
+    messages.push((
+        "button",
+        format!("{}_refresh", settings.id),
+        serde_json::json!({
+            "name": entity_name(settings, Entity::Refresh),
+            "command_topic": topics.command,
+            "payload_press": "REFRESH",
+            "availability": availability.clone(),
+            "availability_mode": "all",
+            "icon": "mdi:refresh",
+        }),
+    ));
+    let sleep_id = format!("{}_sleep", settings.id);
+    if settings.experimental_standby {
+        messages.push((
+            "button",
+            sleep_id,
+            serde_json::json!({
+                "name": entity_name(settings, Entity::Sleep),
+                "command_topic": topics.command,
+                "payload_press": "SLEEP",
+                "availability": availability.clone(),
+                "availability_mode": "all",
+                "icon": "mdi:sleep",
+            }),
+        ));
+    } else {
+        stale.push(("button", sleep_id));
+    }
+    let maintenance_ids = [
+        ("binary_sensor", format!("{}_maintenance_due", settings.id)),
+        ("button", format!("{}_maintenance_done", settings.id)),
+    ];
+    if settings.maintenance.is_some() {
+        let [(due_platform, due_id), (done_platform, done_id)] = maintenance_ids;
+        // Not tied to the controller being available, since the desk needs looking after either
+        // way.
+        messages.push((
+            due_platform,
+            due_id,
+            serde_json::json!({
+                "name": entity_name(settings, Entity::MaintenanceDue),
+                "device_class": "problem",
+                "state_topic": topics.maintenance,
+                "json_attributes_topic": format!("{}/attributes", topics.maintenance),
+                "icon": "mdi:wrench-clock",
+            }),
+        ));
+        messages.push((
+            done_platform,
+            done_id,
+            serde_json::json!({
+                "name": entity_name(settings, Entity::MaintenanceDone),
+                "command_topic": topics.command,
+                "payload_press": "MAINTENANCE_DONE",
+                "icon": "mdi:wrench-check",
+            }),
+        ));
+    } else {
+        stale.extend(maintenance_ids);
+    }
+
+    HassEntities {
+        entities: messages,
+        stale,
+    }
+}
+
+impl HassEntities {
+    /// The entities keyed by object ID, as in a device config.
+    fn components(&self) -> serde_json::Map<String, serde_json::Value> {
+        self.entities
+            .iter()
+            .map(|(platform, object_id, config)| {
+                let mut config = config.clone();
+                config["platform"] = (*platform).into();
+                config["unique_id"] = object_id.clone().into();
+                (object_id.clone(), config)
+            })
+            .collect()
+    }
+
+    /// The entities as the API reports them: laid out as a device config, along with the
+    /// discovery prefixes they are published under.
+    fn to_json(&self, settings: &Settings) -> serde_json::Value {
+        serde_json::json!({
+            "discovery_prefixes": settings.hass_prefixes,
+            "device": hass_device(settings),
+            "origin": hass_origin(settings),
+            "components": self.components(),
+        })
+    }
+}
+
+fn hass_device(settings: &Settings) -> serde_json::Value {
+    serde_json::json!({
+        "identifiers": [format!("laing-controller_{}", settings.id)],
+        "name": settings.name,
+    })
+}
+
+fn hass_origin(settings: &Settings) -> serde_json::Value {
+    serde_json::json!({
+        "name": settings.hass_origin.name,
+        "sw_version": settings.hass_origin.sw_version,
+        "support_url": settings.hass_origin.support_url,
+    })
+}
+
+/// Build the Home Assistant MQTT discovery messages as (topic, payload) pairs.
+///
+/// Each config names laing-controller as its origin, which Home Assistant shows in its logs and
+/// diagnostics. With `hass_discovery: Device`, the configs are combined into a single message for
+/// the desk, and the per-entity configs are cleared in case they were published before; otherwise
+/// the device config is cleared.
+fn discovery_messages(
+    settings: &Settings,
+    hass_prefix: &str,
+    entities: &HassEntities,
+) -> Vec<(String, String)> {
+    let legacy = settings.hass_schema == HassSchema::Legacy;
+    let device = settings.hass_discovery == HassDiscovery::Device;
+    if legacy && device {
+        warn!("hass_discovery: Device needs hass_schema: Current; publishing a config for each entity");
+    }
+    let entity_topic = |platform: &str, object_id: &str| {
+        format!("{}/{}/{}/config", hass_prefix, platform, object_id)
+    };
+    // Topics that another layout or schema may have published to before, so nothing lingers.
+    let mut cleared: Vec<_> = entities
+        .stale
+        .iter()
+        .map(|(platform, object_id)| entity_topic(platform, object_id))
+        .collect();
+    let device_topic = format!("{}/device/{}/config", hass_prefix, settings.id);
+    let mut published = Vec::new();
+    if device && !legacy {
+        cleared.extend(
+            entities
+                .entities
+                .iter()
+                .map(|(platform, object_id, _)| entity_topic(platform, object_id)),
+        );
+        let config = serde_json::json!({
+            "device": hass_device(settings),
+            "origin": hass_origin(settings),
+            "components": entities.components(),
+        });
+        published.push((device_topic, serde_json::to_string(&config).unwrap()));
+    } else {
+        for (platform, object_id, config) in &entities.entities {
+            let mut config = config.clone();
+            // Older releases reject configs with keys they do not know.
+            if !legacy {
+                config["origin"] = hass_origin(settings);
+            }
+            published.push((
+                entity_topic(platform, object_id),
+                serde_json::to_string(&config).unwrap(),
+            ));
+        }
+        if !legacy {
+            cleared.push(device_topic);
+        }
+    }
+    published.extend(cleared.into_iter().map(|topic| (topic, String::new())));
+    published
+}
@@ -0,0 +1,135 @@
+use laing_protocol::display::Condition;
+
+use crate::settings::Settings;
+
+/// A Home Assistant entity that needs a display name.
+#[derive(Clone, Copy)]
+pub enum Entity {
+    Connected,
+    Height,
+    Refresh,
+    Preset(u8),
+    Sleep,
+    Speed,
+    TotalTravel,
+    Condition(Condition),
+    Error,
+    MaintenanceDue,
+    MaintenanceDone,
+}
+
+/// Built-in name templates for a locale, in the same order as the `Entity` variants.
+///
+/// `{name}` is replaced by the desk name and `{preset}` by the preset number.
+fn locale_templates(locale: &str) -> [&'static str; 13] {
+    match locale.split(&['-', '_'][..]).next().unwrap_or_default() {
+        "de" => [
+            "{name} Verbunden",
+            "{name} Höhe",
+            "{name} aktualisieren",
+            "{name} {preset}",
+            "{name} Ruhemodus",
+            "{name} Geschwindigkeit",
+            "{name} Gesamtweg",
+            "{name} Endlage",
+            "{name} Überlast",
+            "{name} Überhitzung",
+            "{name} Fehler",
+            "{name} Wartung fällig",
+            "{name} Wartung erledigt",
+        ],
+        "es" => [
+            "{name} Conectado",
+            "{name} Altura",
+            "{name} actualizar",
+            "{name} {preset}",
+            "{name} reposo",
+            "{name} Velocidad",
+            "{name} Recorrido total",
+            "{name} Fin de recorrido",
+            "{name} Sobrecarga",
+            "{name} Sobrecalentamiento",
+            "{name} Error",
+            "{name} Mantenimiento pendiente",
+            "{name} mantenimiento hecho",
+        ],
+        "fr" => [
+            "{name} Connecté",
+            "{name} Hauteur",
+            "{name} actualiser",
+            "{name} {preset}",
+            "{name} veille",
+            "{name} Vitesse",
+            "{name} Distance parcourue",
+            "{name} Fin de course",
+            "{name} Surcharge",
+            "{name} Surchauffe",
+            "{name} Erreur",
+            "{name} Entretien à faire",
+            "{name} entretien effectué",
+        ],
+        "nl" => [
+            "{name} Verbonden",
+            "{name} Hoogte",
+            "{name} vernieuwen",
+            "{name} {preset}",
+            "{name} slaapstand",
+            "{name} Snelheid",
+            "{name} Totale afstand",
+            "{name} Eindpositie",
+            "{name} Overbelasting",
+            "{name} Oververhitting",
+            "{name} Fout",
+            "{name} Onderhoud nodig",
+            "{name} onderhoud gedaan",
+        ],
+        _ => [
+            "{name} Connected",
+            "{name} Height",
+            "{name} refresh",
+            "{name} {preset}",
+            "{name} sleep",
+            "{name} Speed",
+            "{name} Total travel",
+            "{name} End of travel",
+            "{name} Overload",
+            "{name} Overheat",
+            "{name} Error",
+            "{name} Maintenance due",
+            "{name} maintenance done",
+        ],
+    }
+}
+
+/// Get the display name for an entity, preferring `entity_names` from the settings over the
+/// built-in names for `locale`.
+pub fn entity_name(settings: &Settings, entity: Entity) -> String {
+    let templates = locale_templates(&settings.locale);
+    let names = &settings.entity_names;
+    if let Entity::Preset(preset) = entity {
+        if let Some(name) = names.presets.get(&preset) {
+            return name.replace("{name}", &settings.name);
+        }
+    }
+    let (custom, template, preset) = match entity {
+        Entity::Connected => (&names.connected, templates[0], None),
+        Entity::Height => (&names.height, templates[1], None),
+        Entity::Refresh => (&names.refresh, templates[2], None),
+        Entity::Preset(preset) => (&names.preset, templates[3], Some(preset)),
+        Entity::Sleep => (&names.sleep, templates[4], None),
+        Entity::Speed => (&names.speed, templates[5], None),
+        Entity::TotalTravel => (&names.total_travel, templates[6], None),
+        Entity::Condition(Condition::EndOfTravel) => (&names.end_of_travel, templates[7], None),
+        Entity::Condition(Condition::Overload) => (&names.overload, templates[8], None),
+        Entity::Condition(Condition::Overheat) => (&names.overheat, templates[9], None),
+        Entity::Error => (&names.error, templates[10], None),
+        Entity::MaintenanceDue => (&names.maintenance_due, templates[11], None),
+        Entity::MaintenanceDone => (&names.maintenance_done, templates[12], None),
+    };
+    let template = custom.as_deref().unwrap_or(template);
+    let name = template.replace("{name}", &settings.name);
+    match preset {
+        Some(preset) => name.replace("{preset}", &preset.to_string()),
+        None => name,
+    }
+}
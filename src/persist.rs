@@ -0,0 +1,213 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::{hassio, maintenance::MaintenanceRecord};
+
+/// The layout of the state file. Increase it along with a step in `migrate` when a change to
+/// `PersistedState` cannot be read from older files with serde defaults alone.
+const STATE_VERSION: u64 = 1;
+
+/// Values learned while running, kept separately from the user-edited settings file.
+#[derive(Default, Deserialize, Serialize)]
+pub struct PersistedState {
+    #[serde(default)]
+    pub travel: TravelModel,
+    /// Heights saved by name, in tenths of an inch.
+    #[serde(default)]
+    pub scenes: BTreeMap<String, u16>,
+    /// How far the desk has moved in all, in tenths of an inch.
+    #[serde(default)]
+    pub total_travel: u64,
+    /// When the desk was last looked after.
+    #[serde(default)]
+    pub maintenance: MaintenanceRecord,
+}
+
+/// What has been observed about how the desk moves.
+#[derive(Default, Deserialize, Serialize)]
+pub struct TravelModel {
+    /// Average movement speed in tenths of an inch per second.
+    #[serde(default)]
+    pub speed: Option<f32>,
+    /// The height, in tenths of an inch, each preset was last seen to stop at.
+    #[serde(default)]
+    pub preset_heights: BTreeMap<u8, u16>,
+}
+
+impl TravelModel {
+    /// Estimate how long it will take to get from `from` to `to`.
+    pub fn estimate(&self, from: u16, to: u16) -> Option<Duration> {
+        let speed = self.speed.filter(|&speed| usable_speed(speed))?;
+        let distance = f32::from(from.abs_diff(to));
+        Duration::try_from_secs_f32(distance / speed).ok()
+    }
+
+    /// Forget a speed that could not have been learned, such as one edited into the state file.
+    fn validate(&mut self) {
+        self.speed = self.speed.filter(|&speed| usable_speed(speed));
+    }
+
+    /// Record a completed move to `preset`.
+    pub fn learn(&mut self, preset: u8, from: u16, to: u16, travel_time: Duration) {
+        self.preset_heights.insert(preset, to);
+        let distance = f32::from(from.max(to) - from.min(to));
+        // Very short moves are dominated by acceleration and polling granularity.
+        if distance < 10.0 || travel_time.is_zero() {
+            return;
+        }
+        let speed = distance / travel_time.as_secs_f32();
+        self.speed = Some(match self.speed {
+            Some(previous) => previous * 0.75 + speed * 0.25,
+            None => speed,
+        });
+    }
+}
+
+/// Whether a desk could move at `speed`. An infinite speed would estimate every move as instant.
+fn usable_speed(speed: f32) -> bool {
+    speed.is_finite() && speed > 0.0
+}
+
+fn state_path() -> Result<PathBuf> {
+    let mut path = match hassio::data_dir() {
+        Some(path) => path,
+        None => {
+            let mut path =
+                ::std::env::current_exe().context("Could not find installation directory")?;
+            path.pop();
+            path
+        }
+    };
+    path.push("laing-controller.state.json");
+    Ok(path)
+}
+
+pub fn load_state() -> Result<PersistedState> {
+    let path = state_path()?;
+    if !path.exists() {
+        return Ok(PersistedState::default());
+    }
+    let file = File::open(path).context("Failed to open state")?;
+    state_from_value(serde_json::from_reader(file).context("Failed to load state")?)
+}
+
+/// Read state in any version's layout.
+pub fn state_from_value(value: Value) -> Result<PersistedState> {
+    let mut state: PersistedState =
+        serde_json::from_value(migrate(value)?).context("Failed to load state")?;
+    state.travel.validate();
+    Ok(state)
+}
+
+/// The state in the current layout, with its version.
+pub fn state_to_value(state: &PersistedState) -> Result<Value> {
+    let mut value = serde_json::to_value(state).context("Failed to save state")?;
+    value["version"] = STATE_VERSION.into();
+    Ok(value)
+}
+
+/// Bring state saved by an earlier version up to `STATE_VERSION`.
+fn migrate(mut value: Value) -> Result<Value> {
+    // Version 0 had no version field and is otherwise the same as version 1.
+    let version = value.get("version").and_then(Value::as_u64).unwrap_or(0);
+    if version > STATE_VERSION {
+        // Loading it anyway would lose whatever the newer version added when it is next saved.
+        bail!(
+            "The state was saved by a newer version of laing-controller (state version {})",
+            version
+        );
+    }
+    value["version"] = STATE_VERSION.into();
+    Ok(value)
+}
+
+/// Save the state, replacing the file only once the new one is complete, so a crash or a full disk
+/// leaves the previous state rather than half of a file.
+pub fn save_state(state: &PersistedState) -> Result<()> {
+    let path = state_path()?;
+    let value = state_to_value(state)?;
+    let temporary = path.with_extension("json.tmp");
+    let mut file = File::create(&temporary).context("Failed to create state")?;
+    serde_json::to_writer_pretty(&mut file, &value).context("Failed to save state")?;
+    file.sync_all().context("Failed to save state")?;
+    fs::rename(&temporary, &path).context("Failed to replace state")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::{state_from_value, state_to_value, TravelModel, STATE_VERSION};
+
+    const UNUSABLE_SPEEDS: [f32; 5] = [0.0, -12.5, f32::NAN, f32::INFINITY, f32::NEG_INFINITY];
+
+    fn model(speed: f32) -> TravelModel {
+        TravelModel {
+            speed: Some(speed),
+            ..TravelModel::default()
+        }
+    }
+
+    #[test]
+    fn estimates_need_a_usable_speed() {
+        assert_eq!(
+            model(10.0).estimate(280, 430),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            model(10.0).estimate(430, 280),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(TravelModel::default().estimate(280, 430), None);
+        for speed in UNUSABLE_SPEEDS {
+            assert_eq!(model(speed).estimate(280, 430), None, "speed {}", speed);
+        }
+    }
+
+    #[test]
+    fn unusable_speeds_are_forgotten() {
+        for speed in UNUSABLE_SPEEDS {
+            let mut model = model(speed);
+            model.validate();
+            assert_eq!(model.speed, None, "speed {}", speed);
+        }
+        let mut model = model(12.5);
+        model.validate();
+        assert_eq!(model.speed, Some(12.5));
+    }
+
+    #[test]
+    fn unversioned_state_is_migrated() {
+        // As saved before the state file had a version.
+        let state = state_from_value(json!({
+            "travel": {"speed": 12.5, "preset_heights": {"1": 280, "2": 430}},
+            "scenes": {"standing": 430},
+        }))
+        .unwrap();
+        assert_eq!(state.travel.speed, Some(12.5));
+        assert_eq!(state.travel.preset_heights[&2], 430);
+        assert_eq!(state.scenes["standing"], 430);
+        assert_eq!(state.total_travel, 0);
+        assert_eq!(state_to_value(&state).unwrap()["version"], STATE_VERSION);
+
+        // Speeds edited into old files are checked too.
+        let state = state_from_value(json!({"travel": {"speed": -1.0}})).unwrap();
+        assert_eq!(state.travel.speed, None);
+    }
+
+    #[test]
+    fn state_from_a_newer_version_is_refused() {
+        let err = match state_from_value(json!({"version": STATE_VERSION + 1})) {
+            Ok(_) => panic!("loaded state from a newer version"),
+            Err(err) => err,
+        };
+        assert!(err.to_string().contains("newer version"), "{}", err);
+    }
+}
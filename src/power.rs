@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use log::info;
+
+use crate::{mqtt::MqttHandle, settings::PowerRelaySettings};
+
+/// Switches the controller's power so it does not draw standby power while the desk is not used.
+pub struct PowerRelay<'a> {
+    settings: Option<&'a PowerRelaySettings>,
+    on: bool,
+}
+
+impl<'a> PowerRelay<'a> {
+    pub fn new(settings: Option<&'a PowerRelaySettings>) -> Self {
+        Self { settings, on: true }
+    }
+
+    /// How long the controller may sit idle before its power is cut, if it is currently on.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        match self.settings {
+            Some(settings) if self.on => Some(Duration::from_secs(settings.idle_off_secs)),
+            _ => None,
+        }
+    }
+
+    /// Whether the controller is believed to have power.
+    pub fn is_on(&self) -> bool {
+        self.on
+    }
+
+    /// Turn the power on, waiting for the controller to start, if it is not already on.
+    pub async fn ensure_on(&mut self, mqtt: &mut MqttHandle) -> Result<()> {
+        self.power_on(mqtt, false).await
+    }
+
+    /// Turn the power on, waiting for the controller to start, even if it is believed to be on.
+    pub async fn force_on(&mut self, mqtt: &mut MqttHandle) -> Result<()> {
+        self.power_on(mqtt, true).await
+    }
+
+    async fn power_on(&mut self, mqtt: &mut MqttHandle, force: bool) -> Result<()> {
+        if let Some(settings) = self.settings {
+            if force || !self.on {
+                info!("Turning controller power on");
+                self.set(settings, true, mqtt)?;
+                tokio::time::sleep(Duration::from_secs(settings.power_on_delay_secs)).await;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn turn_off(&mut self, mqtt: &mut MqttHandle) -> Result<()> {
+        if let Some(settings) = self.settings {
+            if self.on {
+                info!("Turning controller power off after being idle");
+                self.set(settings, false, mqtt)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set(
+        &mut self,
+        settings: &PowerRelaySettings,
+        on: bool,
+        mqtt: &mut MqttHandle,
+    ) -> Result<()> {
+        if let Some(gpio) = &settings.gpio {
+            let value = if on != gpio.active_low { "1" } else { "0" };
+            std::fs::write(&gpio.path, value).context("Failed to switch power relay")?;
+        }
+        if settings.mqtt.is_some() {
+            mqtt.request_power(on);
+        }
+        self.on = on;
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+//! Whether someone is using the computer, to avoid moving an empty desk.
+
+use std::time::Duration;
+
+/// How long the computer has had no keyboard or mouse input, or `None` if that is unknown.
+///
+/// On Windows this only sees the session the program runs in, so it has to be run in user mode
+/// rather than as a service. On Linux the idle hints of every logind session are used, and the
+/// computer only counts as idle if every session is.
+pub async fn idle_time() -> Option<Duration> {
+    tokio::task::spawn_blocking(platform::idle_time)
+        .await
+        .ok()
+        .flatten()
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::time::Duration;
+
+    use winapi::um::{
+        sysinfoapi::GetTickCount,
+        winuser::{GetLastInputInfo, LASTINPUTINFO},
+    };
+
+    pub fn idle_time() -> Option<Duration> {
+        let mut info = LASTINPUTINFO {
+            cbSize: std::mem::size_of::<LASTINPUTINFO>() as u32,
+            dwTime: 0,
+        };
+        // The tick count wraps around every 49.7 days, and the subtraction wraps with it.
+        unsafe {
+            if GetLastInputInfo(&mut info) == 0 {
+                return None;
+            }
+            Some(Duration::from_millis(u64::from(
+                GetTickCount().wrapping_sub(info.dwTime),
+            )))
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::{
+        process::Command,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use log::debug;
+
+    pub fn idle_time() -> Option<Duration> {
+        let sessions = loginctl(&["list-sessions", "--no-legend"])?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut idle = None;
+        for session in sessions
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+        {
+            let properties = loginctl(&[
+                "show-session",
+                session,
+                "--property=IdleHint",
+                "--property=IdleSinceHint",
+            ])?;
+            let mut hint = false;
+            let mut since = None;
+            for line in properties.lines() {
+                match line.split_once('=') {
+                    Some(("IdleHint", value)) => hint = value == "yes",
+                    Some(("IdleSinceHint", value)) => since = value.parse::<u64>().ok(),
+                    _ => {}
+                }
+            }
+            let session_idle = match (hint, since) {
+                (true, Some(since)) => now.saturating_sub(Duration::from_micros(since)),
+                _ => Duration::ZERO,
+            };
+            idle = Some(idle.map_or(session_idle, |idle: Duration| idle.min(session_idle)));
+        }
+        // Nobody logged in says nothing about whether the desk is in use if this is not the
+        // computer at the desk.
+        idle
+    }
+
+    fn loginctl(args: &[&str]) -> Option<String> {
+        match Command::new("loginctl").args(args).output() {
+            Ok(output) if output.status.success() => String::from_utf8(output.stdout).ok(),
+            Ok(output) => {
+                debug!(
+                    "loginctl failed: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                );
+                None
+            }
+            Err(err) => {
+                debug!("Failed to run loginctl: {}", err);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+mod platform {
+    use std::time::Duration;
+
+    pub fn idle_time() -> Option<Duration> {
+        None
+    }
+}
@@ -0,0 +1,59 @@
+use std::{fmt, io};
+
+pub use laing_protocol::rtu::Exception;
+
+/// Why an exchange with the controller failed.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The controller answered with an exception instead of data.
+    Exception(Exception, String),
+    /// The controller did not answer in time.
+    Timeout,
+    Io(io::Error),
+}
+
+impl ProtocolError {
+    /// Classify an error from tokio-modbus.
+    ///
+    /// tokio-modbus reports exception responses as `io::Error`s carrying only a description, so
+    /// that is what is matched on.
+    pub fn classify(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::TimedOut {
+            return ProtocolError::Timeout;
+        }
+        let message = err.to_string();
+        let lower = message.to_ascii_lowercase();
+        let exception = if lower.contains("illegal function") {
+            Exception::IllegalFunction
+        } else if lower.contains("illegal data address") {
+            Exception::IllegalDataAddress
+        } else if lower.contains("illegal data value") {
+            Exception::IllegalDataValue
+        } else if lower.contains("server device failure") {
+            Exception::ServerDeviceFailure
+        } else if lower.contains("server device busy") {
+            Exception::ServerDeviceBusy
+        } else if lower.contains("acknowledge") {
+            Exception::Acknowledge
+        } else if lower.contains("exception") {
+            Exception::Other
+        } else {
+            return ProtocolError::Io(err);
+        };
+        ProtocolError::Exception(exception, message)
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Exception(exception, message) => {
+                write!(f, "controller reported {:?}: {}", exception, message)
+            }
+            ProtocolError::Timeout => write!(f, "controller did not respond in time"),
+            ProtocolError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
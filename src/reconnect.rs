@@ -0,0 +1,97 @@
+//! Spacing out attempts to reconnect to the broker, so a fleet of desks that lost the broker at the
+//! same moment does not come back to it all at once.
+
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+/// How long to wait between failed attempts to connect.
+///
+/// The delay doubles with each failure in a row, up to a limit, and a random amount up to `jitter`
+/// is added so desks that failed together try again at different times.
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    jitter: Duration,
+    failures: u32,
+    rng: u64,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration, jitter: Duration) -> Self {
+        // Seeded differently by each process, unlike the time, which desks restarted together share.
+        let seed = RandomState::new().build_hasher().finish();
+        Self::with_seed(min, max, jitter, seed)
+    }
+
+    pub fn with_seed(min: Duration, max: Duration, jitter: Duration, seed: u64) -> Self {
+        Self {
+            min,
+            max: max.max(min),
+            jitter,
+            failures: 0,
+            // xorshift gets stuck at zero.
+            rng: seed | 1,
+        }
+    }
+
+    /// How long after the start of an attempt that failed to make the next one.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .min
+            .checked_mul(1 << self.failures.min(16))
+            .map_or(self.max, |delay| delay.min(self.max));
+        self.failures = self.failures.saturating_add(1);
+        let jitter = self.jitter.as_millis() as u64;
+        if jitter == 0 {
+            delay
+        } else {
+            delay + Duration::from_millis(next(&mut self.rng) % (jitter + 1))
+        }
+    }
+
+    /// Start again from the shortest delay once connected.
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/// The next number from an xorshift64* generator.
+fn next(rng: &mut u64) -> u64 {
+    *rng ^= *rng >> 12;
+    *rng ^= *rng << 25;
+    *rng ^= *rng >> 27;
+    rng.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
+
+/// Limits how many connection attempts are made in any period, however the MQTT connection is
+/// restarted.
+#[derive(Default)]
+pub struct RateLimiter {
+    /// When the attempts within the last period were allowed to start.
+    attempts: VecDeque<Instant>,
+}
+
+impl RateLimiter {
+    /// Reserve an attempt, returning how long after `now` it may start to keep within `limit`
+    /// attempts per `period`.
+    pub fn reserve(&mut self, limit: u32, period: Duration, now: Instant) -> Duration {
+        while self
+            .attempts
+            .front()
+            .map_or(false, |&attempt| attempt + period <= now)
+        {
+            self.attempts.pop_front();
+        }
+        let start = if limit == 0 || self.attempts.len() < limit as usize {
+            now
+        } else {
+            // Wait for the oldest attempt that still counts to leave the period.
+            let start = self.attempts[self.attempts.len() - limit as usize] + period;
+            start.max(now)
+        };
+        self.attempts.push_back(start);
+        start - now
+    }
+}
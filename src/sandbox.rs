@@ -0,0 +1,108 @@
+//! Restricting filesystem access on Linux with Landlock.
+//!
+//! Once applied, the process can only read system directories (for certificates, name resolution,
+//! and shared libraries) and write the files it is configured to use. This cannot be undone, so
+//! paths added to the settings file are only usable after restarting.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use landlock::{
+    Access, AccessFs, PathBeneath, PathFd, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+    ABI,
+};
+use log::{info, warn};
+
+use crate::{hassio, settings::Settings};
+
+/// Directories that are only read, for TLS certificates, name resolution, and shared libraries.
+const READ_ONLY: &[&str] = &["/etc", "/usr", "/lib", "/lib64"];
+
+pub fn restrict(settings: &Settings) -> Result<()> {
+    let abi = ABI::V1;
+    // Devices are files, which cannot be given directory access rights.
+    let mut devices = vec![PathBuf::from(&settings.serial_port)];
+    let mut read_write = vec![install_dir()?];
+    read_write.extend(hassio::data_dir());
+    read_write.extend(
+        settings
+            .capture_file
+            .iter()
+            .chain(&settings.replay_file)
+            .chain(&settings.audit_log)
+            .chain(settings.history.as_ref().map(|history| &history.path))
+            .map(|path| parent(Path::new(path))),
+    );
+    // The API certificate may need writing once, if it is generated.
+    if let Some(tls) = settings.api.as_ref().and_then(|api| api.tls.as_ref()) {
+        read_write.push(parent(&tls.certificate_file));
+        read_write.push(parent(&tls.key_file));
+    }
+    // Credential files are usually replaced rather than rewritten, so allow their directories.
+    let credential_dirs: Vec<_> = settings
+        .mqtt
+        .credential_files()
+        .into_iter()
+        .map(parent)
+        .collect();
+    if let Some(gpio) = settings
+        .power_relay
+        .as_ref()
+        .and_then(|relay| relay.gpio.as_ref())
+    {
+        devices.push(PathBuf::from(&gpio.path));
+    }
+
+    let mut ruleset = Ruleset::new()
+        .handle_access(AccessFs::from_all(abi))?
+        .create()?;
+    for path in READ_ONLY {
+        // Not every distribution has every directory.
+        if let Ok(fd) = PathFd::new(path) {
+            ruleset = ruleset.add_rule(PathBeneath::new(fd, AccessFs::from_read(abi)))?;
+        }
+    }
+    for path in &credential_dirs {
+        let fd = PathFd::new(path)
+            .with_context(|| format!("Failed to open {} for sandboxing", path.display()))?;
+        ruleset = ruleset.add_rule(PathBeneath::new(fd, AccessFs::from_read(abi)))?;
+    }
+    for path in &read_write {
+        let fd = PathFd::new(path)
+            .with_context(|| format!("Failed to open {} for sandboxing", path.display()))?;
+        ruleset = ruleset.add_rule(PathBeneath::new(fd, AccessFs::from_all(abi)))?;
+    }
+    for path in &devices {
+        let fd = PathFd::new(path)
+            .with_context(|| format!("Failed to open {} for sandboxing", path.display()))?;
+        ruleset = ruleset.add_rule(PathBeneath::new(
+            fd,
+            AccessFs::ReadFile | AccessFs::WriteFile,
+        ))?;
+    }
+
+    let status = ruleset.restrict_self().context("Failed to apply sandbox")?;
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => info!("Filesystem access is restricted"),
+        RulesetStatus::PartiallyEnforced => {
+            warn!("Filesystem access is only partially restricted by this kernel")
+        }
+        RulesetStatus::NotEnforced => warn!("This kernel does not support Landlock"),
+    }
+    Ok(())
+}
+
+/// Where the settings and state files are kept.
+fn install_dir() -> Result<PathBuf> {
+    let mut path = std::env::current_exe().context("Could not find installation directory")?;
+    path.pop();
+    Ok(path)
+}
+
+/// The directory containing a file, which also has to be writable for SQLite's journal.
+fn parent(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
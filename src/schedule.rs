@@ -0,0 +1,77 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{Local, NaiveTime};
+use log::{info, warn};
+
+use crate::{
+    events::DeskEvent,
+    mqtt::{Command, Source, State},
+    presence,
+    settings::ScheduleEntry,
+    timetable::{SkewCheck, Timetable},
+};
+
+/// Send commands at times of day.
+///
+/// Waiting is done with monotonic timers so jumps in the wall clock (for example when Windows
+/// resumes from sleep) cannot make the timers misbehave. The wall clock is only consulted when
+/// waking, at least every `RESYNC`, to decide what is due. Jumps are reported as the
+/// `clock_skew_detected` diagnostic.
+///
+/// Movements are rejected as `idle` instead of sent if the computer has had no input for longer
+/// than `idle_limit`.
+pub async fn schedule_loop(
+    entries: &[ScheduleEntry],
+    idle_limit: Option<Duration>,
+    state: State,
+) -> Result<()> {
+    let entries = entries
+        .iter()
+        .map(|entry| {
+            let time = NaiveTime::parse_from_str(&entry.time, "%H:%M")
+                .with_context(|| format!("Invalid schedule time {:?}", entry.time))?;
+            let command = Command::parse(entry.command.as_bytes())
+                .ok_or_else(|| anyhow!("Invalid schedule command {:?}", entry.command))?;
+            Ok((time, command))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut timetable = Timetable::new(entries);
+
+    loop {
+        let now = Local::now().naive_local();
+        for command in timetable.due(now) {
+            if let Some(limit) = idle_limit.filter(|_| command.is_movement()) {
+                if let Some(idle) = presence::idle_time().await.filter(|&idle| idle > limit) {
+                    info!(
+                        "Skipping scheduled {:?} because the computer has been idle for {} minutes",
+                        command,
+                        idle.as_secs() / 60
+                    );
+                    state.events.send(DeskEvent::Rejected {
+                        command,
+                        reason: "idle",
+                        other: None,
+                    });
+                    continue;
+                }
+            }
+            info!("Scheduled command {:?}", command);
+            state
+                .command
+                .send((command, Source::Schedule))
+                .context("failed to accept command")?;
+        }
+
+        let skew_check = SkewCheck::start();
+        tokio::time::sleep(timetable.delay(now)).await;
+        if let Some(skew) = skew_check.finish() {
+            warn!("The wall clock jumped by {:.0} seconds", skew);
+            state
+                .diagnostics
+                .clock_skew_detected
+                .store(true, Ordering::Relaxed);
+            state.events.send(DeskEvent::ClockSkew { seconds: skew });
+        }
+    }
+}
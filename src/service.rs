@@ -0,0 +1,338 @@
+//! Running as a Windows service or in the user's session, and logging to the event log.
+//!
+//! This is the `service` feature. Without it, or on other platforms, laing-controller only runs in
+//! the foreground.
+
+use std::{
+    error::Error,
+    ffi::OsStr,
+    process::Command,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use log::error;
+use tokio_util::sync::CancellationToken;
+use windows_service::{
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+use crate::{settings, user};
+
+const SERVICE_NAME: &str = "laing-controller";
+const FIREWALL_RULE: &str = "laing-controller";
+
+/// Windows' code for a service that has not been registered.
+const ERROR_SERVICE_DOES_NOT_EXIST: i32 = 1060;
+/// Windows' code for starting a service that is already running.
+const ERROR_SERVICE_ALREADY_RUNNING: i32 = 1056;
+/// Windows' code for stopping a service that is not running.
+const ERROR_SERVICE_NOT_ACTIVE: i32 = 1062;
+
+/// Written by `install` when there are no settings yet. Its placeholders, such as the serial port
+/// and broker, have to be replaced before the service can do anything.
+const DEFAULT_SETTINGS: &str = include_str!("../laing-controller.yaml");
+
+/// Run one of the command line parameters for installing and running as a service, or return
+/// `None` if `parameter` is not one of them.
+pub fn command(parameter: &str) -> Option<Result<(), Box<dyn Error>>> {
+    let command: fn() -> Result<(), Box<dyn Error>> = match parameter {
+        "service-register" => register,
+        "service-deregister" => deregister,
+        "user-register" => || Ok(user::register()?),
+        "user-deregister" => || Ok(user::deregister()?),
+        "--user-mode" => || Ok(user::run()?),
+        "log-register" => || Ok(eventlog::register(SERVICE_NAME)?),
+        "log-deregister" => || Ok(eventlog::deregister(SERVICE_NAME)?),
+        "service" => run,
+        "install" => install,
+        "uninstall" => uninstall,
+        _ => return None,
+    };
+    Some(command())
+}
+
+/// The arguments the service is started with, from `--log-level <level>` on the command line.
+fn launch_arguments(command: &str) -> anyhow::Result<Vec<std::ffi::OsString>> {
+    let mut launch_arguments = vec!["service".into()];
+    match std::env::args().skip(2).collect::<Vec<_>>().as_slice() {
+        [] => {}
+        [flag, level] if flag == "--log-level" && level.parse::<log::Level>().is_ok() => {
+            launch_arguments.extend(["--log-level".into(), level.into()]);
+        }
+        _ => bail!("Usage: laing-controller {} [--log-level <level>]", command),
+    }
+    Ok(launch_arguments)
+}
+
+fn register() -> Result<(), Box<dyn Error>> {
+    let launch_arguments = launch_arguments("service-register")?;
+    let manager =
+        ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CREATE_SERVICE)?;
+    create_service(&manager, launch_arguments)?;
+    Ok(())
+}
+
+fn create_service(
+    manager: &ServiceManager,
+    launch_arguments: Vec<std::ffi::OsString>,
+) -> Result<(), Box<dyn Error>> {
+    manager.create_service(
+        &ServiceInfo {
+            name: SERVICE_NAME.into(),
+            display_name: "Laing Controller".into(),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: std::env::current_exe()?,
+            launch_arguments,
+            dependencies: vec![],
+            account_name: None,
+            account_password: None,
+        },
+        ServiceAccess::QUERY_STATUS,
+    )?;
+    Ok(())
+}
+
+fn deregister() -> Result<(), Box<dyn Error>> {
+    let manager =
+        ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CREATE_SERVICE)?;
+    let service = manager.open_service(SERVICE_NAME, ServiceAccess::DELETE)?;
+    service.delete()?;
+    Ok(())
+}
+
+/// Whether a service manager call failed with the Windows error `code`.
+fn is_windows_error(err: &windows_service::Error, code: i32) -> bool {
+    matches!(err, windows_service::Error::Winapi(err) if err.raw_os_error() == Some(code))
+}
+
+/// Set everything up without asking anything, for package managers.
+///
+/// This registers the event log source and the service, writes the example settings if there are
+/// none, and allows the executable through the firewall so the API can be reached if it is bound
+/// to another address. Running it again, such as when upgrading, leaves what is already there. The
+/// service is started only if the settings already existed, since the example's placeholders
+/// have to be filled in first.
+fn install() -> Result<(), Box<dyn Error>> {
+    let launch_arguments = launch_arguments("install")?;
+
+    eventlog::register(SERVICE_NAME)?;
+
+    let settings_path = settings::settings_path()?;
+    let configured = settings_path.exists();
+    if !configured {
+        std::fs::write(&settings_path, DEFAULT_SETTINGS).context("Failed to write settings")?;
+        println!(
+            "Wrote example settings to {}; fill them in and start the service",
+            settings_path.display()
+        );
+    }
+
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+    match manager.open_service(SERVICE_NAME, ServiceAccess::QUERY_STATUS) {
+        Ok(_) => println!("The service is already registered"),
+        Err(err) if is_windows_error(&err, ERROR_SERVICE_DOES_NOT_EXIST) => {
+            create_service(&manager, launch_arguments)?
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    // Replace any rule from an earlier install, which may name an executable that has moved.
+    delete_firewall_rule()?;
+    let output = netsh(&[
+        "add",
+        "rule",
+        &format!("name={}", FIREWALL_RULE),
+        "dir=in",
+        "action=allow",
+        "protocol=TCP",
+        &format!("program={}", std::env::current_exe()?.display()),
+        "enable=yes",
+    ])?;
+    if !output.status.success() {
+        bail!(
+            "Failed to add the firewall rule: {}",
+            String::from_utf8_lossy(&output.stdout).trim()
+        );
+    }
+
+    if configured {
+        let service = manager.open_service(SERVICE_NAME, ServiceAccess::START)?;
+        match service.start(&[] as &[&OsStr]) {
+            Ok(()) => println!("Started the service"),
+            Err(err) if is_windows_error(&err, ERROR_SERVICE_ALREADY_RUNNING) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Undo `install`, leaving the settings and learned state in place.
+///
+/// Parts that are already gone are skipped, so this can be run more than once.
+fn uninstall() -> Result<(), Box<dyn Error>> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    match manager.open_service(
+        SERVICE_NAME,
+        ServiceAccess::STOP | ServiceAccess::QUERY_STATUS | ServiceAccess::DELETE,
+    ) {
+        Ok(service) => {
+            match service.stop() {
+                Ok(_) => {
+                    // The desk may be moving, so give it the time the service asks for to stop.
+                    for _ in 0..20 {
+                        if service.query_status()?.current_state
+                            == windows_service::service::ServiceState::Stopped
+                        {
+                            break;
+                        }
+                        std::thread::sleep(Duration::from_millis(500));
+                    }
+                }
+                Err(err) if is_windows_error(&err, ERROR_SERVICE_NOT_ACTIVE) => {}
+                Err(err) => return Err(err.into()),
+            }
+            service.delete()?;
+        }
+        Err(err) if is_windows_error(&err, ERROR_SERVICE_DOES_NOT_EXIST) => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    delete_firewall_rule()?;
+    if let Err(err) = eventlog::deregister(SERVICE_NAME) {
+        println!("The event log source was not removed: {:?}", err);
+    }
+    Ok(())
+}
+
+fn delete_firewall_rule() -> anyhow::Result<()> {
+    // netsh fails when there is no rule to delete, which is fine.
+    netsh(&["delete", "rule", &format!("name={}", FIREWALL_RULE)]).map(|_| ())
+}
+
+/// Run `netsh advfirewall firewall` with `args`.
+fn netsh(args: &[&str]) -> anyhow::Result<std::process::Output> {
+    Command::new("netsh")
+        .args(["advfirewall", "firewall"])
+        .args(args)
+        .output()
+        .context("Failed to run netsh")
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    // A level given when the service was registered, then the environment, then the settings
+    // file.
+    let argument = match std::env::args().skip(2).collect::<Vec<_>>().as_slice() {
+        [flag, level] if flag == "--log-level" => Some(level.clone()),
+        _ => None,
+    };
+    let level = argument
+        .or_else(|| std::env::var("LC_LOG_LEVEL").ok())
+        .or_else(settings::log_level)
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(log::Level::Info);
+    eventlog::init("laing-controller", level).unwrap();
+
+    service_dispatcher::start("laing-controller", ffi_service_main)?;
+    Ok(())
+}
+
+windows_service::define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<std::ffi::OsString>) {
+    if let Err(err) = real_service_main() {
+        error!("Service failed: {:?}", err);
+        std::process::exit(1);
+    }
+}
+
+fn real_service_main() -> anyhow::Result<()> {
+    let stop = CancellationToken::new();
+    let status_handle = Arc::new(Mutex::new(Option::<ServiceStatusHandle>::None));
+
+    let main = {
+        let mut lock = status_handle.lock().unwrap();
+        let status_handle = status_handle.clone();
+        let service_stop = stop.clone();
+        *lock = Some(
+            service_control_handler::register("laing-controller", move |control_event| {
+                match control_event {
+                    ServiceControl::Shutdown | ServiceControl::Stop => {
+                        if !service_stop.is_cancelled() {
+                            service_stop.cancel();
+                            status_handle
+                                .lock()
+                                .unwrap()
+                                .unwrap()
+                                .set_service_status(ServiceStatus {
+                                    controls_accepted: ServiceControlAccept::empty(),
+                                    current_state:
+                                        windows_service::service::ServiceState::StopPending,
+                                    service_type: ServiceType::OWN_PROCESS,
+                                    exit_code: ServiceExitCode::NO_ERROR,
+                                    checkpoint: 0,
+                                    process_id: Some(std::process::id()),
+                                    // Give us some time to stop in case the desk is in motion.
+                                    wait_hint: Duration::from_secs(10),
+                                })
+                                .unwrap();
+                        }
+                        ServiceControlHandlerResult::NoError
+                    }
+                    ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+                    _ => ServiceControlHandlerResult::NotImplemented,
+                }
+            })
+            .map_err(|e| anyhow!("Failed to register service: {:?}", e))?,
+        );
+
+        let main = crate::Main::init()?;
+
+        lock.unwrap()
+            .set_service_status(ServiceStatus {
+                controls_accepted: ServiceControlAccept::SHUTDOWN | ServiceControlAccept::STOP,
+                current_state: windows_service::service::ServiceState::Running,
+                service_type: ServiceType::OWN_PROCESS,
+                exit_code: ServiceExitCode::NO_ERROR,
+                checkpoint: 0,
+                process_id: Some(std::process::id()),
+                wait_hint: Duration::ZERO,
+            })
+            .map_err(|e| anyhow!("Failed to set service to running: {:?}", e))?;
+        main
+    };
+
+    let result = crate::run_until_stopped(main, stop);
+    let lock = status_handle.lock().unwrap();
+    let code = if let Err(error) = result {
+        error!("Service died: {:?}", error);
+        ServiceExitCode::ServiceSpecific(1)
+    } else {
+        ServiceExitCode::NO_ERROR
+    };
+    lock.unwrap()
+        .set_service_status(ServiceStatus {
+            controls_accepted: ServiceControlAccept::empty(),
+            current_state: windows_service::service::ServiceState::Stopped,
+            service_type: ServiceType::OWN_PROCESS,
+            exit_code: code,
+            checkpoint: 0,
+            process_id: Some(std::process::id()),
+            wait_hint: Duration::ZERO,
+        })
+        .map_err(|e| anyhow!("Failed to set service to stopped: {:?}", e))?;
+
+    Ok(())
+}
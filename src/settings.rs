@@ -1,6 +1,18 @@
 use anyhow::{Context, Result};
-use serde::Deserialize;
+use laing_protocol::display::{Bcd, Condition, Decoder, SevenSegmentBe, SevenSegmentLe};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use std::collections::BTreeMap;
 use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[cfg(feature = "chaos")]
+use crate::transport::chaos::Faults;
+use crate::{
+    error::{self, Error},
+    hassio,
+};
 
 #[derive(Deserialize)]
 pub struct Settings {
@@ -9,9 +21,413 @@ pub struct Settings {
     pub name: String,
     #[serde(default = "default_prefix")]
     pub prefix: String,
-    #[serde(default = "default_hass_prefix")]
-    pub hass_prefix: String,
+    /// Where discovery is published for each Home Assistant instance, written as one prefix or a
+    /// list.
+    #[serde(
+        rename = "hass_prefix",
+        default = "default_hass_prefixes",
+        deserialize_with = "deserialize_hass_prefixes"
+    )]
+    pub hass_prefixes: Vec<String>,
+    #[serde(default)]
+    pub hass_origin: HassOriginSettings,
+    #[serde(default)]
+    pub hass_discovery: HassDiscovery,
+    #[serde(default)]
+    pub hass_schema: HassSchema,
+    /// The level to log at: `error`, `warn`, `info`, `debug`, or `trace`. Read before anything
+    /// else by `log_level`, so only a restart changes it.
+    #[serde(default)]
+    pub log_level: Option<String>,
+    /// Record all serial traffic to this file.
+    #[serde(default)]
+    pub capture_file: Option<String>,
+    /// Play this capture back in place of the serial port.
+    #[serde(default)]
+    pub replay_file: Option<String>,
+    /// Append what became of each movement command to this file, with each entry chained to the
+    /// last by its hash.
+    #[serde(default)]
+    pub audit_log: Option<String>,
+    /// Damage serial traffic on purpose.
+    #[cfg(feature = "chaos")]
+    #[serde(default)]
+    pub chaos: Faults,
+    /// Have Home Assistant consider the height unknown if it has not been updated for this long.
+    #[serde(default)]
+    pub height_expire_after_secs: Option<u64>,
+    #[serde(default)]
+    pub height_filter: HeightFilterSettings,
+    /// Heights read outside this range, in inches, are taken to be corrupted and ignored.
+    #[serde(default = "default_min_possible_height")]
+    pub min_possible_height: f32,
+    #[serde(default = "default_max_possible_height")]
+    pub max_possible_height: f32,
+    /// Measured heights for some of the heights the controller reports, to correct the rest by.
+    #[serde(default)]
+    pub height_correction: Vec<HeightCorrectionPoint>,
+    #[serde(default)]
+    pub display_encoding: DisplayEncoding,
+    /// The first of the two state registers holding the display.
+    #[serde(default)]
+    pub display_offset: usize,
+    /// Look through all of the state registers for the display instead of using `display_offset`.
+    #[serde(default)]
+    pub scan_display_offset: bool,
+    /// The display codes that mean the desk cannot move.
+    #[serde(default)]
+    pub display_codes: DisplayCodes,
+    /// The first of four holding registers where the controller stores the heights of presets 1
+    /// to 4, in tenths of an inch, on controllers where they have been found.
+    #[serde(default)]
+    pub preset_height_registers: Option<u16>,
+    #[serde(default = "default_locale")]
+    pub locale: String,
+    #[serde(default)]
+    pub entity_names: EntityNames,
+    /// Other payloads to accept on the command topic, mapped to the commands they stand for.
+    #[serde(default)]
+    pub command_aliases: BTreeMap<String, String>,
+    #[serde(default = "default_operation_timeout_secs")]
+    pub operation_timeout_secs: u64,
+    /// The silence to leave between frames, instead of the one Modbus specifies for the baud rate.
+    #[serde(default)]
+    pub inter_frame_gap_us: Option<u64>,
+    /// Wait for the line to be quiet for this long after hearing another master before sending.
+    #[serde(default)]
+    pub shared_bus_quiet_ms: Option<u64>,
+    /// Toggle the serial adapter's control lines before waking the controller after a while.
+    #[serde(default)]
+    pub wake_pulse: Option<WakePulseSettings>,
+    /// Move in steps with pauses between them, for a slower average speed.
+    #[serde(default)]
+    pub gentle_movement: Option<GentleMovementSettings>,
+    #[serde(default)]
+    pub register_access: RegisterAccess,
+    /// The time to wait between writing and reading when they are separate requests.
+    #[serde(default = "default_separate_access_gap_ms")]
+    pub separate_access_gap_ms: u64,
+    /// Visit presets whose heights are not known yet when starting.
+    #[serde(default)]
+    pub calibrate_missing_presets: bool,
+    #[serde(default)]
+    pub offline_commands: OfflineCommands,
+    #[serde(default = "default_offline_command_max_age_secs")]
+    pub offline_command_max_age_secs: u64,
+    /// Mark the desk unavailable if the controller has not answered for this long, polling it
+    /// while idle to find out.
+    #[serde(default)]
+    pub availability_timeout_secs: Option<u64>,
+    /// While idle, read the height this often without waking the display, so moves made with the
+    /// handset are noticed.
+    #[serde(default)]
+    pub listen_while_idle_secs: Option<u64>,
+    /// Send the idle message with the handset's activity flag cleared, for SLEEP and for listening
+    /// while idle. No capture of a handset has confirmed that this is what it sends.
+    #[serde(default)]
+    pub experimental_standby: bool,
+    #[serde(default)]
+    pub busy_commands: BusyCommands,
+    /// Answer REFRESH commands received this close together with a single refresh.
+    #[serde(default = "default_refresh_coalesce_ms")]
+    pub refresh_coalesce_ms: u64,
+    /// Stop an UP or DOWN command when it has not been sent again for this long.
+    #[serde(default = "default_jog_hold_ms")]
+    pub jog_hold_ms: u64,
+    /// Answer REFRESH commands with the last height if the controller answered this recently.
+    #[serde(default)]
+    pub refresh_cache_secs: Option<u64>,
+    /// Stop an operation when a height cannot be handed on for publishing, instead of counting it
+    /// in the diagnostics and carrying on.
+    #[serde(default)]
+    pub strict_publishing: bool,
     pub mqtt: MqttSettings,
+    #[serde(default)]
+    pub api: Option<ApiSettings>,
+    #[serde(default)]
+    pub schedule: Vec<ScheduleEntry>,
+    /// Skip scheduled movements when the computer has had no input for this long.
+    #[serde(default)]
+    pub schedule_idle_limit_mins: Option<u64>,
+    #[serde(default)]
+    pub compatibility: CompatibilitySettings,
+    #[serde(default)]
+    pub power_relay: Option<PowerRelaySettings>,
+    #[serde(default)]
+    pub command_auth: Option<CommandAuthSettings>,
+    #[serde(default)]
+    pub history: Option<HistorySettings>,
+    #[serde(default)]
+    pub interlock: Option<InterlockSettings>,
+    #[serde(default)]
+    pub on_event: HookSettings,
+    #[serde(default)]
+    pub height_sensors: Vec<HeightSensor>,
+    /// Answer requests to read any of the controller's registers on `<prefix>/<id>/modbus/read`.
+    #[serde(default)]
+    pub enable_diagnostic_api: bool,
+    /// Restrict filesystem access to the files this needs, on Linux.
+    #[serde(default)]
+    pub sandbox: bool,
+    /// Restart the connection to the controller or the broker when it stops making progress.
+    #[serde(default)]
+    pub watchdog: Option<WatchdogSettings>,
+    /// Remind about looking after the desk once it has been long enough or moved far enough.
+    #[serde(default)]
+    pub maintenance: Option<MaintenanceSettings>,
+}
+
+/// An MQTT binary sensor, such as a chair occupancy or obstruction sensor, that prevents the desk
+/// from being lowered while it reports blocked.
+#[derive(Deserialize)]
+pub struct InterlockSettings {
+    pub topic: String,
+    #[serde(default = "default_payload_on")]
+    pub payload_blocked: String,
+    #[serde(default = "default_payload_off")]
+    pub payload_clear: String,
+    /// Treat the sensor as blocked until it reports otherwise.
+    #[serde(default = "default_true")]
+    pub blocked_until_known: bool,
+}
+
+/// External commands to run when things happen. Each command is the program followed by its
+/// arguments.
+#[derive(Default, Deserialize)]
+pub struct HookSettings {
+    /// When the desk has finished moving to a preset.
+    #[serde(default)]
+    pub movement_finished: Option<Vec<String>>,
+    #[serde(default)]
+    pub error: Option<Vec<String>>,
+    /// When the height rises above a threshold.
+    #[serde(default)]
+    pub height_above: Vec<ThresholdHook>,
+    /// When the height falls below a threshold.
+    #[serde(default)]
+    pub height_below: Vec<ThresholdHook>,
+}
+
+impl HookSettings {
+    pub fn is_empty(&self) -> bool {
+        self.movement_finished.is_none()
+            && self.error.is_none()
+            && self.height_above.is_empty()
+            && self.height_below.is_empty()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ThresholdHook {
+    /// The height, in inches.
+    pub threshold: f32,
+    pub command: Vec<String>,
+}
+
+/// A binary sensor that is on while the height is within a range, such as above standing height.
+#[derive(Clone, Deserialize)]
+pub struct HeightSensor {
+    /// The entity name. Its topic uses the name in lower case with `_` for other characters.
+    pub name: String,
+    /// On while the height is above this, in inches.
+    #[serde(default)]
+    pub above: Option<f32>,
+    /// On while the height is below this, in inches.
+    #[serde(default)]
+    pub below: Option<f32>,
+}
+
+impl HeightSensor {
+    pub fn is_on(&self, height: f32) -> bool {
+        self.above.map_or(true, |above| height > above)
+            && self.below.map_or(true, |below| height < below)
+    }
+
+    pub fn slug(&self) -> String {
+        self.name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_lowercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+}
+
+/// Keep a record of heights in a local database.
+#[derive(Deserialize)]
+pub struct HistorySettings {
+    #[serde(default = "default_history_path")]
+    pub path: String,
+    /// Keep every reading for this many days before replacing them with hourly summaries.
+    #[serde(default = "default_history_raw_days")]
+    pub raw_days: u64,
+    /// Keep hourly summaries for this many days.
+    #[serde(default = "default_history_retention_days")]
+    pub retention_days: u64,
+}
+
+/// Watch the loops that talk to the controller and the broker for ones that have stalled.
+#[derive(Deserialize)]
+pub struct WatchdogSettings {
+    /// How long a loop can go without making progress before it is restarted. It needs to be
+    /// longer than `operation_timeout_secs` and the MQTT `keep_alive_secs`, which a loop can
+    /// legitimately spend waiting.
+    #[serde(default = "default_watchdog_stall_secs")]
+    pub stall_secs: u64,
+}
+
+fn default_watchdog_stall_secs() -> u64 {
+    300
+}
+
+/// When the desk is due to be looked after, such as by re-tightening bolts and checking cables.
+/// Maintenance is due once either limit is reached.
+#[derive(Deserialize)]
+pub struct MaintenanceSettings {
+    #[serde(default)]
+    pub every_days: Option<u64>,
+    #[serde(default)]
+    pub every_inches: Option<f32>,
+    /// When maintenance was last done before this started counting, as `YYYY-MM-DD`.
+    #[serde(default)]
+    pub last_done: Option<String>,
+}
+
+/// Require commands received by MQTT to be signed with a shared key.
+#[derive(Deserialize)]
+pub struct CommandAuthSettings {
+    pub key: String,
+    /// Reject commands whose timestamp is further than this from the current time.
+    #[serde(default = "default_command_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+/// A relay that can cut the controller's power while it is idle.
+#[derive(Deserialize)]
+pub struct PowerRelaySettings {
+    /// Cut power after this long without any commands.
+    pub idle_off_secs: u64,
+    /// How long the controller takes to start after its power is turned on.
+    #[serde(default = "default_power_on_delay_secs")]
+    pub power_on_delay_secs: u64,
+    /// A smart plug controlled by MQTT.
+    #[serde(default)]
+    pub mqtt: Option<MqttRelaySettings>,
+    /// A GPIO pin exported through sysfs, such as on a Raspberry Pi.
+    #[serde(default)]
+    pub gpio: Option<GpioRelaySettings>,
+}
+
+#[derive(Deserialize)]
+pub struct MqttRelaySettings {
+    pub command_topic: String,
+    #[serde(default = "default_payload_on")]
+    pub payload_on: String,
+    #[serde(default = "default_payload_off")]
+    pub payload_off: String,
+}
+
+#[derive(Deserialize)]
+pub struct GpioRelaySettings {
+    /// The GPIO value file, such as `/sys/class/gpio/gpio17/value`.
+    pub path: String,
+    #[serde(default)]
+    pub active_low: bool,
+}
+
+/// Publishing for home automation platforms other than Home Assistant.
+#[derive(Default, Deserialize)]
+pub struct CompatibilitySettings {
+    /// Describe the desk using the Homie convention, which openHAB discovers automatically.
+    #[serde(default)]
+    pub openhab: bool,
+    #[serde(default)]
+    pub domoticz: Option<DomoticzSettings>,
+}
+
+#[derive(Deserialize)]
+pub struct DomoticzSettings {
+    /// The index of the Domoticz device that shows the height.
+    pub idx: u32,
+    #[serde(default = "default_domoticz_topic")]
+    pub topic: String,
+}
+
+/// A command to send every day at a local time.
+#[derive(Deserialize)]
+pub struct ScheduleEntry {
+    /// The time of day, as `HH:MM`.
+    pub time: String,
+    /// The command, as published to the command topic.
+    pub command: String,
+}
+
+/// Overrides for the names of Home Assistant entities.
+///
+/// `{name}` is replaced by the desk name and `{preset}` by the preset number.
+#[derive(Default, Deserialize)]
+pub struct EntityNames {
+    #[serde(default)]
+    pub connected: Option<String>,
+    #[serde(default)]
+    pub height: Option<String>,
+    #[serde(default)]
+    pub refresh: Option<String>,
+    #[serde(default)]
+    pub preset: Option<String>,
+    #[serde(default)]
+    pub sleep: Option<String>,
+    #[serde(default)]
+    pub speed: Option<String>,
+    #[serde(default)]
+    pub total_travel: Option<String>,
+    #[serde(default)]
+    pub end_of_travel: Option<String>,
+    #[serde(default)]
+    pub overload: Option<String>,
+    #[serde(default)]
+    pub overheat: Option<String>,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub maintenance_due: Option<String>,
+    #[serde(default)]
+    pub maintenance_done: Option<String>,
+    /// Names for individual presets, taking priority over `preset`.
+    #[serde(default)]
+    pub presets: BTreeMap<u8, String>,
+}
+
+#[derive(Deserialize)]
+pub struct ApiSettings {
+    #[serde(default = "default_api_bind")]
+    pub bind: String,
+    /// Also serve the API over HTTPS, for dashboards that are themselves served over HTTPS.
+    #[serde(default)]
+    pub tls: Option<ApiTlsSettings>,
+    /// Required as a bearer token on requests that act on the desk. Without it, those requests
+    /// are only accepted from this computer.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ApiTlsSettings {
+    #[serde(default = "default_api_tls_bind")]
+    pub bind: String,
+    /// The PEM certificate chain. A self-signed certificate is generated if neither file exists.
+    #[serde(default = "default_api_certificate_file")]
+    pub certificate_file: PathBuf,
+    /// The PEM private key, in PKCS #8 or PKCS #1 form.
+    #[serde(default = "default_api_key_file")]
+    pub key_file: PathBuf,
+    /// The host names a generated certificate is valid for.
+    #[serde(default = "default_api_tls_names")]
+    pub names: Vec<String>,
 }
 
 #[derive(Deserialize)]
@@ -23,17 +439,384 @@ pub struct MqttSettings {
     pub transport: MqttTransport,
     #[serde(default)]
     pub credentials: Option<MqttCredential>,
+    /// A certificate to present to brokers that authenticate clients over TLS.
+    #[serde(default)]
+    pub client_certificate: Option<ClientCertificate>,
+    /// How often to check the password and certificate files for changes, to reconnect with them.
+    #[serde(default = "default_credential_check_secs")]
+    pub credential_check_secs: u64,
+    #[serde(default)]
+    pub retain: RetainSettings,
+    /// More topics to accept commands from, in addition to `<prefix>/<id>/command`.
+    #[serde(default)]
+    pub command_topic_aliases: Vec<String>,
+    #[serde(default)]
+    pub refresh: Option<RefreshSettings>,
+    /// How many heights to keep while the broker is unreachable, to publish once it is back.
+    #[serde(default = "default_offline_buffer")]
+    pub offline_buffer: usize,
+    /// How often to ping the broker when nothing else is sent. Brokers disconnect clients that
+    /// ask for more than they allow.
+    #[serde(default = "default_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+    /// The largest packet to send or accept, in bytes.
+    #[serde(default = "default_max_packet_size")]
+    pub max_packet_size: usize,
+    /// Have the broker keep commands sent while the connection is down, to deliver on reconnect.
+    #[serde(default)]
+    pub persistent_session: bool,
+    /// With `persistent_session`, drop commands whose timestamps are older than this.
+    #[serde(default = "default_queued_command_max_age_secs")]
+    pub queued_command_max_age_secs: u64,
+    /// How JSON payloads, such as movement and presets, are published.
+    #[serde(default)]
+    pub encoding: PayloadEncoding,
+    /// Sign in to a cloud IoT platform instead of using `transport` and `credentials`.
+    #[serde(default)]
+    pub cloud: Option<CloudAuthSettings>,
+    #[serde(default)]
+    pub reconnect: ReconnectSettings,
+}
+
+impl MqttSettings {
+    /// The files credentials are read from each time the client connects.
+    pub fn credential_files(&self) -> Vec<&Path> {
+        let mut files = Vec::new();
+        files.extend(
+            self.credentials
+                .as_ref()
+                .and_then(|credentials| credentials.password_file.as_deref()),
+        );
+        if let Some(certificate) = &self.client_certificate {
+            files.push(&certificate.certificate_file);
+            files.push(&certificate.key_file);
+        }
+        files
+    }
+}
+
+/// Token-based sign in for cloud IoT platforms.
+#[derive(Deserialize)]
+pub enum CloudAuthSettings {
+    /// A device in Azure IoT Hub, signed in with a shared access signature. `id` is the device ID
+    /// and `host` is the hub's host name.
+    AzureIotHub {
+        /// The device's base64 symmetric key.
+        key: String,
+        #[serde(default = "default_token_lifetime_secs")]
+        token_lifetime_secs: u64,
+    },
+    /// An AWS IoT custom authorizer, connected on port 443.
+    AwsCustomAuthorizer {
+        authorizer: String,
+        token_key_name: String,
+        token: String,
+        /// The token signed with the authorizer's private key, if token signing is enabled.
+        #[serde(default)]
+        signature: Option<String>,
+        #[serde(default)]
+        username: Option<String>,
+    },
+    /// AWS IoT over a WebSocket, signed with IAM credentials.
+    AwsSigV4 {
+        region: String,
+        access_key_id: String,
+        secret_access_key: String,
+        #[serde(default)]
+        session_token: Option<String>,
+        #[serde(default = "default_token_lifetime_secs")]
+        token_lifetime_secs: u64,
+    },
+}
+
+/// Publish retained state again periodically, in case the broker has lost it.
+#[derive(Deserialize)]
+pub struct RefreshSettings {
+    pub interval_hours: u64,
+    /// Also publish the Home Assistant discovery configuration.
+    #[serde(default)]
+    pub discovery: bool,
+}
+
+/// The `origin` given in Home Assistant discovery configs, for builds distributed by someone else.
+#[derive(Deserialize)]
+pub struct HassOriginSettings {
+    #[serde(default = "default_origin_name")]
+    pub name: String,
+    #[serde(default = "default_origin_sw_version")]
+    pub sw_version: String,
+    #[serde(default = "default_origin_support_url")]
+    pub support_url: String,
+}
+
+impl Default for HassOriginSettings {
+    fn default() -> Self {
+        HassOriginSettings {
+            name: default_origin_name(),
+            sw_version: default_origin_sw_version(),
+            support_url: default_origin_support_url(),
+        }
+    }
+}
+
+/// How to space out attempts to reconnect to the broker.
+#[derive(Deserialize)]
+pub struct ReconnectSettings {
+    /// The delay after the first failed attempt, which doubles with each failure in a row.
+    #[serde(default = "default_reconnect_min_delay_ms")]
+    pub min_delay_ms: u64,
+    #[serde(default = "default_reconnect_max_delay_secs")]
+    pub max_delay_secs: u64,
+    /// Up to how much longer to wait, chosen at random for each attempt.
+    #[serde(default)]
+    pub jitter_ms: u64,
+    /// The most attempts to make in any minute, counted across restarts of the MQTT connection.
+    #[serde(default)]
+    pub max_per_minute: Option<u32>,
+}
+
+impl Default for ReconnectSettings {
+    fn default() -> Self {
+        ReconnectSettings {
+            min_delay_ms: default_reconnect_min_delay_ms(),
+            max_delay_secs: default_reconnect_max_delay_secs(),
+            jitter_ms: 0,
+            max_per_minute: None,
+        }
+    }
+}
+
+fn default_reconnect_min_delay_ms() -> u64 {
+    1000
+}
+
+fn default_reconnect_max_delay_secs() -> u64 {
+    1
+}
+
+/// Whether messages published to each topic are retained by the broker.
+#[derive(Deserialize)]
+pub struct RetainSettings {
+    #[serde(default = "default_true")]
+    pub connected: bool,
+    #[serde(default = "default_true")]
+    pub height: bool,
+    #[serde(default)]
+    pub movement: bool,
+    #[serde(default)]
+    pub error: bool,
+    #[serde(default = "default_true")]
+    pub info: bool,
+    #[serde(default = "default_true")]
+    pub available: bool,
+    #[serde(default = "default_true")]
+    pub total_travel: bool,
+    /// Both `maintenance_due` and its attributes.
+    #[serde(default = "default_true")]
+    pub maintenance: bool,
+    /// Every topic under `condition`.
+    #[serde(default = "default_true")]
+    pub condition: bool,
+    #[serde(default = "default_true")]
+    pub presets: bool,
+    /// Every topic under `height_sensor`.
+    #[serde(default = "default_true")]
+    pub height_sensors: bool,
+}
+
+impl Default for RetainSettings {
+    fn default() -> Self {
+        RetainSettings {
+            connected: true,
+            height: true,
+            movement: false,
+            error: false,
+            info: true,
+            available: true,
+            total_travel: true,
+            maintenance: true,
+            condition: true,
+            presets: true,
+            height_sensors: true,
+        }
+    }
+}
+
+fn default_offline_buffer() -> usize {
+    32
+}
+
+fn default_keep_alive_secs() -> u64 {
+    60
+}
+
+fn default_max_packet_size() -> usize {
+    10 * 1024
+}
+
+fn default_queued_command_max_age_secs() -> u64 {
+    60
+}
+
+fn default_credential_check_secs() -> u64 {
+    30
+}
+
+fn default_token_lifetime_secs() -> u64 {
+    60 * 60
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_history_path() -> String {
+    "laing-controller.history.sqlite".into()
+}
+
+fn default_history_raw_days() -> u64 {
+    7
+}
+
+fn default_history_retention_days() -> u64 {
+    365
+}
+
+fn default_command_max_age_secs() -> u64 {
+    30
+}
+
+fn default_power_on_delay_secs() -> u64 {
+    5
+}
+
+fn default_payload_on() -> String {
+    "ON".into()
+}
+
+fn default_payload_off() -> String {
+    "OFF".into()
+}
+
+fn default_domoticz_topic() -> String {
+    "domoticz/in".into()
 }
 
 fn default_prefix() -> String {
     "desk".to_string()
 }
 
-fn default_hass_prefix() -> String {
-    "homeassistant".into()
+fn default_hass_prefixes() -> Vec<String> {
+    vec!["homeassistant".into()]
 }
 
 #[derive(Deserialize)]
+#[serde(untagged)]
+enum HassPrefixes {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// An empty prefix turns discovery off for that instance, so it is left out.
+fn deserialize_hass_prefixes<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let prefixes = match HassPrefixes::deserialize(deserializer)? {
+        HassPrefixes::One(prefix) => vec![prefix],
+        HassPrefixes::Many(prefixes) => prefixes,
+    };
+    Ok(prefixes
+        .into_iter()
+        .filter(|prefix| !prefix.is_empty())
+        .collect())
+}
+
+fn default_jog_hold_ms() -> u64 {
+    1000
+}
+
+fn default_origin_name() -> String {
+    "laing-controller".into()
+}
+
+fn default_origin_sw_version() -> String {
+    env!("CARGO_PKG_VERSION").into()
+}
+
+fn default_origin_support_url() -> String {
+    "https://github.com/mdonoughe/laing-controller".into()
+}
+
+fn default_min_possible_height() -> f32 {
+    15.0
+}
+
+fn default_max_possible_height() -> f32 {
+    60.0
+}
+
+fn default_locale() -> String {
+    "en".into()
+}
+
+fn default_api_bind() -> String {
+    "127.0.0.1:7207".into()
+}
+
+fn default_overheat_codes() -> Vec<String> {
+    vec!["HOT".into()]
+}
+
+fn default_wake_pulse_settle_ms() -> u64 {
+    200
+}
+
+fn default_wake_pulse_idle_secs() -> u64 {
+    60
+}
+
+fn default_gentle_move_ms() -> u64 {
+    1000
+}
+
+fn default_gentle_pause_ms() -> u64 {
+    1000
+}
+
+fn default_api_tls_bind() -> String {
+    "127.0.0.1:7208".into()
+}
+
+fn default_api_certificate_file() -> PathBuf {
+    "laing-controller.api.crt".into()
+}
+
+fn default_api_key_file() -> PathBuf {
+    "laing-controller.api.key".into()
+}
+
+fn default_api_tls_names() -> Vec<String> {
+    vec!["localhost".into()]
+}
+
+fn default_operation_timeout_secs() -> u64 {
+    60
+}
+
+fn default_refresh_coalesce_ms() -> u64 {
+    500
+}
+
+fn default_separate_access_gap_ms() -> u64 {
+    10
+}
+
+fn default_offline_command_max_age_secs() -> u64 {
+    60
+}
+
+#[derive(Deserialize, Serialize)]
 pub enum MqttTransport {
     Tcp,
     Tls,
@@ -45,16 +828,315 @@ impl Default for MqttTransport {
     }
 }
 
+/// How structured payloads are encoded, for bridges over links that count every byte.
+#[derive(Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+pub enum PayloadEncoding {
+    Json,
+    /// CBOR (RFC 8949) with the same fields as the JSON, in place of it.
+    Cbor,
+    /// JSON, and CBOR as well on the same topic with `/cbor` appended.
+    Both,
+}
+
+impl Default for PayloadEncoding {
+    fn default() -> Self {
+        PayloadEncoding::Json
+    }
+}
+
+/// How heights read while moving are smoothed before being published.
+#[derive(Clone, Copy, Deserialize)]
+pub enum HeightFilterSettings {
+    None,
+    /// The median of the last three readings.
+    Median3,
+    /// An exponential moving average. Higher `alpha` follows new readings more closely.
+    Ema {
+        alpha: f32,
+    },
+}
+
+impl Default for HeightFilterSettings {
+    fn default() -> Self {
+        HeightFilterSettings::None
+    }
+}
+
+/// How the controller reports what the handset display shows.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisplayEncoding {
+    SevenSegmentLe,
+    SevenSegmentBe,
+    Bcd,
+}
+
+impl Default for DisplayEncoding {
+    fn default() -> Self {
+        DisplayEncoding::SevenSegmentLe
+    }
+}
+
+impl DisplayEncoding {
+    pub fn decoder(self) -> Box<dyn Decoder> {
+        match self {
+            DisplayEncoding::SevenSegmentLe => Box::new(SevenSegmentLe),
+            DisplayEncoding::SevenSegmentBe => Box::new(SevenSegmentBe),
+            DisplayEncoding::Bcd => Box::new(Bcd),
+        }
+    }
+}
+
+/// The codes the controller shows on the display for each `Condition`.
+#[derive(Clone, Deserialize)]
+pub struct DisplayCodes {
+    #[serde(default)]
+    pub end_of_travel: Vec<String>,
+    #[serde(default)]
+    pub overload: Vec<String>,
+    #[serde(default = "default_overheat_codes")]
+    pub overheat: Vec<String>,
+}
+
+impl Default for DisplayCodes {
+    fn default() -> Self {
+        DisplayCodes {
+            end_of_travel: Vec::new(),
+            overload: Vec::new(),
+            overheat: default_overheat_codes(),
+        }
+    }
+}
+
+impl DisplayCodes {
+    /// The condition the display text signals, if any.
+    pub fn condition(&self, text: &str) -> Option<Condition> {
+        Condition::ALL.into_iter().find(|&condition| {
+            let codes = match condition {
+                Condition::EndOfTravel => &self.end_of_travel,
+                Condition::Overload => &self.overload,
+                Condition::Overheat => &self.overheat,
+            };
+            codes.iter().any(|code| code.eq_ignore_ascii_case(text))
+        })
+    }
+}
+
+/// For adapters powered from a USB hub that suspends, which lose the first message after a while.
+#[derive(Clone, Deserialize)]
+pub struct WakePulseSettings {
+    #[serde(default = "default_true")]
+    pub dtr: bool,
+    #[serde(default)]
+    pub rts: bool,
+    /// How long to wait for the adapter after toggling the lines.
+    #[serde(default = "default_wake_pulse_settle_ms")]
+    pub settle_ms: u64,
+    /// Only toggle the lines once nothing has been sent or received for this long.
+    #[serde(default = "default_wake_pulse_idle_secs")]
+    pub idle_secs: u64,
+}
+
+/// A height the controller reported and the height the desk was measured at, in inches.
+#[derive(Clone, Copy, Deserialize)]
+pub struct HeightCorrectionPoint {
+    pub reported: f32,
+    pub actual: f32,
+}
+
+/// Moving for a while and pausing for a while, since the controller only has one speed.
+#[derive(Clone, Deserialize)]
+pub struct GentleMovementSettings {
+    #[serde(default = "default_gentle_move_ms")]
+    pub move_ms: u64,
+    #[serde(default = "default_gentle_pause_ms")]
+    pub pause_ms: u64,
+}
+
+impl GentleMovementSettings {
+    /// How long a movement that takes `duration` at full speed takes with the pauses.
+    pub fn stretch(&self, duration: Duration) -> Duration {
+        let cycle = self.move_ms.max(1) + self.pause_ms;
+        duration.mul_f64(cycle as f64 / self.move_ms.max(1) as f64)
+    }
+}
+
+/// Which Modbus functions are used to write the command registers and read the state registers.
+#[derive(Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+pub enum RegisterAccess {
+    /// Use Read/Write Multiple Registers, switching to `Separate` if it is rejected as an illegal
+    /// function.
+    Auto,
+    /// Always use Read/Write Multiple Registers (0x17).
+    Combined,
+    /// Use Write Multiple Registers (0x10) and then Read Holding Registers (0x03), for gateways
+    /// that do not support 0x17.
+    Separate,
+}
+
+impl Default for RegisterAccess {
+    fn default() -> Self {
+        RegisterAccess::Auto
+    }
+}
+
+/// What to do with movement commands received while the controller is not responding.
+#[derive(Deserialize)]
+pub enum OfflineCommands {
+    Reject,
+    QueueLatest,
+}
+
+impl Default for OfflineCommands {
+    fn default() -> Self {
+        OfflineCommands::Reject
+    }
+}
+
+/// How the Home Assistant discovery configuration is laid out.
+#[derive(Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+pub enum HassDiscovery {
+    /// A config topic for each entity, which every version of Home Assistant understands.
+    Entity,
+    /// A single config topic for the desk holding every entity, for Home Assistant 2024.11 and
+    /// later.
+    Device,
+}
+
+impl Default for HassDiscovery {
+    fn default() -> Self {
+        HassDiscovery::Entity
+    }
+}
+
+/// Which releases of Home Assistant the discovery configuration is written for.
+#[derive(Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+pub enum HassSchema {
+    /// Home Assistant 2023.9 and later.
+    Current,
+    /// Earlier releases, which have no event entities and reject the origin block. The last error
+    /// is published as a sensor instead.
+    Legacy,
+}
+
+impl Default for HassSchema {
+    fn default() -> Self {
+        HassSchema::Current
+    }
+}
+
+/// What to do with commands received while the desk is moving.
+#[derive(Deserialize, Eq, PartialEq)]
+pub enum BusyCommands {
+    /// Reject every command until the current one has finished.
+    Reject,
+    /// Stop the current movement and start a new one. Other commands are rejected.
+    Preempt,
+}
+
+impl Default for BusyCommands {
+    fn default() -> Self {
+        BusyCommands::Reject
+    }
+}
+
 #[derive(Deserialize)]
 pub struct MqttCredential {
     pub username: String,
+    #[serde(default)]
     pub password: String,
+    /// Read the password from this file instead, so it can be changed without editing the
+    /// settings.
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
 }
 
-pub fn load_settings() -> Result<Settings> {
+#[derive(Clone, Deserialize)]
+pub struct ClientCertificate {
+    /// The PEM certificate chain.
+    pub certificate_file: PathBuf,
+    /// The PEM private key, in PKCS #8 or PKCS #1 form.
+    pub key_file: PathBuf,
+}
+
+/// Where the settings file is kept, next to the executable.
+pub fn settings_path() -> Result<PathBuf> {
     let mut path = ::std::env::current_exe().context("Could not find installation directory")?;
     path.pop();
     path.push("laing-controller.yaml");
-    let file = File::open(path).context("Failed to open settings")?;
-    serde_yaml::from_reader(file).context("Failed to load settings")
+    Ok(path)
+}
+
+/// Top level settings that only affect the MQTT connection, so they can be changed without
+/// restarting the connection to the controller.
+const MQTT_ONLY_SETTINGS: &[&str] = &[
+    "id",
+    "name",
+    "prefix",
+    "hass_prefix",
+    "hass_origin",
+    "hass_discovery",
+    "hass_schema",
+    "height_expire_after_secs",
+    "locale",
+    "entity_names",
+    "command_aliases",
+    "mqtt",
+    "compatibility",
+    "command_auth",
+];
+
+pub fn load_settings() -> error::Result<Settings> {
+    serde_yaml::from_value(load_settings_value()?).map_err(|err| Error::Config(err.to_string()))
+}
+
+/// Load the settings file without interpreting it, for comparing with a later version.
+///
+/// When running as a Home Assistant add-on, the add-on options are loaded instead.
+pub fn load_settings_value() -> error::Result<Value> {
+    let config = |err: anyhow::Error| Error::Config(format!("{:#}", err));
+    if hassio::data_dir().is_some() {
+        return hassio::load_options().map_err(config);
+    }
+    let path = settings_path().map_err(config)?;
+    let file = File::open(path)
+        .context("Failed to open settings")
+        .map_err(config)?;
+    serde_yaml::from_reader(file).map_err(|err| Error::Config(err.to_string()))
+}
+
+/// The `log_level` from the settings file, read on its own so that logging can start before the
+/// rest of the settings are checked.
+pub fn log_level() -> Option<String> {
+    load_settings_value()
+        .ok()?
+        .get("log_level")?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// Top level settings that are applied while running, without restarting anything.
+const LIVE_SETTINGS: &[&str] = &["serial_port"];
+
+fn without(value: &Value, keys: &[&[&str]]) -> Value {
+    let mut value = value.clone();
+    if let Value::Mapping(mapping) = &mut value {
+        for key in keys.iter().copied().flatten() {
+            mapping.remove(&Value::from(*key));
+        }
+    }
+    value
+}
+
+/// Whether the settings have changed in a way that needs more than the MQTT connection to be
+/// restarted.
+pub fn needs_restart(old: &Value, new: &Value) -> bool {
+    let keys = [MQTT_ONLY_SETTINGS, LIVE_SETTINGS];
+    without(old, &keys) != without(new, &keys)
+}
+
+/// Whether the settings have changed in a way that needs the MQTT connection to be restarted,
+/// rather than only applying settings while running.
+pub fn needs_mqtt_restart(old: &Value, new: &Value) -> bool {
+    without(old, &[LIVE_SETTINGS]) != without(new, &[LIVE_SETTINGS])
 }
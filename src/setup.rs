@@ -0,0 +1,230 @@
+//! An interactive wizard for writing `laing-controller.yaml`.
+
+use std::{
+    io::{BufRead, Write},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use laing_protocol::{
+    display::{self, Reading},
+    frames::{COMMAND_ADDRESS, IDLE, STATE_ADDRESS, STATE_LEN, WAKE},
+};
+use rumqttc::{AsyncClient, ConnAck, Event, Packet};
+use tokio_modbus::prelude::*;
+use tokio_serial::{SerialPortType, SerialStream};
+
+use crate::{
+    mqtt::mqtt_options,
+    settings::{settings_path, Settings},
+};
+
+/// How long to wait for the broker to accept the connection.
+const BROKER_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn setup() -> Result<()> {
+    let path = settings_path()?;
+    println!("This will write the settings to {}.", path.display());
+    if path.exists() && !confirm("The settings file already exists. Replace it?")? {
+        return Ok(());
+    }
+
+    let serial_port = choose_port()?;
+    match probe(&serial_port) {
+        Ok(Some(reading)) => println!("The controller responded. The display shows {}.", reading),
+        Ok(None) => println!("The controller responded."),
+        Err(err) => {
+            println!("The controller did not respond: {:#}", err);
+            if !confirm("Continue anyway?")? {
+                return Ok(());
+            }
+        }
+    }
+
+    let name = ask("Name to show in Home Assistant", Some("My Desk"))?;
+    let default_id = name.to_lowercase().replace(' ', "-");
+    let id = ask("ID to use in MQTT topics", Some(&default_id))?;
+
+    let settings = loop {
+        let yaml = mqtt_yaml(&serial_port, &id, &name)?;
+        let settings: Settings = serde_yaml::from_str(&yaml).context("Invalid settings")?;
+        match test_broker(&settings) {
+            Ok(()) => {
+                println!("Connected to the broker.");
+                break yaml;
+            }
+            Err(err) => {
+                println!("Could not connect to the broker: {:#}", err);
+                if confirm("Save these settings anyway?")? {
+                    break yaml;
+                }
+            }
+        }
+    };
+
+    std::fs::write(&path, settings).context("Failed to write settings")?;
+    println!(
+        "Saved. See the example laing-controller.yaml for the other settings that can be added."
+    );
+    Ok(())
+}
+
+/// Ask for the broker details and produce the settings file.
+fn mqtt_yaml(serial_port: &str, id: &str, name: &str) -> Result<String> {
+    let host = ask("MQTT broker host name", None)?;
+    let transport = loop {
+        match ask("Use TLS? (yes/no)", Some("yes"))?.as_str() {
+            "yes" | "y" => break "Tls",
+            "no" | "n" => break "Tcp",
+            _ => {}
+        }
+    };
+    let port = loop {
+        let port = ask("MQTT broker port (blank for the default)", Some(""))?;
+        if port.is_empty() || port.parse::<u16>().is_ok() {
+            break port;
+        }
+    };
+    let username = ask("MQTT user name (blank for none)", Some(""))?;
+    let password = if username.is_empty() {
+        String::new()
+    } else {
+        ask("MQTT password", None)?
+    };
+
+    // JSON strings are also YAML strings, and this takes care of quoting.
+    let quote = |value: &str| serde_json::to_string(value).unwrap();
+    let mut yaml = format!(
+        "id: {}\nname: {}\nserial_port: {}\nmqtt:\n  host: {}\n  transport: {}\n",
+        quote(id),
+        quote(name),
+        quote(serial_port),
+        quote(&host),
+        transport,
+    );
+    if !port.is_empty() {
+        yaml += &format!("  port: {}\n", port);
+    }
+    if !username.is_empty() {
+        yaml += &format!(
+            "  credentials:\n    username: {}\n    password: {}\n",
+            quote(&username),
+            quote(&password),
+        );
+    }
+    Ok(yaml)
+}
+
+fn choose_port() -> Result<String> {
+    let ports = tokio_serial::available_ports().unwrap_or_default();
+    if ports.is_empty() {
+        println!("No serial ports were found.");
+        return ask("Serial port", None);
+    }
+    println!("Serial ports:");
+    for (index, port) in ports.iter().enumerate() {
+        match &port.port_type {
+            SerialPortType::UsbPort(usb) => println!(
+                "  {}: {} ({})",
+                index + 1,
+                port.port_name,
+                usb.product.as_deref().unwrap_or("USB")
+            ),
+            _ => println!("  {}: {}", index + 1, port.port_name),
+        }
+    }
+    let answer = ask("Serial port (number or name)", Some("1"))?;
+    Ok(match answer.parse::<usize>() {
+        Ok(index) if (1..=ports.len()).contains(&index) => ports[index - 1].port_name.clone(),
+        _ => answer,
+    })
+}
+
+/// Check that a controller responds on the serial port, returning what its display shows.
+#[tokio::main(flavor = "current_thread")]
+async fn probe(serial_port: &str) -> Result<Option<String>> {
+    let port = SerialStream::open(
+        &tokio_serial::new(serial_port, 57600).timeout(Duration::from_millis(250)),
+    )?;
+    let mut client = rtu::connect_slave(port, Slave(0x01)).await?;
+    let response = tokio::time::timeout(
+        Duration::from_secs(2),
+        client.read_write_multiple_registers(
+            STATE_ADDRESS,
+            STATE_LEN as u16,
+            COMMAND_ADDRESS,
+            &WAKE[..],
+        ),
+    )
+    .await
+    .map_err(|_| anyhow!("timed out"))??;
+    let _ = tokio::time::timeout(
+        Duration::from_secs(2),
+        client.read_write_multiple_registers(
+            STATE_ADDRESS,
+            STATE_LEN as u16,
+            COMMAND_ADDRESS,
+            &IDLE[..],
+        ),
+    )
+    .await;
+    Ok(match display::read((&response[0..2]).try_into().unwrap()) {
+        Some(Reading::Height(height)) => Some(format!("{:.1}", f32::from(height) / 10.0)),
+        Some(Reading::Text(text)) => Some(text),
+        Some(Reading::Blank) | None => None,
+    })
+}
+
+/// Check that the broker accepts a connection with the settings.
+#[tokio::main(flavor = "current_thread")]
+async fn test_broker(settings: &Settings) -> Result<()> {
+    let (client, mut event_loop) = AsyncClient::new(mqtt_options(settings)?, 1);
+    let result = tokio::time::timeout(BROKER_TIMEOUT, async {
+        loop {
+            match event_loop.poll().await? {
+                Event::Incoming(Packet::ConnAck(ConnAck {
+                    code: rumqttc::ConnectReturnCode::Success,
+                    ..
+                })) => return Ok(()),
+                Event::Incoming(Packet::ConnAck(ConnAck { code, .. })) => {
+                    return Err(anyhow!("the broker refused the connection: {:?}", code))
+                }
+                _ => {}
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("timed out"))?;
+    let _ = client.try_disconnect();
+    result
+}
+
+fn ask(question: &str, default: Option<&str>) -> Result<String> {
+    loop {
+        match default {
+            Some(default) if !default.is_empty() => print!("{} [{}]: ", question, default),
+            _ => print!("{}: ", question),
+        }
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if std::io::stdin().lock().read_line(&mut line)? == 0 {
+            return Err(anyhow!("Setup cancelled"));
+        }
+        let answer = line.trim();
+        match default {
+            Some(default) if answer.is_empty() => return Ok(default.to_string()),
+            None if answer.is_empty() => {}
+            _ => return Ok(answer.to_string()),
+        }
+    }
+}
+
+fn confirm(question: &str) -> Result<bool> {
+    loop {
+        match ask(&format!("{} (yes/no)", question), None)?.as_str() {
+            "yes" | "y" => return Ok(true),
+            "no" | "n" => return Ok(false),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! Saving and comparing the controller's registers, for working out what they mean.
+//!
+//! Take a snapshot, change a setting on the handset, take another, and diff them to see which
+//! registers changed. While laing-controller is running with the API enabled, snapshots are read
+//! through it, as the serial port can only be opened once.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use hyper::{body::to_bytes, client::HttpConnector, Body, Client, Request, StatusCode};
+use laing_protocol::frames::{COMMAND_ADDRESS, STATE_ADDRESS, STATE_LEN, WAKE};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_modbus::{client::Context, prelude::*};
+use tokio_serial::SerialStream;
+
+use crate::{
+    settings::{load_settings, Settings},
+    transport::transfer::{TransferMetrics, TransferPort},
+};
+
+const USAGE: &str = "Usage:
+  laing-controller snapshot <file> [<start> [<count>]]
+  laing-controller diff <before> <after>
+
+Addresses may be decimal or hexadecimal with 0x. The window defaults to 0x0000 and 256 registers.";
+
+/// Modbus cannot read more than this many registers at once.
+const MAX_READ: u16 = 125;
+
+#[derive(Deserialize, Serialize)]
+struct Snapshot {
+    /// Seconds since 1970.
+    time: u64,
+    start: u16,
+    /// Registers that could not be read are `null`.
+    registers: Vec<Option<u16>>,
+}
+
+pub fn snapshot(args: &[String]) -> Result<()> {
+    let (path, start, count) = match args {
+        [path] => (path, 0, 256),
+        [path, start] => (path, parse_number(start)?, 256),
+        [path, start, count] => (path, parse_number(start)?, parse_number(count)?),
+        _ => return Err(anyhow!(USAGE)),
+    };
+    let settings = load_settings()?;
+    let registers = read_window(&settings, start, count)?;
+    let snapshot = Snapshot {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        start,
+        registers,
+    };
+    let file = std::fs::File::create(path).context("Failed to create snapshot")?;
+    serde_json::to_writer_pretty(file, &snapshot).context("Failed to save snapshot")?;
+    let unreadable = snapshot.registers.iter().filter(|r| r.is_none()).count();
+    println!(
+        "Saved {} registers to {} ({} could not be read)",
+        snapshot.registers.len(),
+        path,
+        unreadable
+    );
+    Ok(())
+}
+
+pub fn diff(args: &[String]) -> Result<()> {
+    let (before, after) = match args {
+        [before, after] => (load(before)?, load(after)?),
+        _ => return Err(anyhow!(USAGE)),
+    };
+    let start = before.start.min(after.start);
+    let end = (usize::from(before.start) + before.registers.len())
+        .max(usize::from(after.start) + after.registers.len());
+    let get = |snapshot: &Snapshot, address: usize| {
+        address
+            .checked_sub(usize::from(snapshot.start))
+            .and_then(|index| snapshot.registers.get(index).copied().flatten())
+    };
+    let format = |value: Option<u16>| match value {
+        Some(value) => format!("0x{:04x}", value),
+        None => "------".to_string(),
+    };
+    let mut changes = 0;
+    for address in usize::from(start)..end {
+        let (old, new) = (get(&before, address), get(&after, address));
+        if old != new {
+            changes += 1;
+            println!("0x{:04x}: {} -> {}", address, format(old), format(new));
+        }
+    }
+    println!("{} registers changed", changes);
+    Ok(())
+}
+
+fn load(path: &str) -> Result<Snapshot> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    serde_json::from_reader(file).with_context(|| format!("Failed to load {}", path))
+}
+
+fn parse_number(text: &str) -> Result<u16> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+    .map_err(|_| anyhow!("Invalid number: {}\n\n{}", text, USAGE))
+}
+
+/// Where registers are read from.
+enum Reader {
+    /// The serial port, opened here.
+    Serial {
+        port: TransferPort<SerialStream>,
+        client: Context,
+    },
+    /// The API of a running laing-controller, which has the serial port open.
+    Daemon {
+        client: Client<HttpConnector>,
+        address: String,
+        token: Option<String>,
+    },
+}
+
+impl Reader {
+    /// Use the running laing-controller if there is one, or else open the serial port.
+    async fn open(settings: &Settings) -> Result<Self> {
+        if let Some(api) = &settings.api {
+            let client = Client::new();
+            let uri = format!("http://{}/status", api.bind).parse()?;
+            if client.get(uri).await.is_ok() {
+                println!("Reading through laing-controller at {}", api.bind);
+                return Ok(Reader::Daemon {
+                    client,
+                    address: api.bind.clone(),
+                    token: api.token.clone(),
+                });
+            }
+        }
+        let port = TransferPort::new(
+            SerialStream::open(
+                &tokio_serial::new(&settings.serial_port, 57600)
+                    .timeout(Duration::from_millis(250)),
+            )?,
+            Arc::new(TransferMetrics::default()),
+        );
+        let mut client = rtu::connect_slave(port.take(), Slave(0x01)).await?;
+        // The controller does not answer anything until it has been woken.
+        tokio::time::timeout(
+            Duration::from_secs(2),
+            client.read_write_multiple_registers(
+                STATE_ADDRESS,
+                STATE_LEN as u16,
+                COMMAND_ADDRESS,
+                &WAKE[..],
+            ),
+        )
+        .await
+        .map_err(|_| anyhow!("The controller did not respond"))??;
+        Ok(Reader::Serial { port, client })
+    }
+
+    /// Read some registers, or `None` if the controller does not give them.
+    async fn read(&mut self, address: u16, count: u16) -> Result<Option<Vec<u16>>> {
+        match self {
+            Reader::Serial { port, client } => read(port, client, address, count).await,
+            Reader::Daemon {
+                client,
+                address: api,
+                token,
+            } => {
+                let mut request = Request::get(format!(
+                    "http://{}/registers?addr={}&count={}",
+                    api, address, count
+                ));
+                // Reading registers acts on the desk, so the API may ask for its token.
+                if let Some(token) = token {
+                    request = request.header("authorization", format!("Bearer {}", token));
+                }
+                let response = client
+                    .request(request.body(Body::empty())?)
+                    .await
+                    .context("Failed to reach laing-controller")?;
+                let status = response.status();
+                let body: serde_json::Value = serde_json::from_slice(&to_bytes(response).await?)?;
+                match status {
+                    StatusCode::OK => Ok(Some(serde_json::from_value(body["registers"].clone())?)),
+                    StatusCode::BAD_GATEWAY => Ok(None),
+                    _ => bail!(
+                        "laing-controller could not read registers: {}",
+                        body["error"].as_str().unwrap_or_default()
+                    ),
+                }
+            }
+        }
+    }
+
+    async fn close(self) -> Result<()> {
+        if let Reader::Serial { mut client, .. } = self {
+            client.disconnect().await?;
+        }
+        Ok(())
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn read_window(settings: &Settings, start: u16, count: u16) -> Result<Vec<Option<u16>>> {
+    let mut reader = Reader::open(settings).await?;
+    let mut registers = Vec::with_capacity(usize::from(count));
+    let end = u32::from(start) + u32::from(count);
+    let mut address = u32::from(start);
+    while address < end {
+        let size = (end - address).min(u32::from(MAX_READ)) as u16;
+        match reader.read(address as u16, size).await? {
+            Some(values) => registers.extend(values.into_iter().map(Some)),
+            // The controller rejects ranges containing registers it does not have, so read them
+            // one at a time to find the ones it does.
+            None => {
+                for single in address..address + u32::from(size) {
+                    let value = reader.read(single as u16, 1).await?;
+                    registers.push(value.and_then(|values| values.first().copied()));
+                }
+            }
+        }
+        address += u32::from(size);
+    }
+    reader.close().await?;
+    Ok(registers)
+}
+
+/// Read some registers, starting over with a new context if the controller does not answer.
+async fn read<T: AsyncRead + AsyncWrite + Send + 'static>(
+    port: &TransferPort<T>,
+    client: &mut Context,
+    address: u16,
+    count: u16,
+) -> Result<Option<Vec<u16>>> {
+    match tokio::time::timeout(
+        Duration::from_secs(1),
+        client.read_holding_registers(address, count),
+    )
+    .await
+    {
+        Ok(Ok(values)) => Ok(Some(values)),
+        Ok(Err(_)) => Ok(None),
+        Err(_) => {
+            // tokio-modbus does not recover from timeouts.
+            *client = rtu::connect_slave(port.take(), Slave(0x01)).await?;
+            Ok(None)
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Working out when scheduled commands are due, and noticing when the wall clock jumps, apart from
+//! sending anything.
+
+use std::{
+    collections::BTreeSet,
+    time::{Duration, Instant, SystemTime},
+};
+
+use chrono::{NaiveDateTime, NaiveTime};
+
+/// The longest the scheduler sleeps before checking the wall clock again.
+pub const RESYNC: Duration = Duration::from_secs(60);
+/// How far the wall clock may drift from the monotonic clock before it counts as a jump.
+const SKEW_THRESHOLD: Duration = Duration::from_secs(5);
+/// How late a scheduled command may run, e.g. after oversleeping.
+const GRACE_MINUTES: i64 = 2;
+
+/// Commands to run at times of day.
+pub struct Timetable<T> {
+    entries: Vec<(NaiveTime, T)>,
+    /// Occurrences that have already run, so they are not repeated if the clock goes backwards.
+    fired: BTreeSet<NaiveDateTime>,
+}
+
+impl<T: Copy> Timetable<T> {
+    pub fn new(entries: Vec<(NaiveTime, T)>) -> Self {
+        Self {
+            entries,
+            fired: BTreeSet::new(),
+        }
+    }
+
+    /// The commands due at `now` that have not run yet.
+    pub fn due(&mut self, now: NaiveDateTime) -> Vec<T> {
+        let mut due = Vec::new();
+        for &(time, command) in &self.entries {
+            let occurrence = now.date().and_time(time);
+            if occurrence <= now
+                && now - occurrence <= chrono::Duration::minutes(GRACE_MINUTES)
+                && self.fired.insert(occurrence)
+            {
+                due.push(command);
+            }
+        }
+        self.fired
+            .retain(|&occurrence| now - occurrence < chrono::Duration::days(2));
+        due
+    }
+
+    /// How long to wait from `now` before checking again: until the next occurrence, but no longer
+    /// than `RESYNC`.
+    pub fn delay(&self, now: NaiveDateTime) -> Duration {
+        self.entries
+            .iter()
+            .map(|&(time, _)| {
+                let mut occurrence = now.date().and_time(time);
+                if occurrence <= now {
+                    occurrence += chrono::Duration::days(1);
+                }
+                (occurrence - now).to_std().unwrap_or_default()
+            })
+            .min()
+            .unwrap_or(RESYNC)
+            .min(RESYNC)
+    }
+}
+
+/// Both clocks as they were before a wait, for comparing how far each moved.
+pub struct SkewCheck {
+    wall: SystemTime,
+    monotonic: Instant,
+}
+
+impl SkewCheck {
+    pub fn start() -> Self {
+        Self::at(SystemTime::now(), Instant::now())
+    }
+
+    pub fn at(wall: SystemTime, monotonic: Instant) -> Self {
+        Self { wall, monotonic }
+    }
+
+    /// How many seconds the wall clock jumped by since starting, if it was more than could be
+    /// drift.
+    pub fn finish(self) -> Option<f64> {
+        self.finish_at(SystemTime::now(), Instant::now())
+    }
+
+    pub fn finish_at(self, wall: SystemTime, monotonic: Instant) -> Option<f64> {
+        let monotonic = monotonic.duration_since(self.monotonic).as_secs_f64();
+        let wall = match wall.duration_since(self.wall) {
+            Ok(elapsed) => elapsed.as_secs_f64(),
+            Err(err) => -err.duration().as_secs_f64(),
+        };
+        let skew = wall - monotonic;
+        if skew.abs() > SKEW_THRESHOLD.as_secs_f64() {
+            Some(skew)
+        } else {
+            None
+        }
+    }
+}
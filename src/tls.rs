@@ -0,0 +1,77 @@
+//! Reading PEM certificates and keys, and making a self-signed certificate when there is none.
+
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use rustls::internal::pemfile;
+
+/// Read a PEM certificate chain and the private key that goes with it, in PKCS #8 or PKCS #1 form.
+pub fn read_certificate(
+    certificate_file: &Path,
+    key_file: &Path,
+) -> Result<(Vec<rustls::Certificate>, rustls::PrivateKey)> {
+    let read =
+        |path: &Path| fs::read(path).with_context(|| format!("Failed to read {}", path.display()));
+    let chain = pemfile::certs(&mut &read(certificate_file)?[..])
+        .ok()
+        .filter(|chain| !chain.is_empty())
+        .ok_or_else(|| anyhow!("No certificates in {}", certificate_file.display()))?;
+    let key = read(key_file)?;
+    let key = pemfile::pkcs8_private_keys(&mut &key[..])
+        .ok()
+        .and_then(|keys| keys.into_iter().next())
+        .or_else(|| {
+            pemfile::rsa_private_keys(&mut &key[..])
+                .ok()
+                .and_then(|keys| keys.into_iter().next())
+        })
+        .ok_or_else(|| anyhow!("No private key in {}", key_file.display()))?;
+    Ok((chain, key))
+}
+
+/// Write a new self-signed certificate for `names` unless both files already exist.
+///
+/// Nothing is overwritten if only one of them exists, as that is more likely a mistake in the
+/// settings than a certificate to replace.
+pub fn ensure_self_signed(
+    certificate_file: &Path,
+    key_file: &Path,
+    names: &[String],
+) -> Result<()> {
+    match (certificate_file.exists(), key_file.exists()) {
+        (true, true) => return Ok(()),
+        (false, false) => {}
+        (true, false) => return Err(anyhow!("{} does not exist", key_file.display())),
+        (false, true) => return Err(anyhow!("{} does not exist", certificate_file.display())),
+    }
+    info!(
+        "Generating a self-signed certificate for {} in {}",
+        names.join(", "),
+        certificate_file.display()
+    );
+    let certificate = rcgen::generate_simple_self_signed(names.to_vec())
+        .context("Failed to generate a certificate")?;
+    let pem = certificate
+        .serialize_pem()
+        .context("Failed to generate a certificate")?;
+    write_private(key_file, &certificate.serialize_private_key_pem())?;
+    fs::write(certificate_file, pem)
+        .with_context(|| format!("Failed to write {}", certificate_file.display()))
+}
+
+/// Write `contents` to a file only the owner can read.
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    std::io::Write::write_all(&mut file, contents.as_bytes())
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
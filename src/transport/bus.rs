@@ -0,0 +1,113 @@
+use pin_project::pin_project;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// A wrapper around an AsyncRead+AsyncWrite that gives way to another master on the same line.
+///
+/// Anything that arrives between frames, after a response has been read and before the next
+/// request, was sent by someone else, such as the vendor's own software talking to the controller.
+/// Before the first write of a frame, this reads and discards whatever has arrived and waits until
+/// nothing more has for `quiet`, so the two masters take turns instead of talking over each other.
+#[pin_project]
+pub struct SharedBusPort<T> {
+    #[pin]
+    inner: T,
+    /// `None` to write straight away, as the only master.
+    quiet: Option<Duration>,
+    /// When another master was last heard.
+    last_foreign: Option<Instant>,
+    in_frame: bool,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> SharedBusPort<T> {
+    pub fn new(inner: T, quiet: Option<Duration>) -> Self {
+        Self {
+            inner,
+            quiet,
+            last_foreign: None,
+            in_frame: false,
+            delay: None,
+        }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for SharedBusPort<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let before = buf.filled().len();
+        let result = this.inner.poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            if buf.filled().len() > before {
+                *this.in_frame = false;
+            }
+        }
+        result
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> AsyncWrite for SharedBusPort<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let mut this = self.project();
+        if let (Some(quiet), false) = (*this.quiet, *this.in_frame) {
+            loop {
+                // Polling the read side also wakes this write when more arrives.
+                let mut discard = [0; 64];
+                let mut discard = ReadBuf::new(&mut discard);
+                match this.inner.as_mut().poll_read(cx, &mut discard) {
+                    Poll::Ready(Ok(())) if !discard.filled().is_empty() => {
+                        *this.last_foreign = Some(Instant::now());
+                        *this.delay = None;
+                    }
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    // The end of the stream, or nothing waiting.
+                    Poll::Ready(Ok(())) | Poll::Pending => break,
+                }
+            }
+            if let Some(last_foreign) = *this.last_foreign {
+                let remaining = quiet.saturating_sub(last_foreign.elapsed());
+                if !remaining.is_zero() {
+                    let delay = this
+                        .delay
+                        .get_or_insert_with(|| Box::pin(tokio::time::sleep(remaining)));
+                    if delay.as_mut().poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                }
+            }
+            *this.delay = None;
+        }
+        let result = this.inner.poll_write(cx, buf);
+        if let Poll::Ready(Ok(_)) = result {
+            *this.in_frame = true;
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.project();
+        let result = this.inner.poll_flush(cx);
+        if let Poll::Ready(Ok(())) = result {
+            *this.in_frame = false;
+        }
+        result
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.project();
+        this.inner.poll_shutdown(cx)
+    }
+}
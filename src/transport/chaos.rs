@@ -0,0 +1,137 @@
+use pin_project::pin_project;
+use serde::Deserialize;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// How often each kind of fault happens, as a probability per read.
+#[derive(Clone, Default, Deserialize)]
+pub struct Faults {
+    /// Hold back a read for `delay_ms`.
+    #[serde(default)]
+    pub delay_probability: f64,
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// Lose the end of the bytes read.
+    #[serde(default)]
+    pub truncate_probability: f64,
+    /// Flip one bit of the bytes read.
+    #[serde(default)]
+    pub bit_flip_probability: f64,
+    /// Seed for choosing faults, to make a run repeatable.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+/// A wrapper around an AsyncRead+AsyncWrite that damages data read through it, for development.
+///
+/// This imitates a noisy or loose RS-485 connection so the retry and recovery logic can be tried
+/// without one. Writes are passed through unchanged.
+#[pin_project]
+pub struct ChaosPort<T> {
+    #[pin]
+    inner: T,
+    faults: Faults,
+    rng: u64,
+    delay: Option<Pin<Box<Sleep>>>,
+    /// Whether the current read has already been delayed.
+    delayed: bool,
+}
+
+impl<T> ChaosPort<T> {
+    pub fn new(inner: T, faults: Faults) -> Self {
+        let seed = faults.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64
+        });
+        Self {
+            inner,
+            faults,
+            // xorshift gets stuck at zero.
+            rng: seed | 1,
+            delay: None,
+            delayed: false,
+        }
+    }
+}
+
+/// The next number from an xorshift64* generator.
+fn next(rng: &mut u64) -> u64 {
+    *rng ^= *rng >> 12;
+    *rng ^= *rng << 25;
+    *rng ^= *rng >> 27;
+    rng.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
+
+fn roll(rng: &mut u64, probability: f64) -> bool {
+    // The top 53 bits, as a fraction between 0 and 1.
+    let sample = (next(rng) >> 11) as f64 / (1u64 << 53) as f64;
+    probability > 0.0 && sample < probability
+}
+
+impl<T: AsyncRead> AsyncRead for ChaosPort<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        if !*this.delayed && this.delay.is_none() && roll(this.rng, this.faults.delay_probability) {
+            let delay = Duration::from_millis(this.faults.delay_ms);
+            *this.delay = Some(Box::pin(tokio::time::sleep(delay)));
+        }
+        if let Some(delay) = this.delay.as_mut() {
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            *this.delay = None;
+            *this.delayed = true;
+        }
+
+        let before = buf.filled().len();
+        let result = this.inner.poll_read(cx, buf);
+        if result.is_pending() {
+            return result;
+        }
+        *this.delayed = false;
+        let read = buf.filled().len() - before;
+        // Returning nothing would look like the end of the stream.
+        if read > 1 && roll(this.rng, this.faults.truncate_probability) {
+            let keep = 1 + next(this.rng) as usize % (read - 1);
+            buf.set_filled(before + keep);
+        }
+        let read = buf.filled().len() - before;
+        if read > 0 && roll(this.rng, this.faults.bit_flip_probability) {
+            let bit = next(this.rng) as usize % (read * 8);
+            buf.filled_mut()[before + bit / 8] ^= 1 << (bit % 8);
+        }
+        result
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for ChaosPort<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.project();
+        this.inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.project();
+        this.inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.project();
+        this.inner.poll_shutdown(cx)
+    }
+}
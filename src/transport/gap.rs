@@ -0,0 +1,113 @@
+use pin_project::pin_project;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// The silence Modbus RTU requires between frames at a baud rate.
+///
+/// This is 3.5 characters of 11 bits each, except that above 19200 baud the specification fixes it
+/// at 1.75ms.
+pub fn silent_interval(baud: u32) -> Duration {
+    if baud > 19200 {
+        Duration::from_micros(1750)
+    } else {
+        Duration::from_secs_f64(3.5 * 11.0 / f64::from(baud.max(1)))
+    }
+}
+
+/// A wrapper around an AsyncRead+AsyncWrite that keeps the line quiet between frames.
+///
+/// A frame is everything written between flushes. Before the first write of a frame, this waits
+/// until `gap` has passed since anything was last read or written, so a retry sent straight after
+/// a timeout or a response cannot run into it on adapters that do not enforce the gap themselves.
+#[pin_project]
+pub struct GapPort<T> {
+    #[pin]
+    inner: T,
+    gap: Duration,
+    last_activity: Option<Instant>,
+    in_frame: bool,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> GapPort<T> {
+    pub fn new(inner: T, gap: Duration) -> Self {
+        Self {
+            inner,
+            gap,
+            last_activity: None,
+            in_frame: false,
+            delay: None,
+        }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for GapPort<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let before = buf.filled().len();
+        let result = this.inner.poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            if buf.filled().len() > before {
+                *this.last_activity = Some(Instant::now());
+                *this.in_frame = false;
+            }
+        }
+        result
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for GapPort<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.project();
+        if !*this.in_frame {
+            if let Some(last_activity) = *this.last_activity {
+                let remaining = this.gap.saturating_sub(last_activity.elapsed());
+                if !remaining.is_zero() {
+                    let delay = this
+                        .delay
+                        .get_or_insert_with(|| Box::pin(tokio::time::sleep(remaining)));
+                    if delay.as_mut().poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                }
+            }
+            *this.delay = None;
+        }
+        let result = this.inner.poll_write(cx, buf);
+        if let Poll::Ready(Ok(_)) = result {
+            *this.last_activity = Some(Instant::now());
+            *this.in_frame = true;
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.project();
+        let result = this.inner.poll_flush(cx);
+        if let Poll::Ready(Ok(())) = result {
+            if *this.in_frame {
+                *this.last_activity = Some(Instant::now());
+                *this.in_frame = false;
+            }
+        }
+        result
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.project();
+        this.inner.poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context as _};
+use log::{log_enabled, trace, Level};
+use pin_project::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    Read,
+    Write,
+}
+
+/// A wrapper around an AsyncRead+AsyncWrite that shows every chunk of data passing through it to a
+/// callback.
+///
+/// The callback is given the direction, the bytes, and the time the bytes were seen.
+#[pin_project]
+pub struct InspectPort<T, F> {
+    #[pin]
+    inner: T,
+    inspect: F,
+}
+
+impl<T, F: FnMut(Direction, &[u8], SystemTime)> InspectPort<T, F> {
+    pub fn new(inner: T, inspect: F) -> Self {
+        Self { inner, inspect }
+    }
+}
+
+impl<T: AsyncRead, F: FnMut(Direction, &[u8], SystemTime)> AsyncRead for InspectPort<T, F> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let before = buf.filled().len();
+        let result = this.inner.poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let bytes = &buf.filled()[before..];
+            if !bytes.is_empty() {
+                (this.inspect)(Direction::Read, bytes, SystemTime::now());
+            }
+        }
+        result
+    }
+}
+
+impl<T: AsyncWrite, F: FnMut(Direction, &[u8], SystemTime)> AsyncWrite for InspectPort<T, F> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.project();
+        let result = this.inner.poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            if written > 0 {
+                (this.inspect)(Direction::Write, &buf[..written], SystemTime::now());
+            }
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.project();
+        this.inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.project();
+        this.inner.poll_shutdown(cx)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    text.split_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect()
+}
+
+/// Log every chunk at trace level.
+pub fn trace_chunk(direction: Direction, bytes: &[u8]) {
+    if log_enabled!(Level::Trace) {
+        trace!("{:?} {}", direction, hex(bytes));
+    }
+}
+
+/// Counts of traffic seen on the serial port.
+#[derive(Default)]
+pub struct PortMetrics {
+    pub bytes_read: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub chunks_read: AtomicU64,
+    pub chunks_written: AtomicU64,
+}
+
+impl PortMetrics {
+    pub fn record(&self, direction: Direction, bytes: &[u8]) {
+        let (count, chunks) = match direction {
+            Direction::Read => (&self.bytes_read, &self.chunks_read),
+            Direction::Write => (&self.bytes_written, &self.chunks_written),
+        };
+        count.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        chunks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "bytes_read": self.bytes_read.load(Ordering::Relaxed),
+            "bytes_written": self.bytes_written.load(Ordering::Relaxed),
+            "chunks_read": self.chunks_read.load(Ordering::Relaxed),
+            "chunks_written": self.chunks_written.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Writes every chunk to a file, one JSON object per line:
+/// `{"time": <seconds since the epoch>, "direction": "read"|"write", "bytes": "<hex>"}`.
+pub struct Capture {
+    file: File,
+}
+
+impl Capture {
+    pub fn create(path: &str) -> anyhow::Result<Self> {
+        let file = File::create(path).context("Failed to create capture file")?;
+        Ok(Self { file })
+    }
+
+    pub fn record(&mut self, direction: Direction, bytes: &[u8], time: SystemTime) {
+        let line = serde_json::json!({
+            "time": time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+            "direction": match direction {
+                Direction::Read => "read",
+                Direction::Write => "write",
+            },
+            "bytes": hex(bytes),
+        });
+        // Losing part of a capture is not worth interrupting the desk for.
+        let _ = writeln!(self.file, "{}", line);
+    }
+}
+
+/// Plays a capture back in place of the serial port, to reproduce a problem without the desk.
+///
+/// Writes are checked against the captured writes, in order, and reads are given the captured
+/// reads that followed them. A write that differs from the capture fails, as does anything after
+/// the end of the capture.
+pub struct ReplayPort {
+    chunks: VecDeque<(Direction, Vec<u8>)>,
+    /// How much of the first chunk has been read or written already.
+    offset: usize,
+    /// A read waiting for the next write to be replayed.
+    reader: Option<Waker>,
+}
+
+impl ReplayPort {
+    pub fn new(chunks: impl IntoIterator<Item = (Direction, Vec<u8>)>) -> Self {
+        Self {
+            chunks: chunks.into_iter().collect(),
+            offset: 0,
+            reader: None,
+        }
+    }
+
+    /// Load a capture written by `Capture`.
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let file = File::open(path).context("Failed to open capture file")?;
+        let mut chunks = Vec::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.context("Failed to read capture file")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let chunk = serde_json::from_str::<serde_json::Value>(&line)
+                .ok()
+                .and_then(|chunk| {
+                    let direction = match chunk["direction"].as_str()? {
+                        "read" => Direction::Read,
+                        "write" => Direction::Write,
+                        _ => return None,
+                    };
+                    Some((direction, unhex(chunk["bytes"].as_str()?)?))
+                })
+                .ok_or_else(|| anyhow!("Invalid chunk on line {} of the capture", number + 1))?;
+            chunks.push(chunk);
+        }
+        Ok(Self::new(chunks))
+    }
+
+    fn advance(&mut self, count: usize) {
+        self.offset += count;
+        if self.offset == self.chunks[0].1.len() {
+            self.chunks.pop_front();
+            self.offset = 0;
+        }
+    }
+}
+
+impl AsyncRead for ReplayPort {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.chunks.front() {
+            Some((Direction::Read, bytes)) => {
+                let count = buf.remaining().min(bytes.len() - this.offset);
+                buf.put_slice(&bytes[this.offset..this.offset + count]);
+                this.advance(count);
+                Poll::Ready(Ok(()))
+            }
+            // Nothing arrives until the write the capture has next.
+            Some((Direction::Write, _)) => {
+                this.reader = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl AsyncWrite for ReplayPort {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        let expected = match this.chunks.front() {
+            Some((Direction::Write, bytes)) => &bytes[this.offset..],
+            Some((Direction::Read, _)) => {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the capture has more to read before the next write",
+                )))
+            }
+            None => {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the capture has ended",
+                )))
+            }
+        };
+        let count = buf.len().min(expected.len());
+        if buf[..count] != expected[..count] {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "wrote {} where the capture has {}",
+                    hex(&buf[..count]),
+                    hex(&expected[..count])
+                ),
+            )));
+        }
+        this.advance(count);
+        if let Some(reader) = this.reader.take() {
+            reader.wake();
+        }
+        Poll::Ready(Ok(count))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
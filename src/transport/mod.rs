@@ -0,0 +1,8 @@
+pub mod bus;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod gap;
+pub mod inspect;
+pub mod timeout;
+pub mod transfer;
+pub mod wake;
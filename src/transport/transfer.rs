@@ -0,0 +1,374 @@
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{mpsc, oneshot, watch};
+
+/// A wrapper type that allows ownership of an async read+write object to be forcibly transferred.
+///
+/// This is a workaround for problems with tokio-modbus and tokio-serial. If a read timeout occurs,
+/// tokio-modbus does not recover, so we need to reset. However, disconnecting and dropping the
+/// modbus `Context` object does not close the serial port handle, so trying to open the serial port
+/// again for the new context will fail.
+///
+/// The port is owned by a pair of tasks, one reading and one writing, which perform operations
+/// requested by handles. When `take` is called, a `TransferPortHandle` implementing `AsyncRead` and
+/// `AsyncWrite` is returned. Calling `take` again will invalidate the old handle, causing its
+/// operation in progress and all its later operations to immediately fail, before returning a new
+/// handle.
+///
+/// Must be created from within a tokio runtime.
+pub struct TransferPort<T> {
+    shared: Arc<Shared>,
+    reads: mpsc::UnboundedSender<ReadRequest>,
+    writes: mpsc::UnboundedSender<WriteRequest>,
+    inner: PhantomData<fn() -> T>,
+}
+
+/// A handle granting revokable access to an AsyncRead+AsyncWrite.
+pub struct TransferPortHandle<T> {
+    id: usize,
+    shared: Arc<Shared>,
+    reads: mpsc::UnboundedSender<ReadRequest>,
+    writes: mpsc::UnboundedSender<WriteRequest>,
+    pending_read: Option<oneshot::Receiver<io::Result<Vec<u8>>>>,
+    pending_write: Option<(WriteKind, oneshot::Receiver<io::Result<usize>>)>,
+    inner: PhantomData<fn() -> T>,
+}
+
+/// Counts of how the port has been handed out.
+///
+/// Revocations are handles taken away while they were waiting to read or write, which happens
+/// when the controller stops responding. A rising count can be the first sign of a failing adapter.
+#[derive(Default)]
+pub struct TransferMetrics {
+    pub handles_opened: AtomicU64,
+    pub handles_closed: AtomicU64,
+    pub revocations: AtomicU64,
+}
+
+impl TransferMetrics {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "handles_opened": self.handles_opened.load(Ordering::Relaxed),
+            "handles_closed": self.handles_closed.load(Ordering::Relaxed),
+            "revocations": self.revocations.load(Ordering::Relaxed),
+        })
+    }
+}
+
+struct Shared {
+    last_owner: AtomicUsize,
+    owner: watch::Sender<usize>,
+    /// The last owner counted as revoked, so an owner interrupted while both reading and writing
+    /// is only counted once.
+    last_revoked: AtomicUsize,
+    metrics: Arc<TransferMetrics>,
+}
+
+struct Request<Op, Reply> {
+    owner: usize,
+    op: Op,
+    reply: oneshot::Sender<io::Result<Reply>>,
+}
+
+/// A request to read up to the given number of bytes.
+type ReadRequest = Request<usize, Vec<u8>>;
+type WriteRequest = Request<WriteOp, usize>;
+
+enum WriteOp {
+    Write(Vec<u8>),
+    Flush,
+    Shutdown,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum WriteKind {
+    Write,
+    Flush,
+    Shutdown,
+}
+
+impl<T: AsyncRead + AsyncWrite + Send + 'static> TransferPort<T> {
+    pub fn new(inner: T, metrics: Arc<TransferMetrics>) -> Self {
+        let (owner, _) = watch::channel(0);
+        let shared = Arc::new(Shared {
+            last_owner: AtomicUsize::new(0),
+            owner,
+            last_revoked: AtomicUsize::new(0),
+            metrics,
+        });
+        let (reads, writes) = spawn_loops(inner, &shared);
+        Self {
+            shared,
+            reads,
+            writes,
+            inner: PhantomData,
+        }
+    }
+
+    /// Use `inner` from now on, such as after the serial adapter was plugged in again under
+    /// another name.
+    ///
+    /// The current handle is revoked as by `take`, and the old port is closed once the handles
+    /// taken from it, and any clones of this made before, are gone.
+    pub fn replace(&mut self, inner: T) {
+        let id = self.shared.last_owner.fetch_add(1, Ordering::SeqCst) + 1;
+        let _ = self.shared.owner.send(id);
+        let (reads, writes) = spawn_loops(inner, &self.shared);
+        self.reads = reads;
+        self.writes = writes;
+    }
+}
+
+/// Start the tasks that read and write `inner` for handles.
+fn spawn_loops<T: AsyncRead + AsyncWrite + Send + 'static>(
+    inner: T,
+    shared: &Arc<Shared>,
+) -> (
+    mpsc::UnboundedSender<ReadRequest>,
+    mpsc::UnboundedSender<WriteRequest>,
+) {
+    let (reads, read_requests) = mpsc::unbounded_channel();
+    let (writes, write_requests) = mpsc::unbounded_channel();
+    let (read_half, write_half) = tokio::io::split(inner);
+    tokio::spawn(read_loop(
+        read_half,
+        read_requests,
+        shared.owner.subscribe(),
+        shared.clone(),
+    ));
+    tokio::spawn(write_loop(
+        write_half,
+        write_requests,
+        shared.owner.subscribe(),
+        shared.clone(),
+    ));
+    (reads, writes)
+}
+
+impl<T> TransferPort<T> {
+    /// Disconnect the current user's handle and get a new handle.
+    ///
+    /// The operation in progress will fail, and further attempts to use the previous handle will
+    /// return `BrokenPipe`.
+    pub fn take(&self) -> TransferPortHandle<T> {
+        let id = self.shared.last_owner.fetch_add(1, Ordering::SeqCst) + 1;
+        // The loops only stop once every sender is gone, which includes this one.
+        let _ = self.shared.owner.send(id);
+        self.shared
+            .metrics
+            .handles_opened
+            .fetch_add(1, Ordering::Relaxed);
+        TransferPortHandle {
+            id,
+            shared: self.shared.clone(),
+            reads: self.reads.clone(),
+            writes: self.writes.clone(),
+            pending_read: None,
+            pending_write: None,
+            inner: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for TransferPort<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            reads: self.reads.clone(),
+            writes: self.writes.clone(),
+            inner: PhantomData,
+        }
+    }
+}
+
+impl Shared {
+    fn record_revocation(&self, owner: usize) {
+        if self.last_revoked.swap(owner, Ordering::Relaxed) != owner {
+            self.metrics.revocations.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn broken_pipe() -> io::Error {
+    io::Error::from(io::ErrorKind::BrokenPipe)
+}
+
+async fn read_loop<R: AsyncRead + Unpin>(
+    mut inner: R,
+    mut requests: mpsc::UnboundedReceiver<ReadRequest>,
+    mut owner: watch::Receiver<usize>,
+    shared: Arc<Shared>,
+) {
+    while let Some(request) = requests.recv().await {
+        let mut buf = vec![0; request.op];
+        let result = run(&mut owner, &shared, request.owner, async {
+            let read = inner.read(&mut buf).await?;
+            buf.truncate(read);
+            Ok(buf)
+        })
+        .await;
+        let _ = request.reply.send(result);
+    }
+}
+
+async fn write_loop<W: AsyncWrite + Unpin>(
+    mut inner: W,
+    mut requests: mpsc::UnboundedReceiver<WriteRequest>,
+    mut owner: watch::Receiver<usize>,
+    shared: Arc<Shared>,
+) {
+    while let Some(request) = requests.recv().await {
+        let result = run(&mut owner, &shared, request.owner, async {
+            match &request.op {
+                WriteOp::Write(data) => inner.write(data).await,
+                WriteOp::Flush => inner.flush().await.map(|_| 0),
+                WriteOp::Shutdown => inner.shutdown().await.map(|_| 0),
+            }
+        })
+        .await;
+        let _ = request.reply.send(result);
+    }
+}
+
+/// Run an operation for a handle, abandoning it if the port is taken by another handle.
+async fn run<R>(
+    owner: &mut watch::Receiver<usize>,
+    shared: &Shared,
+    id: usize,
+    operation: impl Future<Output = io::Result<R>>,
+) -> io::Result<R> {
+    if *owner.borrow_and_update() != id {
+        return Err(broken_pipe());
+    }
+    tokio::pin!(operation);
+    loop {
+        tokio::select! {
+            result = &mut operation => return result,
+            changed = owner.changed() => {
+                if changed.is_err() || *owner.borrow_and_update() != id {
+                    shared.record_revocation(id);
+                    return Err(broken_pipe());
+                }
+            }
+        }
+    }
+}
+
+impl<T> TransferPortHandle<T> {
+    /// Send a write, flush, or shutdown and wait for it to finish.
+    ///
+    /// If a different kind of operation was abandoned before it finished, it is waited for and
+    /// its result is discarded first.
+    fn poll_write_op(
+        &mut self,
+        cx: &mut Context<'_>,
+        kind: WriteKind,
+        op: impl FnOnce() -> WriteOp,
+    ) -> Poll<io::Result<usize>> {
+        let mut op = Some(op);
+        loop {
+            match &mut self.pending_write {
+                None => {
+                    let (reply, receiver) = oneshot::channel();
+                    let op = (op.take().expect("operation sent twice"))();
+                    self.writes
+                        .send(Request {
+                            owner: self.id,
+                            op,
+                            reply,
+                        })
+                        .map_err(|_| broken_pipe())?;
+                    self.pending_write = Some((kind, receiver));
+                }
+                Some((pending_kind, receiver)) => {
+                    let result = match Pin::new(receiver).poll(cx) {
+                        Poll::Ready(result) => result,
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    let pending_kind = *pending_kind;
+                    self.pending_write = None;
+                    if pending_kind == kind {
+                        return Poll::Ready(result.map_err(|_| broken_pipe()).and_then(|r| r));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<T> AsyncRead for TransferPortHandle<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pending_read.is_none() {
+            let (reply, receiver) = oneshot::channel();
+            this.reads
+                .send(Request {
+                    owner: this.id,
+                    op: buf.remaining(),
+                    reply,
+                })
+                .map_err(|_| broken_pipe())?;
+            this.pending_read = Some(receiver);
+        }
+        let receiver = this.pending_read.as_mut().unwrap();
+        let result = match Pin::new(receiver).poll(cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.pending_read = None;
+        let data = result.map_err(|_| broken_pipe())??;
+        // The buffer is normally the same one the read was requested for, but never overflow it.
+        let len = data.len().min(buf.remaining());
+        buf.put_slice(&data[..len]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> AsyncWrite for TransferPortHandle<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        self.get_mut()
+            .poll_write_op(cx, WriteKind::Write, || WriteOp::Write(buf.to_vec()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.get_mut()
+            .poll_write_op(cx, WriteKind::Flush, || WriteOp::Flush)
+            .map_ok(|_| ())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.get_mut()
+            .poll_write_op(cx, WriteKind::Shutdown, || WriteOp::Shutdown)
+            .map_ok(|_| ())
+    }
+}
+
+impl<T> Drop for TransferPortHandle<T> {
+    fn drop(&mut self) {
+        self.shared
+            .metrics
+            .handles_closed
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<T> std::fmt::Debug for TransferPortHandle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TransferPortHandle").finish()
+    }
+}
@@ -0,0 +1,103 @@
+use pin_project::pin_project;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// A wrapper around an AsyncRead+AsyncWrite that rouses the adapter before writing after a while.
+///
+/// Adapters powered from a USB hub that suspends lose the first message sent after a long idle.
+/// Before the first write once nothing has been read or written for `idle`, this calls `pulse`,
+/// which toggles the adapter's control lines, and waits `settle` for it to come back.
+#[pin_project]
+pub struct WakePort<T, F> {
+    #[pin]
+    inner: T,
+    pulse: F,
+    /// `None` to never pulse.
+    idle: Option<Duration>,
+    settle: Duration,
+    last_activity: Option<Instant>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T, F> WakePort<T, F> {
+    pub fn new(inner: T, pulse: F, idle: Option<Duration>, settle: Duration) -> Self {
+        Self {
+            inner,
+            pulse,
+            idle,
+            settle,
+            last_activity: None,
+            delay: None,
+        }
+    }
+}
+
+impl<T: AsyncRead, F> AsyncRead for WakePort<T, F> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let before = buf.filled().len();
+        let result = this.inner.poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            if buf.filled().len() > before {
+                *this.last_activity = Some(Instant::now());
+            }
+        }
+        result
+    }
+}
+
+impl<T, F> AsyncWrite for WakePort<T, F>
+where
+    T: AsyncWrite + Unpin,
+    F: FnMut(&mut T) -> io::Result<()>,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let mut this = self.project();
+        let asleep = this.idle.map_or(false, |idle| {
+            this.last_activity
+                .map_or(true, |last_activity| last_activity.elapsed() >= idle)
+        });
+        if asleep {
+            if this.delay.is_none() {
+                (this.pulse)(this.inner.as_mut().get_mut())?;
+                *this.delay = Some(Box::pin(tokio::time::sleep(*this.settle)));
+            }
+            if let Some(delay) = this.delay.as_mut() {
+                if delay.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+            }
+            *this.delay = None;
+            // Counts as activity so the write below goes ahead.
+            *this.last_activity = Some(Instant::now());
+        }
+        let result = this.inner.poll_write(cx, buf);
+        if let Poll::Ready(Ok(_)) = result {
+            *this.last_activity = Some(Instant::now());
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.project();
+        this.inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.project();
+        this.inner.poll_shutdown(cx)
+    }
+}
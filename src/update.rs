@@ -0,0 +1,246 @@
+//! Replacing the program with the latest signed release and restarting the service.
+//!
+//! Releases carry a binary for each platform, named as by `asset_name`, and an Ed25519 signature
+//! of it in base64 with `.sig` appended to the name. The public key is built in from the
+//! `LC_RELEASE_KEY` environment variable when a release is built, so builds without it cannot
+//! update themselves.
+
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use hyper::{client::HttpConnector, header, Body, Client, Request, StatusCode};
+use hyper_rustls::HttpsConnector;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::Deserialize;
+
+const USAGE: &str = "Usage: laing-controller update [--check] [--service <name>]
+
+--check only reports whether a newer release is available. The service (the Windows service or
+the systemd unit) defaults to laing-controller, and is restarted if it is running.";
+
+const LATEST_RELEASE: &str =
+    "https://api.github.com/repos/mdonoughe/laing-controller/releases/latest";
+
+/// The Ed25519 public key releases are signed with, in base64.
+const RELEASE_KEY: Option<&str> = option_env!("LC_RELEASE_KEY");
+
+/// GitHub redirects downloads to its storage servers.
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+pub fn update(args: &[String]) -> Result<()> {
+    let mut check = false;
+    let mut service = "laing-controller".to_string();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--service" => service = args.next().ok_or_else(|| anyhow!(USAGE))?.clone(),
+            _ => return Err(anyhow!(USAGE)),
+        }
+    }
+    let key = RELEASE_KEY
+        .ok_or_else(|| anyhow!("This build has no release key, so updates cannot be verified"))?;
+    let key = base64::decode(key).context("Invalid release key")?;
+
+    let release = match download_release(check, &key)? {
+        Some(release) => release,
+        None => return Ok(()),
+    };
+    println!("Installed {}", release);
+    restart(&service)
+}
+
+/// Download, check, and install the latest release if it is not the running one.
+///
+/// Returns the release installed, if any.
+#[tokio::main(flavor = "current_thread")]
+async fn download_release(check: bool, key: &[u8]) -> Result<Option<String>> {
+    let client = Client::builder().build(HttpsConnector::with_native_roots());
+    let release: Release = serde_json::from_slice(&get(&client, LATEST_RELEASE).await?)
+        .context("Failed to read the latest release")?;
+    let current = env!("CARGO_PKG_VERSION");
+    if release.tag_name.trim_start_matches('v') == current {
+        println!("Already running the latest release, {}", release.tag_name);
+        return Ok(None);
+    }
+    if check {
+        println!("{} is available; running {}", release.tag_name, current);
+        return Ok(None);
+    }
+
+    let name = asset_name();
+    let find = |name: &str| {
+        release
+            .assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| anyhow!("{} has no {}", release.tag_name, name))
+    };
+    let binary = get(&client, &find(&name)?.browser_download_url).await?;
+    let signature = get(
+        &client,
+        &find(&format!("{}.sig", name))?.browser_download_url,
+    )
+    .await?;
+    verify_signature(key, &binary, &signature)
+        .with_context(|| format!("Refusing to install {}", name))?;
+
+    replace(&std::env::current_exe()?, &binary)?;
+    Ok(Some(release.tag_name))
+}
+
+/// Check `binary` against the contents of its `.sig` file.
+pub fn verify_signature(key: &[u8], binary: &[u8], signature: &[u8]) -> Result<()> {
+    let signature = base64::decode(String::from_utf8_lossy(signature).trim())
+        .context("Invalid release signature")?;
+    UnparsedPublicKey::new(&ED25519, key)
+        .verify(binary, &signature)
+        .map_err(|_| anyhow!("The signature does not match"))
+}
+
+/// The name of the release binary for this platform, like `laing-controller-x86_64-windows.exe`.
+fn asset_name() -> String {
+    format!(
+        "laing-controller-{}-{}{}",
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+        std::env::consts::EXE_SUFFIX
+    )
+}
+
+async fn get(client: &Client<HttpsConnector<HttpConnector>>, url: &str) -> Result<Vec<u8>> {
+    let mut url = url.to_string();
+    for _ in 0..MAX_REDIRECTS {
+        let request = Request::get(&url)
+            // GitHub rejects API requests without one.
+            .header(
+                header::USER_AGENT,
+                concat!("laing-controller/", env!("CARGO_PKG_VERSION")),
+            )
+            .body(Body::empty())?;
+        let response = client
+            .request(request)
+            .await
+            .with_context(|| format!("Failed to request {}", url))?;
+        if response.status().is_redirection() {
+            url = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or_else(|| anyhow!("{} redirected without a location", url))?
+                .to_string();
+            continue;
+        }
+        if response.status() != StatusCode::OK {
+            bail!("{} returned {}", url, response.status());
+        }
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .with_context(|| format!("Failed to download {}", url))?;
+        return Ok(body.to_vec());
+    }
+    bail!("Too many redirects from {}", url)
+}
+
+/// Put `binary` in place of the executable at `exe`.
+///
+/// Windows will not overwrite a running executable but will rename one, so the old one is moved
+/// aside first and removed by the next update.
+fn replace(exe: &Path, binary: &[u8]) -> Result<()> {
+    let new = exe.with_extension("new");
+    let old = exe.with_extension("old");
+    let _ = fs::remove_file(&old);
+    fs::write(&new, binary).context("Failed to save the new executable")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&new, fs::Permissions::from_mode(0o755))
+            .context("Failed to make the new executable runnable")?;
+    }
+    fs::rename(exe, &old).context("Failed to move the old executable aside")?;
+    if let Err(err) = fs::rename(&new, exe) {
+        // Put the old one back so the service can still start.
+        let _ = fs::rename(&old, exe);
+        return Err(err).context("Failed to move the new executable into place");
+    }
+    Ok(())
+}
+
+#[cfg(all(windows, feature = "service"))]
+fn restart(name: &str) -> Result<()> {
+    use std::time::Duration;
+
+    use windows_service::{
+        service::{ServiceAccess, ServiceState},
+        service_manager::{ServiceManager, ServiceManagerAccess},
+    };
+
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = match manager.open_service(
+        name,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::START,
+    ) {
+        Ok(service) => service,
+        Err(_) => {
+            println!("There is no {} service to restart", name);
+            return Ok(());
+        }
+    };
+    if service.query_status()?.current_state == ServiceState::Stopped {
+        return Ok(());
+    }
+    service.stop()?;
+    let mut stopped = false;
+    for _ in 0..30 {
+        std::thread::sleep(Duration::from_secs(1));
+        if service.query_status()?.current_state == ServiceState::Stopped {
+            stopped = true;
+            break;
+        }
+    }
+    if !stopped {
+        bail!("The {} service did not stop", name);
+    }
+    service.start::<&str>(&[])?;
+    Ok(())
+}
+
+#[cfg(all(windows, not(feature = "service")))]
+fn restart(name: &str) -> Result<()> {
+    println!(
+        "This build cannot run as a service; restart {} yourself",
+        name
+    );
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn restart(unit: &str) -> Result<()> {
+    // try-restart leaves the unit alone if it is not running.
+    match std::process::Command::new("systemctl")
+        .args(["try-restart", unit])
+        .status()
+    {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => bail!("systemctl try-restart {} failed: {}", unit, status),
+        Err(err) => {
+            println!(
+                "Could not run systemctl ({}); restart {} yourself",
+                err, unit
+            );
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Running at login as the current user, for when a service cannot be installed.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use log::{Level, Log, Metadata, Record};
+use tokio_util::sync::CancellationToken;
+use winreg::{enums::HKEY_CURRENT_USER, RegKey};
+use winrt_notification::Toast;
+
+const RUN_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\Run";
+const RUN_VALUE: &str = "laing-controller";
+
+/// Don't show more than one notification in this time, since errors tend to repeat.
+const TOAST_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Start in user mode when the current user logs in.
+pub fn register() -> anyhow::Result<()> {
+    let exe = std::env::current_exe()?;
+    let (key, _) = RegKey::predef(HKEY_CURRENT_USER)
+        .create_subkey(RUN_KEY)
+        .context("Failed to open Run key")?;
+    key.set_value(RUN_VALUE, &format!("\"{}\" --user-mode", exe.display()))
+        .context("Failed to register")?;
+    Ok(())
+}
+
+pub fn deregister() -> anyhow::Result<()> {
+    let (key, _) = RegKey::predef(HKEY_CURRENT_USER)
+        .create_subkey(RUN_KEY)
+        .context("Failed to open Run key")?;
+    key.delete_value(RUN_VALUE)
+        .context("Failed to deregister")?;
+    Ok(())
+}
+
+/// Run without a console window, showing errors as notifications.
+pub fn run() -> anyhow::Result<()> {
+    // Started from the Run key, the console window would stay open for as long as this runs.
+    unsafe {
+        winapi::um::wincon::FreeConsole();
+    }
+
+    let level = crate::settings::log_level().unwrap_or_else(|| "info".into());
+    let inner =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).build();
+    log::set_max_level(inner.filter());
+    log::set_boxed_logger(Box::new(ToastLogger {
+        inner,
+        last_toast: Mutex::new(None),
+    }))?;
+
+    // Nothing cancels this; it runs until logoff.
+    let stop = CancellationToken::new();
+    let result = crate::Main::init().and_then(|main| crate::run_until_stopped(main, stop));
+    if let Err(err) = &result {
+        show_toast(&format!("Stopped: {}", err));
+    }
+    result
+}
+
+/// Passes messages to `env_logger` and shows errors as notifications.
+struct ToastLogger {
+    inner: env_logger::Logger,
+    last_toast: Mutex<Option<Instant>>,
+}
+
+impl Log for ToastLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.log(record);
+        if record.level() == Level::Error {
+            let mut last_toast = self.last_toast.lock().unwrap();
+            if last_toast.map_or(true, |last| last.elapsed() >= TOAST_INTERVAL) {
+                *last_toast = Some(Instant::now());
+                show_toast(&record.args().to_string());
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+fn show_toast(message: &str) {
+    // There is nowhere left to report a failure to show an error.
+    let _ = Toast::new(Toast::POWERSHELL_APP_ID)
+        .title("Laing Controller")
+        .text1(message)
+        .show();
+}
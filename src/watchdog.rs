@@ -0,0 +1,101 @@
+//! Noticing when a loop has stopped making progress, so it can be restarted instead of leaving the
+//! desk unresponsive until someone notices.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// When a loop last made progress, and what it was doing then.
+///
+/// The loop beats each time around and while it waits, so a heartbeat that has gone quiet means
+/// the loop is stuck in the middle of something.
+pub struct Heartbeat {
+    epoch: Instant,
+    /// Milliseconds from `epoch` to the last beat.
+    last: AtomicU64,
+    activity: Mutex<&'static str>,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+            last: AtomicU64::new(0),
+            activity: Mutex::new("starting"),
+        }
+    }
+}
+
+impl Heartbeat {
+    pub fn beat(&self, activity: &'static str) {
+        self.beat_at(Instant::now(), activity);
+    }
+
+    pub fn beat_at(&self, now: Instant, activity: &'static str) {
+        let millis = now.saturating_duration_since(self.epoch).as_millis() as u64;
+        self.last.store(millis, Ordering::Relaxed);
+        *self.activity.lock().unwrap() = activity;
+    }
+
+    /// How long it has been since the last beat.
+    pub fn age(&self, now: Instant) -> Duration {
+        let last = self.epoch + Duration::from_millis(self.last.load(Ordering::Relaxed));
+        now.saturating_duration_since(last)
+    }
+
+    pub fn activity(&self) -> &'static str {
+        *self.activity.lock().unwrap()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "secs_since_progress": self.age(Instant::now()).as_secs_f32(),
+            "activity": self.activity(),
+        })
+    }
+}
+
+/// What to do about a loop after checking its heartbeat.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Healthy,
+    /// The loop has stalled, so restart it.
+    Restart,
+    /// A restart was asked for and has not happened yet.
+    Waiting,
+    /// Restarting did not help, so only starting the whole process again is left.
+    Exit,
+}
+
+/// Keeps track of a restart asked for because a heartbeat went quiet.
+pub struct Watch {
+    stall: Duration,
+    restarted_at: Option<Instant>,
+}
+
+impl Watch {
+    pub fn new(stall: Duration) -> Self {
+        Self {
+            stall,
+            restarted_at: None,
+        }
+    }
+
+    pub fn check(&mut self, heartbeat: &Heartbeat, now: Instant) -> Verdict {
+        if heartbeat.age(now) < self.stall {
+            self.restarted_at = None;
+            return Verdict::Healthy;
+        }
+        match self.restarted_at {
+            None => {
+                self.restarted_at = Some(now);
+                Verdict::Restart
+            }
+            // A loop that cannot even be restarted is likely blocking the runtime's only thread.
+            Some(restarted_at) if now.saturating_duration_since(restarted_at) >= self.stall => {
+                Verdict::Exit
+            }
+            Some(_) => Verdict::Waiting,
+        }
+    }
+}
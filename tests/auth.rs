@@ -0,0 +1,99 @@
+#[path = "../src/auth.rs"]
+mod auth;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use auth::{check_age, CommandAuth};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+const KEY: &str = "correct horse battery staple";
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn sign(key: &str, command: &str, timestamp: u64) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+    mac.update(format!("{}:{}", timestamp, command).as_bytes());
+    let signature: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    serde_json::to_vec(&serde_json::json!({
+        "command": command,
+        "timestamp": timestamp,
+        "signature": signature,
+    }))
+    .unwrap()
+}
+
+#[test]
+fn signed_commands_are_accepted() {
+    let mut auth = CommandAuth::new(KEY, 30);
+    assert_eq!(auth.verify(&sign(KEY, "2", now())).as_deref(), Some("2"));
+    assert_eq!(auth.verify(&sign(KEY, "UP", now())).as_deref(), Some("UP"));
+}
+
+#[test]
+fn unsigned_commands_are_ignored() {
+    let mut auth = CommandAuth::new(KEY, 30);
+    assert_eq!(auth.verify(b"2"), None);
+    assert_eq!(auth.verify(br#"{"command": "2", "timestamp": 0}"#), None);
+}
+
+#[test]
+fn tampered_commands_are_ignored() {
+    let mut auth = CommandAuth::new(KEY, 30);
+    let timestamp = now();
+
+    let mut signed: serde_json::Value = serde_json::from_slice(&sign(KEY, "1", timestamp)).unwrap();
+    signed["command"] = "4".into();
+    assert_eq!(auth.verify(&serde_json::to_vec(&signed).unwrap()), None);
+
+    assert_eq!(auth.verify(&sign("another key", "1", timestamp)), None);
+
+    let mut signed: serde_json::Value = serde_json::from_slice(&sign(KEY, "1", timestamp)).unwrap();
+    signed["signature"] = "not hex".into();
+    assert_eq!(auth.verify(&serde_json::to_vec(&signed).unwrap()), None);
+}
+
+#[test]
+fn stale_commands_are_ignored() {
+    let mut auth = CommandAuth::new(KEY, 30);
+    assert_eq!(auth.verify(&sign(KEY, "2", now() - 60)), None);
+    assert_eq!(auth.verify(&sign(KEY, "2", now() + 60)), None);
+}
+
+#[test]
+fn replayed_commands_are_ignored() {
+    let mut auth = CommandAuth::new(KEY, 30);
+    let signed = sign(KEY, "2", now());
+    assert_eq!(auth.verify(&signed).as_deref(), Some("2"));
+    assert_eq!(auth.verify(&signed), None);
+
+    // The same command signed again later is a new command.
+    assert_eq!(
+        auth.verify(&sign(KEY, "2", now() + 1)).as_deref(),
+        Some("2")
+    );
+}
+
+#[test]
+fn kept_commands_are_checked_by_age() {
+    let timestamped = |timestamp: u64| {
+        serde_json::to_vec(&serde_json::json!({"command": "2", "timestamp": timestamp})).unwrap()
+    };
+    assert_eq!(check_age(&timestamped(now()), 60).as_deref(), Some("2"));
+    assert_eq!(
+        check_age(&timestamped(now() - 30), 60).as_deref(),
+        Some("2")
+    );
+    assert_eq!(check_age(&timestamped(now() - 120), 60), None);
+    assert_eq!(check_age(b"2", 60), None);
+}
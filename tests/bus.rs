@@ -0,0 +1,52 @@
+#[path = "../src/transport/bus.rs"]
+mod bus;
+
+use std::time::{Duration, Instant};
+
+use bus::SharedBusPort;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[tokio::test]
+async fn writes_straight_away_when_alone() {
+    let (port, mut controller) = tokio::io::duplex(64);
+    let mut port = SharedBusPort::new(port, Some(Duration::from_millis(50)));
+    let start = Instant::now();
+    port.write_all(&[1, 2]).await.unwrap();
+    port.flush().await.unwrap();
+    assert!(start.elapsed() < Duration::from_millis(50));
+
+    let mut received = [0; 2];
+    controller.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, [1, 2]);
+}
+
+#[tokio::test]
+async fn waits_for_another_master() {
+    let quiet = Duration::from_millis(50);
+    let (port, mut other) = tokio::io::duplex(64);
+    let mut port = SharedBusPort::new(port, Some(quiet));
+
+    // Traffic between requests is discarded, and the request waits for it to stop.
+    other.write_all(&[9, 9, 9]).await.unwrap();
+    let start = Instant::now();
+    port.write_all(&[1]).await.unwrap();
+    port.flush().await.unwrap();
+    assert!(start.elapsed() >= quiet);
+
+    // The response to the request is read as usual.
+    other.write_all(&[2]).await.unwrap();
+    let mut response = [0; 1];
+    port.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, [2]);
+}
+
+#[tokio::test]
+async fn passes_through_without_quiet_period() {
+    let (port, mut other) = tokio::io::duplex(64);
+    let mut port = SharedBusPort::new(port, None);
+    other.write_all(&[9]).await.unwrap();
+    port.write_all(&[1]).await.unwrap();
+    let mut received = [0; 1];
+    port.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, [9]);
+}
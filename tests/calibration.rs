@@ -0,0 +1,34 @@
+#[path = "../src/calibration.rs"]
+mod calibration;
+
+use calibration::HeightCorrection;
+
+#[test]
+fn no_points_leaves_heights_alone() {
+    let correction = HeightCorrection::new([]).unwrap();
+    assert_eq!(correction.apply(250), 250);
+}
+
+#[test]
+fn single_point_is_an_offset() {
+    let correction = HeightCorrection::new([(30.0, 30.7)]).unwrap();
+    assert_eq!(correction.apply(250), 257);
+    assert_eq!(correction.apply(450), 457);
+}
+
+#[test]
+fn interpolates_between_points_and_extends_beyond_them() {
+    // Reads 0.7 low at the bottom and 1.2 low at the top.
+    let correction = HeightCorrection::new([(45.0, 46.2), (25.0, 25.7)]).unwrap();
+    assert_eq!(correction.apply(250), 257);
+    assert_eq!(correction.apply(350), 360);
+    assert_eq!(correction.apply(450), 462);
+    // The slope between the points carries on past them.
+    assert_eq!(correction.apply(150), 155);
+    assert_eq!(correction.apply(500), 513);
+}
+
+#[test]
+fn rejects_two_corrections_for_one_height() {
+    assert!(HeightCorrection::new([(25.0, 25.7), (25.0, 26.0)]).is_err());
+}
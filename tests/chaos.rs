@@ -0,0 +1,136 @@
+#[path = "../src/transport/chaos.rs"]
+mod chaos;
+#[path = "../src/transport/timeout.rs"]
+mod timeout;
+
+use std::time::{Duration, Instant};
+
+use chaos::{ChaosPort, Faults};
+use timeout::TimeoutPort;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const FRAME: [u8; 8] = [0x01, 0x17, 0x04, 0x80, 0x6d, 0x00, 0x5b, 0xe2];
+
+fn faults() -> Faults {
+    Faults {
+        seed: Some(42),
+        ..Faults::default()
+    }
+}
+
+#[tokio::test]
+async fn passes_data_through_without_faults() {
+    let (mut controller, port) = tokio::io::duplex(64);
+    let mut port = ChaosPort::new(port, faults());
+    controller.write_all(&FRAME).await.unwrap();
+    let mut received = [0; 8];
+    port.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, FRAME);
+}
+
+#[tokio::test]
+async fn flips_one_bit() {
+    let (mut controller, port) = tokio::io::duplex(64);
+    let mut port = ChaosPort::new(
+        port,
+        Faults {
+            bit_flip_probability: 1.0,
+            ..faults()
+        },
+    );
+    controller.write_all(&FRAME).await.unwrap();
+    let mut received = [0; 8];
+    port.read_exact(&mut received).await.unwrap();
+    let flipped: u32 = received
+        .iter()
+        .zip(FRAME.iter())
+        .map(|(a, b)| (a ^ b).count_ones())
+        .sum();
+    // Each read flips a bit, and the frame may have taken more than one read.
+    assert!(flipped >= 1);
+}
+
+#[tokio::test]
+async fn delays_reads() {
+    let (mut controller, port) = tokio::io::duplex(64);
+    let mut port = ChaosPort::new(
+        port,
+        Faults {
+            delay_probability: 1.0,
+            delay_ms: 50,
+            ..faults()
+        },
+    );
+    controller.write_all(&FRAME).await.unwrap();
+    let start = Instant::now();
+    let mut received = [0; 8];
+    port.read_exact(&mut received).await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert_eq!(received, FRAME);
+}
+
+#[tokio::test]
+async fn truncated_frame_times_out() {
+    let (mut controller, port) = tokio::io::duplex(64);
+    let mut port = TimeoutPort::new(
+        ChaosPort::new(
+            port,
+            Faults {
+                truncate_probability: 1.0,
+                ..faults()
+            },
+        ),
+        Duration::from_millis(50),
+    );
+    controller.write_all(&FRAME).await.unwrap();
+    let mut received = [0; 8];
+    let err = port.read_exact(&mut received).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+}
+
+/// Retrying a request, as is done when waking the controller, gets an intact response through an
+/// unreliable line.
+#[tokio::test]
+async fn retries_recover() {
+    let (mut controller, port) = tokio::io::duplex(64);
+    let mut port = TimeoutPort::new(
+        ChaosPort::new(
+            port,
+            Faults {
+                truncate_probability: 0.3,
+                bit_flip_probability: 0.3,
+                ..faults()
+            },
+        ),
+        Duration::from_millis(50),
+    );
+    tokio::spawn(async move {
+        let mut request = [0; 1];
+        while controller.read_exact(&mut request).await.is_ok() {
+            controller.write_all(&FRAME).await.unwrap();
+        }
+    });
+
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        assert!(attempts <= 50, "never received an intact frame");
+        port.write_all(&[0]).await.unwrap();
+        let mut received = [0; 8];
+        match port.read_exact(&mut received).await {
+            Ok(_) if received == FRAME => break,
+            // Let anything left of a damaged frame arrive before trying again.
+            _ => {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                let mut discard = [0; 64];
+                while let Ok(Ok(n)) =
+                    tokio::time::timeout(Duration::from_millis(10), port.read(&mut discard)).await
+                {
+                    if n == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,93 @@
+#[path = "../src/compat.rs"]
+#[allow(dead_code)]
+mod compat;
+#[path = "../src/error.rs"]
+#[allow(dead_code)]
+mod error;
+#[path = "../src/hassio.rs"]
+#[allow(dead_code)]
+mod hassio;
+#[path = "../src/protocol.rs"]
+#[allow(dead_code)]
+mod protocol;
+#[path = "../src/settings.rs"]
+#[allow(dead_code)]
+mod settings;
+#[cfg(feature = "chaos")]
+#[path = "../src/transport"]
+#[allow(dead_code)]
+mod transport {
+    pub mod chaos;
+}
+
+use std::collections::BTreeMap;
+
+use compat::{
+    domoticz_height_payload, homie_command_topic, homie_height_topic, homie_messages,
+    homie_state_topic,
+};
+use settings::Settings;
+
+fn settings() -> Settings {
+    serde_yaml::from_str(
+        "
+serial_port: /dev/ttyUSB0
+id: office
+name: Office Desk
+mqtt:
+  host: localhost
+",
+    )
+    .unwrap()
+}
+
+#[test]
+fn homie_topics_are_under_the_device() {
+    let settings = settings();
+    assert_eq!(homie_height_topic(&settings), "homie/office/desk/height");
+    assert_eq!(
+        homie_command_topic(&settings),
+        "homie/office/desk/command/set"
+    );
+    assert_eq!(homie_state_topic(&settings), "homie/office/$state");
+}
+
+#[test]
+fn homie_describes_the_device() {
+    let messages: BTreeMap<String, String> =
+        homie_messages(&settings(), &["1", "2", "REFRESH", "sit"])
+            .into_iter()
+            .collect();
+    assert_eq!(messages["homie/office/$homie"], "4.0");
+    assert_eq!(messages["homie/office/$name"], "Office Desk");
+    assert_eq!(messages["homie/office/$nodes"], "desk");
+    assert_eq!(messages["homie/office/desk/$properties"], "height,command");
+    assert_eq!(messages["homie/office/desk/height/$datatype"], "float");
+    assert_eq!(messages["homie/office/desk/height/$unit"], "in");
+    assert_eq!(messages["homie/office/desk/command/$datatype"], "enum");
+    assert_eq!(
+        messages["homie/office/desk/command/$format"],
+        "1,2,REFRESH,sit"
+    );
+    assert_eq!(messages["homie/office/desk/command/$settable"], "true");
+    // $state is published separately, as the device comes and goes.
+    assert!(!messages.contains_key("homie/office/$state"));
+}
+
+#[test]
+fn homie_leaves_out_commands_containing_commas() {
+    let messages: BTreeMap<String, String> = homie_messages(&settings(), &["1", "sit, please"])
+        .into_iter()
+        .collect();
+    assert_eq!(messages["homie/office/desk/command/$format"], "1");
+}
+
+#[test]
+fn domoticz_receives_the_height_as_text() {
+    let payload: serde_json::Value =
+        serde_json::from_str(&domoticz_height_payload(42, 28.5)).unwrap();
+    assert_eq!(
+        payload,
+        serde_json::json!({"idx": 42, "nvalue": 0, "svalue": "28.5"})
+    );
+}
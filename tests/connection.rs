@@ -0,0 +1,50 @@
+#[path = "../src/connection.rs"]
+#[allow(dead_code)]
+mod connection;
+#[path = "../src/transport"]
+#[allow(dead_code)]
+mod transport {
+    pub mod transfer;
+}
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use connection::{Connection, Health};
+use tokio_modbus::prelude::*;
+use transport::transfer::{TransferMetrics, TransferPort};
+
+#[test]
+fn failures_count_until_a_success() {
+    let mut health = Health::default();
+    assert_eq!(health.consecutive_failures, 0);
+    health.record_failure();
+    health.record_failure();
+    assert_eq!(health.consecutive_failures, 2);
+    assert!(health.last_success.is_none());
+
+    health.record_success();
+    assert_eq!(health.consecutive_failures, 0);
+    assert!(health.last_success.is_some());
+}
+
+#[tokio::test]
+async fn reconnecting_takes_a_new_handle() {
+    let (port, _controller) = tokio::io::duplex(64);
+    let metrics = Arc::new(TransferMetrics::default());
+    let port = TransferPort::new(port, metrics.clone());
+
+    let mut connection = Connection::open(&port, Slave(1)).await.unwrap();
+    assert_eq!(metrics.handles_opened.load(Ordering::Relaxed), 1);
+
+    connection.record(&Err::<(), _>("no answer"));
+    connection.reconnect().await.unwrap();
+    assert_eq!(metrics.handles_opened.load(Ordering::Relaxed), 2);
+    assert_eq!(connection.health().reconnects, 1);
+    // Reconnecting is not an answer from the controller.
+    assert_ne!(connection.health().consecutive_failures, 0);
+
+    connection.record(&Ok::<_, ()>(()));
+    assert_eq!(connection.health().consecutive_failures, 0);
+    connection.close().await.unwrap();
+}
@@ -0,0 +1,42 @@
+#[path = "../src/transport/gap.rs"]
+mod gap;
+
+use std::time::{Duration, Instant};
+
+use gap::{silent_interval, GapPort};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[test]
+fn interval_from_baud() {
+    assert_eq!(silent_interval(57600), Duration::from_micros(1750));
+    assert_eq!(
+        silent_interval(9600),
+        Duration::from_secs_f64(38.5 / 9600.0)
+    );
+}
+
+#[tokio::test]
+async fn waits_between_frames_only() {
+    let gap = Duration::from_millis(50);
+    let (port, mut controller) = tokio::io::duplex(64);
+    let mut port = GapPort::new(port, gap);
+
+    // Writes within a frame are not held back.
+    let start = Instant::now();
+    port.write_all(&[1, 2]).await.unwrap();
+    port.write_all(&[3, 4]).await.unwrap();
+    port.flush().await.unwrap();
+    assert!(start.elapsed() < gap);
+
+    // The next frame waits for the line to be quiet, counting from the last response byte.
+    controller.write_all(&[5]).await.unwrap();
+    let mut response = [0; 1];
+    port.read_exact(&mut response).await.unwrap();
+    let start = Instant::now();
+    port.write_all(&[6]).await.unwrap();
+    assert!(start.elapsed() >= gap);
+
+    let mut received = [0; 5];
+    controller.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, [1, 2, 3, 4, 6]);
+}
@@ -0,0 +1,47 @@
+#[path = "../src/transport/inspect.rs"]
+#[allow(dead_code)]
+mod inspect;
+
+use std::sync::{Arc, Mutex};
+
+use inspect::{Capture, Direction, InspectPort, ReplayPort};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[tokio::test]
+async fn replays_what_was_captured() {
+    let path = std::env::temp_dir().join(format!("laing-capture-{}.jsonl", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    let (port, mut controller) = tokio::io::duplex(64);
+    let capture = Arc::new(Mutex::new(Capture::create(path).unwrap()));
+    let recorder = capture.clone();
+    let mut port = InspectPort::new(port, move |direction, bytes: &[u8], time| {
+        recorder.lock().unwrap().record(direction, bytes, time);
+    });
+    port.write_all(&[1, 2, 3]).await.unwrap();
+    let mut request = [0; 3];
+    controller.read_exact(&mut request).await.unwrap();
+    controller.write_all(&[4, 5]).await.unwrap();
+    let mut response = [0; 2];
+    port.read_exact(&mut response).await.unwrap();
+    drop(port);
+    drop(capture);
+
+    let mut replay = ReplayPort::open(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    replay.write_all(&[1, 2]).await.unwrap();
+    replay.write_all(&[3]).await.unwrap();
+    let mut response = [0; 2];
+    replay.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, [4, 5]);
+    // The capture has ended.
+    assert_eq!(replay.read(&mut response).await.unwrap(), 0);
+    assert!(replay.write_all(&[1]).await.is_err());
+}
+
+#[tokio::test]
+async fn rejects_writes_that_differ() {
+    let mut replay = ReplayPort::new([(Direction::Write, vec![1, 2]), (Direction::Read, vec![3])]);
+    let err = replay.write_all(&[1, 9]).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
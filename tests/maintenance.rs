@@ -0,0 +1,58 @@
+#[path = "../src/maintenance.rs"]
+#[allow(dead_code)]
+mod maintenance;
+
+use chrono::NaiveDate;
+
+use maintenance::{parse_date, MaintenanceRecord, Reminder};
+
+fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(year, month, day).unwrap()
+}
+
+#[test]
+fn dates_are_parsed() {
+    assert_eq!(parse_date("2024-03-09"), Some(date(2024, 3, 9)));
+    assert_eq!(parse_date(" 2024-3-9 "), Some(date(2024, 3, 9)));
+    assert_eq!(parse_date("2024-02-30"), None);
+    assert_eq!(parse_date("March 9"), None);
+}
+
+#[test]
+fn counting_starts_from_the_configured_date() {
+    let mut record = MaintenanceRecord::default();
+    assert!(record.start(Some(date(2024, 1, 1)), date(2024, 3, 1), 500));
+    assert_eq!(record.done_on.as_deref(), Some("2024-01-01"));
+    assert_eq!(record.travel_at, 500);
+
+    // A later reset is kept over an older configured date.
+    record.reset(date(2024, 2, 1), 700);
+    assert!(!record.start(Some(date(2024, 1, 1)), date(2024, 3, 1), 900));
+    assert_eq!(record.done_on.as_deref(), Some("2024-02-01"));
+
+    // Changing the setting to a newer date takes over.
+    assert!(record.start(Some(date(2024, 2, 15)), date(2024, 3, 1), 900));
+    assert_eq!(record.done_on.as_deref(), Some("2024-02-15"));
+    assert_eq!(record.travel_at, 900);
+}
+
+#[test]
+fn due_after_enough_days_or_travel() {
+    let reminder = Reminder {
+        every_days: Some(90),
+        every_travel: Some(10_000),
+    };
+    let mut record = MaintenanceRecord::default();
+    record.reset(date(2024, 1, 1), 2_000);
+
+    let status = reminder.status(&record, 5_000, date(2024, 3, 1));
+    assert!(!status.due);
+    assert_eq!(status.days_since, Some(60));
+    assert_eq!(status.travel_since, 3_000);
+
+    assert!(reminder.status(&record, 5_000, date(2024, 3, 31)).due);
+    assert!(reminder.status(&record, 12_000, date(2024, 1, 2)).due);
+
+    record.reset(date(2024, 4, 1), 12_000);
+    assert!(!reminder.status(&record, 12_000, date(2024, 4, 1)).due);
+}
@@ -0,0 +1,64 @@
+#[path = "../src/reconnect.rs"]
+#[allow(dead_code)]
+mod reconnect;
+
+use std::time::{Duration, Instant};
+
+use reconnect::{Backoff, RateLimiter};
+
+#[test]
+fn backoff_doubles_up_to_the_limit() {
+    let mut backoff = Backoff::with_seed(
+        Duration::from_secs(1),
+        Duration::from_secs(5),
+        Duration::ZERO,
+        1,
+    );
+    let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+    assert_eq!(delays, [1, 2, 4, 5, 5]);
+
+    backoff.reset();
+    assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+}
+
+#[test]
+fn jitter_stays_within_its_range() {
+    let mut backoff = Backoff::with_seed(
+        Duration::from_secs(1),
+        Duration::from_secs(1),
+        Duration::from_millis(500),
+        7,
+    );
+    let delays: Vec<Duration> = (0..100).map(|_| backoff.next_delay()).collect();
+    assert!(delays
+        .iter()
+        .all(|delay| (Duration::from_secs(1)..=Duration::from_millis(1500)).contains(delay)));
+    // Desks that failed together should not all pick the same delay.
+    assert!(delays.iter().any(|&delay| delay != delays[0]));
+}
+
+#[test]
+fn rate_limiter_spaces_out_attempts() {
+    let mut limiter = RateLimiter::default();
+    let period = Duration::from_secs(60);
+    let now = Instant::now();
+    assert_eq!(limiter.reserve(2, period, now), Duration::ZERO);
+    assert_eq!(
+        limiter.reserve(2, period, now + Duration::from_secs(10)),
+        Duration::ZERO
+    );
+    // The third waits for the first to be a minute old, and the fourth for the second.
+    assert_eq!(
+        limiter.reserve(2, period, now + Duration::from_secs(20)),
+        Duration::from_secs(40)
+    );
+    assert_eq!(
+        limiter.reserve(2, period, now + Duration::from_secs(20)),
+        Duration::from_secs(50)
+    );
+    // Once attempts are over a minute old they no longer count.
+    assert_eq!(
+        limiter.reserve(2, period, now + Duration::from_secs(200)),
+        Duration::ZERO
+    );
+}
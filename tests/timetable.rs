@@ -0,0 +1,77 @@
+#[path = "../src/timetable.rs"]
+#[allow(dead_code)]
+mod timetable;
+
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+
+use timetable::{SkewCheck, Timetable, RESYNC};
+
+fn at(hour: u32, minute: u32, second: u32) -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(2024, 3, 9)
+        .unwrap()
+        .and_hms_opt(hour, minute, second)
+        .unwrap()
+}
+
+fn time(hour: u32, minute: u32) -> NaiveTime {
+    NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+}
+
+#[test]
+fn commands_run_once_when_due() {
+    let mut timetable = Timetable::new(vec![(time(9, 0), "1"), (time(17, 30), "4")]);
+    assert!(timetable.due(at(8, 59, 59)).is_empty());
+    assert_eq!(timetable.due(at(9, 0, 0)), ["1"]);
+    assert!(timetable.due(at(9, 1, 0)).is_empty());
+
+    // Going back in time does not run it again.
+    assert!(timetable.due(at(9, 0, 30)).is_empty());
+}
+
+#[test]
+fn late_commands_run_within_the_grace_period() {
+    let mut timetable = Timetable::new(vec![(time(9, 0), "1")]);
+    assert_eq!(timetable.due(at(9, 2, 0)), ["1"]);
+
+    let mut timetable = Timetable::new(vec![(time(9, 0), "1")]);
+    assert!(timetable.due(at(9, 2, 1)).is_empty());
+}
+
+#[test]
+fn waits_until_the_next_command_or_resync() {
+    let timetable = Timetable::new(vec![(time(9, 0), "1"), (time(17, 30), "4")]);
+    assert_eq!(timetable.delay(at(8, 59, 30)), Duration::from_secs(30));
+    assert_eq!(timetable.delay(at(12, 0, 0)), RESYNC);
+
+    // Past the last one today, the first one tomorrow is next.
+    let timetable = Timetable::new(vec![(time(0, 0), "1"), (time(9, 0), "2")]);
+    assert_eq!(timetable.delay(at(23, 59, 50)), Duration::from_secs(10));
+
+    let empty = Timetable::<&str>::new(Vec::new());
+    assert_eq!(empty.delay(at(12, 0, 0)), RESYNC);
+}
+
+#[test]
+fn clock_jumps_are_noticed() {
+    let wall = SystemTime::now();
+    let monotonic = Instant::now();
+    let slept = Duration::from_secs(60);
+
+    let check = SkewCheck::at(wall, monotonic);
+    assert_eq!(check.finish_at(wall + slept, monotonic + slept), None);
+
+    // Small drift is not a jump.
+    let check = SkewCheck::at(wall, monotonic);
+    let drifted = wall + slept + Duration::from_secs(2);
+    assert_eq!(check.finish_at(drifted, monotonic + slept), None);
+
+    let check = SkewCheck::at(wall, monotonic);
+    let forward = wall + slept + Duration::from_secs(3600);
+    assert_eq!(check.finish_at(forward, monotonic + slept), Some(3600.0));
+
+    let check = SkewCheck::at(wall, monotonic);
+    let backward = wall - Duration::from_secs(3600);
+    assert_eq!(check.finish_at(backward, monotonic + slept), Some(-3660.0));
+}
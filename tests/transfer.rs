@@ -0,0 +1,75 @@
+#[path = "../src/transport/transfer.rs"]
+mod transfer;
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use transfer::{TransferMetrics, TransferPort};
+
+#[tokio::test]
+async fn taking_revokes_the_previous_handle() {
+    let (port, mut device) = tokio::io::duplex(64);
+    let port = TransferPort::new(port, Arc::new(TransferMetrics::default()));
+
+    let mut old = port.take();
+    let mut new = port.take();
+    let err = old.write_all(&[1]).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+
+    new.write_all(&[2]).await.unwrap();
+    let mut received = [0; 1];
+    device.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, [2]);
+}
+
+#[tokio::test]
+async fn replacing_moves_handles_to_the_new_port() {
+    let (first, mut first_device) = tokio::io::duplex(64);
+    let (second, mut second_device) = tokio::io::duplex(64);
+    let mut port = TransferPort::new(first, Arc::new(TransferMetrics::default()));
+
+    let mut old = port.take();
+    old.write_all(&[1]).await.unwrap();
+    let mut received = [0; 1];
+    first_device.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, [1]);
+
+    port.replace(second);
+    let err = old.write_all(&[2]).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+
+    let mut new = port.take();
+    new.write_all(&[3]).await.unwrap();
+    second_device.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, [3]);
+    second_device.write_all(&[4]).await.unwrap();
+    new.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, [4]);
+
+    // The old port is closed once nothing uses it.
+    drop(old);
+    assert_eq!(first_device.read(&mut received).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn revocations_are_counted() {
+    let (port, _device) = tokio::io::duplex(64);
+    let metrics = Arc::new(TransferMetrics::default());
+    let port = TransferPort::new(port, metrics.clone());
+
+    // The old handle is waiting for a read when the port is taken from it.
+    let mut old = port.take();
+    let mut received = [0; 1];
+    let (read, new) = tokio::join!(old.read(&mut received), async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        port.take()
+    });
+    assert_eq!(read.unwrap_err().kind(), std::io::ErrorKind::BrokenPipe);
+
+    drop(old);
+    drop(new);
+    assert_eq!(
+        metrics.to_json(),
+        serde_json::json!({"handles_opened": 2, "handles_closed": 2, "revocations": 1})
+    );
+}
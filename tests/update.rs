@@ -0,0 +1,65 @@
+#[path = "../src/update.rs"]
+#[allow(dead_code)]
+mod update;
+
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+
+use update::verify_signature;
+
+const BINARY: &[u8] = b"\x7fELF not really a release";
+
+fn key_pair() -> Ed25519KeyPair {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+    Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+}
+
+fn sig_file(key_pair: &Ed25519KeyPair, binary: &[u8]) -> Vec<u8> {
+    // As uploaded, with a trailing newline.
+    format!("{}\n", base64::encode(key_pair.sign(binary))).into_bytes()
+}
+
+#[test]
+fn signed_releases_are_accepted() {
+    let key_pair = key_pair();
+    verify_signature(
+        key_pair.public_key().as_ref(),
+        BINARY,
+        &sig_file(&key_pair, BINARY),
+    )
+    .unwrap();
+}
+
+#[test]
+fn changed_releases_are_refused() {
+    let key_pair = key_pair();
+    let mut binary = BINARY.to_vec();
+    binary[4] ^= 1;
+    assert!(verify_signature(
+        key_pair.public_key().as_ref(),
+        &binary,
+        &sig_file(&key_pair, BINARY)
+    )
+    .is_err());
+}
+
+#[test]
+fn releases_signed_with_another_key_are_refused() {
+    let trusted = key_pair();
+    assert!(verify_signature(
+        trusted.public_key().as_ref(),
+        BINARY,
+        &sig_file(&key_pair(), BINARY)
+    )
+    .is_err());
+}
+
+#[test]
+fn malformed_signatures_are_refused() {
+    let key_pair = key_pair();
+    let public_key = key_pair.public_key().as_ref();
+    assert!(verify_signature(public_key, BINARY, b"not base64!").is_err());
+    assert!(verify_signature(public_key, BINARY, b"").is_err());
+}
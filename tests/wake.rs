@@ -0,0 +1,61 @@
+#[path = "../src/transport/wake.rs"]
+mod wake;
+
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use wake::WakePort;
+
+#[tokio::test]
+async fn pulses_only_after_idle() {
+    let settle = Duration::from_millis(50);
+    let idle = Duration::from_millis(200);
+    let (port, mut controller) = tokio::io::duplex(64);
+    let mut pulses = 0;
+    let mut port = WakePort::new(
+        port,
+        |_: &mut DuplexStream| {
+            pulses += 1;
+            Ok(())
+        },
+        Some(idle),
+        settle,
+    );
+
+    // The first write waits for the adapter to settle.
+    let start = Instant::now();
+    port.write_all(&[1]).await.unwrap();
+    assert!(start.elapsed() >= settle);
+
+    // Writes soon after do not.
+    controller.write_all(&[2]).await.unwrap();
+    let mut response = [0; 1];
+    port.read_exact(&mut response).await.unwrap();
+    let start = Instant::now();
+    port.write_all(&[3]).await.unwrap();
+    assert!(start.elapsed() < settle);
+
+    tokio::time::sleep(idle).await;
+    port.write_all(&[4]).await.unwrap();
+
+    let mut received = [0; 3];
+    controller.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, [1, 3, 4]);
+    drop(port);
+    assert_eq!(pulses, 2);
+}
+
+#[tokio::test]
+async fn never_pulses_without_idle() {
+    let (port, mut controller) = tokio::io::duplex(64);
+    let mut port = WakePort::new(
+        port,
+        |_: &mut DuplexStream| panic!("pulsed"),
+        None,
+        Duration::from_secs(1),
+    );
+    port.write_all(&[1]).await.unwrap();
+    let mut received = [0; 1];
+    controller.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, [1]);
+}
@@ -0,0 +1,48 @@
+#[path = "../src/watchdog.rs"]
+#[allow(dead_code)]
+mod watchdog;
+
+use std::time::{Duration, Instant};
+
+use watchdog::{Heartbeat, Verdict, Watch};
+
+#[test]
+fn heartbeat_ages_from_the_last_beat() {
+    let heartbeat = Heartbeat::default();
+    let start = Instant::now();
+    heartbeat.beat_at(start, "waiting");
+    assert_eq!(heartbeat.age(start + Duration::from_secs(5)).as_secs(), 5);
+    assert_eq!(heartbeat.activity(), "waiting");
+
+    heartbeat.beat_at(start + Duration::from_secs(4), "running");
+    assert_eq!(heartbeat.age(start + Duration::from_secs(5)).as_secs(), 1);
+    assert_eq!(heartbeat.activity(), "running");
+}
+
+#[test]
+fn stalled_loop_is_restarted_then_given_up_on() {
+    let heartbeat = Heartbeat::default();
+    let start = Instant::now();
+    heartbeat.beat_at(start, "running");
+    let mut watch = Watch::new(Duration::from_secs(60));
+
+    let at = |secs| start + Duration::from_secs(secs);
+    assert_eq!(watch.check(&heartbeat, at(59)), Verdict::Healthy);
+    assert_eq!(watch.check(&heartbeat, at(60)), Verdict::Restart);
+    assert_eq!(watch.check(&heartbeat, at(90)), Verdict::Waiting);
+    assert_eq!(watch.check(&heartbeat, at(120)), Verdict::Exit);
+}
+
+#[test]
+fn recovered_loop_can_be_restarted_again() {
+    let heartbeat = Heartbeat::default();
+    let start = Instant::now();
+    heartbeat.beat_at(start, "running");
+    let mut watch = Watch::new(Duration::from_secs(60));
+
+    let at = |secs| start + Duration::from_secs(secs);
+    assert_eq!(watch.check(&heartbeat, at(60)), Verdict::Restart);
+    heartbeat.beat_at(at(70), "waiting");
+    assert_eq!(watch.check(&heartbeat, at(80)), Verdict::Healthy);
+    assert_eq!(watch.check(&heartbeat, at(130)), Verdict::Restart);
+}